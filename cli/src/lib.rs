@@ -3,6 +3,8 @@
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
 use reqwest;
+use sha2::{Digest, Sha256};
+use xmbl_node_identity::{NodeIdentity, SignedEnvelope};
 
 // REAL STORAGE TYPES
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -31,6 +33,51 @@ pub struct NetworkStatus {
     pub network_status: String,
 }
 
+// COMPUTE TYPES, as returned by /api/compute/tasks
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputeTaskResult {
+    pub output_data: Vec<u8>,
+    pub execution_time_ms: u64,
+    pub success: bool,
+    pub error_message: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputeTaskRecord {
+    pub task_id: String,
+    pub submitter: String,
+    pub status: String,
+    pub submitted_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub result: Option<ComputeTaskResult>,
+}
+
+impl ComputeTaskRecord {
+    fn summary(&self) -> String {
+        let mut lines = vec![
+            format!("  Task ID: {}", self.task_id),
+            format!("  Status: {}", self.status),
+            format!("  Submitter: {}", self.submitter),
+            format!("  Submitted: {}", self.submitted_at),
+        ];
+        if let Some(at) = self.started_at {
+            lines.push(format!("  Started: {}", at));
+        }
+        if let Some(at) = self.finished_at {
+            lines.push(format!("  Finished: {}", at));
+        }
+        if let Some(result) = &self.result {
+            lines.push(format!("  Execution time: {}ms", result.execution_time_ms));
+            lines.push(format!("  Output: {} bytes", result.output_data.len()));
+            if let Some(error) = &result.error_message {
+                lines.push(format!("  Error: {}", error));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
 // CLI COMMANDS
 #[derive(Clone, Debug)]
 pub enum CliCommand {
@@ -43,6 +90,7 @@ pub enum CliCommand {
     StorageStats,
     ComputeSubmit { wasm_file: String, input_file: String },
     ComputeStatus { task_id: String },
+    ComputeHistory { submitter: Option<String>, status: Option<String> },
     BlockchainBalance { address: String },
     BlockchainTransfer { from: String, to: String, amount: u64 },
     NetworkPeers,
@@ -53,13 +101,16 @@ pub enum CliCommand {
 pub struct CliService {
     pub node_id: String,
     pub api_url: String,
+    // Signs requests the API only takes from a known node, like compute
+    pub identity: Option<NodeIdentity>,
 }
 
 impl CliService {
     pub fn new(node_id: String) -> Self {
         CliService { 
             node_id,
-            api_url: "http://localhost:3200".to_string()
+            api_url: "http://localhost:3200".to_string(),
+            identity: None,
         }
     }
     
@@ -87,11 +138,13 @@ impl CliService {
                 self.storage_stats().await
             }
             CliCommand::ComputeSubmit { wasm_file, input_file } => {
-                Ok(format!("Submitting compute task:\n  WASM file: {}\n  Input file: {}\n  Task ID: task_{}", 
-                    wasm_file, input_file, wasm_file.replace("/", "_")))
+                self.compute_submit(wasm_file, input_file).await
             }
             CliCommand::ComputeStatus { task_id } => {
-                Ok(format!("Task status for {}: Completed", task_id))
+                self.compute_status(task_id).await
+            }
+            CliCommand::ComputeHistory { submitter, status } => {
+                self.compute_history(submitter, status).await
             }
             CliCommand::BlockchainBalance { address } => {
                 Ok(format!("Balance for {}: 1000 XMBL", address))
@@ -190,10 +243,101 @@ impl CliService {
             stats.total_gb, stats.used_gb, stats.shard_count, stats.available_nodes))
     }
     
+    // REAL COMPUTE METHODS
+    async fn compute_submit(&self, wasm_file: String, input_file: String) -> Result<String> {
+        let wasm_bytes = std::fs::read(&wasm_file)?;
+        let input_data = std::fs::read(&input_file)?;
+        let identity = self.identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Submitting compute tasks needs a node identity"))?;
+        let client = reqwest::Client::new();
+        
+        // Signed for the API's own node ID, over the module's hash, the same
+        // one the API computes
+        let api: serde_json::Value = client.get(format!("{}/api/identity", self.api_url))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let api_node_id = api["node_id"].as_str()
+            .ok_or_else(|| anyhow::anyhow!("API did not say which node it is"))?;
+        let auth = serde_json::json!({
+            "action": "compute",
+            "shard_id": hex::encode(Sha256::digest(&wasm_bytes)),
+        });
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        let envelope = SignedEnvelope::seal(identity, api_node_id, &auth, now)?;
+        
+        let response = client.post(format!("{}/api/compute/submit", self.api_url))
+            .header("x-xmbl-auth", serde_json::to_string(&envelope)?)
+            .json(&serde_json::json!({
+                "wasm_bytes": wasm_bytes,
+                "input_data": input_data,
+            }))
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            return Ok(format!("❌ Compute submission rejected: HTTP {}\n  {}", status, response.text().await?));
+        }
+        
+        let submitted: serde_json::Value = response.json().await?;
+        Ok(format!("⚡ Compute task submitted:\n  WASM file: {}\n  Input file: {}\n  Task ID: {}", 
+            wasm_file, input_file, submitted["task_id"].as_str().unwrap_or_default()))
+    }
+    
+    async fn compute_status(&self, task_id: String) -> Result<String> {
+        let client = reqwest::Client::new();
+        
+        let response = client.get(format!("{}/api/compute/tasks/{}", self.api_url, task_id))
+            .send()
+            .await?;
+        
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(format!("❌ Task not found: {}", task_id));
+        }
+        if !response.status().is_success() {
+            return Ok(format!("❌ Failed to connect to compute API: HTTP {}", response.status()));
+        }
+        
+        let record: ComputeTaskRecord = response.json().await?;
+        Ok(format!("⚡ Compute Task:\n\n{}", record.summary()))
+    }
+    
+    async fn compute_history(&self, submitter: Option<String>, status: Option<String>) -> Result<String> {
+        let client = reqwest::Client::new();
+        
+        let mut query = Vec::new();
+        if let Some(submitter) = submitter {
+            query.push(("submitter", submitter));
+        }
+        if let Some(status) = status {
+            query.push(("status", status));
+        }
+        let response = client.get(format!("{}/api/compute/tasks", self.api_url))
+            .query(&query)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            return Ok(format!("❌ Failed to query compute API: HTTP {}", response.status()));
+        }
+        
+        let records: Vec<ComputeTaskRecord> = response.json().await?;
+        if records.is_empty() {
+            return Ok("⚡ No matching compute tasks".to_string());
+        }
+        let list = records.iter()
+            .map(ComputeTaskRecord::summary)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        Ok(format!("⚡ Compute tasks ({} total):\n\n{}", records.len(), list))
+    }
+    
     async fn network_status(&self) -> Result<String> {
         let client = reqwest::Client::new();
         
-        let response = client.get(&format!("{}/api/network/status", self.api_url))
+        let response = client.get(format!("{}/api/network/status", self.api_url))
             .send()
             .await?;
         
@@ -234,4 +378,15 @@ mod tests {
         assert!(result.contains("test_node"));
         assert!(result.contains("Running"));
     }
+
+    #[test]
+    fn test_compute_task_record_from_api_json() {
+        let json = r#"{"task_id":"t1","submitter":"alice","task_type":"WASM","priority":1,"status":"Failed",
+            "submitted_at":10,"started_at":11,"finished_at":12,
+            "result":{"task_id":"t1","output_data":[],"execution_time_ms":5,"success":false,"error_message":"trap"}}"#;
+        let record: ComputeTaskRecord = serde_json::from_str(json).unwrap();
+        let summary = record.summary();
+        assert!(summary.contains("Status: Failed"));
+        assert!(summary.contains("Error: trap"));
+    }
 }
@@ -1,11 +1,16 @@
 use xmbl_cli::{CliService, CliCommand};
 use std::env;
+use xmbl_node_identity::NodeIdentity;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
     
-    let cli = CliService::new("demo_node_001".to_string());
+    // Requests that need signing use this key; XMBL_IDENTITY picks another
+    let identity_path = env::var("XMBL_IDENTITY").unwrap_or_else(|_| "cli_identity.key".to_string());
+    let identity = NodeIdentity::load_or_create(identity_path)?;
+    let mut cli = CliService::new(identity.node_id.clone());
+    cli.identity = Some(identity);
     
     if args.len() < 2 {
         println!("XMBL CLI - Available commands:");
@@ -18,6 +23,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("  storage-stats");
         println!("  compute-submit <wasm_file> <input_file>");
         println!("  compute-status <task_id>");
+        println!("  compute-history [submitter] [status]");
         println!("  blockchain-balance <address>");
         println!("  blockchain-transfer <from> <to> <amount>");
         println!("  network-peers");
@@ -66,6 +72,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             CliCommand::ComputeStatus { task_id: args[2].clone() }
         },
+        "compute-history" => CliCommand::ComputeHistory {
+            submitter: args.get(2).cloned(),
+            status: args.get(3).cloned(),
+        },
         "blockchain-balance" => {
             if args.len() < 3 {
                 println!("Usage: blockchain-balance <address>");
@@ -0,0 +1,269 @@
+// XMBL Batch - one module mapped over many inputs, then reduced
+//
+// A job applies the same module to every input (or every chunk of a file)
+// as independent map tasks, at most `parallelism` at a time. A map task that
+// fails is retried up to `max_attempts` times in all; the runner is told the
+// attempt number so it can send a retry somewhere else. Once every map task
+// has succeeded the outputs are handed to the reducer module, if any, as
+//   for each map output, in input order: len: u32 (little-endian), bytes
+// and the reducer's output is the job's output. Without a reducer the job
+// output is that encoding itself.
+//
+// Progress is tracked per job in a JobTracker, so callers ask about one job
+// ID rather than following every task. Finished jobs are dropped from it by
+// the same kind of retention policy the task journal uses.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use tokio::sync::{watch, Semaphore};
+use uuid::Uuid;
+
+use crate::journal::{self, RetentionPolicy};
+pub use xmbl_types::{JobState, JobStatus};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_PARALLELISM: usize = 8;
+pub const DEFAULT_CHUNK_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchJob {
+    pub job_id: String,
+    // Only the submitter may follow the job; empty for jobs started locally
+    pub submitter: String,
+    pub wasm_bytes: Vec<u8>,
+    pub inputs: Vec<Vec<u8>>,
+    pub reducer: Option<Vec<u8>>,
+    pub max_attempts: u32,
+    pub parallelism: usize,
+}
+
+impl BatchJob {
+    pub fn new(wasm_bytes: Vec<u8>, inputs: Vec<Vec<u8>>) -> Self {
+        BatchJob {
+            job_id: Uuid::new_v4().to_string(),
+            submitter: String::new(),
+            wasm_bytes,
+            inputs,
+            reducer: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            parallelism: DEFAULT_PARALLELISM,
+        }
+    }
+
+    // One map task per chunk_size bytes of data
+    pub fn chunked(wasm_bytes: Vec<u8>, data: &[u8], chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(anyhow::anyhow!("Chunk size must be greater than 0"));
+        }
+        Ok(Self::new(wasm_bytes, data.chunks(chunk_size).map(<[u8]>::to_vec).collect()))
+    }
+}
+
+fn initial_status(job: &BatchJob) -> JobStatus {
+    JobStatus {
+        job_id: job.job_id.clone(),
+        state: JobState::Mapping,
+        total: job.inputs.len(),
+        succeeded: 0,
+        failed: Vec::new(),
+        retries: 0,
+        output: None,
+        error: None,
+        finished_at: None,
+    }
+}
+
+#[derive(Debug)]
+struct TrackedJob {
+    submitter: String,
+    status: watch::Sender<JobStatus>,
+}
+
+#[derive(Debug)]
+pub struct JobTracker {
+    jobs: Mutex<HashMap<String, TrackedJob>>,
+    // Finished jobs hold their whole output, so keep fewer than the journal
+    pub retention: RetentionPolicy,
+}
+
+impl Default for JobTracker {
+    fn default() -> Self {
+        JobTracker {
+            jobs: Mutex::default(),
+            retention: RetentionPolicy {
+                max_age_secs: Some(24 * 60 * 60),
+                max_records: Some(1_000),
+                max_output_bytes: Some(64 * 1024 * 1024),
+            },
+        }
+    }
+}
+
+impl JobTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every new job prunes first, so the tracker stays bounded without
+    // anyone having to call prune
+    pub fn start(&self, job: &BatchJob) -> Result<()> {
+        if job.inputs.is_empty() {
+            return Err(anyhow::anyhow!("Job has no inputs"));
+        }
+        self.prune(journal::now_secs());
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.contains_key(&job.job_id) {
+            return Err(anyhow::anyhow!("Job {} already exists", job.job_id));
+        }
+        jobs.insert(job.job_id.clone(), TrackedJob {
+            submitter: job.submitter.clone(),
+            status: watch::Sender::new(initial_status(job)),
+        });
+        Ok(())
+    }
+
+    pub fn status(&self, job_id: &str) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(job_id).map(|job| job.status.borrow().clone())
+    }
+
+    // As status, but None unless the job is the submitter's own
+    pub fn status_for(&self, job_id: &str, submitter: &str) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(job_id)
+            .filter(|job| job.submitter == submitter)
+            .map(|job| job.status.borrow().clone())
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().values().map(|job| job.status.borrow().clone()).collect()
+    }
+
+    pub async fn wait(&self, job_id: &str) -> Result<JobStatus> {
+        let mut updates = self.jobs.lock().unwrap()
+            .get(job_id)
+            .map(|job| job.status.subscribe())
+            .ok_or_else(|| anyhow::anyhow!("Job not found"))?;
+        let status = updates.wait_for(JobStatus::is_finished).await?;
+        Ok(status.clone())
+    }
+
+    // Drop finished jobs the retention policy no longer covers. Returns the
+    // job IDs dropped.
+    pub fn prune(&self, now: u64) -> Vec<String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished = jobs.iter()
+            .filter_map(|(id, job)| {
+                let status = job.status.borrow();
+                let bytes = status.output.as_ref().map_or(0, |output| output.len() as u64);
+                status.finished_at.map(|at| (at, id.clone(), bytes))
+            })
+            .collect();
+        let expired = self.retention.expired(finished, now);
+        for job_id in &expired {
+            jobs.remove(job_id);
+        }
+        expired
+    }
+
+    fn update(&self, job_id: &str, change: impl FnOnce(&mut JobStatus)) {
+        if let Some(job) = self.jobs.lock().unwrap().get(job_id) {
+            job.status.send_modify(change);
+        }
+    }
+}
+
+pub fn encode_outputs(outputs: &[Vec<u8>]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for output in outputs {
+        encoded.extend_from_slice(&(output.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(output);
+    }
+    encoded
+}
+
+// Run a job registered with tracker.start. run(unit, attempt, wasm, input)
+// executes one task wherever the caller likes; units 0..total are the map
+// tasks and unit total is the reducer.
+pub async fn run_job<F, Fut>(job: BatchJob, tracker: Arc<JobTracker>, run: F) -> JobStatus
+where
+    F: Fn(usize, u32, Arc<Vec<u8>>, Vec<u8>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Vec<u8>>> + Send + 'static,
+{
+    let job_id = job.job_id.clone();
+    let total = job.inputs.len();
+    let wasm_bytes = Arc::new(job.wasm_bytes);
+    let slots = Arc::new(Semaphore::new(job.parallelism.max(1)));
+    let max_attempts = job.max_attempts.max(1);
+
+    let maps: Vec<_> = job.inputs.into_iter()
+        .enumerate()
+        .map(|(unit, input)| {
+            let (run, tracker, wasm_bytes, slots, job_id) =
+                (run.clone(), Arc::clone(&tracker), Arc::clone(&wasm_bytes), Arc::clone(&slots), job_id.clone());
+            tokio::spawn(async move {
+                let _slot = slots.acquire_owned().await;
+                let mut attempt = 0;
+                loop {
+                    match run(unit, attempt, Arc::clone(&wasm_bytes), input.clone()).await {
+                        Ok(output) => {
+                            tracker.update(&job_id, |s| s.succeeded += 1);
+                            return Ok(output);
+                        }
+                        Err(e) if attempt + 1 >= max_attempts => {
+                            log::warn!("Job {} input {} failed after {} attempts: {}", job_id, unit, max_attempts, e);
+                            tracker.update(&job_id, |s| s.failed.push(unit));
+                            return Err(e);
+                        }
+                        Err(e) => {
+                            log::debug!("Job {} input {} failed, retrying: {}", job_id, unit, e);
+                            tracker.update(&job_id, |s| s.retries += 1);
+                            attempt += 1;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut outputs = Vec::with_capacity(total);
+    let mut first_error = None;
+    for map in maps {
+        match map.await {
+            Ok(Ok(output)) => outputs.push(output),
+            Ok(Err(e)) => { first_error.get_or_insert(e.to_string()); }
+            Err(e) => { first_error.get_or_insert(format!("Map task panicked: {}", e)); }
+        }
+    }
+
+    let result = match first_error {
+        Some(error) => Err(anyhow::anyhow!("{} of {} inputs failed: {}", total - outputs.len(), total, error)),
+        None => {
+            let encoded = encode_outputs(&outputs);
+            match job.reducer {
+                Some(reducer) => {
+                    tracker.update(&job_id, |s| s.state = JobState::Reducing);
+                    run(total, 0, Arc::new(reducer), encoded).await
+                        .map_err(|e| anyhow::anyhow!("Reducer failed: {}", e))
+                }
+                None => Ok(encoded),
+            }
+        }
+    };
+
+    tracker.update(&job_id, |s| {
+        match result {
+            Ok(output) => {
+                s.state = JobState::Completed;
+                s.output = Some(output);
+            }
+            Err(e) => {
+                s.state = JobState::Failed;
+                s.error = Some(e.to_string());
+            }
+        }
+        s.finished_at = Some(journal::now_secs());
+    });
+    tracker.status(&job_id).expect("job registered before it runs")
+}
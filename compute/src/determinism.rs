@@ -0,0 +1,91 @@
+// XMBL Determinism - tasks whose output depends only on their input
+//
+// Verifying a result by comparing hashes across peers only works if every
+// peer computes the same bytes. A task submitted under the deterministic
+// profile is checked before it is queued and refused if it
+//   - uses floating point or SIMD, whose NaN bit patterns differ across
+//     hardware,
+//   - uses threads, atomics or shared memory,
+//   - imports anything besides the xmbl host functions below.
+// xmbl.fetch_shard is left out: each peer reads its own storage, so
+// replicas could see different bytes for the same shard ID.
+// xmbl.random is available to every task but is a stream seeded from the
+// task ID, so replicas that share an ID draw the same numbers.
+
+use sha2::{Sha256, Digest};
+use anyhow::Result;
+use wasmparser::{Parser, Payload, TypeRef, Validator, WasmFeatures};
+
+use crate::runtime::HOST_MODULE;
+
+pub const ALLOWED_IMPORTS: &[&str] = &[
+    "input_len",
+    "read_input",
+    "write_output",
+    "log",
+    "random",
+];
+
+pub use xmbl_types::ExecutionProfile;
+
+// Refuse modules that could produce different output on different peers
+pub fn check_module(wasm_bytes: &[u8]) -> Result<()> {
+    let features = WasmFeatures::default()
+        - WasmFeatures::FLOATS
+        - WasmFeatures::SIMD
+        - WasmFeatures::RELAXED_SIMD
+        - WasmFeatures::THREADS
+        - WasmFeatures::SHARED_EVERYTHING_THREADS;
+    Validator::new_with_features(features)
+        .validate_all(wasm_bytes)
+        .map_err(|e| anyhow::anyhow!("Module is not eligible for deterministic execution: {}", e))?;
+
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        if let Payload::ImportSection(imports) = payload? {
+            for import in imports {
+                let import = import?;
+                let allowed = import.module == HOST_MODULE
+                    && matches!(import.ty, TypeRef::Func(_))
+                    && ALLOWED_IMPORTS.contains(&import.name);
+                if !allowed {
+                    return Err(anyhow::anyhow!(
+                        "Module is not eligible for deterministic execution: import {}.{} is not allowed",
+                        import.module, import.name
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Byte stream behind xmbl.random: sha256(seed || counter) blocks
+pub struct TaskRng {
+    seed: [u8; 32],
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl TaskRng {
+    pub fn new(task_id: &str) -> Self {
+        TaskRng {
+            seed: Sha256::digest(format!("xmbl-random:{}", task_id)).into(),
+            counter: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [u8]) {
+        for byte in out.iter_mut() {
+            if self.buffer.is_empty() {
+                let mut hasher = Sha256::new();
+                hasher.update(self.seed);
+                hasher.update(self.counter.to_le_bytes());
+                self.counter += 1;
+                self.buffer = hasher.finalize().to_vec();
+                self.buffer.reverse();
+            }
+            *byte = self.buffer.pop().unwrap();
+        }
+    }
+}
@@ -0,0 +1,363 @@
+// XMBL Task Journal - what happened to every task, kept across restarts
+//
+// Each state change is appended to a JSON-lines file and synced as it
+// happens: Submitted, Started, then Finished or Failed with the task's
+// result. On open the file is replayed to rebuild the records, and tasks
+// that never finished are marked failed since the process running them is
+// gone. Finished records are dropped according to the retention policy by
+// prune(), which also rewrites the file without them; a finished task that
+// takes the outputs held over their byte budget prunes straight away.
+// Without a path the journal lives in memory only.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+use crate::{ComputeTask, TaskResult, TaskStatus, TaskType, Termination};
+
+const TMP_SUFFIX: &str = ".tmp";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event")]
+enum JournalEntry {
+    Submitted { task_id: String, submitter: String, task_type: TaskType, priority: u8, at: u64 },
+    Started { task_id: String, at: u64 },
+    Finished { result: TaskResult, at: u64 },
+    Failed { error: String, result: TaskResult, at: u64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub task_id: String,
+    pub submitter: String,
+    pub task_type: TaskType,
+    pub priority: u8,
+    pub status: TaskStatus,
+    pub submitted_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub result: Option<TaskResult>,
+}
+
+impl TaskRecord {
+    pub fn is_finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+}
+
+// All fields are optional filters; times are unix seconds of submission
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskQuery {
+    pub submitter: Option<String>,
+    pub status: Option<TaskStatus>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl TaskQuery {
+    fn matches(&self, record: &TaskRecord) -> bool {
+        self.submitter.as_ref().map(|s| *s == record.submitter).unwrap_or(true)
+            && self.status.map(|s| s == record.status).unwrap_or(true)
+            && self.since.map(|t| record.submitted_at >= t).unwrap_or(true)
+            && self.until.map(|t| record.submitted_at <= t).unwrap_or(true)
+    }
+}
+
+// Limits on finished records; unfinished tasks are always kept
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_age_secs: Option<u64>,
+    pub max_records: Option<usize>,
+    // Outputs of finished records held at once. Past it the oldest records
+    // go until three quarters of the budget is left, so a full journal isn't
+    // rewritten on every task.
+    #[serde(default)]
+    pub max_output_bytes: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age_secs: Some(7 * 24 * 60 * 60),
+            max_records: Some(10_000),
+            max_output_bytes: Some(256 * 1024 * 1024),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    // Given every finished record as (finished_at, id, output bytes), the
+    // IDs to drop, oldest first
+    pub fn expired(&self, mut finished: Vec<(u64, String, u64)>, now: u64) -> Vec<String> {
+        finished.sort();
+        let mut expired = Vec::new();
+        if let Some(max_age) = self.max_age_secs {
+            let cutoff = now.saturating_sub(max_age);
+            let old = finished.iter().take_while(|(at, _, _)| *at < cutoff).count();
+            expired.extend(finished.drain(..old).map(|(_, id, _)| id));
+        }
+        if let Some(max_records) = self.max_records {
+            let excess = finished.len().saturating_sub(max_records);
+            expired.extend(finished.drain(..excess).map(|(_, id, _)| id));
+        }
+        if let Some(max_bytes) = self.max_output_bytes {
+            let mut held: u64 = finished.iter().map(|(_, _, bytes)| bytes).sum();
+            if held > max_bytes {
+                let target = max_bytes / 4 * 3;
+                let over = finished.iter()
+                    .take_while(|(_, _, bytes)| {
+                        let still_over = held > target;
+                        held -= bytes;
+                        still_over
+                    })
+                    .count();
+                expired.extend(finished.drain(..over).map(|(_, id, _)| id));
+            }
+        }
+        expired
+    }
+
+    pub fn over_output_budget(&self, held: u64) -> bool {
+        self.max_output_bytes.is_some_and(|max| held > max)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TaskJournal {
+    path: Option<PathBuf>,
+    records: HashMap<String, TaskRecord>,
+    pub retention: RetentionPolicy,
+    // Output bytes held by finished records
+    output_bytes: u64,
+}
+
+impl TaskJournal {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut journal = TaskJournal { path: Some(path.clone()), ..Self::default() };
+
+        if path.exists() {
+            let contents = fs::read(&path)?;
+            let contents = String::from_utf8_lossy(&contents);
+            // A line cut short by a crash has no newline, and the next entry
+            // appended would run on from it, so rewrite the file without it
+            let mut damaged = !contents.is_empty() && !contents.ends_with('\n');
+            for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                match serde_json::from_str(line) {
+                    Ok(entry) => journal.apply(entry),
+                    Err(e) => {
+                        log::warn!("Skipping unreadable journal entry in {:?}: {}", path, e);
+                        damaged = true;
+                    }
+                }
+            }
+            if damaged {
+                journal.compact()?;
+            }
+        }
+
+        let interrupted: Vec<String> = journal.records.values()
+            .filter(|r| !r.is_finished())
+            .map(|r| r.task_id.clone())
+            .collect();
+        for task_id in interrupted {
+            let result = TaskResult {
+                error_message: Some("Interrupted by node restart".to_string()),
+                termination: Termination::Trap,
+                ..crate::cancelled_result(&task_id)
+            };
+            journal.finished(&result, now_secs())?;
+        }
+        Ok(journal)
+    }
+
+    pub fn submitted(&mut self, task: &ComputeTask, at: u64) -> Result<()> {
+        self.record(JournalEntry::Submitted {
+            task_id: task.task_id.clone(),
+            submitter: task.submitter.clone(),
+            task_type: task.task_type.clone(),
+            priority: task.priority,
+            at,
+        })
+    }
+
+    pub fn started(&mut self, task_id: &str, at: u64) -> Result<()> {
+        self.record(JournalEntry::Started { task_id: task_id.to_string(), at })
+    }
+
+    // Enforces the output budget straight away, since one large output can
+    // blow it long before the next scheduled prune
+    pub fn finished(&mut self, result: &TaskResult, at: u64) -> Result<()> {
+        self.record(finish_entry(result.clone(), at))?;
+        if self.retention.over_output_budget(self.output_bytes) {
+            self.prune(at)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, task_id: &str) -> Option<&TaskRecord> {
+        self.records.get(task_id)
+    }
+
+    pub fn result(&self, task_id: &str) -> Option<&TaskResult> {
+        self.records.get(task_id)?.result.as_ref()
+    }
+
+    // Matching records, oldest submission first
+    pub fn query(&self, query: &TaskQuery) -> Vec<TaskRecord> {
+        let mut matching: Vec<TaskRecord> = self.records.values()
+            .filter(|r| query.matches(r))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| (a.submitted_at, &a.task_id).cmp(&(b.submitted_at, &b.task_id)));
+        if let Some(limit) = query.limit {
+            matching.truncate(limit);
+        }
+        matching
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn finished_count(&self) -> usize {
+        self.records.values().filter(|r| r.is_finished()).count()
+    }
+
+    // Drop finished records the retention policy no longer covers and
+    // compact the file. Returns the task IDs dropped.
+    pub fn prune(&mut self, now: u64) -> Result<Vec<String>> {
+        let finished = self.records.values()
+            .filter_map(|r| r.finished_at.map(|at| (at, r.task_id.clone(), output_len(r))))
+            .collect();
+        let expired = self.retention.expired(finished, now);
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+
+        for task_id in &expired {
+            if let Some(record) = self.records.remove(task_id) {
+                self.output_bytes -= output_len(&record);
+            }
+        }
+        self.compact()?;
+        Ok(expired)
+    }
+
+    fn record(&mut self, entry: JournalEntry) -> Result<()> {
+        if let Some(path) = &self.path {
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(&line)?;
+            // A task reported finished must still be on record after a crash
+            file.sync_data()?;
+        }
+        self.apply(entry);
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Submitted { task_id, submitter, task_type, priority, at } => {
+                let replaced = self.records.insert(task_id.clone(), TaskRecord {
+                    task_id,
+                    submitter,
+                    task_type,
+                    priority,
+                    status: TaskStatus::Queued,
+                    submitted_at: at,
+                    started_at: None,
+                    finished_at: None,
+                    result: None,
+                });
+                if let Some(replaced) = replaced {
+                    self.output_bytes -= output_len(&replaced);
+                }
+            }
+            JournalEntry::Started { task_id, at } => {
+                if let Some(record) = self.records.get_mut(&task_id) {
+                    record.status = TaskStatus::Running;
+                    record.started_at = Some(at);
+                }
+            }
+            JournalEntry::Finished { result, at } | JournalEntry::Failed { result, at, .. } => {
+                if let Some(record) = self.records.get_mut(&result.task_id) {
+                    self.output_bytes -= output_len(record);
+                    self.output_bytes += result.output_data.len() as u64;
+                    record.status = TaskStatus::of(&result);
+                    record.finished_at = Some(at);
+                    record.result = Some(result);
+                }
+            }
+        }
+    }
+
+    // Rewrite the file as one Submitted/Started/Finished run per record
+    fn compact(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut data = Vec::new();
+        for record in self.query(&TaskQuery::default()) {
+            let mut entries = vec![JournalEntry::Submitted {
+                task_id: record.task_id.clone(),
+                submitter: record.submitter,
+                task_type: record.task_type,
+                priority: record.priority,
+                at: record.submitted_at,
+            }];
+            if let Some(at) = record.started_at {
+                entries.push(JournalEntry::Started { task_id: record.task_id, at });
+            }
+            if let (Some(result), Some(at)) = (record.result, record.finished_at) {
+                entries.push(finish_entry(result, at));
+            }
+            for entry in entries {
+                data.extend(serde_json::to_vec(&entry)?);
+                data.push(b'\n');
+            }
+        }
+
+        let tmp_path = path.with_extension(TMP_SUFFIX.trim_start_matches('.'));
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn output_len(record: &TaskRecord) -> u64 {
+    record.result.as_ref().map_or(0, |r| r.output_data.len() as u64)
+}
+
+fn finish_entry(result: TaskResult, at: u64) -> JournalEntry {
+    match (result.success, result.error_message.clone()) {
+        (false, Some(error)) => JournalEntry::Failed { error, result, at },
+        _ => JournalEntry::Finished { result, at },
+    }
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
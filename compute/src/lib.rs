@@ -2,9 +2,31 @@
 
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use anyhow::Result;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
+pub mod batch;
+pub mod determinism;
+pub mod journal;
+pub mod modules;
+pub mod mpc;
+pub mod runtime;
+pub mod scheduler;
+pub mod verification;
+
+pub use batch::{BatchJob, JobState, JobStatus, JobTracker};
+pub use determinism::ExecutionProfile;
+pub use journal::{RetentionPolicy, TaskJournal, TaskQuery, TaskRecord};
+pub use modules::ModuleRegistry;
+pub use mpc::MpcConfig;
+pub use runtime::{ResourceLimits, Termination, ShardSource, ShardAccess};
+pub use scheduler::{TaskQueue, DEFAULT_QUEUE_CAPACITY};
+pub use xmbl_types::TaskType;
+pub use verification::{VerificationPolicy, VerificationReport, PeerOutcome, Verdict, ComputeReputation};
+
 // MOCK TYPES
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MockNodeIdentity {
@@ -25,14 +47,14 @@ pub struct ComputeTask {
     pub input_data: Vec<u8>,
     pub task_type: TaskType,
     pub priority: u8,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum TaskType {
-    WASM,
-    MPC,
-    Batch,
-    RealTime,
+    // Node that asked for the task, for fair scheduling
+    #[serde(default)]
+    pub submitter: String,
+    #[serde(default)]
+    pub profile: ExecutionProfile,
+    // Run a registered module instead of wasm_bytes
+    #[serde(default)]
+    pub module_hash: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -42,112 +64,540 @@ pub struct TaskResult {
     pub execution_time_ms: u64,
     pub success: bool,
     pub error_message: Option<String>,
+    #[serde(default)]
+    pub fuel_used: u64,
+    #[serde(default)]
+    pub peak_memory_bytes: u64,
+    #[serde(default)]
+    pub termination: Termination,
+    // Messages the task sent through xmbl.log
+    #[serde(default)]
+    pub logs: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ComputeService {
     pub node_id: String,
+    // Tasks currently executing
     pub active_tasks: HashMap<String, ComputeTask>,
-    pub completed_tasks: HashMap<String, TaskResult>,
+    // Every task's history and result; in memory unless opened on a file
+    #[serde(skip)]
+    pub journal: TaskJournal,
+    // Size of the worker pool
     pub max_concurrent_tasks: usize,
+    // Share of workers busy right now
     pub current_load: f64,
+    // Applied to every task this node runs
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    // Backs xmbl.fetch_shard; tasks get NO_SHARD_SOURCE without one
+    #[serde(skip)]
+    pub shard_source: Option<Arc<dyn ShardSource>>,
+    // Registered modules and the compiled-module cache
+    #[serde(skip)]
+    pub modules: Arc<ModuleRegistry>,
+    #[serde(default)]
+    pub queue: TaskQueue,
+    // Batch jobs started on this node, whether run here or across peers
+    #[serde(skip)]
+    pub jobs: Arc<JobTracker>,
+    #[serde(skip)]
+    work_ready: Arc<Notify>,
+    #[serde(skip)]
+    task_done: Arc<Notify>,
+    #[serde(skip)]
+    cancellations: HashMap<String, Arc<Notify>>,
+    // Workers still waiting for a cancelled or timed-out task's interpreter
+    // to stop, counted as busy
+    #[serde(skip)]
+    draining: usize,
 }
 
+pub const DEFAULT_PRIORITY: u8 = 1;
+
 impl ComputeService {
     pub fn new(node_id: String, max_concurrent_tasks: usize) -> Self {
         ComputeService {
             node_id,
             active_tasks: HashMap::new(),
-            completed_tasks: HashMap::new(),
+            journal: TaskJournal::in_memory(),
             max_concurrent_tasks,
             current_load: 0.0,
+            limits: ResourceLimits::default(),
+            shard_source: None,
+            modules: Arc::new(ModuleRegistry::default()),
+            queue: TaskQueue::default(),
+            jobs: Arc::new(JobTracker::new()),
+            work_ready: Arc::new(Notify::new()),
+            task_done: Arc::new(Notify::new()),
+            cancellations: HashMap::new(),
+            draining: 0,
         }
     }
     
     pub async fn submit_task(&mut self, wasm_bytes: Vec<u8>, input_data: Vec<u8>, task_type: TaskType) -> Result<String> {
-        if self.active_tasks.len() >= self.max_concurrent_tasks {
-            return Err(anyhow::anyhow!("Maximum concurrent tasks reached"));
-        }
-        
+        let submitter = self.node_id.clone();
+        self.submit(wasm_bytes, input_data, task_type, &submitter, DEFAULT_PRIORITY).await
+    }
+    
+    // Queue a task for the worker pool; higher priorities run first
+    pub async fn submit(&mut self, wasm_bytes: Vec<u8>, input_data: Vec<u8>, task_type: TaskType, submitter: &str, priority: u8) -> Result<String> {
         let task_id = Uuid::new_v4().to_string();
         let task = ComputeTask {
             task_id: task_id.clone(),
             wasm_bytes,
             input_data,
             task_type,
-            priority: 1,
+            priority,
+            submitter: submitter.to_string(),
+            profile: ExecutionProfile::Standard,
+            module_hash: None,
+        };
+        self.enqueue(task).await
+    }
+    
+    // Queue a task built by the caller, e.g. one that must run under the
+    // deterministic profile or reuse a task ID shared with other replicas
+    pub async fn enqueue(&mut self, task: ComputeTask) -> Result<String> {
+        let task_id = task.task_id.clone();
+        // Rerunning a finished ID replaces the old result, so IDs that come
+        // from peers must be namespaced by whoever sent them
+        if self.queue.contains(&task_id) || self.active_tasks.contains_key(&task_id) {
+            return Err(anyhow::anyhow!("Task {} is already queued or running", task_id));
+        }
+        let registered = match &task.module_hash {
+            Some(hash) => Some(self.modules.bytes(hash)
+                .ok_or_else(|| modules::UnknownModule(hash.clone()))?),
+            None => None,
         };
+        if task.profile == ExecutionProfile::Deterministic {
+            determinism::check_module(registered.as_deref().unwrap_or(&task.wasm_bytes))?;
+        }
+        if task.task_type == TaskType::MPC {
+            mpc::check_party_input(&task.input_data)?;
+        }
+        if self.queue.is_full() {
+            return Err(anyhow::anyhow!("Task queue is full"));
+        }
         
-        self.active_tasks.insert(task_id.clone(), task);
-        self.current_load = self.active_tasks.len() as f64 / self.max_concurrent_tasks as f64;
+        self.journal.submitted(&task, journal::now_secs())?;
+        self.queue.push(task)?;
+        self.work_ready.notify_one();
         
         Ok(task_id)
     }
     
+    // Run a queued task right away on the caller, outside the worker pool
     pub async fn execute_task(&mut self, task_id: &str) -> Result<TaskResult> {
-        let task = self.active_tasks.get(task_id)
+        let task = self.queue.remove(task_id)
             .ok_or_else(|| anyhow::anyhow!("Task not found"))?;
         
-        let start_time = std::time::Instant::now();
-        
-        // TODO: Implement actual WASM execution
-        let output_data = self.simulate_wasm_execution(&task.wasm_bytes, &task.input_data).await?;
-        let execution_time = start_time.elapsed().as_millis() as u64;
+        let cancel = self.start_task(task.clone());
+        let (result, still_running) = run_task(task, self.limits.clone(), self.shard_source.clone(), Arc::clone(&self.modules), cancel).await;
+        self.finish_task(result.clone());
+        if let Some(interpreter) = still_running {
+            let _ = interpreter.await;
+        }
         
-        let result = TaskResult {
-            task_id: task_id.to_string(),
-            output_data,
-            execution_time_ms: execution_time,
-            success: true,
-            error_message: None,
+        Ok(result)
+    }
+    
+    // Start one worker per concurrent slot, each pulling from the queue
+    pub async fn spawn_workers(service: &Arc<Mutex<Self>>) -> Vec<JoinHandle<()>> {
+        let workers = service.lock().await.max_concurrent_tasks;
+        (0..workers)
+            .map(|_| tokio::spawn(Self::worker(Arc::clone(service))))
+            .collect()
+    }
+    
+    async fn worker(service: Arc<Mutex<Self>>) {
+        loop {
+            let (job, work_ready) = {
+                let mut compute = service.lock().await;
+                let job = compute.next_task();
+                (job, Arc::clone(&compute.work_ready))
+            };
+            let (task, limits, shards, modules, cancel) = match job {
+                Some(job) => job,
+                None => {
+                    work_ready.notified().await;
+                    continue;
+                }
+            };
+            
+            let (result, still_running) = run_task(task, limits, shards, modules, cancel).await;
+            let Some(interpreter) = still_running else {
+                service.lock().await.finish_task(result);
+                continue;
+            };
+            
+            // The result is reported now, but the interpreter can't be
+            // stopped from outside and runs on until its fuel is gone. The
+            // slot stays taken until then, so abandoned tasks can't pile up.
+            {
+                let mut compute = service.lock().await;
+                compute.finish_task(result);
+                compute.draining += 1;
+                compute.update_load();
+            }
+            let _ = interpreter.await;
+            let mut compute = service.lock().await;
+            compute.draining -= 1;
+            compute.update_load();
+        }
+    }
+    
+    #[allow(clippy::type_complexity)]
+    fn next_task(&mut self) -> Option<(ComputeTask, ResourceLimits, Option<Arc<dyn ShardSource>>, Arc<ModuleRegistry>, Arc<Notify>)> {
+        let task = self.queue.pop()?;
+        // Pass the wakeup on so idle workers pick up the rest concurrently
+        if !self.queue.is_empty() {
+            self.work_ready.notify_one();
+        }
+        let cancel = self.start_task(task.clone());
+        Some((task, self.limits.clone(), self.shard_source.clone(), Arc::clone(&self.modules), cancel))
+    }
+    
+    fn start_task(&mut self, task: ComputeTask) -> Arc<Notify> {
+        if let Err(e) = self.journal.started(&task.task_id, journal::now_secs()) {
+            log::warn!("Failed to journal start of task {}: {}", task.task_id, e);
+        }
+        let cancel = Arc::new(Notify::new());
+        self.cancellations.insert(task.task_id.clone(), Arc::clone(&cancel));
+        self.active_tasks.insert(task.task_id.clone(), task);
+        self.update_load();
+        cancel
+    }
+    
+    fn finish_task(&mut self, result: TaskResult) {
+        self.cancellations.remove(&result.task_id);
+        self.active_tasks.remove(&result.task_id);
+        if let Err(e) = self.journal.finished(&result, journal::now_secs()) {
+            log::warn!("Failed to journal result of task {}: {}", result.task_id, e);
+        }
+        self.update_load();
+        self.task_done.notify_waiters();
+    }
+    
+    fn update_load(&mut self) {
+        self.current_load = (self.active_tasks.len() + self.draining) as f64 / self.max_concurrent_tasks.max(1) as f64;
+    }
+    
+    // Drop a queued task, or stop waiting on a running one
+    pub fn cancel_task(&mut self, task_id: &str) -> Result<()> {
+        if self.queue.remove(task_id).is_some() {
+            self.finish_task(cancelled_result(task_id));
+            return Ok(());
+        }
+        match self.cancellations.get(task_id) {
+            Some(cancel) => {
+                cancel.notify_one();
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Task not found")),
+        }
+    }
+    
+    pub async fn wait_for(service: &Arc<Mutex<Self>>, task_id: &str) -> Result<TaskResult> {
+        loop {
+            let done = {
+                let compute = service.lock().await;
+                if let Some(result) = compute.journal.result(task_id) {
+                    return Ok(result.clone());
+                }
+                if compute.get_task_status(task_id).is_none() {
+                    return Err(anyhow::anyhow!("Task not found"));
+                }
+                // Registered before the lock is released, so no completion is missed
+                Arc::clone(&compute.task_done).notified_owned()
+            };
+            done.await;
+        }
+    }
+    
+    // Keep a module so tasks can name it by the returned hash
+    pub fn register_module(&self, wasm_bytes: Vec<u8>) -> Result<String> {
+        self.modules.register(wasm_bytes)
+    }
+    
+    // Run a batch job on this node's worker pool; follow it with job_status
+    pub async fn submit_job(service: &Arc<Mutex<Self>>, job: BatchJob) -> Result<String> {
+        let (jobs, submitter) = {
+            let compute = service.lock().await;
+            (Arc::clone(&compute.jobs), compute.node_id.clone())
         };
+        jobs.start(&job)?;
+        let job_id = job.job_id.clone();
         
-        // Move task from active to completed
-        self.active_tasks.remove(task_id);
-        self.completed_tasks.insert(task_id.to_string(), result.clone());
-        self.current_load = self.active_tasks.len() as f64 / self.max_concurrent_tasks as f64;
+        let service = Arc::clone(service);
+        let run = move |_unit, _attempt, wasm_bytes: Arc<Vec<u8>>, input_data| {
+            let service = Arc::clone(&service);
+            let submitter = submitter.clone();
+            async move {
+                let task_id = service.lock().await
+                    .submit(wasm_bytes.to_vec(), input_data, TaskType::Batch, &submitter, DEFAULT_PRIORITY)
+                    .await?;
+                let result = Self::wait_for(&service, &task_id).await?;
+                if result.success {
+                    Ok(result.output_data)
+                } else {
+                    Err(anyhow::anyhow!(result.error_message.unwrap_or_default()))
+                }
+            }
+        };
+        tokio::spawn(batch::run_job(job, jobs, run));
         
-        Ok(result)
+        Ok(job_id)
     }
     
-    async fn simulate_wasm_execution(&self, wasm_bytes: &[u8], input_data: &[u8]) -> Result<Vec<u8>> {
-        // Simulate processing time
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        
-        // Return mock output based on input size
-        Ok(vec![0u8; input_data.len()])
+    pub fn job_status(&self, job_id: &str, submitter: &str) -> Option<JobStatus> {
+        self.jobs.status_for(job_id, submitter)
     }
     
     pub fn get_task_status(&self, task_id: &str) -> Option<TaskStatus> {
         if self.active_tasks.contains_key(task_id) {
             Some(TaskStatus::Running)
-        } else if self.completed_tasks.contains_key(task_id) {
-            Some(TaskStatus::Completed)
+        } else if self.queue.contains(task_id) {
+            Some(TaskStatus::Queued)
         } else {
-            None
+            self.journal.result(task_id).map(TaskStatus::of)
         }
     }
     
+    // Result of a finished task, including ones from before a restart
+    pub fn get_task_result(&self, task_id: &str) -> Option<TaskResult> {
+        self.journal.result(task_id).cloned()
+    }
+    
+    pub fn task_history(&self, query: &TaskQuery) -> Vec<TaskRecord> {
+        self.journal.query(query)
+    }
+    
+    pub fn prune_history(&mut self, now: u64) -> Result<Vec<String>> {
+        self.journal.prune(now)
+    }
+    
     pub fn get_service_stats(&self) -> (usize, usize, f64) {
         (
             self.active_tasks.len(),
-            self.completed_tasks.len(),
+            self.journal.finished_count(),
             self.current_load
         )
     }
+    
+    pub fn queued_tasks(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+// Returns once the task finishes, times out or is cancelled. In the last two
+// cases the interpreter thread is handed back still running.
+async fn run_task(task: ComputeTask, limits: ResourceLimits, shards: Option<Arc<dyn ShardSource>>, modules: Arc<ModuleRegistry>, cancel: Arc<Notify>) -> (TaskResult, Option<JoinHandle<runtime::Execution>>) {
+    let start_time = std::time::Instant::now();
+    let timeout_ms = limits.timeout_ms;
+    
+    // The interpreter is synchronous, keep it off the async workers
+    let task_id = task.task_id.clone();
+    let mut handle = tokio::task::spawn_blocking(move || {
+        let module = match &task.module_hash {
+            Some(hash) => modules.load(hash),
+            None => modules.load_bytes(&task.wasm_bytes),
+        };
+        match module {
+            Ok(module) => {
+                let shards = shards.map(|source| ShardAccess::new(source, &task.submitter));
+                runtime::execute_module(&task_id, &module, &task.input_data, &limits, shards)
+            }
+            Err(e) => runtime::Execution::failed(Termination::InvalidModule, e.to_string()),
+        }
+    });
+    let execution = tokio::select! {
+        finished = tokio::time::timeout(tokio::time::Duration::from_millis(timeout_ms), &mut handle) => match finished {
+            Ok(Ok(execution)) => execution,
+            Ok(Err(e)) => runtime::Execution::failed(Termination::Trap, format!("Task execution panicked: {}", e)),
+            Err(_) => runtime::Execution::failed(
+                Termination::Timeout,
+                format!("Task exceeded its {}ms time limit", timeout_ms),
+            ),
+        },
+        _ = cancel.notified() => return (cancelled_result(&task.task_id), Some(handle)),
+    };
+    let still_running = (!handle.is_finished()).then_some(handle);
+    let execution_time = start_time.elapsed().as_millis() as u64;
+    
+    if let Some(error) = &execution.error {
+        log::warn!("Task {} failed: {}", task.task_id, error);
+    }
+    let result = TaskResult {
+        task_id: task.task_id,
+        success: execution.termination == Termination::Completed,
+        output_data: execution.output,
+        execution_time_ms: execution_time,
+        error_message: execution.error,
+        fuel_used: execution.fuel_used,
+        peak_memory_bytes: execution.peak_memory_bytes,
+        termination: execution.termination,
+        logs: execution.logs,
+    };
+    (result, still_running)
 }
 
-#[derive(Clone, Debug, PartialEq)]
+fn cancelled_result(task_id: &str) -> TaskResult {
+    TaskResult {
+        task_id: task_id.to_string(),
+        output_data: Vec::new(),
+        execution_time_ms: 0,
+        success: false,
+        error_message: Some("Task cancelled".to_string()),
+        fuel_used: 0,
+        peak_memory_bytes: 0,
+        termination: Termination::Cancelled,
+        logs: Vec::new(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TaskStatus {
+    Queued,
     Running,
     Completed,
     Failed,
+    Cancelled,
+}
+
+impl TaskStatus {
+    pub fn of(result: &TaskResult) -> Self {
+        if result.success {
+            TaskStatus::Completed
+        } else if result.termination == Termination::Cancelled {
+            TaskStatus::Cancelled
+        } else {
+            TaskStatus::Failed
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // Returns its input reversed
+    const REVERSE_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "run") (param $ptr i32) (param $len i32) (result i64)
+            (local $out i32) (local $i i32)
+            (local.set $out (call 0 (local.get $len)))
+            (block $done
+              (loop $copy
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (i32.store8
+                  (i32.add (local.get $out) (local.get $i))
+                  (i32.load8_u (i32.sub (i32.add (local.get $ptr) (local.get $len))
+                                        (i32.add (local.get $i) (i32.const 1)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $copy)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    const TRAP_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "run") (param i32 i32) (result i64) unreachable))
+    "#;
+
+    // Asks for a table bigger than MAX_TABLE_ELEMENTS and traps if refused
+    const TABLE_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (table 0 funcref)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "run") (param i32 i32) (result i64)
+            (if (i32.eq (table.grow (ref.null func) (i32.const 20000)) (i32.const -1))
+              (then unreachable))
+            (i64.const 0)))
+    "#;
+
+    // Spins forever, or grows memory one page at a time until refused
+    const LOOP_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "run") (param i32 i32) (result i64)
+            (loop $spin (br $spin))
+            (i64.const 0)))
+    "#;
+
+    const GROW_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "run") (param i32 i32) (result i64)
+            (loop $grow
+              (br_if $grow (i32.ne (memory.grow (i32.const 1)) (i32.const -1))))
+            unreachable))
+    "#;
+
+    // Host ABI: logs, then writes the shard named by the input followed by the input itself
+    const HOST_ABI_WAT: &str = r#"
+        (module
+          (import "xmbl" "input_len" (func $input_len (result i32)))
+          (import "xmbl" "read_input" (func $read_input (param i32 i32) (result i32)))
+          (import "xmbl" "write_output" (func $write_output (param i32 i32)))
+          (import "xmbl" "log" (func $log (param i32 i32 i32)))
+          (import "xmbl" "fetch_shard" (func $fetch_shard (param i32 i32 i32 i32) (result i64)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "starting")
+          (func (export "run") (result i32)
+            (local $len i32) (local $size i64)
+            (call $log (i32.const 2) (i32.const 0) (i32.const 8))
+            (local.set $len (call $read_input (i32.const 1024) (call $input_len)))
+            (local.set $size (call $fetch_shard (i32.const 1024) (local.get $len) (i32.const 4096) (i32.const 1024)))
+            (if (i64.lt_s (local.get $size) (i64.const 0)) (then (return (i32.const 1))))
+            (call $write_output (i32.const 4096) (i32.wrap_i64 (local.get $size)))
+            (call $write_output (i32.const 1024) (local.get $len))
+            (i32.const 0)))
+    "#;
+
+    #[derive(Debug)]
+    struct FixedShards;
+
+    // One shard, owned by test_node
+    impl ShardSource for FixedShards {
+        fn shard_size(&self, submitter: &str, shard_id: &str) -> Option<u64> {
+            self.fetch_shard(submitter, shard_id).map(|shard| shard.len() as u64)
+        }
+
+        fn fetch_shard(&self, submitter: &str, shard_id: &str) -> Option<Vec<u8>> {
+            (submitter == "test_node" && shard_id == "shard_1").then(|| b"stored:".to_vec())
+        }
+    }
+
+    fn queued(priority: u8, submitter: &str) -> ComputeTask {
+        ComputeTask {
+            task_id: format!("{}_{}", submitter, Uuid::new_v4()),
+            wasm_bytes: Vec::new(),
+            input_data: Vec::new(),
+            task_type: TaskType::WASM,
+            priority,
+            submitter: submitter.to_string(),
+            profile: ExecutionProfile::Standard,
+            module_hash: None,
+        }
+    }
+
+    fn wasm(source: &str) -> Vec<u8> {
+        wat::parse_str(source).unwrap()
+    }
+
     #[tokio::test]
     async fn test_compute_service_creation() {
         let service = ComputeService::new("test_node".to_string(), 10);
@@ -158,7 +608,7 @@ mod tests {
     #[tokio::test]
     async fn test_task_submission_and_execution() {
         let mut service = ComputeService::new("test_node".to_string(), 5);
-        let wasm_bytes = b"mock_wasm_code".to_vec();
+        let wasm_bytes = wasm(REVERSE_WAT);
         let input_data = b"test_input".to_vec();
         
         let task_id = service.submit_task(wasm_bytes, input_data, TaskType::WASM).await.unwrap();
@@ -166,17 +616,38 @@ mod tests {
         
         assert!(result.success);
         assert_eq!(result.task_id, task_id);
+        assert_eq!(result.output_data, b"tupni_tset".to_vec());
     }
 
     #[tokio::test]
-    async fn test_max_concurrent_tasks_error() {
+    async fn test_invalid_module_and_trap_reported_in_result() {
+        let mut service = ComputeService::new("test_node".to_string(), 2);
+
+        let task_id = service.submit_task(b"mock_wasm_code".to_vec(), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert!(!result.success);
+        assert!(result.error_message.unwrap().starts_with("Invalid WASM module"));
+        assert_eq!(service.get_task_status(&task_id), Some(TaskStatus::Failed));
+
+        let task_id = service.submit_task(wasm(TRAP_WAT), b"input".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert!(!result.success);
+        assert!(result.error_message.unwrap().contains("WASM trap in run"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_full_error() {
         let mut service = ComputeService::new("test_node".to_string(), 1);
-        let wasm_bytes = b"mock_wasm_code".to_vec();
+        service.queue.capacity = 2;
+        let wasm_bytes = wasm(REVERSE_WAT);
         let input_data = b"test_input".to_vec();
-        let _ = service.submit_task(wasm_bytes.clone(), input_data.clone(), TaskType::WASM).await.unwrap();
+        // Beyond the worker count tasks queue up, until the queue is full
+        for _ in 0..2 {
+            service.submit_task(wasm_bytes.clone(), input_data.clone(), TaskType::WASM).await.unwrap();
+        }
         let err = service.submit_task(wasm_bytes, input_data, TaskType::WASM).await;
         assert!(err.is_err());
-        assert_eq!(err.unwrap_err().to_string(), "Maximum concurrent tasks reached");
+        assert_eq!(err.unwrap_err().to_string(), "Task queue is full");
     }
 
     #[tokio::test]
@@ -190,10 +661,10 @@ mod tests {
     #[tokio::test]
     async fn test_get_task_status_variants() {
         let mut service = ComputeService::new("test_node".to_string(), 2);
-        let wasm_bytes = b"mock_wasm_code".to_vec();
+        let wasm_bytes = wasm(REVERSE_WAT);
         let input_data = b"test_input".to_vec();
         let task_id = service.submit_task(wasm_bytes, input_data, TaskType::WASM).await.unwrap();
-        assert_eq!(service.get_task_status(&task_id), Some(TaskStatus::Running));
+        assert_eq!(service.get_task_status(&task_id), Some(TaskStatus::Queued));
         let _ = service.execute_task(&task_id).await.unwrap();
         assert_eq!(service.get_task_status(&task_id), Some(TaskStatus::Completed));
         assert_eq!(service.get_task_status("bad_id"), None);
@@ -202,13 +673,14 @@ mod tests {
     #[tokio::test]
     async fn test_get_service_stats() {
         let mut service = ComputeService::new("test_node".to_string(), 2);
-        let wasm_bytes = b"mock_wasm_code".to_vec();
+        let wasm_bytes = wasm(REVERSE_WAT);
         let input_data = b"test_input".to_vec();
         let task_id = service.submit_task(wasm_bytes, input_data, TaskType::WASM).await.unwrap();
         let (active, completed, load) = service.get_service_stats();
-        assert_eq!(active, 1);
+        assert_eq!(active, 0);
         assert_eq!(completed, 0);
-        assert!(load > 0.0);
+        assert_eq!(load, 0.0);
+        assert_eq!(service.queued_tasks(), 1);
         let _ = service.execute_task(&task_id).await.unwrap();
         let (active, completed, load) = service.get_service_stats();
         assert_eq!(active, 0);
@@ -234,4 +706,550 @@ mod tests {
             _ => panic!("Should be failed"),
         }
     }
+
+    #[tokio::test]
+    async fn test_resource_limits_terminate_tasks() {
+        let mut service = ComputeService::new("test_node".to_string(), 4);
+        service.limits = ResourceLimits {
+            fuel: 100_000,
+            max_memory_bytes: 4 * 65536,
+            timeout_ms: 5_000,
+            max_output_bytes: 4,
+        };
+
+        let task_id = service.submit_task(wasm(LOOP_WAT), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert!(!result.success);
+        assert_eq!(result.termination, Termination::OutOfFuel);
+        assert!(result.fuel_used > 99_000 && result.fuel_used <= 100_000);
+
+        let task_id = service.submit_task(wasm(GROW_WAT), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::MemoryLimit);
+        assert_eq!(result.peak_memory_bytes, 4 * 65536);
+
+        let task_id = service.submit_task(wasm(TABLE_WAT), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::MemoryLimit);
+
+        let task_id = service.submit_task(wasm(REVERSE_WAT), b"too long".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::OutputLimit);
+
+        let task_id = service.submit_task(wasm(REVERSE_WAT), b"ok".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::Completed);
+        assert!(result.fuel_used > 0 && result.fuel_used < 100_000);
+        assert_eq!(result.peak_memory_bytes, 65536);
+    }
+
+    #[tokio::test]
+    async fn test_host_abi_reads_input_fetches_shards_and_logs() {
+        let mut service = ComputeService::new("test_node".to_string(), 4);
+
+        // Without storage the module sees NO_SHARD_SOURCE and exits non-zero
+        let task_id = service.submit_task(wasm(HOST_ABI_WAT), b"shard_1".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert!(!result.success);
+        assert_eq!(result.error_message.unwrap(), "Task exited with status 1");
+
+        service.shard_source = Some(Arc::new(FixedShards));
+        let task_id = service.submit_task(wasm(HOST_ABI_WAT), b"shard_1".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert!(result.success);
+        assert_eq!(result.output_data, b"stored:shard_1".to_vec());
+        assert_eq!(result.logs, vec!["INFO: starting".to_string()]);
+
+        // Someone else's task can't read test_node's shard
+        let task_id = service.submit(wasm(HOST_ABI_WAT), b"shard_1".to_vec(), TaskType::WASM, "mallory", DEFAULT_PRIORITY).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.error_message.unwrap(), "Task exited with status 1");
+
+        service.limits.max_output_bytes = 4;
+        let task_id = service.submit_task(wasm(HOST_ABI_WAT), b"shard_1".to_vec(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::OutputLimit);
+    }
+
+    #[tokio::test]
+    async fn test_host_calls_burn_fuel_per_byte_and_read_shards_once() {
+        // A hundred 64KiB random fills: cheap in instructions, not in bytes
+        const RANDOM_LOOP_WAT: &str = r#"
+            (module
+              (import "xmbl" "random" (func $random (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (local $i i32)
+                (loop $again
+                  (call $random (i32.const 0) (i32.const 65536))
+                  (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                  (br_if $again (i32.lt_u (local.get $i) (i32.const 100))))
+                (i32.const 0)))
+        "#;
+        const FETCH_LOOP_WAT: &str = r#"
+            (module
+              (import "xmbl" "fetch_shard" (func $fetch_shard (param i32 i32 i32 i32) (result i64)))
+              (memory (export "memory") 1)
+              (data (i32.const 0) "shard_1")
+              (func (export "run") (result i32)
+                (loop $again
+                  (drop (call $fetch_shard (i32.const 0) (i32.const 7) (i32.const 1024) (i32.const 1024)))
+                  (br $again))
+                (i32.const 0)))
+        "#;
+
+        #[derive(Debug, Default)]
+        struct CountingShards {
+            reads: std::sync::atomic::AtomicUsize,
+        }
+
+        impl ShardSource for CountingShards {
+            fn shard_size(&self, _: &str, _: &str) -> Option<u64> {
+                self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Some(1000)
+            }
+
+            fn fetch_shard(&self, _: &str, _: &str) -> Option<Vec<u8>> {
+                self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Some(vec![7; 1000])
+            }
+        }
+
+        let mut service = ComputeService::new("test_node".to_string(), 4);
+        service.limits.fuel = 1_000_000;
+        let task_id = service.submit_task(wasm(RANDOM_LOOP_WAT), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::OutOfFuel);
+
+        // Looping over the same shard pays for every copy but reads it once
+        let shards = Arc::new(CountingShards::default());
+        service.shard_source = Some(shards.clone());
+        let task_id = service.submit_task(wasm(FETCH_LOOP_WAT), Vec::new(), TaskType::WASM).await.unwrap();
+        let result = service.execute_task(&task_id).await.unwrap();
+        assert_eq!(result.termination, Termination::OutOfFuel);
+        assert_eq!(shards.reads.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_queue_orders_by_priority_then_submitter_fairness() {
+        let mut queue = TaskQueue::new(16);
+        for _ in 0..3 {
+            queue.push(queued(1, "busy")).unwrap();
+        }
+        queue.push(queued(1, "quiet")).unwrap();
+        queue.push(queued(5, "busy")).unwrap();
+
+        let order: Vec<(u8, String)> = std::iter::from_fn(|| queue.pop())
+            .map(|t| (t.priority, t.submitter))
+            .collect();
+        assert_eq!(order, vec![
+            (5, "busy".to_string()),
+            (1, "quiet".to_string()),
+            (1, "busy".to_string()),
+            (1, "busy".to_string()),
+            (1, "busy".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_runs_and_cancels_tasks() {
+        let mut service = ComputeService::new("test_node".to_string(), 2);
+        service.limits.timeout_ms = 60_000;
+        service.limits.fuel = 30_000_000;
+        let service = Arc::new(Mutex::new(service));
+
+        // Two spinning tasks occupy both workers, the third waits in the queue
+        let (spin_a, spin_b, reverse) = {
+            let mut compute = service.lock().await;
+            (
+                compute.submit(wasm(LOOP_WAT), Vec::new(), TaskType::WASM, "peer_a", 1).await.unwrap(),
+                compute.submit(wasm(LOOP_WAT), Vec::new(), TaskType::WASM, "peer_b", 1).await.unwrap(),
+                compute.submit(wasm(REVERSE_WAT), b"abc".to_vec(), TaskType::WASM, "peer_a", 1).await.unwrap(),
+            )
+        };
+        let workers = ComputeService::spawn_workers(&service).await;
+
+        for _ in 0..100 {
+            if service.lock().await.active_tasks.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        {
+            let mut compute = service.lock().await;
+            assert_eq!(compute.current_load, 1.0);
+            assert_eq!(compute.get_task_status(&reverse), Some(TaskStatus::Queued));
+            compute.cancel_task(&spin_a).unwrap();
+            compute.cancel_task(&spin_b).unwrap();
+        }
+
+        let result = ComputeService::wait_for(&service, &reverse).await.unwrap();
+        assert_eq!(result.output_data, b"cba".to_vec());
+        let cancelled = ComputeService::wait_for(&service, &spin_a).await.unwrap();
+        assert_eq!(cancelled.termination, Termination::Cancelled);
+        assert_eq!(service.lock().await.get_task_status(&spin_b), Some(TaskStatus::Cancelled));
+
+        for worker in workers {
+            worker.abort();
+        }
+    }
+
+    #[test]
+    fn test_majority_resolves_disagreement_and_updates_reputation() {
+        let good = verification::output_hash(b"42");
+        let outcome = |peer: &str, hash: Option<&String>| PeerOutcome {
+            peer_id: peer.to_string(),
+            output_hash: hash.cloned(),
+            error: hash.is_none().then(|| "timed out".to_string()),
+        };
+        let bad = verification::output_hash(b"41");
+
+        let policy = VerificationPolicy::majority(4);
+        let report = verification::resolve(&policy, &[
+            outcome("peer_a", Some(&good)),
+            outcome("peer_b", Some(&bad)),
+            outcome("peer_c", Some(&good)),
+            outcome("peer_d", None),
+        ]);
+        // Two of four is not a majority
+        assert!(!report.verified());
+        assert!(report.peers_with(Verdict::Agreed).is_empty());
+        assert_eq!(report.peers_with(Verdict::Inconclusive).len(), 3);
+
+        let report = verification::resolve(&VerificationPolicy::majority(3), &[
+            outcome("peer_a", Some(&good)),
+            outcome("peer_b", Some(&bad)),
+            outcome("peer_c", Some(&good)),
+        ]);
+        assert_eq!(report.output_hash, Some(good.clone()));
+        assert_eq!(report.peers_with(Verdict::Disagreed), vec!["peer_b".to_string()]);
+
+        let mut reputation = ComputeReputation::new();
+        reputation.record(&report);
+        assert_eq!(reputation.get("peer_a").agreed, 1);
+        assert_eq!(reputation.score("peer_b"), 0.0);
+        let peers: Vec<String> = ["peer_b", "peer_new", "peer_a"].iter().map(|p| p.to_string()).collect();
+        assert_eq!(reputation.rank(&peers), vec!["peer_a", "peer_new", "peer_b"]);
+    }
+
+    #[test]
+    fn test_invalid_policies_and_ties_never_verify() {
+        assert!(VerificationPolicy::majority(1).validate().is_ok());
+        assert!(VerificationPolicy::majority(4).validate().is_ok());
+        assert!(VerificationPolicy { replicas: 0, quorum: 0 }.validate().is_err());
+        assert!(VerificationPolicy { replicas: 4, quorum: 2 }.validate().is_err());
+        assert!(VerificationPolicy { replicas: 3, quorum: 4 }.validate().is_err());
+        assert!(VerificationPolicy::majority(1000).validate().is_err());
+
+        // Resolving under a policy that was never validated still refuses
+        // to pick between two hashes that both reach the quorum
+        let outcome = |peer: &str, output: &[u8]| PeerOutcome {
+            peer_id: peer.to_string(),
+            output_hash: Some(verification::output_hash(output)),
+            error: None,
+        };
+        let report = verification::resolve(&VerificationPolicy { replicas: 4, quorum: 2 }, &[
+            outcome("peer_a", b"42"),
+            outcome("peer_b", b"41"),
+            outcome("peer_c", b"42"),
+            outcome("peer_d", b"41"),
+        ]);
+        assert!(!report.verified());
+        assert_eq!(report.peers_with(Verdict::Inconclusive).len(), 4);
+    }
+    
+    #[tokio::test]
+    async fn test_deterministic_profile_validates_and_seeds_random() {
+        const RANDOM_WAT: &str = r#"
+            (module
+              (import "xmbl" "random" (func $random (param i32 i32)))
+              (import "xmbl" "write_output" (func $write_output (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (call $random (i32.const 0) (i32.const 48))
+                (call $write_output (i32.const 0) (i32.const 48))
+                (i32.const 0)))
+        "#;
+        const FLOAT_WAT: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (i32.trunc_f32_s (f32.div (f32.const 0) (f32.const 0)))))
+        "#;
+        const CLOCK_WAT: &str = r#"
+            (module
+              (import "env" "now" (func (result i64)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32) (i32.const 0)))
+        "#;
+        
+        let mut service = ComputeService::new("test_node".to_string(), 1);
+        let task = |task_id: &str, wat: &str| ComputeTask {
+            task_id: task_id.to_string(),
+            wasm_bytes: wasm(wat),
+            profile: ExecutionProfile::Deterministic,
+            ..queued(DEFAULT_PRIORITY, "peer")
+        };
+        
+        let error = service.enqueue(task("float", FLOAT_WAT)).await.unwrap_err().to_string();
+        assert!(error.contains("not eligible for deterministic execution"), "{}", error);
+        assert!(error.contains("floating-point"), "{}", error);
+        let error = service.enqueue(task("clock", CLOCK_WAT)).await.unwrap_err().to_string();
+        assert!(error.contains("import env.now is not allowed"), "{}", error);
+        let error = service.enqueue(task("fetch", HOST_ABI_WAT)).await.unwrap_err().to_string();
+        assert!(error.contains("import xmbl.fetch_shard is not allowed"), "{}", error);
+        assert!(service.queue.is_empty());
+        
+        // Same task ID on another node draws the same bytes, another ID does not
+        let mut other = ComputeService::new("other_node".to_string(), 1);
+        service.enqueue(task("shared", RANDOM_WAT)).await.unwrap();
+        other.enqueue(task("shared", RANDOM_WAT)).await.unwrap();
+        other.enqueue(task("different", RANDOM_WAT)).await.unwrap();
+        assert!(service.enqueue(task("shared", RANDOM_WAT)).await.is_err());
+        
+        let first = service.execute_task("shared").await.unwrap();
+        let second = other.execute_task("shared").await.unwrap();
+        let third = other.execute_task("different").await.unwrap();
+        assert!(first.success, "{:?}", first.error_message);
+        assert_eq!(first.output_data.len(), 48);
+        assert_eq!(first.output_data, second.output_data);
+        assert_ne!(first.output_data, third.output_data);
+    }
+    
+    #[tokio::test]
+    async fn test_mpc_local_parties_compute_on_shares() {
+        // Host ABI party: every share word times 3, plus 1 on party 0 only
+        const AFFINE_WAT: &str = r#"
+            (module
+              (import "xmbl" "input_len" (func $input_len (result i32)))
+              (import "xmbl" "read_input" (func $read_input (param i32 i32) (result i32)))
+              (import "xmbl" "write_output" (func $write_output (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (local $len i32) (local $i i32)
+                (local.set $len (call $read_input (i32.const 0) (call $input_len)))
+                (local.set $i (i32.const 12))
+                (block $done
+                  (loop $words
+                    (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                    (i32.store (local.get $i) (i32.mul (i32.load (local.get $i)) (i32.const 3)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 4)))
+                    (br $words)))
+                (if (i32.and (i32.eqz (i32.load (i32.const 0))) (i32.gt_u (local.get $len) (i32.const 12)))
+                  (then (i32.store (i32.const 12) (i32.add (i32.load (i32.const 12)) (i32.const 1)))))
+                (call $write_output (i32.const 12) (i32.sub (local.get $len) (i32.const 12)))
+                (i32.const 0)))
+        "#;
+        
+        let input: Vec<u8> = [5u32, 100, u32::MAX].iter().flat_map(|w| w.to_le_bytes()).collect();
+        let shares = mpc::share(&input, 3).unwrap();
+        assert_eq!(shares.len(), 3);
+        assert!(shares.iter().all(|share| *share != input));
+        assert_eq!(mpc::reconstruct(&shares).unwrap(), input);
+        assert!(mpc::share(&[1, 2, 3], 3).is_err());
+        assert!(mpc::reconstruct(&[input.clone(), input[..8].to_vec()]).is_err());
+        
+        // Two rounds of 3x + 1 on the first word, 3x on the others
+        let config = MpcConfig { parties: 3, rounds: 2 };
+        let output = mpc::run_local(&wasm(AFFINE_WAT), &input, &config, &ResourceLimits::default()).await.unwrap();
+        let expected: Vec<u8> = [49u32, 900, u32::MAX.wrapping_mul(9)].iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(output, expected);
+        
+        let error = mpc::run_local(&wasm(TRAP_WAT), &input, &config, &ResourceLimits::default()).await.unwrap_err();
+        assert!(error.to_string().contains("failed in round 0"), "{}", error);
+        
+        let mut service = ComputeService::new("test_node".to_string(), 1);
+        let task = ComputeTask { task_type: TaskType::MPC, input_data: vec![0; 5], ..queued(DEFAULT_PRIORITY, "peer") };
+        assert!(service.enqueue(task).await.is_err());
+        // Headers asking for more parties or rounds than allowed are refused
+        assert!(MpcConfig { parties: mpc::MAX_PARTIES + 1, rounds: 1 }.validate().is_err());
+        let header = mpc::party_input(0, 3, mpc::MAX_ROUNDS, &[]);
+        let task = ComputeTask { task_type: TaskType::MPC, input_data: header, ..queued(DEFAULT_PRIORITY, "peer") };
+        assert!(service.enqueue(task).await.is_err());
+        let header = mpc::party_input(3, 3, 0, &[]);
+        assert!(mpc::check_party_input(&header).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_batch_job_maps_retries_and_reduces() {
+        // Reducer: the total length of the encoded map outputs, as one byte
+        const LENGTH_WAT: &str = r#"
+            (module
+              (import "xmbl" "input_len" (func $input_len (result i32)))
+              (import "xmbl" "write_output" (func $write_output (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (i32.store8 (i32.const 0) (call $input_len))
+                (call $write_output (i32.const 0) (i32.const 1))
+                (i32.const 0)))
+        "#;
+        
+        let service = Arc::new(Mutex::new(ComputeService::new("test_node".to_string(), 2)));
+        let workers = ComputeService::spawn_workers(&service).await;
+        
+        let mut job = BatchJob::chunked(wasm(REVERSE_WAT), b"abcdefg", 3).unwrap();
+        job.reducer = Some(wasm(LENGTH_WAT));
+        let job_id = ComputeService::submit_job(&service, job).await.unwrap();
+        let jobs = Arc::clone(&service.lock().await.jobs);
+        let status = jobs.wait(&job_id).await.unwrap();
+        assert_eq!(status.state, JobState::Completed, "{:?}", status.error);
+        assert_eq!((status.total, status.succeeded, status.progress()), (3, 3, 1.0));
+        // Three length prefixes plus seven bytes of reversed chunks
+        assert_eq!(status.output, Some(vec![19]));
+        
+        let mut job = BatchJob::new(wasm(REVERSE_WAT), vec![b"ab".to_vec(), b"cd".to_vec()]);
+        job.parallelism = 1;
+        job.submitter = "alice".to_string();
+        let job_id = ComputeService::submit_job(&service, job).await.unwrap();
+        let status = jobs.wait(&job_id).await.unwrap();
+        assert_eq!(status.output, Some(batch::encode_outputs(&[b"ba".to_vec(), b"dc".to_vec()])));
+        assert_eq!(service.lock().await.job_status(&job_id, "alice").unwrap().state, JobState::Completed);
+        // Nobody else can follow alice's job
+        assert!(service.lock().await.job_status(&job_id, "bob").is_none());
+        
+        // Input 1 fails on its first two attempts and then succeeds; input 2 never does
+        let job = BatchJob::new(Vec::new(), vec![vec![0], vec![1], vec![2]]);
+        let job_id = job.job_id.clone();
+        let tracker = Arc::new(JobTracker::new());
+        tracker.start(&job).unwrap();
+        let status = batch::run_job(job, Arc::clone(&tracker), |unit, attempt, _, input| async move {
+            match (unit, attempt) {
+                (1, 0..=1) | (2, _) => Err(anyhow::anyhow!("flaky")),
+                _ => Ok(input),
+            }
+        }).await;
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!((status.succeeded, status.failed.clone(), status.retries), (2, vec![2], 4));
+        assert!(status.error.unwrap().contains("1 of 3 inputs failed"));
+        assert_eq!(tracker.status(&job_id).unwrap().state, JobState::Failed);
+        
+        // Finished jobs age out, or make way once there are too many
+        let finished_at = status.finished_at.unwrap();
+        assert!(tracker.prune(finished_at).is_empty());
+        assert_eq!(tracker.prune(finished_at + 25 * 60 * 60), vec![job_id.clone()]);
+        assert!(tracker.status(&job_id).is_none());
+        let mut tracker = JobTracker::new();
+        tracker.retention.max_records = Some(1);
+        let tracker = Arc::new(tracker);
+        for _ in 0..3 {
+            let job = BatchJob::new(Vec::new(), vec![vec![0]]);
+            tracker.start(&job).unwrap();
+            batch::run_job(job, Arc::clone(&tracker), |_, _, _, input| async move { Ok(input) }).await;
+        }
+        assert_eq!(tracker.list().len(), 2);
+        assert_eq!(tracker.prune(finished_at).len(), 1);
+        
+        for worker in workers {
+            worker.abort();
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_task_journal_survives_restart_and_prunes() {
+        let path = std::env::temp_dir().join(format!("xmbl_compute_{}", Uuid::new_v4())).join("tasks.jsonl");
+        let mut service = ComputeService::new("test_node".to_string(), 1);
+        service.journal = TaskJournal::open(&path).unwrap();
+        
+        let done = service.submit(wasm(REVERSE_WAT), b"abc".to_vec(), TaskType::WASM, "alice", DEFAULT_PRIORITY).await.unwrap();
+        service.execute_task(&done).await.unwrap();
+        let failed = service.submit(wasm(TRAP_WAT), Vec::new(), TaskType::WASM, "bob", DEFAULT_PRIORITY).await.unwrap();
+        service.execute_task(&failed).await.unwrap();
+        let pending = service.submit(wasm(REVERSE_WAT), Vec::new(), TaskType::Batch, "alice", DEFAULT_PRIORITY).await.unwrap();
+        
+        // Crash partway through writing an entry
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, br#"{"event":"Started","task_"#).unwrap();
+        
+        // A new process sees the results, and the task it never ran as failed
+        let mut service = ComputeService::new("test_node".to_string(), 1);
+        service.journal = TaskJournal::open(&path).unwrap();
+        assert_eq!(service.get_task_result(&done).unwrap().output_data, b"cba".to_vec());
+        assert_eq!(service.get_task_status(&failed), Some(TaskStatus::Failed));
+        let interrupted = service.get_task_result(&pending).unwrap();
+        assert_eq!(interrupted.error_message.as_deref(), Some("Interrupted by node restart"));
+        // The torn line was dropped rather than swallowing what came after it
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()));
+        
+        let record = service.journal.get(&failed).unwrap();
+        assert!(record.started_at.is_some() && record.finished_at.is_some());
+        assert!(record.result.as_ref().unwrap().error_message.as_ref().unwrap().contains("unreachable"));
+        
+        let alice = service.task_history(&TaskQuery { submitter: Some("alice".to_string()), ..TaskQuery::default() });
+        assert_eq!(alice.len(), 2);
+        let failures = service.task_history(&TaskQuery { status: Some(TaskStatus::Failed), ..TaskQuery::default() });
+        assert_eq!(failures.len(), 2);
+        let now = journal::now_secs();
+        assert!(service.task_history(&TaskQuery { since: Some(now + 60), ..TaskQuery::default() }).is_empty());
+        
+        // Keep only the newest record, and make sure compaction persisted it
+        service.journal.retention = RetentionPolicy { max_age_secs: None, max_records: Some(1), max_output_bytes: None };
+        assert_eq!(service.prune_history(now).unwrap().len(), 2);
+        let reopened = TaskJournal::open(&path).unwrap();
+        assert_eq!(reopened.len(), 1);
+        
+        service.journal.retention = RetentionPolicy { max_age_secs: Some(0), max_records: None, max_output_bytes: None };
+        assert_eq!(service.prune_history(now + 1).unwrap().len(), 1);
+        assert!(TaskJournal::open(&path).unwrap().is_empty());
+        
+        // A finished task that takes outputs over budget drops the oldest
+        // straight away, down to three quarters of it
+        let mut journal = TaskJournal::open(&path).unwrap();
+        journal.retention = RetentionPolicy { max_age_secs: None, max_records: None, max_output_bytes: Some(100) };
+        let mut tasks = Vec::new();
+        for (i, size) in [40, 30, 20, 20].into_iter().enumerate() {
+            let task = queued(1, "alice");
+            journal.submitted(&task, now).unwrap();
+            let result = TaskResult { output_data: vec![0; size], success: true, ..cancelled_result(&task.task_id) };
+            journal.finished(&result, now + i as u64).unwrap();
+            tasks.push(task.task_id);
+        }
+        assert!(journal.get(&tasks[0]).is_none());
+        assert!(journal.get(&tasks[1]).is_some());
+        assert_eq!(TaskJournal::open(&path).unwrap().len(), 3);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_registered_modules_run_by_hash_from_bounded_cache() {
+        let mut service = ComputeService::new("test_node".to_string(), 1);
+        let hash = service.register_module(wasm(REVERSE_WAT)).unwrap();
+        assert_eq!(hash, modules::module_hash(&wasm(REVERSE_WAT)));
+        assert!(service.register_module(b"not wasm".to_vec()).is_err());
+        
+        for (input, output) in [(b"abc", b"cba"), (b"xyz", b"zyx")] {
+            let task = ComputeTask { module_hash: Some(hash.clone()), input_data: input.to_vec(), ..queued(DEFAULT_PRIORITY, "peer") };
+            let task_id = service.enqueue(task).await.unwrap();
+            assert_eq!(service.execute_task(&task_id).await.unwrap().output_data, output.to_vec());
+        }
+        let stats = service.modules.stats();
+        assert_eq!((stats.registered, stats.cached, stats.hits, stats.misses), (1, 1, 2, 0));
+        
+        let task = ComputeTask { module_hash: Some("feed".to_string()), ..queued(DEFAULT_PRIORITY, "peer") };
+        let error = service.enqueue(task).await.unwrap_err();
+        assert!(error.downcast_ref::<modules::UnknownModule>().is_some(), "{}", error);
+        
+        // Room for one compiled module: the least recently used is dropped but stays registered
+        let registry = ModuleRegistry::new(wasm(REVERSE_WAT).len().max(wasm(TRAP_WAT).len()));
+        let reverse = registry.register(wasm(REVERSE_WAT)).unwrap();
+        let trap = registry.register(wasm(TRAP_WAT)).unwrap();
+        assert_eq!(registry.stats().cached, 1);
+        assert!(registry.load(&reverse).is_ok());
+        assert!(registry.load(&trap).is_ok());
+        let stats = registry.stats();
+        assert_eq!((stats.registered, stats.cached, stats.misses), (2, 1, 2));
+        assert!(stats.cached_bytes <= registry.max_cache_bytes);
+        
+        // Room for two registered modules: a third pushes out the least recently used
+        let mut registry = ModuleRegistry::default();
+        registry.max_registered_bytes = wasm(REVERSE_WAT).len() + wasm(TRAP_WAT).len().max(wasm(LOOP_WAT).len());
+        let reverse = registry.register(wasm(REVERSE_WAT)).unwrap();
+        let trap = registry.register(wasm(TRAP_WAT)).unwrap();
+        assert!(registry.bytes(&reverse).is_some());
+        let looping = registry.register(wasm(LOOP_WAT)).unwrap();
+        assert!(registry.contains(&looping) && registry.contains(&reverse));
+        assert!(!registry.contains(&trap));
+        assert!(registry.stats().registered_bytes <= registry.max_registered_bytes);
+        assert!(registry.register(vec![0; registry.max_registered_bytes + 1]).is_err());
+    }
 }
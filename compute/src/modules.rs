@@ -0,0 +1,237 @@
+// XMBL Modules - upload a task module once, run it by hash
+//
+// Registered modules are kept by the sha256 of their bytes, so a task can
+// name its module by hash instead of carrying it. Any peer can register one,
+// so the registry is bounded by total wasm size too and forgets the least
+// recently used module first; a peer naming a forgotten module is told so
+// and uploads it again. Separately, compiled
+// modules are cached by the same hash, for registered and inline modules
+// alike, so a module that runs again is not parsed and validated again. The
+// cache is bounded by the size of the wasm it holds (a stand-in for the
+// compiled size, which wasmi does not report) and drops the least recently
+// used module first.
+//
+// The registry locks internally and is shared between the service and the
+// blocking threads tasks run on; compilation happens outside the lock.
+
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use wasmi::Module;
+
+use crate::runtime;
+
+pub const DEFAULT_CACHE_BYTES: usize = 256 * 1024 * 1024;
+pub const DEFAULT_REGISTRY_BYTES: usize = 256 * 1024 * 1024;
+
+// A hash this node has never seen, or has since forgotten; uploading the
+// module fixes it
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown module {0}")]
+pub struct UnknownModule(pub String);
+
+pub fn module_hash(wasm_bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(wasm_bytes))
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub registered: usize,
+    pub registered_bytes: usize,
+    pub cached: usize,
+    pub cached_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct CachedModule {
+    module: Module,
+    size: usize,
+    last_used: u64,
+}
+
+struct RegisteredModule {
+    wasm_bytes: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    registered: HashMap<String, RegisteredModule>,
+    registered_bytes: usize,
+    compiled: HashMap<String, CachedModule>,
+    cached_bytes: usize,
+    ticks: u64,
+    hits: u64,
+    misses: u64,
+}
+
+pub struct ModuleRegistry {
+    inner: Mutex<Inner>,
+    pub max_cache_bytes: usize,
+    pub max_registered_bytes: usize,
+}
+
+impl std::fmt::Debug for ModuleRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModuleRegistry").field("stats", &self.stats()).finish()
+    }
+}
+
+impl Default for ModuleRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_BYTES)
+    }
+}
+
+impl ModuleRegistry {
+    pub fn new(max_cache_bytes: usize) -> Self {
+        ModuleRegistry {
+            inner: Mutex::new(Inner::default()),
+            max_cache_bytes,
+            max_registered_bytes: DEFAULT_REGISTRY_BYTES,
+        }
+    }
+
+    // Validate and keep a module; registering the same bytes again is a no-op
+    pub fn register(&self, wasm_bytes: Vec<u8>) -> Result<String> {
+        let hash = module_hash(&wasm_bytes);
+        if self.bytes(&hash).is_some() {
+            return Ok(hash);
+        }
+        let size = wasm_bytes.len();
+        if size > self.max_registered_bytes {
+            return Err(anyhow::anyhow!("Module is {} bytes, more than the {} this node keeps", size, self.max_registered_bytes));
+        }
+        let module = runtime::compile(&wasm_bytes).map_err(|e| anyhow::anyhow!(e))?;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.registered.contains_key(&hash) {
+            return Ok(hash);
+        }
+        while inner.registered_bytes + size > self.max_registered_bytes {
+            let oldest = inner.registered.iter()
+                .min_by_key(|(_, registered)| registered.last_used)
+                .map(|(hash, _)| hash.clone());
+            match oldest {
+                Some(oldest) => Self::forget(&mut inner, &oldest),
+                None => break,
+            };
+        }
+        inner.ticks += 1;
+        let last_used = inner.ticks;
+        inner.registered.insert(hash.clone(), RegisteredModule { wasm_bytes: Arc::new(wasm_bytes), last_used });
+        inner.registered_bytes += size;
+        self.cache(&mut inner, &hash, module, size);
+        Ok(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.inner.lock().unwrap().registered.contains_key(hash)
+    }
+
+    // Counts as a use, so modules tasks keep naming stay registered
+    pub fn bytes(&self, hash: &str) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ticks += 1;
+        let tick = inner.ticks;
+        let registered = inner.registered.get_mut(hash)?;
+        registered.last_used = tick;
+        Some(Arc::clone(&registered.wasm_bytes))
+    }
+
+    pub fn remove(&self, hash: &str) -> bool {
+        Self::forget(&mut self.inner.lock().unwrap(), hash)
+    }
+
+    fn forget(inner: &mut Inner, hash: &str) -> bool {
+        if let Some(cached) = inner.compiled.remove(hash) {
+            inner.cached_bytes -= cached.size;
+        }
+        match inner.registered.remove(hash) {
+            Some(registered) => {
+                inner.registered_bytes -= registered.wasm_bytes.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Compiled form of a registered module
+    pub fn load(&self, hash: &str) -> Result<Module> {
+        if let Some(module) = self.cached(hash) {
+            return Ok(module);
+        }
+        let wasm_bytes = self.bytes(hash)
+            .ok_or_else(|| UnknownModule(hash.to_string()))?;
+        self.compile(hash, &wasm_bytes)
+    }
+
+    // Compiled form of an inline module, cached by its hash
+    pub fn load_bytes(&self, wasm_bytes: &[u8]) -> Result<Module> {
+        let hash = module_hash(wasm_bytes);
+        match self.cached(&hash) {
+            Some(module) => Ok(module),
+            None => self.compile(&hash, wasm_bytes),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            registered: inner.registered.len(),
+            registered_bytes: inner.registered_bytes,
+            cached: inner.compiled.len(),
+            cached_bytes: inner.cached_bytes,
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+
+    fn cached(&self, hash: &str) -> Option<Module> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ticks += 1;
+        let tick = inner.ticks;
+        match inner.compiled.get_mut(hash) {
+            Some(cached) => {
+                cached.last_used = tick;
+                let module = cached.module.clone();
+                inner.hits += 1;
+                Some(module)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn compile(&self, hash: &str, wasm_bytes: &[u8]) -> Result<Module> {
+        let module = runtime::compile(wasm_bytes).map_err(|e| anyhow::anyhow!(e))?;
+        let mut inner = self.inner.lock().unwrap();
+        self.cache(&mut inner, hash, module.clone(), wasm_bytes.len());
+        Ok(module)
+    }
+
+    fn cache(&self, inner: &mut Inner, hash: &str, module: Module, size: usize) {
+        // Bigger than the whole cache: use it this once and let it go
+        if size > self.max_cache_bytes || inner.compiled.contains_key(hash) {
+            return;
+        }
+        while inner.cached_bytes + size > self.max_cache_bytes {
+            let oldest = inner.compiled.iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(hash, _)| hash.clone());
+            match oldest.and_then(|hash| inner.compiled.remove(&hash)) {
+                Some(evicted) => inner.cached_bytes -= evicted.size,
+                None => break,
+            }
+        }
+        inner.ticks += 1;
+        let last_used = inner.ticks;
+        inner.compiled.insert(hash.to_string(), CachedModule { module, size, last_used });
+        inner.cached_bytes += size;
+    }
+}
@@ -0,0 +1,175 @@
+// XMBL MPC - compute over additively secret-shared input
+//
+// The submitting node splits input_data into one share per party: the input is
+// read as little-endian u32 words and every word is split into random words
+// that sum to it mod 2^32, so no party alone learns anything about it. Each
+// party runs the same module on its own share, round after round, with the
+// output of one round fed back as its input for the next. Between rounds the
+// shares are re-randomized. After the last round the coordinator adds the
+// parties' outputs word by word to get the result. Sharing and coordinating
+// both happen on the submitter, so the plain input never leaves it. A client
+// that sends a node an MPC ComputeRequest makes that node the submitter, and
+// so trusts it with the plain input.
+//
+// Any computation that is linear mod 2^32 over the words (sums, scaling by
+// public constants, adding a public constant on party 0 only) comes out
+// exactly as if it had run on the plain input.
+//
+// Each party's input is a 12-byte header followed by its share:
+//   party: u32, parties: u32, round: u32   (little-endian)
+// and its output must be a whole number of words.
+
+use std::future::Future;
+use std::sync::Arc;
+use anyhow::Result;
+
+use crate::runtime::{self, ResourceLimits};
+
+pub use xmbl_types::{MpcConfig, MAX_PARTIES, MAX_ROUNDS};
+
+pub const WORD_BYTES: usize = 4;
+pub const HEADER_BYTES: usize = 12;
+
+fn words(data: &[u8]) -> Result<Vec<u32>> {
+    if !data.len().is_multiple_of(WORD_BYTES) {
+        return Err(anyhow::anyhow!("MPC data must be a whole number of {}-byte words, got {} bytes", WORD_BYTES, data.len()));
+    }
+    Ok(data.chunks_exact(WORD_BYTES)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect())
+}
+
+fn bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+pub fn share(input: &[u8], parties: usize) -> Result<Vec<Vec<u8>>> {
+    let secret = words(input)?;
+    let mut last = secret.clone();
+    let mut shares = Vec::with_capacity(parties);
+    for _ in 1..parties {
+        let random: Vec<u32> = secret.iter().map(|_| rand::random()).collect();
+        for (word, r) in last.iter_mut().zip(&random) {
+            *word = word.wrapping_sub(*r);
+        }
+        shares.push(bytes(&random));
+    }
+    shares.push(bytes(&last));
+    Ok(shares)
+}
+
+// Shares only line up word for word if they are all the same length
+fn share_len(shares: &[Vec<u8>]) -> Result<usize> {
+    let len = shares.first().map(Vec::len)
+        .ok_or_else(|| anyhow::anyhow!("MPC has no shares"))?;
+    if shares.iter().any(|share| share.len() != len) {
+        return Err(anyhow::anyhow!("MPC parties returned shares of different lengths"));
+    }
+    Ok(len)
+}
+
+pub fn reconstruct(shares: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let len = share_len(shares)?;
+    let mut sum = vec![0u32; len / WORD_BYTES];
+    for share in shares {
+        for (total, word) in sum.iter_mut().zip(words(share)?) {
+            *total = total.wrapping_add(word);
+        }
+    }
+    Ok(bytes(&sum))
+}
+
+// Add a fresh sharing of zero so the next round's shares are unrelated to
+// the outputs the parties just saw
+fn refresh(shares: &mut [Vec<u8>]) -> Result<()> {
+    let len = share_len(shares)?;
+    let zero = share(&vec![0u8; len], shares.len())?;
+    for (share, mask) in shares.iter_mut().zip(zero) {
+        let masked: Vec<u32> = words(share)?.iter()
+            .zip(words(&mask)?)
+            .map(|(word, m)| word.wrapping_add(m))
+            .collect();
+        *share = bytes(&masked);
+    }
+    Ok(())
+}
+
+pub fn party_input(party: usize, parties: usize, round: u32, share: &[u8]) -> Vec<u8> {
+    let mut input = Vec::with_capacity(HEADER_BYTES + share.len());
+    input.extend_from_slice(&(party as u32).to_le_bytes());
+    input.extend_from_slice(&(parties as u32).to_le_bytes());
+    input.extend_from_slice(&round.to_le_bytes());
+    input.extend_from_slice(share);
+    input
+}
+
+pub fn check_party_input(input: &[u8]) -> Result<()> {
+    if input.len() < HEADER_BYTES {
+        return Err(anyhow::anyhow!("MPC input is missing its {}-byte party header", HEADER_BYTES));
+    }
+    let field = |i: usize| u32::from_le_bytes(input[i * 4..i * 4 + 4].try_into().unwrap());
+    let (party, parties, round) = (field(0), field(1), field(2));
+    MpcConfig { parties: parties as usize, rounds: round.saturating_add(1) }.validate().map_err(anyhow::Error::msg)?;
+    if party >= parties {
+        return Err(anyhow::anyhow!("MPC party {} is out of range for {} parties", party, parties));
+    }
+    words(&input[HEADER_BYTES..]).map(|_| ())
+}
+
+// Share the input, run every round on every party and reconstruct the
+// result. run_party(party, round, input) executes one party's round
+// wherever that party lives and returns its output share.
+pub async fn coordinate<F, Fut>(config: &MpcConfig, input: &[u8], run_party: F) -> Result<Vec<u8>>
+where
+    F: Fn(usize, u32, Vec<u8>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Vec<u8>>> + Send + 'static,
+{
+    config.validate().map_err(anyhow::Error::msg)?;
+    let mut shares = share(input, config.parties)?;
+
+    for round in 0..config.rounds {
+        if round > 0 {
+            refresh(&mut shares)?;
+        }
+        let runs: Vec<_> = shares.iter()
+            .enumerate()
+            .map(|(party, share)| {
+                let run_party = run_party.clone();
+                let input = party_input(party, config.parties, round, share);
+                tokio::spawn(async move { run_party(party, round, input).await })
+            })
+            .collect();
+
+        let mut outputs = Vec::with_capacity(runs.len());
+        for (party, run) in runs.into_iter().enumerate() {
+            let output = run.await
+                .map_err(|e| anyhow::anyhow!("MPC party {} panicked in round {}: {}", party, round, e))?
+                .map_err(|e| anyhow::anyhow!("MPC party {} failed in round {}: {}", party, round, e))?;
+            words(&output)?;
+            outputs.push(output);
+        }
+        shares = outputs;
+    }
+
+    reconstruct(&shares)
+}
+
+// Every party in this process, for testing MPC modules without a network
+pub async fn run_local(wasm_bytes: &[u8], input: &[u8], config: &MpcConfig, limits: &ResourceLimits) -> Result<Vec<u8>> {
+    let wasm_bytes: Arc<[u8]> = wasm_bytes.into();
+    let limits = limits.clone();
+    coordinate(config, input, move |party, round, party_input| {
+        let wasm_bytes = Arc::clone(&wasm_bytes);
+        let limits = limits.clone();
+        async move {
+            let task_id = format!("mpc-local-{}-{}", party, round);
+            let execution = tokio::task::spawn_blocking(move || {
+                runtime::execute(&task_id, &wasm_bytes, &party_input, &limits, None)
+            }).await?;
+            match execution.error {
+                Some(error) => Err(anyhow::anyhow!(error)),
+                None => Ok(execution.output),
+            }
+        }
+    }).await
+}
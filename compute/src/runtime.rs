@@ -0,0 +1,461 @@
+// XMBL WASM Runtime - compute tasks run in an embedded wasmi interpreter
+//
+// Task modules export their linear memory and a `run` entry point in one of
+// two shapes:
+//
+// Host ABI (what xmbl_compute_sdk produces):
+//   run() -> i32                    0 on success, anything else fails the task
+// with these imports from the "xmbl" module, all pointers into the module's
+// exported memory:
+//   input_len() -> i32                          size of input_data
+//   read_input(ptr, len) -> i32                 copy up to len input bytes to
+//                                               ptr, returns bytes copied
+//   write_output(ptr, len)                      append to the task output
+//   log(level, ptr, len)                        0 error, 1 warn, 2 info, 3 debug
+//   fetch_shard(id_ptr, id_len, out_ptr, cap) -> i64
+//                                               size of the shard named by the
+//                                               UTF-8 id, copied to out_ptr if
+//                                               it fits in cap; -1 not found
+//                                               or not the submitter's,
+//                                               -2 no storage on this node
+//   random(ptr, len)                            fill with bytes seeded from
+//                                               the task ID
+//
+// Buffer ABI, for modules that import nothing:
+//   alloc(len: i32) -> i32          reserve len bytes for the input
+//   run(ptr: i32, len: i32) -> i64  process the input and return
+//                                   (output_ptr << 32) | output_len
+// The host copies input_data into the buffer returned by alloc, calls run
+// and reads the output back out of linear memory.
+//
+// Every instruction burns fuel and every memory.grow goes through a limiter,
+// so a task can neither spin forever nor allocate without bound. Host calls
+// burn fuel too, per call and per byte they move, so looping over them is
+// no cheaper than doing the work in WASM. The report says how much of each
+// it used and why it stopped.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use wasmi::core::TrapCode;
+use wasmi::errors::{MemoryError, TableError};
+use crate::determinism::TaskRng;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, ResourceLimiter, Store};
+
+pub const HOST_MODULE: &str = "xmbl";
+pub const MEMORY_EXPORT: &str = "memory";
+pub const ALLOC_EXPORT: &str = "alloc";
+pub const ENTRY_POINT: &str = "run";
+
+pub const SHARD_NOT_FOUND: i64 = -1;
+pub const NO_SHARD_SOURCE: i64 = -2;
+
+// Caps what a task can push through xmbl.log
+const MAX_LOG_BYTES: usize = 64 * 1024;
+
+// Table entries are host-allocated too, so table.grow is capped like memory
+pub const MAX_TABLE_ELEMENTS: u32 = 10_000;
+
+// What a host call costs on top of the bytes it reads or writes
+pub const HOST_CALL_FUEL: u64 = 100;
+pub const FUEL_PER_BYTE: u64 = 1;
+
+// Read access to stored shards for xmbl.fetch_shard. Called from the
+// blocking thread the task runs on. Both return None for shards the
+// submitter doesn't own, so a task can't tell those from missing ones.
+pub trait ShardSource: Send + Sync + std::fmt::Debug {
+    fn shard_size(&self, submitter: &str, shard_id: &str) -> Option<u64>;
+    fn fetch_shard(&self, submitter: &str, shard_id: &str) -> Option<Vec<u8>>;
+}
+
+// A shard source as seen by one task: reads are made on behalf of whoever
+// submitted it
+#[derive(Clone, Debug)]
+pub struct ShardAccess {
+    pub source: Arc<dyn ShardSource>,
+    pub submitter: String,
+}
+
+impl ShardAccess {
+    pub fn new(source: Arc<dyn ShardSource>, submitter: &str) -> Self {
+        ShardAccess { source, submitter: submitter.to_string() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    // Roughly one unit per executed instruction
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+    pub timeout_ms: u64,
+    pub max_output_bytes: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            fuel: 1_000_000_000,
+            max_memory_bytes: 64 * 1024 * 1024,
+            timeout_ms: 30_000,
+            max_output_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Termination {
+    #[default]
+    Completed,
+    InvalidModule,
+    Trap,
+    OutOfFuel,
+    MemoryLimit,
+    OutputLimit,
+    Timeout,
+    Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct Execution {
+    pub output: Vec<u8>,
+    pub error: Option<String>,
+    pub termination: Termination,
+    pub fuel_used: u64,
+    pub peak_memory_bytes: u64,
+    pub logs: Vec<String>,
+}
+
+impl Execution {
+    pub fn failed(termination: Termination, error: String) -> Self {
+        Execution {
+            output: Vec::new(),
+            error: Some(error),
+            termination,
+            fuel_used: 0,
+            peak_memory_bytes: 0,
+            logs: Vec::new(),
+        }
+    }
+}
+
+struct HostState {
+    limiter: MemoryLimiter,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    max_output_bytes: usize,
+    output_exceeded: bool,
+    logs: Vec<String>,
+    log_bytes: usize,
+    shards: Option<ShardAccess>,
+    // Shards are sized, read and verified at most once per task; contents
+    // are kept while they fit in the task's memory limit
+    shard_sizes: HashMap<String, Option<u64>>,
+    shard_data: HashMap<String, Arc<Vec<u8>>>,
+    shard_data_bytes: usize,
+    rng: TaskRng,
+}
+
+impl HostState {
+    fn cache_shard(&mut self, shard_id: &str, shard: &Arc<Vec<u8>>) {
+        if self.shard_data_bytes + shard.len() <= self.limiter.max_bytes {
+            self.shard_data_bytes += shard.len();
+            self.shard_data.insert(shard_id.to_string(), Arc::clone(shard));
+        }
+    }
+}
+
+// Caps linear memory at max_bytes and tables at MAX_TABLE_ELEMENTS, and
+// remembers the memory high-water mark
+struct MemoryLimiter {
+    max_bytes: usize,
+    peak_bytes: usize,
+    denied: bool,
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool, MemoryError> {
+        if desired > self.max_bytes {
+            self.denied = true;
+            return Ok(false);
+        }
+        self.peak_bytes = self.peak_bytes.max(desired);
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> Result<bool, TableError> {
+        if desired > MAX_TABLE_ELEMENTS {
+            self.denied = true;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+// One engine for the whole process, so compiled modules can be cached and
+// reused by every task
+pub fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    })
+}
+
+pub fn compile(wasm_bytes: &[u8]) -> Result<Module, String> {
+    Module::new(engine(), wasm_bytes).map_err(|e| format!("Invalid WASM module: {}", e))
+}
+
+pub fn execute(task_id: &str, wasm_bytes: &[u8], input_data: &[u8], limits: &ResourceLimits, shards: Option<ShardAccess>) -> Execution {
+    match compile(wasm_bytes) {
+        Ok(module) => execute_module(task_id, &module, input_data, limits, shards),
+        Err(error) => Execution::failed(Termination::InvalidModule, error),
+    }
+}
+
+// Runs to completion or until a limit is hit. The wall-clock timeout is left
+// to the caller, since the interpreter cannot be interrupted from outside;
+// fuel guarantees an abandoned run still stops, and callers keep its worker
+// slot taken until it does.
+pub fn execute_module(task_id: &str, module: &Module, input_data: &[u8], limits: &ResourceLimits, shards: Option<ShardAccess>) -> Execution {
+    let engine = engine();
+    let mut store = Store::new(engine, HostState {
+        limiter: MemoryLimiter { max_bytes: limits.max_memory_bytes, peak_bytes: 0, denied: false },
+        input: input_data.to_vec(),
+        output: Vec::new(),
+        max_output_bytes: limits.max_output_bytes,
+        output_exceeded: false,
+        logs: Vec::new(),
+        log_bytes: 0,
+        shards,
+        shard_sizes: HashMap::new(),
+        shard_data: HashMap::new(),
+        shard_data_bytes: 0,
+        rng: TaskRng::new(task_id),
+    });
+    store.limiter(|state| &mut state.limiter);
+    if let Err(e) = store.set_fuel(limits.fuel) {
+        return Execution::failed(Termination::Trap, format!("Failed to set fuel: {}", e));
+    }
+
+    let result = run(engine, &mut store, module, input_data, limits);
+    let fuel_used = limits.fuel.saturating_sub(store.get_fuel().unwrap_or(0));
+    let peak_memory_bytes = store.data().limiter.peak_bytes as u64;
+    let logs = std::mem::take(&mut store.data_mut().logs);
+
+    match result {
+        Ok(output) => Execution {
+            output,
+            error: None,
+            termination: Termination::Completed,
+            fuel_used,
+            peak_memory_bytes,
+            logs,
+        },
+        Err((termination, error)) => Execution {
+            fuel_used,
+            peak_memory_bytes,
+            logs,
+            ..Execution::failed(termination, error)
+        },
+    }
+}
+
+fn run(
+    engine: &Engine,
+    store: &mut Store<HostState>,
+    module: &Module,
+    input_data: &[u8],
+    limits: &ResourceLimits,
+) -> Result<Vec<u8>, (Termination, String)> {
+    let linker = host_linker(engine)
+        .map_err(|e| (Termination::Trap, format!("Failed to link host functions: {}", e)))?;
+    let instance = linker.instantiate(&mut *store, module)
+        .and_then(|pre| pre.start(&mut *store))
+        .map_err(|e| trap(store, "instantiation", e))?;
+
+    let memory = instance.get_memory(&*store, MEMORY_EXPORT)
+        .ok_or_else(|| (Termination::InvalidModule, format!("Module does not export '{}'", MEMORY_EXPORT)))?;
+
+    // Host ABI modules take no arguments and talk to us through imports
+    if let Ok(entry) = instance.get_typed_func::<(), i32>(&*store, ENTRY_POINT) {
+        let status = entry.call(&mut *store, ())
+            .map_err(|e| trap(store, ENTRY_POINT, e))?;
+        if status != 0 {
+            return Err((Termination::Trap, format!("Task exited with status {}", status)));
+        }
+        return Ok(std::mem::take(&mut store.data_mut().output));
+    }
+
+    let alloc = instance.get_typed_func::<i32, i32>(&*store, ALLOC_EXPORT)
+        .map_err(|e| (Termination::InvalidModule, format!("Module does not export '{}(i32) -> i32': {}", ALLOC_EXPORT, e)))?;
+    let entry = instance.get_typed_func::<(i32, i32), i64>(&*store, ENTRY_POINT)
+        .map_err(|e| (Termination::InvalidModule, format!("Module does not export '{}(i32, i32) -> i64': {}", ENTRY_POINT, e)))?;
+
+    let input_len = i32::try_from(input_data.len())
+        .map_err(|_| (Termination::MemoryLimit, "Input too large for a 32-bit module".to_string()))?;
+    let input_ptr = alloc.call(&mut *store, input_len)
+        .map_err(|e| trap(store, ALLOC_EXPORT, e))?;
+    memory.write(&mut *store, input_ptr as u32 as usize, input_data)
+        .map_err(|_| (Termination::Trap, format!("{} returned a buffer outside linear memory", ALLOC_EXPORT)))?;
+
+    let packed = entry.call(&mut *store, (input_ptr, input_len))
+        .map_err(|e| trap(store, ENTRY_POINT, e))?;
+    read_output(store, memory, packed, limits.max_output_bytes)
+}
+
+fn host_linker(engine: &Engine) -> Result<Linker<HostState>, wasmi::errors::LinkerError> {
+    let mut linker = <Linker<HostState>>::new(engine);
+
+    linker.func_wrap(HOST_MODULE, "input_len", |mut caller: Caller<'_, HostState>| -> Result<i32, wasmi::Error> {
+        charge(&mut caller, 0)?;
+        Ok(caller.data().input.len() as i32)
+    })?;
+
+    linker.func_wrap(HOST_MODULE, "read_input", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+        let count = (len.max(0) as usize).min(caller.data().input.len());
+        charge(&mut caller, count)?;
+        let memory = exported_memory(&caller)?;
+        let (data, state) = memory.data_and_store_mut(&mut caller);
+        guest_slice(data, ptr, count as i32)?.copy_from_slice(&state.input[..count]);
+        Ok(count as i32)
+    })?;
+
+    linker.func_wrap(HOST_MODULE, "write_output", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, len.max(0) as usize)?;
+        let memory = exported_memory(&caller)?;
+        let (data, state) = memory.data_and_store_mut(&mut caller);
+        let bytes = guest_slice(data, ptr, len)?;
+        if state.output.len() + bytes.len() > state.max_output_bytes {
+            state.output_exceeded = true;
+            return Err(wasmi::Error::new(format!("Output exceeds the {} byte limit", state.max_output_bytes)));
+        }
+        state.output.extend_from_slice(bytes);
+        Ok(())
+    })?;
+
+    linker.func_wrap(HOST_MODULE, "log", |mut caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, len.max(0) as usize)?;
+        let memory = exported_memory(&caller)?;
+        let (data, state) = memory.data_and_store_mut(&mut caller);
+        let message = String::from_utf8_lossy(guest_slice(data, ptr, len)?).into_owned();
+        let level = match level {
+            0 => log::Level::Error,
+            1 => log::Level::Warn,
+            2 => log::Level::Info,
+            _ => log::Level::Debug,
+        };
+        log::log!(level, "[task] {}", message);
+        if state.log_bytes + message.len() <= MAX_LOG_BYTES {
+            state.log_bytes += message.len();
+            state.logs.push(format!("{}: {}", level, message));
+        }
+        Ok(())
+    })?;
+
+    linker.func_wrap(HOST_MODULE, "fetch_shard", |mut caller: Caller<'_, HostState>, id_ptr: i32, id_len: i32, out_ptr: i32, out_cap: i32| -> Result<i64, wasmi::Error> {
+        charge(&mut caller, id_len.max(0) as usize)?;
+        let shards = match caller.data().shards.clone() {
+            Some(shards) => shards,
+            None => return Ok(NO_SHARD_SOURCE),
+        };
+        let memory = exported_memory(&caller)?;
+        let shard_id = std::str::from_utf8(guest_slice(memory.data_mut(&mut caller), id_ptr, id_len)?)
+            .map_err(|_| wasmi::Error::new("Shard id is not valid UTF-8"))?
+            .to_string();
+        let size = match caller.data().shard_sizes.get(&shard_id) {
+            Some(size) => *size,
+            None => {
+                let size = shards.source.shard_size(&shards.submitter, &shard_id);
+                caller.data_mut().shard_sizes.insert(shard_id.clone(), size);
+                size
+            }
+        };
+        let size = match size {
+            Some(size) => size,
+            None => return Ok(SHARD_NOT_FOUND),
+        };
+        // Too small a buffer just reports the size so the guest can retry,
+        // without the shard ever being read
+        if size > out_cap.max(0) as u64 {
+            return Ok(size as i64);
+        }
+        charge(&mut caller, size as usize)?;
+        let shard = match caller.data().shard_data.get(&shard_id).cloned() {
+            Some(shard) => shard,
+            None => match shards.source.fetch_shard(&shards.submitter, &shard_id) {
+                Some(shard) if shard.len() as u64 == size => {
+                    let shard = Arc::new(shard);
+                    caller.data_mut().cache_shard(&shard_id, &shard);
+                    shard
+                }
+                _ => return Ok(SHARD_NOT_FOUND),
+            },
+        };
+        guest_slice(memory.data_mut(&mut caller), out_ptr, shard.len() as i32)?.copy_from_slice(&shard);
+        Ok(size as i64)
+    })?;
+
+    linker.func_wrap(HOST_MODULE, "random", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, len.max(0) as usize)?;
+        let memory = exported_memory(&caller)?;
+        let (data, state) = memory.data_and_store_mut(&mut caller);
+        state.rng.fill(guest_slice(data, ptr, len)?);
+        Ok(())
+    })?;
+
+    Ok(linker)
+}
+
+// Burn fuel for a host call touching `bytes` bytes, trapping like an
+// instruction would once it runs out
+fn charge(caller: &mut Caller<'_, HostState>, bytes: usize) -> Result<(), wasmi::Error> {
+    let cost = HOST_CALL_FUEL.saturating_add((bytes as u64).saturating_mul(FUEL_PER_BYTE));
+    let fuel = caller.get_fuel()?;
+    caller.set_fuel(fuel.saturating_sub(cost))?;
+    if fuel < cost {
+        return Err(TrapCode::OutOfFuel.into());
+    }
+    Ok(())
+}
+
+fn exported_memory(caller: &Caller<'_, HostState>) -> Result<Memory, wasmi::Error> {
+    caller.get_export(MEMORY_EXPORT)
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new(format!("Module does not export '{}'", MEMORY_EXPORT)))
+}
+
+fn guest_slice(data: &mut [u8], ptr: i32, len: i32) -> Result<&mut [u8], wasmi::Error> {
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len.max(0) as usize)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| wasmi::Error::new("Pointer outside linear memory"))?;
+    Ok(&mut data[start..end])
+}
+
+fn trap(store: &Store<HostState>, during: &str, error: wasmi::Error) -> (Termination, String) {
+    let termination = if error.as_trap_code() == Some(TrapCode::OutOfFuel) {
+        Termination::OutOfFuel
+    } else if store.data().output_exceeded {
+        Termination::OutputLimit
+    } else if store.data().limiter.denied {
+        Termination::MemoryLimit
+    } else {
+        Termination::Trap
+    };
+    (termination, format!("WASM trap in {}: {}", during, error))
+}
+
+fn read_output(store: &Store<HostState>, memory: Memory, packed: i64, max_output_bytes: usize) -> Result<Vec<u8>, (Termination, String)> {
+    let packed = packed as u64;
+    let ptr = (packed >> 32) as usize;
+    let len = (packed & 0xffff_ffff) as usize;
+    if len > max_output_bytes {
+        return Err((Termination::OutputLimit, format!("Output of {} bytes exceeds the {} byte limit", len, max_output_bytes)));
+    }
+
+    let mut output = vec![0u8; len];
+    memory.read(store, ptr, &mut output)
+        .map_err(|_| (Termination::Trap, format!("Output {}+{} lies outside linear memory", ptr, len)))?;
+    Ok(output)
+}
@@ -0,0 +1,91 @@
+// XMBL Scheduler - which queued task runs next
+//
+// Tasks wait in a bounded queue. Higher priority always goes first. Among
+// tasks of equal priority the submitter that was served least recently goes
+// next, so one busy peer cannot starve everyone else; a submitter's own
+// tasks run in the order they were submitted.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use anyhow::Result;
+
+use crate::ComputeTask;
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedTask {
+    pub task: ComputeTask,
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskQueue {
+    pub capacity: usize,
+    pending: Vec<QueuedTask>,
+    // Tick at which each submitter last had a task started
+    last_served: HashMap<String, u64>,
+    next_seq: u64,
+    ticks: u64,
+}
+
+impl TaskQueue {
+    pub fn new(capacity: usize) -> Self {
+        TaskQueue {
+            capacity,
+            pending: Vec::new(),
+            last_served: HashMap::new(),
+            next_seq: 0,
+            ticks: 0,
+        }
+    }
+
+    pub fn push(&mut self, task: ComputeTask) -> Result<()> {
+        if self.is_full() {
+            return Err(anyhow::anyhow!("Task queue is full"));
+        }
+        self.next_seq += 1;
+        self.pending.push(QueuedTask { task, seq: self.next_seq });
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<ComputeTask> {
+        let top = self.pending.iter().map(|q| q.task.priority).max()?;
+        let (index, _) = self.pending.iter()
+            .enumerate()
+            .filter(|(_, q)| q.task.priority == top)
+            .min_by_key(|(_, q)| (self.last_served.get(&q.task.submitter).copied().unwrap_or(0), q.seq))?;
+
+        let queued = self.pending.remove(index);
+        self.ticks += 1;
+        self.last_served.insert(queued.task.submitter.clone(), self.ticks);
+        Some(queued.task)
+    }
+
+    pub fn remove(&mut self, task_id: &str) -> Option<ComputeTask> {
+        let index = self.pending.iter().position(|q| q.task.task_id == task_id)?;
+        Some(self.pending.remove(index).task)
+    }
+
+    pub fn contains(&self, task_id: &str) -> bool {
+        self.pending.iter().any(|q| q.task.task_id == task_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.capacity
+    }
+}
+
+impl Default for TaskQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUEUE_CAPACITY)
+    }
+}
@@ -0,0 +1,118 @@
+// XMBL Verification - run a task on several peers and trust the majority
+//
+// The coordinator sends the same task to N peers. One of them returns the
+// full output and the rest only its sha256, so validators cost a hash on the
+// wire rather than a second copy of the result. Reports are grouped by hash
+// and a hash reported by a quorum (by default more than half of N) wins.
+// Every peer's verdict is recorded so the coordinator can favour peers that
+// keep agreeing with the majority.
+
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+
+pub use xmbl_types::{VerificationPolicy, VerificationReport, Verdict};
+
+// What one peer reported: the hash of its output, or why it produced none
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerOutcome {
+    pub peer_id: String,
+    pub output_hash: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn output_hash(output: &[u8]) -> String {
+    hex::encode(Sha256::digest(output))
+}
+
+pub fn resolve(policy: &VerificationPolicy, outcomes: &[PeerOutcome]) -> VerificationReport {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for hash in outcomes.iter().filter_map(|o| o.output_hash.as_deref()) {
+        *counts.entry(hash).or_default() += 1;
+    }
+    let reached: Vec<(&str, usize)> = counts.into_iter()
+        .filter(|(_, count)| *count >= policy.quorum.max(1))
+        .collect();
+    let best = reached.iter().map(|(_, count)| *count).max();
+    // Two hashes tied at the top means neither can be trusted
+    let mut leaders = reached.iter().filter(|(_, count)| Some(*count) == best);
+    let winner = match (leaders.next(), leaders.next()) {
+        (Some((hash, _)), None) => Some(hash.to_string()),
+        _ => None,
+    };
+
+    let verdicts = outcomes.iter()
+        .map(|o| {
+            let verdict = match (&o.output_hash, &winner) {
+                (None, _) => Verdict::Failed,
+                (Some(hash), Some(winner)) if hash == winner => Verdict::Agreed,
+                (Some(_), Some(_)) => Verdict::Disagreed,
+                // Without a quorum nobody can be shown to be right
+                (Some(_), None) => Verdict::Inconclusive,
+            };
+            (o.peer_id.clone(), verdict)
+        })
+        .collect();
+
+    VerificationReport { output_hash: winner, verdicts }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerComputeRecord {
+    pub agreed: u64,
+    pub disagreed: u64,
+    pub failed: u64,
+}
+
+impl PeerComputeRecord {
+    // Share of verified runs the peer got right; unknown peers start at 1.0
+    pub fn score(&self) -> f64 {
+        let total = self.agreed + self.disagreed + self.failed;
+        if total == 0 {
+            return 1.0;
+        }
+        self.agreed as f64 / total as f64
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ComputeReputation {
+    records: HashMap<String, PeerComputeRecord>,
+}
+
+impl ComputeReputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only runs that reached a quorum say anything about individual peers
+    pub fn record(&mut self, report: &VerificationReport) {
+        if !report.verified() {
+            return;
+        }
+        for (peer_id, verdict) in &report.verdicts {
+            let record = self.records.entry(peer_id.clone()).or_default();
+            match verdict {
+                Verdict::Agreed => record.agreed += 1,
+                Verdict::Disagreed => record.disagreed += 1,
+                Verdict::Failed => record.failed += 1,
+                Verdict::Inconclusive => {}
+            }
+        }
+    }
+
+    pub fn get(&self, peer_id: &str) -> PeerComputeRecord {
+        self.records.get(peer_id).cloned().unwrap_or_default()
+    }
+
+    pub fn score(&self, peer_id: &str) -> f64 {
+        self.get(peer_id).score()
+    }
+
+    // Best-scoring peers first
+    pub fn rank(&self, peers: &[String]) -> Vec<String> {
+        let mut ranked = peers.to_vec();
+        ranked.sort_by(|a, b| self.score(b).total_cmp(&self.score(a)).then_with(|| a.cmp(b)));
+        ranked
+    }
+}
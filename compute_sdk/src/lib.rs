@@ -0,0 +1,286 @@
+// XMBL Compute SDK - write compute tasks against the xmbl host ABI
+//
+// A task is a cdylib built for wasm32-unknown-unknown whose entry function
+// is exported with the entry! macro:
+//
+//     use xmbl_compute_sdk as xmbl;
+//
+//     fn reverse() -> Result<(), String> {
+//         let mut data = xmbl::input();
+//         data.reverse();
+//         xmbl::write_output(&data);
+//         Ok(())
+//     }
+//
+//     xmbl::entry!(reverse);
+//
+// On any other target the same calls go to an in-process mock host, so task
+// logic can be unit tested natively with testing::run.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchError {
+    NotFound,
+    // The node running the task has no storage attached
+    Unavailable,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::NotFound => write!(f, "Shard not found"),
+            FetchError::Unavailable => write!(f, "No storage available to this task"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+// The task's input_data
+pub fn input() -> Vec<u8> {
+    host::input()
+}
+
+// Append to the task's output
+pub fn write_output(data: &[u8]) {
+    host::write_output(data)
+}
+
+pub fn log(level: Level, message: &str) {
+    host::log(level, message)
+}
+
+pub fn fetch_shard(shard_id: &str) -> Result<Vec<u8>, FetchError> {
+    host::fetch_shard(shard_id)
+}
+
+// Pseudo-random bytes seeded from the task ID, the same on every replica
+pub fn random(buffer: &mut [u8]) {
+    host::random(buffer)
+}
+
+// Export `run() -> i32` calling the given fn() -> Result<(), E: Display>.
+// Errors are logged and fail the task.
+#[macro_export]
+macro_rules! entry {
+    ($task:path) => {
+        #[no_mangle]
+        pub extern "C" fn run() -> i32 {
+            match $task() {
+                Ok(()) => 0,
+                Err(e) => {
+                    $crate::log($crate::Level::Error, &e.to_string());
+                    1
+                }
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "wasm32")]
+mod host {
+    use super::{FetchError, Level};
+
+    // Status codes from xmbl.fetch_shard, see xmbl_compute::runtime
+    const SHARD_NOT_FOUND: i64 = -1;
+    const NO_SHARD_SOURCE: i64 = -2;
+
+    #[link(wasm_import_module = "xmbl")]
+    extern "C" {
+        fn input_len() -> i32;
+        fn read_input(ptr: *mut u8, len: i32) -> i32;
+        #[link_name = "write_output"]
+        fn host_write_output(ptr: *const u8, len: i32);
+        #[link_name = "log"]
+        fn host_log(level: i32, ptr: *const u8, len: i32);
+        #[link_name = "fetch_shard"]
+        fn host_fetch_shard(id_ptr: *const u8, id_len: i32, out_ptr: *mut u8, out_cap: i32) -> i64;
+        #[link_name = "random"]
+        fn host_random(ptr: *mut u8, len: i32);
+    }
+
+    pub fn input() -> Vec<u8> {
+        unsafe {
+            let mut buffer = vec![0u8; input_len() as usize];
+            let read = read_input(buffer.as_mut_ptr(), buffer.len() as i32);
+            buffer.truncate(read as usize);
+            buffer
+        }
+    }
+
+    pub fn write_output(data: &[u8]) {
+        unsafe { host_write_output(data.as_ptr(), data.len() as i32) }
+    }
+
+    pub fn log(level: Level, message: &str) {
+        unsafe { host_log(level as i32, message.as_ptr(), message.len() as i32) }
+    }
+
+    pub fn fetch_shard(shard_id: &str) -> Result<Vec<u8>, FetchError> {
+        // Ask for the size first, then fetch into a buffer that fits
+        let mut buffer = Vec::new();
+        loop {
+            let size = unsafe {
+                host_fetch_shard(shard_id.as_ptr(), shard_id.len() as i32, buffer.as_mut_ptr(), buffer.len() as i32)
+            };
+            match size {
+                SHARD_NOT_FOUND => return Err(FetchError::NotFound),
+                NO_SHARD_SOURCE => return Err(FetchError::Unavailable),
+                size if size as usize <= buffer.len() => {
+                    buffer.truncate(size as usize);
+                    return Ok(buffer);
+                }
+                size => buffer.resize(size as usize, 0),
+            }
+        }
+    }
+
+    pub fn random(buffer: &mut [u8]) {
+        unsafe { host_random(buffer.as_mut_ptr(), buffer.len() as i32) }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    use super::{FetchError, Level};
+    use super::testing::HOST;
+
+    pub fn input() -> Vec<u8> {
+        HOST.with(|host| host.borrow().input.clone())
+    }
+
+    pub fn write_output(data: &[u8]) {
+        HOST.with(|host| host.borrow_mut().output.extend_from_slice(data))
+    }
+
+    pub fn log(level: Level, message: &str) {
+        HOST.with(|host| host.borrow_mut().logs.push((level, message.to_string())))
+    }
+
+    pub fn fetch_shard(shard_id: &str) -> Result<Vec<u8>, FetchError> {
+        HOST.with(|host| {
+            let host = host.borrow();
+            match &host.shards {
+                Some(shards) => shards.get(shard_id).cloned().ok_or(FetchError::NotFound),
+                None => Err(FetchError::Unavailable),
+            }
+        })
+    }
+
+    // xorshift64 over MockHost::random_state, repeatable for a given seed
+    pub fn random(buffer: &mut [u8]) {
+        HOST.with(|host| {
+            let state = &mut host.borrow_mut().random_state;
+            for byte in buffer.iter_mut() {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                *byte = *state as u8;
+            }
+        })
+    }
+}
+
+// Native stand-in for the host, one per thread
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing {
+    use super::Level;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Debug)]
+    pub struct MockHost {
+        pub input: Vec<u8>,
+        // None behaves like a node without storage
+        pub shards: Option<HashMap<String, Vec<u8>>>,
+        pub output: Vec<u8>,
+        pub logs: Vec<(Level, String)>,
+        // Seed for random; must not be zero
+        pub random_state: u64,
+    }
+
+    impl Default for MockHost {
+        fn default() -> Self {
+            MockHost {
+                input: Vec::new(),
+                shards: None,
+                output: Vec::new(),
+                logs: Vec::new(),
+                random_state: 0x2545_f491_4f6c_dd1d,
+            }
+        }
+    }
+
+    thread_local! {
+        pub(crate) static HOST: RefCell<MockHost> = RefCell::new(MockHost::default());
+    }
+
+    // Run a task against the given host state and return what it produced
+    pub fn run<R>(host: MockHost, task: impl FnOnce() -> R) -> (R, MockHost) {
+        HOST.with(|h| *h.borrow_mut() = host);
+        let result = task();
+        (result, HOST.with(|h| h.take()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{self, MockHost};
+    use std::collections::HashMap;
+
+    fn prefix_with_shard() -> Result<(), FetchError> {
+        let input = input();
+        let shard_id = String::from_utf8_lossy(&input);
+        log(Level::Info, &format!("fetching {}", shard_id));
+        write_output(&fetch_shard(&shard_id)?);
+        write_output(&input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_task_runs_against_mock_host() {
+        let host = MockHost {
+            input: b"shard_1".to_vec(),
+            shards: Some(HashMap::from([("shard_1".to_string(), b"stored:".to_vec())])),
+            ..MockHost::default()
+        };
+        let (result, host) = testing::run(host, prefix_with_shard);
+        assert!(result.is_ok());
+        assert_eq!(host.output, b"stored:shard_1".to_vec());
+        assert_eq!(host.logs, vec![(Level::Info, "fetching shard_1".to_string())]);
+    }
+
+    #[test]
+    fn test_fetch_errors_from_mock_host() {
+        let host = MockHost { input: b"missing".to_vec(), shards: Some(HashMap::new()), ..MockHost::default() };
+        let (result, _) = testing::run(host, prefix_with_shard);
+        assert_eq!(result, Err(FetchError::NotFound));
+
+        let (result, _) = testing::run(MockHost::default(), || fetch_shard("any"));
+        assert_eq!(result, Err(FetchError::Unavailable));
+    }
+
+    #[test]
+    fn test_mock_random_repeats_per_seed() {
+        let draw = || {
+            let mut buffer = [0u8; 16];
+            random(&mut buffer);
+            buffer
+        };
+        let (first, _) = testing::run(MockHost::default(), draw);
+        let (second, _) = testing::run(MockHost::default(), draw);
+        let (other, _) = testing::run(MockHost { random_state: 7, ..MockHost::default() }, draw);
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+}
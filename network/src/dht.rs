@@ -0,0 +1,409 @@
+// XMBL DHT - Kademlia-style peer discovery and key/value storage
+//
+// Nodes are placed in a 160-bit space by their 20-byte node IDs and know
+// each other through k-buckets: bucket i holds up to K contacts whose IDs
+// share exactly i leading bits with ours, least recently seen first. A full
+// bucket only takes a newcomer once its oldest contact fails to answer, so
+// long-lived peers are preferred. Lookups ask the closest known contacts,
+// ALPHA at a time, for contacts closer still until no one closer turns up.
+// Values are stored on the K nodes closest to the hash of their key.
+//
+// Seeds are bare addresses; a seed's node ID is learned from the signature
+// on its first answer.
+
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_protocol::{self as protocol, Hello, P2PMessage, PeerInfo};
+
+pub use xmbl_protocol::{Contact, DhtMessage};
+
+use crate::handshake;
+use crate::secure::SecureStream;
+
+pub const ID_BYTES: usize = 20;
+pub const K: usize = 20;
+pub const ALPHA: usize = 3;
+pub const MAX_VALUE_BYTES: usize = 64 * 1024;
+pub const DEFAULT_VALUE_TTL_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_MAX_VALUES: usize = 10_000;
+// A peer that stops answering partway must not hold a lookup up forever
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Comma-separated seed addresses
+pub const SEEDS_ENV: &str = "XMBL_SEEDS";
+pub const DEFAULT_SEEDS: &[&str] = &["127.0.0.1:3010"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeKey(pub [u8; ID_BYTES]);
+
+impl NodeKey {
+    // NodeIdentity IDs ("0x" and 40 hex digits) are used as-is; anything
+    // else is hashed into the key space
+    pub fn from_node_id(node_id: &str) -> Self {
+        let parsed = node_id.strip_prefix("0x")
+            .and_then(|hex_id| hex::decode(hex_id).ok())
+            .and_then(|bytes| <[u8; ID_BYTES]>::try_from(bytes).ok());
+        match parsed {
+            Some(bytes) => NodeKey(bytes),
+            None => Self::for_value(node_id),
+        }
+    }
+
+    pub fn for_value(key: &str) -> Self {
+        let digest = Sha256::digest(key.as_bytes());
+        let mut bytes = [0u8; ID_BYTES];
+        bytes.copy_from_slice(&digest[..ID_BYTES]);
+        NodeKey(bytes)
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.0))
+    }
+
+    pub fn distance(&self, other: &NodeKey) -> NodeKey {
+        let mut bytes = [0u8; ID_BYTES];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeKey(bytes)
+    }
+
+    pub fn of(contact: &Contact) -> Self {
+        Self::from_node_id(&contact.node_id)
+    }
+
+    // Leading bits shared with other, None for the same key
+    fn bucket_index(&self, other: &NodeKey) -> Option<usize> {
+        let distance = self.distance(other);
+        let first = distance.0.iter().position(|b| *b != 0)?;
+        Some(first * 8 + distance.0[first].leading_zeros() as usize)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Added,
+    Updated,
+    // The bucket is full; ping oldest and replace it if it is gone
+    Full { oldest: Contact },
+    // Our own ID
+    Ignored,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoutingTable {
+    pub local: NodeKey,
+    pub k: usize,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(local_node_id: &str) -> Self {
+        RoutingTable {
+            local: NodeKey::from_node_id(local_node_id),
+            k: K,
+            buckets: vec![Vec::new(); ID_BYTES * 8],
+        }
+    }
+
+    pub fn insert(&mut self, contact: Contact) -> InsertOutcome {
+        let index = match self.local.bucket_index(&NodeKey::of(&contact)) {
+            Some(index) => index,
+            None => return InsertOutcome::Ignored,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|c| c.node_id == contact.node_id) {
+            // Most recently seen goes to the back
+            bucket.remove(position);
+            bucket.push(contact);
+            return InsertOutcome::Updated;
+        }
+        if bucket.len() >= self.k {
+            return InsertOutcome::Full { oldest: bucket[0].clone() };
+        }
+        bucket.push(contact);
+        InsertOutcome::Added
+    }
+
+    // Swap a contact that stopped answering for one waiting on its bucket
+    pub fn replace(&mut self, stale_node_id: &str, contact: Contact) -> InsertOutcome {
+        self.remove(stale_node_id);
+        self.insert(contact)
+    }
+
+    pub fn remove(&mut self, node_id: &str) -> Option<Contact> {
+        let index = self.local.bucket_index(&NodeKey::from_node_id(node_id))?;
+        let bucket = &mut self.buckets[index];
+        let position = bucket.iter().position(|c| c.node_id == node_id)?;
+        Some(bucket.remove(position))
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<&Contact> {
+        let index = self.local.bucket_index(&NodeKey::from_node_id(node_id))?;
+        self.buckets[index].iter().find(|c| c.node_id == node_id)
+    }
+
+    pub fn closest(&self, target: &NodeKey, count: usize) -> Vec<Contact> {
+        let mut contacts = self.contacts();
+        contacts.sort_by_key(|c| NodeKey::of(c).distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Values this node holds for others, dropped once their TTL runs out. At
+// most max_values are held; new keys are refused while it is full.
+#[derive(Clone, Debug)]
+pub struct ValueStore {
+    pub ttl_secs: u64,
+    pub max_values: usize,
+    values: HashMap<String, (Vec<u8>, u64)>,
+}
+
+impl ValueStore {
+    pub fn new(ttl_secs: u64) -> Self {
+        ValueStore {
+            ttl_secs,
+            max_values: DEFAULT_MAX_VALUES,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: &str, value: Vec<u8>, now: u64) -> Result<()> {
+        if value.len() > MAX_VALUE_BYTES {
+            return Err(anyhow::anyhow!("Value of {} bytes exceeds the {} byte limit", value.len(), MAX_VALUE_BYTES));
+        }
+        if !self.values.contains_key(key) && self.values.len() >= self.max_values && self.expire(now) == 0 {
+            return Err(anyhow::anyhow!("Already holding {} values", self.max_values));
+        }
+        self.values.insert(key.to_string(), (value, now));
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str, now: u64) -> Option<Vec<u8>> {
+        self.values.get(key)
+            .filter(|(_, stored_at)| stored_at.saturating_add(self.ttl_secs) > now)
+            .map(|(value, _)| value.clone())
+    }
+
+    pub fn expire(&mut self, now: u64) -> usize {
+        let before = self.values.len();
+        let ttl_secs = self.ttl_secs;
+        self.values.retain(|_, (_, stored_at)| stored_at.saturating_add(ttl_secs) > now);
+        before - self.values.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl Default for ValueStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_VALUE_TTL_SECS)
+    }
+}
+
+// Answer a DHT request from what this node knows
+pub fn respond(table: &RoutingTable, values: &mut ValueStore, request: DhtMessage, now: u64) -> DhtMessage {
+    match request {
+        DhtMessage::FindNode { target } => DhtMessage::FindNodeResponse {
+            nodes: table.closest(&NodeKey::from_node_id(&target), table.k),
+        },
+        DhtMessage::Store { key, value } => match values.put(&key, value, now) {
+            Ok(()) => DhtMessage::StoreResponse { success: true, message: "Value stored".to_string() },
+            Err(e) => DhtMessage::StoreResponse { success: false, message: e.to_string() },
+        },
+        DhtMessage::FindValue { key } => match values.get(&key, now) {
+            Some(value) => DhtMessage::FindValueResponse { value: Some(value), nodes: Vec::new() },
+            None => DhtMessage::FindValueResponse {
+                value: None,
+                nodes: table.closest(&NodeKey::for_value(&key), table.k),
+            },
+        },
+        _ => DhtMessage::StoreResponse { success: false, message: "Not a DHT request".to_string() },
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Lookup {
+    // Closest contacts that answered, nearest first
+    pub closest: Vec<Contact>,
+    pub value: Option<Vec<u8>>,
+    pub failed: Vec<Contact>,
+}
+
+// Walk towards target, starting from seeds. query(address, request) returns
+// the verified node ID that answered along with its response.
+pub async fn lookup<F, Fut>(target: NodeKey, seeds: Vec<Contact>, request: DhtMessage, query: F) -> Lookup
+where
+    F: Fn(String, DhtMessage) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<(String, DhtMessage)>> + Send + 'static,
+{
+    let mut shortlist: Vec<Contact> = Vec::new();
+    let mut queried: HashSet<String> = HashSet::new();
+    let mut result = Lookup::default();
+    merge(&mut shortlist, seeds, &target);
+
+    loop {
+        let batch: Vec<Contact> = shortlist.iter()
+            .take(K)
+            .filter(|c| !queried.contains(&c.node_id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+        let queries: Vec<_> = batch.into_iter()
+            .map(|contact| {
+                queried.insert(contact.node_id.clone());
+                let query = query.clone();
+                let request = request.clone();
+                let address = contact.address.clone();
+                (contact, tokio::spawn(async move { query(address, request).await }))
+            })
+            .collect();
+
+        for (contact, response) in queries {
+            match response.await {
+                // Someone else answering at that address doesn't count
+                Ok(Ok((sender, response))) if sender == contact.node_id => match response {
+                    DhtMessage::FindValueResponse { value: Some(value), .. } => {
+                        result.value = Some(value);
+                        result.closest.push(contact);
+                    }
+                    DhtMessage::FindNodeResponse { nodes } | DhtMessage::FindValueResponse { nodes, .. } => {
+                        merge(&mut shortlist, nodes, &target);
+                        result.closest.push(contact);
+                    }
+                    _ => result.failed.push(contact),
+                },
+                _ => result.failed.push(contact),
+            }
+        }
+        if result.value.is_some() {
+            break;
+        }
+        shortlist.retain(|c| !result.failed.iter().any(|f| f.node_id == c.node_id));
+    }
+
+    result.closest.sort_by_key(|c| NodeKey::of(c).distance(&target));
+    result.closest.truncate(K);
+    result
+}
+
+fn merge(shortlist: &mut Vec<Contact>, contacts: Vec<Contact>, target: &NodeKey) {
+    for contact in contacts {
+        if !contact.address.is_empty() && !shortlist.iter().any(|c| c.node_id == contact.node_id) {
+            shortlist.push(contact);
+        }
+    }
+    shortlist.sort_by_key(|c| NodeKey::of(c).distance(target));
+}
+
+// Ask each seed for contacts near local, then look local up from the ones
+// that answered. Seeds are queried by address since their IDs are unknown.
+pub async fn bootstrap<F, Fut>(local: NodeKey, seeds: &[String], now: u64, query: F) -> Lookup
+where
+    F: Fn(String, DhtMessage) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<(String, DhtMessage)>> + Send + 'static,
+{
+    let request = DhtMessage::FindNode { target: local.to_hex() };
+    let mut known = Vec::new();
+    for seed in seeds {
+        if let Ok((seed_id, response)) = query(seed.clone(), request.clone()).await {
+            if NodeKey::from_node_id(&seed_id) == local {
+                continue;
+            }
+            known.push(Contact::new(&seed_id, seed, now));
+            if let DhtMessage::FindNodeResponse { nodes } = response {
+                known.extend(nodes);
+            }
+        }
+    }
+    known.retain(|c| NodeKey::of(c) != local);
+    lookup(local, known, request, query).await
+}
+
+// One request to the node at address over a fresh connection, after
+// introducing ourselves with hello; returns what the node said about itself
+// along with its answer. Callers that don't accept connections themselves
+// advertise no addresses. Gives up after QUERY_TIMEOUT.
+pub async fn query_node(identity: &NodeIdentity, hello: &Hello, address: &str, request: DhtMessage) -> Result<(PeerInfo, DhtMessage)> {
+    tokio::time::timeout(QUERY_TIMEOUT, exchange(identity, hello, address, request)).await
+        .map_err(|_| anyhow::anyhow!("{} did not answer within {:?}", address, QUERY_TIMEOUT))?
+}
+
+async fn exchange(identity: &NodeIdentity, hello: &Hello, address: &str, request: DhtMessage) -> Result<(PeerInfo, DhtMessage)> {
+    let replay = Mutex::new(ReplayGuard::default());
+    let own_address = hello.addresses.first().cloned().unwrap_or_default();
+    let wire = P2PMessage::Dht { message: request, from: identity.node_id.clone(), address: own_address };
+    let mut stream = SecureStream::connect(address, identity).await?;
+    let peer = handshake::greet(&mut stream, identity, hello, &replay).await?;
+    let to = stream.remote_node_id().to_string();
+    stream.write_json(&protocol::seal(identity, &to, &wire, now_secs())?).await?;
+    let envelope: SignedEnvelope = stream.read_json().await?
+        .ok_or_else(|| anyhow::anyhow!("{} closed the connection without answering", address))?;
+    let (sender, answer) = protocol::open(&envelope, &identity.node_id, &mut replay.lock().unwrap(), now_secs())?;
+    if sender != stream.remote_node_id() {
+        return Err(anyhow::anyhow!("Answer from {} was signed by {}", stream.remote_node_id(), sender));
+    }
+    match answer {
+        P2PMessage::Dht { message, .. } => Ok((peer, message)),
+        _ => Err(anyhow::anyhow!("{} answered a DHT request with something else", address)),
+    }
+}
+
+// Up to K nodes closest to us that answered a lookup from the seeds, for
+// callers that only need a list
+pub async fn discover(identity: Arc<NodeIdentity>, seeds: &[String]) -> Lookup {
+    let local = NodeKey::from_node_id(&identity.node_id);
+    let hello = Arc::new(Hello::client(&identity.node_id));
+    bootstrap(local, seeds, now_secs(), move |address, request| {
+        let (identity, hello) = (Arc::clone(&identity), Arc::clone(&hello));
+        async move { query_node(&identity, &hello, &address, request).await.map(|(peer, answer)| (peer.node_id, answer)) }
+    }).await
+}
+
+pub fn seeds_from_env() -> Vec<String> {
+    match std::env::var(SEEDS_ENV) {
+        Ok(seeds) => parse_seeds(&seeds),
+        Err(_) => DEFAULT_SEEDS.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+pub fn parse_seeds(seeds: &str) -> Vec<String> {
+    seeds.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
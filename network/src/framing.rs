@@ -0,0 +1,52 @@
+// XMBL Framing - length-delimited messages over a byte stream
+//
+// Each frame is a 4-byte big-endian payload length followed by the payload.
+// A stream carries any number of frames back to back, so one connection can
+// hold a whole conversation, and a reader never has to guess where one
+// message ends. Frames over the reader's limit are refused before anything
+// is allocated for them.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use anyhow::Result;
+
+pub const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_BYTES {
+        return Err(anyhow::anyhow!("Frame of {} bytes exceeds the {} byte limit", payload.len(), MAX_FRAME_BYTES));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// None when the peer closed the stream cleanly between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_bytes: usize) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_bytes {
+        return Err(anyhow::anyhow!("Frame of {} bytes exceeds the {} byte limit", len, max_bytes));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+pub async fn write_json<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    write_frame(writer, &serde_json::to_vec(message)?).await
+}
+
+pub async fn read_json<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    match read_frame(reader, MAX_FRAME_BYTES).await? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,64 @@
+// XMBL Handshake - the Hello exchange that opens every node connection
+//
+// Right after the encrypted transport is up, the side that dialed sends a
+// Hello and the other answers with its own in a HelloAck, or explains in a
+// HelloRejected why it won't talk. Both then know which protocol version
+// the connection speaks and what the other side offers. A Hello must come
+// from the node that completed the transport handshake.
+
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use anyhow::Result;
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_protocol::{self as protocol, Hello, P2PMessage, PeerInfo};
+
+use crate::dht::now_secs;
+use crate::secure::SecureStream;
+
+// Dialing side: introduce ourselves and read the answer
+pub async fn greet<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut SecureStream<S>, identity: &NodeIdentity, hello: &Hello, replay: &Mutex<ReplayGuard>) -> Result<PeerInfo> {
+    send(stream, identity, P2PMessage::Hello { hello: hello.clone() }).await?;
+    match receive(stream, identity, replay).await? {
+        P2PMessage::HelloAck { hello: theirs } => {
+            let version = hello.negotiate(&theirs)?;
+            Ok(PeerInfo::new(theirs, version, now_secs()))
+        }
+        P2PMessage::HelloRejected { reason } => Err(anyhow::anyhow!("{} rejected us: {}", stream.remote_node_id(), reason)),
+        _ => Err(anyhow::anyhow!("{} answered our hello with something else", stream.remote_node_id())),
+    }
+}
+
+// Accepting side: nothing else is read until the peer has introduced itself
+// and speaks a version we do
+pub async fn welcome<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut SecureStream<S>, identity: &NodeIdentity, hello: &Hello, replay: &Mutex<ReplayGuard>) -> Result<PeerInfo> {
+    let theirs = match receive(stream, identity, replay).await? {
+        P2PMessage::Hello { hello } => hello,
+        _ => return reject(stream, identity, "Connections must open with a hello".to_string()).await,
+    };
+    let version = match hello.negotiate(&theirs) {
+        Ok(version) => version,
+        Err(e) => return reject(stream, identity, e.to_string()).await,
+    };
+    send(stream, identity, P2PMessage::HelloAck { hello: hello.clone() }).await?;
+    Ok(PeerInfo::new(theirs, version, now_secs()))
+}
+
+async fn reject<S: AsyncWrite + Unpin>(stream: &mut SecureStream<S>, identity: &NodeIdentity, reason: String) -> Result<PeerInfo> {
+    send(stream, identity, P2PMessage::HelloRejected { reason: reason.clone() }).await?;
+    Err(anyhow::anyhow!("Rejected {}: {}", stream.remote_node_id(), reason))
+}
+
+async fn send<S: AsyncWrite + Unpin>(stream: &mut SecureStream<S>, identity: &NodeIdentity, message: P2PMessage) -> Result<()> {
+    let to = stream.remote_node_id().to_string();
+    stream.write_json(&protocol::seal(identity, &to, &message, now_secs())?).await
+}
+
+async fn receive<S: AsyncRead + Unpin>(stream: &mut SecureStream<S>, identity: &NodeIdentity, replay: &Mutex<ReplayGuard>) -> Result<P2PMessage> {
+    let envelope: SignedEnvelope = stream.read_json().await?
+        .ok_or_else(|| anyhow::anyhow!("{} closed the connection during the handshake", stream.remote_node_id()))?;
+    let (sender, message) = protocol::open(&envelope, &identity.node_id, &mut replay.lock().unwrap(), now_secs())?;
+    if sender != stream.remote_node_id() {
+        return Err(anyhow::anyhow!("Hello signed by {} on a connection with {}", sender, stream.remote_node_id()));
+    }
+    Ok(message)
+}
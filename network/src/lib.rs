@@ -4,8 +4,23 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use anyhow::Result;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use uuid::Uuid;
+use xmbl_node_identity::NodeIdentity;
+
+pub mod dht;
+pub mod framing;
+pub mod handshake;
+pub mod pool;
+pub mod secure;
+pub mod transport;
+
+pub use dht::{Contact, DhtMessage, NodeKey, RoutingTable, ValueStore};
+pub use pool::{ConnectionPool, PooledStream};
+pub use secure::SecureStream;
+pub use transport::Transport;
+pub use xmbl_protocol::{MessageType, NetworkMessage};
 
 // MOCK TYPES - Define these locally until real dependencies exist
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -14,7 +29,7 @@ pub struct MockNodeIdentity {
     pub public_key: Vec<u8>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct MockNodeCapabilities {
     pub storage_gb: f64,
     pub compute_flops: u64,
@@ -38,38 +53,45 @@ pub enum NodeStatus {
     Available,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct NetworkMessage {
-    pub id: String,
-    pub from: String,
-    pub to: String,
-    pub payload: Vec<u8>,
-    pub message_type: MessageType,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum MessageType {
-    Ping,
-    Pong,
-    Data,
-    Control,
-    Discovery,
-}
-
 pub struct NetworkService {
     pub node_id: String,
     pub nodes: HashMap<String, NetworkNode>,
+    pub routing: RoutingTable,
+    // Values held for other nodes
+    pub values: ValueStore,
+    // Addresses to bootstrap discovery from
+    pub seeds: Vec<String>,
+    // Where start() listens for other nodes, if anywhere
+    pub listen_address: Option<String>,
+    pub transport: Arc<Transport>,
+    // Messages from other nodes that nobody was waiting for
     pub message_tx: mpsc::Sender<NetworkMessage>,
     pub message_rx: mpsc::Receiver<NetworkMessage>,
 }
 
 impl NetworkService {
+    // Without an identity of its own the service handshakes with a throwaway
+    // key, which peers will not accept as node_id; enough for lookups, but
+    // anything that sends should use with_identity
     pub fn new(node_id: String) -> Self {
+        Self::build(node_id, Arc::new(NodeIdentity::new()))
+    }
+    
+    pub fn with_identity(identity: Arc<NodeIdentity>) -> Self {
+        Self::build(identity.node_id.clone(), identity)
+    }
+    
+    fn build(node_id: String, identity: Arc<NodeIdentity>) -> Self {
         let (message_tx, message_rx) = mpsc::channel(100);
         
         NetworkService {
+            routing: RoutingTable::new(&node_id),
             node_id,
             nodes: HashMap::new(),
+            values: ValueStore::default(),
+            seeds: dht::seeds_from_env(),
+            listen_address: None,
+            transport: Arc::new(Transport::new(identity, message_tx.clone())),
             message_tx,
             message_rx,
         }
@@ -78,6 +100,10 @@ impl NetworkService {
     pub async fn start(&mut self) -> Result<()> {
         log::info!("Starting network service for node: {}", self.node_id);
         
+        if let Some(address) = self.listen_address.clone() {
+            self.listen(&address).await?;
+        }
+        
         // Start network discovery
         self.discover_nodes().await?;
         
@@ -87,36 +113,63 @@ impl NetworkService {
         Ok(())
     }
     
+    // Accept connections from other nodes; returns the bound address, which
+    // matters when asked for port 0
+    pub async fn listen(&mut self, address: &str) -> Result<String> {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        let bound = listener.local_addr()?.to_string();
+        self.transport.listen(listener);
+        // Tell the nodes we dial where they can reach us in turn
+        let hello = self.transport.hello();
+        self.transport.set_hello(xmbl_protocol::Hello { addresses: vec![bound.clone()], ..(*hello).clone() });
+        self.listen_address = Some(bound.clone());
+        log::info!("Listening for nodes on {}", bound);
+        Ok(bound)
+    }
+    
+    // Walk the DHT from the seeds, signing lookups with our identity
     pub async fn discover_nodes(&mut self) -> Result<()> {
-        // TODO: Implement actual node discovery
-        // For now, add some mock nodes
-        let mock_node = NetworkNode {
-            node_id: "mock_node_1".to_string(),
-            address: "127.0.0.1:8080".to_string(),
-            capabilities: MockNodeCapabilities {
-                storage_gb: 1000.0,
-                compute_flops: 1_000_000_000,
-                bandwidth_mbps: 100.0,
-            },
-            status: NodeStatus::Online,
-        };
+        let lookup = dht::discover(Arc::clone(&self.transport.identity), &self.seeds).await;
+        if lookup.closest.is_empty() && !self.seeds.is_empty() {
+            return Err(anyhow::anyhow!("No seed answered: {}", self.seeds.join(", ")));
+        }
         
-        self.nodes.insert(mock_node.node_id.clone(), mock_node);
+        for contact in lookup.closest {
+            self.nodes.insert(contact.node_id.clone(), NetworkNode {
+                node_id: contact.node_id.clone(),
+                address: contact.address.clone(),
+                capabilities: MockNodeCapabilities::default(),
+                status: NodeStatus::Online,
+            });
+            self.routing.insert(contact);
+        }
         Ok(())
     }
     
     pub async fn process_messages(&mut self) -> Result<()> {
         while let Some(message) = self.message_rx.recv().await {
-            match message.message_type {
-                MessageType::Ping => {
-                    self.handle_ping(message).await?;
-                }
-                MessageType::Data => {
-                    self.handle_data(message).await?;
-                }
-                _ => {
-                    log::debug!("Received message: {:?}", message);
-                }
+            self.handle_message(message).await?;
+        }
+        Ok(())
+    }
+    
+    // For services shared behind a lock: whoever drains the inbox can pass
+    // each message to handle_message without holding the lock in between
+    pub fn take_inbox(&mut self) -> mpsc::Receiver<NetworkMessage> {
+        let (_, closed) = mpsc::channel(1);
+        std::mem::replace(&mut self.message_rx, closed)
+    }
+    
+    pub async fn handle_message(&self, message: NetworkMessage) -> Result<()> {
+        match message.message_type {
+            MessageType::Ping => {
+                self.handle_ping(message).await?;
+            }
+            MessageType::Data => {
+                self.handle_data(message).await?;
+            }
+            _ => {
+                log::debug!("Received message: {:?}", message);
             }
         }
         Ok(())
@@ -124,8 +177,7 @@ impl NetworkService {
     
     async fn handle_ping(&self, message: NetworkMessage) -> Result<()> {
         log::debug!("Handling ping from: {}", message.from);
-        // TODO: Send pong response
-        Ok(())
+        self.reply(&message, Vec::new(), MessageType::Pong).await
     }
     
     async fn handle_data(&self, message: NetworkMessage) -> Result<()> {
@@ -134,19 +186,52 @@ impl NetworkService {
         Ok(())
     }
     
+    // Deliver a message to the node named by `to`, over a pooled connection
+    // or one dialed from what discovery knows about it
     pub async fn send_message(&self, to: String, payload: Vec<u8>, message_type: MessageType) -> Result<()> {
-        let message = NetworkMessage {
-            id: Uuid::new_v4().to_string(),
+        let message = self.message(Uuid::new_v4().to_string(), to, payload, message_type);
+        self.deliver(message).await
+    }
+    
+    // Send and wait for the response carrying the same message ID
+    pub async fn request(&self, to: String, payload: Vec<u8>, message_type: MessageType) -> Result<NetworkMessage> {
+        let message = self.message(Uuid::new_v4().to_string(), to, payload, message_type);
+        if message.to == self.node_id {
+            return Err(anyhow::anyhow!("Cannot send a request to ourselves"));
+        }
+        let address = self.address_of(&message.to);
+        self.transport.request(message, address.as_deref()).await
+    }
+    
+    // Answer a message; the response reuses its ID so the sender can match it
+    pub async fn reply(&self, request: &NetworkMessage, payload: Vec<u8>, message_type: MessageType) -> Result<()> {
+        let message = self.message(request.id.clone(), request.from.clone(), payload, message_type);
+        self.deliver(message).await
+    }
+    
+    fn message(&self, id: String, to: String, payload: Vec<u8>, message_type: MessageType) -> NetworkMessage {
+        NetworkMessage {
+            id,
             from: self.node_id.clone(),
             to,
             payload,
             message_type,
-        };
-        
-        self.message_tx.send(message).await
-            .map_err(|e| anyhow::anyhow!("Failed to send message: {}", e))?;
-        
-        Ok(())
+        }
+    }
+    
+    async fn deliver(&self, message: NetworkMessage) -> Result<()> {
+        if message.to == self.node_id {
+            return self.message_tx.send(message).await
+                .map_err(|e| anyhow::anyhow!("Failed to send message: {}", e));
+        }
+        let address = self.address_of(&message.to);
+        self.transport.send(message, address.as_deref()).await
+    }
+    
+    fn address_of(&self, node_id: &str) -> Option<String> {
+        self.routing.get(node_id).map(|c| c.address.clone())
+            .or_else(|| self.nodes.get(node_id).map(|n| n.address.clone()))
+            .filter(|address| !address.is_empty())
     }
     
     pub fn get_connected_nodes(&self) -> Vec<&NetworkNode> {
@@ -164,21 +249,293 @@ mod tests {
         assert_eq!(service.node_id, "test_node");
     }
 
+    // A node answering DHT requests from its own routing table
+    async fn serve_dht(identity: NodeIdentity, table: RoutingTable, listener: tokio::net::TcpListener) {
+        let address = listener.local_addr().unwrap().to_string();
+        let mut values = ValueStore::default();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = SecureStream::accept(stream, &identity).await.unwrap();
+            let hello = xmbl_protocol::Hello::new(&identity.node_id, Vec::new(), None, vec![address.clone()]);
+            handshake::welcome(&mut stream, &identity, &hello, &Default::default()).await.unwrap();
+            while let Ok(Some(envelope)) = stream.read_json::<xmbl_node_identity::SignedEnvelope>().await {
+                let now = dht::now_secs();
+                let message = match xmbl_protocol::open(&envelope, &identity.node_id, &mut Default::default(), now).unwrap() {
+                    (_, xmbl_protocol::P2PMessage::Dht { message, .. }) => message,
+                    (_, other) => panic!("Not a DHT request: {:?}", other),
+                };
+                let response = xmbl_protocol::P2PMessage::Dht {
+                    message: dht::respond(&table, &mut values, message, now),
+                    from: identity.node_id.clone(),
+                    address: address.clone(),
+                };
+                let sealed = xmbl_protocol::seal(&identity, stream.remote_node_id(), &response, now).unwrap();
+                stream.write_json(&sealed).await.unwrap();
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_node_discovery() {
+        // A seed that knows one other node, which knows only the seed
+        let (seed, other) = (NodeIdentity::new(), NodeIdentity::new());
+        let seed_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seed_address = seed_listener.local_addr().unwrap().to_string();
+        let other_address = other_listener.local_addr().unwrap().to_string();
+        let now = dht::now_secs();
+
+        let mut seed_table = RoutingTable::new(&seed.node_id);
+        seed_table.insert(Contact::new(&other.node_id, &other_address, now));
+        let mut other_table = RoutingTable::new(&other.node_id);
+        other_table.insert(Contact::new(&seed.node_id, &seed_address, now));
+        let (seed_id, other_id) = (seed.node_id.clone(), other.node_id.clone());
+        tokio::spawn(serve_dht(seed, seed_table, seed_listener));
+        tokio::spawn(serve_dht(other, other_table, other_listener));
+
         let mut service = NetworkService::new("test_node".to_string());
+        service.seeds = vec![seed_address.clone()];
         service.discover_nodes().await.unwrap();
-        assert!(!service.nodes.is_empty());
+        assert_eq!(service.nodes.len(), 2);
+        assert_eq!(service.nodes[&seed_id].address, seed_address);
+        assert_eq!(service.routing.get(&other_id).unwrap().address, other_address);
+
+        service.seeds = vec!["127.0.0.1:1".to_string()];
+        assert!(service.discover_nodes().await.is_err());
+
+        // A peer that accepts and then says nothing is given up on
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_address = silent.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let _held = silent.accept().await;
+            std::future::pending::<()>().await;
+        });
+        let identity = NodeIdentity::new();
+        let hello = xmbl_protocol::Hello::client(&identity.node_id);
+        let query = dht::query_node(&identity, &hello, &silent_address, DhtMessage::FindNode { target: identity.node_id.clone() });
+        let error = tokio::time::timeout(dht::QUERY_TIMEOUT * 2, query).await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("did not answer"), "{}", error);
+    }
+
+    #[test]
+    fn test_routing_table_buckets_and_distance() {
+        let local = NodeKey([0u8; dht::ID_BYTES]);
+        let mut table = RoutingTable::new(&local.to_hex());
+        table.k = 2;
+        let contact = |last: u8, first: u8| {
+            let mut key = [0u8; dht::ID_BYTES];
+            key[0] = first;
+            key[dht::ID_BYTES - 1] = last;
+            Contact::new(&NodeKey(key).to_hex(), &format!("127.0.0.1:{}", 4000 + last as u16), 0)
+        };
+
+        // Three keys sharing no leading bits with ours land in one bucket
+        assert_eq!(table.insert(contact(1, 0x80)), dht::InsertOutcome::Added);
+        assert_eq!(table.insert(contact(2, 0x80)), dht::InsertOutcome::Added);
+        assert_eq!(table.insert(contact(3, 0x80)), dht::InsertOutcome::Full { oldest: contact(1, 0x80) });
+        assert_eq!(table.insert(contact(1, 0x80)), dht::InsertOutcome::Updated);
+        assert_eq!(table.insert(contact(3, 0x80)), dht::InsertOutcome::Full { oldest: contact(2, 0x80) });
+        assert_eq!(table.replace(&contact(2, 0x80).node_id, contact(3, 0x80)), dht::InsertOutcome::Added);
+        assert_eq!(table.insert(contact(4, 0x01)), dht::InsertOutcome::Added);
+        assert_eq!(table.insert(Contact::new(&local.to_hex(), "", 0)), dht::InsertOutcome::Ignored);
+
+        let closest = table.closest(&local, 2);
+        assert_eq!(closest, vec![contact(4, 0x01), contact(1, 0x80)]);
+        assert_eq!(table.len(), 3);
+
+        // A full value store takes new keys only once old ones expire
+        let mut values = ValueStore::new(10);
+        values.max_values = 1;
+        values.put("a", vec![1], 0).unwrap();
+        values.put("a", vec![2], 5).unwrap();
+        assert!(values.put("b", vec![1], 5).is_err());
+        values.put("b", vec![1], 20).unwrap();
+        assert_eq!((values.len(), values.get("b", 20)), (1, Some(vec![1])));
     }
 
     #[tokio::test]
     async fn test_message_sending() {
-        let service = NetworkService::new("test_node".to_string());
-        let result = service.send_message(
-            "target_node".to_string(),
-            b"hello".to_vec(),
-            MessageType::Data
-        ).await;
-        assert!(result.is_ok());
+        let mut server = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        let address = server.listen("127.0.0.1:0").await.unwrap();
+        let server_id = server.node_id.clone();
+
+        let mut client = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        assert!(client.send_message(server_id.clone(), b"hello".to_vec(), MessageType::Data).await.is_err());
+        client.routing.insert(Contact::new(&server_id, &address, dht::now_secs()));
+
+        // Data lands in the server's inbox, tagged with who sent it
+        client.send_message(server_id.clone(), b"hello".to_vec(), MessageType::Data).await.unwrap();
+        let received = server.message_rx.recv().await.unwrap();
+        assert_eq!((received.from.as_str(), received.payload.as_slice()), (client.node_id.as_str(), &b"hello"[..]));
+
+        // A ping comes back as a pong on the pooled connection, matched by ID
+        // rather than dropped in the client's inbox
+        tokio::spawn(async move { server.process_messages().await });
+        let pong = client.request(server_id.clone(), Vec::new(), MessageType::Ping).await.unwrap();
+        assert!(matches!(pong.message_type, MessageType::Pong));
+        assert_eq!(pong.from, server_id);
+        assert!(client.transport.is_connected(&server_id));
+        assert!(client.message_rx.try_recv().is_err());
+
+        // Messages to ourselves never touch the network
+        client.send_message(client.node_id.clone(), b"loopback".to_vec(), MessageType::Data).await.unwrap();
+        assert_eq!(client.message_rx.recv().await.unwrap().payload, b"loopback");
+    }
+
+    #[tokio::test]
+    async fn test_responses_only_complete_requests_to_their_sender() {
+        let mut client = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        let client_address = client.listen("127.0.0.1:0").await.unwrap();
+        let mut server = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        let server_address = server.listen("127.0.0.1:0").await.unwrap();
+        let mut other = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        other.routing.insert(Contact::new(&client.node_id, &client_address, dht::now_secs()));
+        server.routing.insert(Contact::new(&client.node_id, &client_address, dht::now_secs()));
+        client.routing.insert(Contact::new(&server.node_id, &server_address, dht::now_secs()));
+
+        // Fill the client's inbox; its connections keep reading regardless
+        for _ in 0..=100 {
+            server.send_message(client.node_id.clone(), Vec::new(), MessageType::Data).await.unwrap();
+        }
+
+        let request = NetworkMessage {
+            id: "request".to_string(),
+            from: client.node_id.clone(),
+            to: server.node_id.clone(),
+            payload: Vec::new(),
+            message_type: MessageType::Ping,
+        };
+        let transport = Arc::clone(&client.transport);
+        let waiting = tokio::spawn(async move { transport.request(request, Some(&server_address)).await });
+
+        // Another node answering with the same ID doesn't complete it
+        let ping = server.message_rx.recv().await.unwrap();
+        other.reply(&NetworkMessage { from: client.node_id.clone(), ..ping.clone() }, Vec::new(), MessageType::Pong).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        server.reply(&ping, Vec::new(), MessageType::Pong).await.unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap().from, server.node_id);
+    }
+
+    #[tokio::test]
+    async fn test_transport_connections_open_with_a_hello() {
+        let mut server = NetworkService::with_identity(Arc::new(NodeIdentity::new()));
+        let address = server.listen("127.0.0.1:0").await.unwrap();
+        assert_eq!(server.transport.hello().addresses, vec![address.clone()]);
+
+        // Skipping the hello gets a rejection, not a hearing
+        let identity = NodeIdentity::new();
+        let mut stream = SecureStream::connect(&address, &identity).await.unwrap();
+        let message = NetworkMessage {
+            id: "1".to_string(),
+            from: identity.node_id.clone(),
+            to: server.node_id.clone(),
+            payload: Vec::new(),
+            message_type: MessageType::Data,
+        };
+        let envelope = xmbl_protocol::seal(&identity, &server.node_id, &xmbl_protocol::P2PMessage::Network { message }, dht::now_secs()).unwrap();
+        stream.write_json(&envelope).await.unwrap();
+        let answer: xmbl_node_identity::SignedEnvelope = stream.read_json().await.unwrap().unwrap();
+        let mut replay = xmbl_node_identity::ReplayGuard::default();
+        let (_, answer) = xmbl_protocol::open(&answer, &identity.node_id, &mut replay, dht::now_secs()).unwrap();
+        assert!(matches!(answer, xmbl_protocol::P2PMessage::HelloRejected { .. }));
+        assert!(server.message_rx.try_recv().is_err());
+
+        // Nothing gets written at a version we don't speak
+        let ping = xmbl_protocol::P2PMessage::Ping { from: identity.node_id.clone(), timestamp: 0 };
+        assert!(xmbl_protocol::seal_version(&identity, &server.node_id, &ping, xmbl_protocol::PROTOCOL_VERSION + 1, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frames_survive_split_and_coalesced_reads() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let large = vec![7u8; 10_000];
+        let writer = tokio::spawn(async move {
+            framing::write_frame(&mut client, b"first").await.unwrap();
+            framing::write_frame(&mut client, &large).await.unwrap();
+            framing::write_json(&mut client, &vec!["third"]).await.unwrap();
+            framing::write_frame(&mut client, &[0u8; 100]).await.unwrap();
+        });
+
+        assert_eq!(framing::read_frame(&mut server, framing::MAX_FRAME_BYTES).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(framing::read_frame(&mut server, framing::MAX_FRAME_BYTES).await.unwrap().unwrap().len(), 10_000);
+        let third: Option<Vec<String>> = framing::read_json(&mut server).await.unwrap();
+        assert_eq!(third, Some(vec!["third".to_string()]));
+        assert!(framing::read_frame(&mut server, 99).await.is_err());
+
+        writer.await.unwrap();
+        let (_, mut closed) = tokio::io::duplex(64);
+        assert_eq!(framing::read_frame(&mut closed, framing::MAX_FRAME_BYTES).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_secure_stream_authenticates_and_encrypts() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let (client_identity, server_identity) = (NodeIdentity::new(), NodeIdentity::new());
+        let (client_id, server_id) = (client_identity.node_id.clone(), server_identity.node_id.clone());
+        let large: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+        let expected = large.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = SecureStream::accept(server_io, &server_identity).await.unwrap();
+            assert_eq!(stream.remote_node_id(), client_id);
+            assert_eq!(stream.read_frame(framing::MAX_FRAME_BYTES).await.unwrap(), Some(expected));
+            assert_eq!(stream.read_frame(framing::MAX_FRAME_BYTES).await.unwrap(), Some(Vec::new()));
+            stream.write_json(&"done").await.unwrap();
+        });
+
+        let mut stream = SecureStream::initiate(client_io, &client_identity).await.unwrap();
+        assert_eq!(stream.remote_node_id(), server_id);
+        stream.write_frame(&large).await.unwrap();
+        stream.write_frame(&[]).await.unwrap();
+        assert_eq!(stream.read_json::<String>().await.unwrap(), Some("done".to_string()));
+        server.await.unwrap();
+
+        // Nothing readable goes over the wire, and a peer without the
+        // session keys gets nowhere
+        let (mut raw_client, raw_server) = tokio::io::duplex(1024);
+        let server_identity = NodeIdentity::new();
+        let server = tokio::spawn(async move { SecureStream::accept(raw_server, &server_identity).await.is_err() });
+        framing::write_json(&mut raw_client, &"plaintext hello").await.unwrap();
+        assert!(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hello_negotiates_version_and_rejects_incompatible_peers() {
+        use xmbl_protocol::{Feature, Hello, PROTOCOL_VERSION};
+
+        async fn connect(server_hello: Hello) -> (Result<xmbl_protocol::PeerInfo>, Result<xmbl_protocol::PeerInfo>) {
+            let (client_io, server_io) = tokio::io::duplex(4096);
+            let (client, server) = (NodeIdentity::new(), NodeIdentity::new());
+            let client_hello = Hello::new(&client.node_id, vec![Feature::Relay], None, Vec::new());
+            let server_hello = Hello { node_id: server.node_id.clone(), ..server_hello };
+            let accepted = tokio::spawn(async move {
+                let mut stream = SecureStream::accept(server_io, &server).await.unwrap();
+                handshake::welcome(&mut stream, &server, &server_hello, &Default::default()).await
+            });
+            let mut stream = SecureStream::initiate(client_io, &client).await.unwrap();
+            let greeted = handshake::greet(&mut stream, &client, &client_hello, &Default::default()).await;
+            (greeted, accepted.await.unwrap())
+        }
+
+        let capabilities = xmbl_protocol::NodeCapabilities {
+            storage_gb: 10.0, compute_flops: 1, bandwidth_mbps: 1.0, memory_gb: 1.0, cpu_cores: 2, gpu_memory_gb: 0.0,
+        };
+        let node = Hello::new("", vec![Feature::Storage, Feature::Compute], Some(capabilities), vec!["127.0.0.1:4000".to_string()]);
+        let (server, client) = connect(node.clone()).await;
+        let (server, client) = (server.unwrap(), client.unwrap());
+        assert_eq!(server.version, PROTOCOL_VERSION);
+        assert!(server.supports(Feature::Compute) && !server.supports(Feature::Relay));
+        assert_eq!(server.capabilities.unwrap().cpu_cores, 2);
+        assert_eq!(server.addresses, vec!["127.0.0.1:4000".to_string()]);
+        assert!(client.supports(Feature::Relay) && client.addresses.is_empty());
+
+        // A node that has moved past our version says so instead of misreading us
+        let newer = Hello { version: PROTOCOL_VERSION + 1, min_version: PROTOCOL_VERSION + 1, ..node };
+        let (server, client) = connect(newer).await;
+        let error = server.unwrap_err().to_string();
+        assert!(error.contains("rejected us") && error.contains("protocol versions"), "{}", error);
+        assert!(client.is_err());
     }
 }
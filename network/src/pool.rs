@@ -0,0 +1,73 @@
+// XMBL Connection Pool - reuse request/response connections between nodes
+//
+// Nodes answer one request after another on a connection until the caller
+// hangs up, so a caller can keep one open per peer instead of paying for a
+// fresh key exchange and hello on every message. Only idle connections are
+// reused: a request that finds its peer's connection busy (say with a long
+// compute task) dials a one-off connection rather than queue behind it. A
+// connection that fails a request is dropped from the pool.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::secure::SecureStream;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+// A connection checked out of the pool, or dialed for just one request
+pub type PooledStream = OwnedMutexGuard<SecureStream>;
+
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<String, Arc<AsyncMutex<SecureStream>>>>,
+    // Past this, new connections are used once and closed
+    pub max_connections: usize,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool {
+            connections: Mutex::new(HashMap::new()),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The pooled connection to node_id, if there is one nobody is using
+    pub fn idle(&self, node_id: &str) -> Option<PooledStream> {
+        let connection = Arc::clone(self.connections.lock().unwrap().get(node_id)?);
+        connection.try_lock_owned().ok()
+    }
+
+    // Pool a freshly dialed connection unless node_id already has one or
+    // the pool is full, and hand it back checked out either way
+    pub fn insert(&self, node_id: &str, stream: SecureStream) -> PooledStream {
+        let connection = Arc::new(AsyncMutex::new(stream));
+        let mut connections = self.connections.lock().unwrap();
+        if !connections.contains_key(node_id) && connections.len() < self.max_connections {
+            connections.insert(node_id.to_string(), Arc::clone(&connection));
+        }
+        connection.try_lock_owned().expect("a new connection is unlocked")
+    }
+
+    // Forget a connection that failed, leaving any other in its place
+    pub fn remove(&self, node_id: &str, stream: &PooledStream) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.get(node_id).is_some_and(|pooled| Arc::ptr_eq(pooled, OwnedMutexGuard::mutex(stream))) {
+            connections.remove(node_id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.lock().unwrap().is_empty()
+    }
+}
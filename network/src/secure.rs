@@ -0,0 +1,211 @@
+// XMBL Secure transport - Noise XX over TCP, bound to node identities
+//
+// Every connection starts with a Noise_XX_25519_ChaChaPoly_SHA256
+// handshake. Each side uses a fresh X25519 static key and proves it speaks
+// for its node by signing that key with its secp256k1 identity; the proof
+// rides inside the handshake, encrypted, and a connection whose proof does
+// not check out is dropped before any application data moves. Afterwards
+// each frame (see framing) is split into Noise messages of at most 64 KiB,
+// each prefixed by one plaintext byte saying whether more follow. A stream
+// can be split so one task reads while another writes.
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use secp256k1::PublicKey;
+use secp256k1::ecdsa::Signature;
+use snow::{HandshakeState, TransportState};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use anyhow::Result;
+use xmbl_node_identity::NodeIdentity;
+
+use crate::framing;
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_BYTES: usize = 16;
+// Room for the tag and the continuation byte
+const CHUNK_BYTES: usize = NOISE_MAX_MESSAGE - NOISE_TAG_BYTES - 1;
+
+// Signed statement that a Noise static key belongs to a node
+#[derive(Serialize, Deserialize)]
+struct IdentityProof {
+    public_key: PublicKey,
+    signature: String,
+}
+
+impl IdentityProof {
+    fn new(identity: &NodeIdentity, static_key: &[u8]) -> Self {
+        IdentityProof {
+            public_key: identity.public_key,
+            signature: hex::encode(identity.sign(&proof_bytes(static_key)).serialize_compact()),
+        }
+    }
+
+    // The node ID the proof is for, if it really covers static_key
+    fn verify(&self, static_key: &[u8]) -> Result<String> {
+        let signature = Signature::from_compact(&hex::decode(&self.signature)?)?;
+        NodeIdentity::verify(&self.public_key, &proof_bytes(static_key), &signature)
+            .map_err(|_| anyhow::anyhow!("Peer's identity does not sign its transport key"))?;
+        Ok(NodeIdentity::node_id_for(&self.public_key))
+    }
+}
+
+fn proof_bytes(static_key: &[u8]) -> Vec<u8> {
+    let mut data = b"xmbl-noise-static\0".to_vec();
+    data.extend_from_slice(static_key);
+    data
+}
+
+pub struct SecureStream<S = TcpStream> {
+    stream: S,
+    // Shared by the halves of a split stream; sending and receiving keep
+    // separate nonces, so only the lock is shared
+    transport: Arc<Mutex<TransportState>>,
+    remote_node_id: String,
+}
+
+impl SecureStream<TcpStream> {
+    pub async fn connect(address: &str, identity: &NodeIdentity) -> Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        Self::initiate(stream, identity).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SecureStream<S> {
+    // -> e
+    // <- e, ee, s, es, responder's proof
+    // -> s, se, initiator's proof
+    pub async fn initiate(mut stream: S, identity: &NodeIdentity) -> Result<Self> {
+        let (mut handshake, static_key) = handshake_state(true)?;
+        send_handshake(&mut stream, &mut handshake, &[]).await?;
+        let remote_node_id = receive_proof(&mut stream, &mut handshake).await?;
+        let proof = serde_json::to_vec(&IdentityProof::new(identity, &static_key))?;
+        send_handshake(&mut stream, &mut handshake, &proof).await?;
+        Self::established(stream, handshake, remote_node_id)
+    }
+
+    pub async fn accept(mut stream: S, identity: &NodeIdentity) -> Result<Self> {
+        let (mut handshake, static_key) = handshake_state(false)?;
+        receive_handshake(&mut stream, &mut handshake).await?;
+        let proof = serde_json::to_vec(&IdentityProof::new(identity, &static_key))?;
+        send_handshake(&mut stream, &mut handshake, &proof).await?;
+        let remote_node_id = receive_proof(&mut stream, &mut handshake).await?;
+        Self::established(stream, handshake, remote_node_id)
+    }
+
+    fn established(stream: S, handshake: HandshakeState, remote_node_id: String) -> Result<Self> {
+        Ok(SecureStream {
+            stream,
+            transport: Arc::new(Mutex::new(handshake.into_transport_mode()?)),
+            remote_node_id,
+        })
+    }
+
+    pub fn into_split(self) -> (SecureStream<ReadHalf<S>>, SecureStream<WriteHalf<S>>) {
+        let (reader, writer) = tokio::io::split(self.stream);
+        let reader = SecureStream { stream: reader, transport: Arc::clone(&self.transport), remote_node_id: self.remote_node_id.clone() };
+        let writer = SecureStream { stream: writer, transport: self.transport, remote_node_id: self.remote_node_id };
+        (reader, writer)
+    }
+}
+
+impl<S> SecureStream<S> {
+    // Proven by the handshake, not claimed by the peer
+    pub fn remote_node_id(&self) -> &str {
+        &self.remote_node_id
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SecureStream<S> {
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > framing::MAX_FRAME_BYTES {
+            return Err(anyhow::anyhow!("Frame of {} bytes exceeds the {} byte limit", payload.len(), framing::MAX_FRAME_BYTES));
+        }
+        let mut chunks = payload.chunks(CHUNK_BYTES).peekable();
+        let mut plaintext = Vec::with_capacity(CHUNK_BYTES + 1);
+        let mut message = vec![0u8; NOISE_MAX_MESSAGE];
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            let more = chunks.peek().is_some();
+            plaintext.clear();
+            plaintext.push(more as u8);
+            plaintext.extend_from_slice(chunk);
+            let len = self.transport.lock().unwrap().write_message(&plaintext, &mut message)?;
+            framing::write_frame(&mut self.stream, &message[..len]).await?;
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn write_json<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        self.write_frame(&serde_json::to_vec(message)?).await
+    }
+}
+
+impl<S: AsyncRead + Unpin> SecureStream<S> {
+    // None when the peer closed the stream cleanly between frames
+    pub async fn read_frame(&mut self, max_bytes: usize) -> Result<Option<Vec<u8>>> {
+        let mut payload = Vec::new();
+        let mut plaintext = vec![0u8; NOISE_MAX_MESSAGE];
+        loop {
+            let message = match framing::read_frame(&mut self.stream, NOISE_MAX_MESSAGE).await? {
+                Some(message) => message,
+                None if payload.is_empty() => return Ok(None),
+                None => return Err(anyhow::anyhow!("Connection closed in the middle of a frame")),
+            };
+            let len = self.transport.lock().unwrap().read_message(&message, &mut plaintext)
+                .map_err(|_| anyhow::anyhow!("Could not decrypt a message from {}", self.remote_node_id))?;
+            let (more, chunk) = plaintext[..len].split_first()
+                .ok_or_else(|| anyhow::anyhow!("Empty transport message"))?;
+            if payload.len() + chunk.len() > max_bytes {
+                return Err(anyhow::anyhow!("Frame exceeds the {} byte limit", max_bytes));
+            }
+            payload.extend_from_slice(chunk);
+            if *more == 0 {
+                return Ok(Some(payload));
+            }
+        }
+    }
+
+    pub async fn read_json<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.read_frame(framing::MAX_FRAME_BYTES).await? {
+            Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn handshake_state(initiator: bool) -> Result<(HandshakeState, Vec<u8>)> {
+    let builder = snow::Builder::new(NOISE_PARAMS.parse()?);
+    let keypair = builder.generate_keypair()?;
+    let builder = builder.local_private_key(&keypair.private);
+    let handshake = if initiator { builder.build_initiator()? } else { builder.build_responder()? };
+    Ok((handshake, keypair.public))
+}
+
+async fn send_handshake<S: AsyncWrite + Unpin>(stream: &mut S, handshake: &mut HandshakeState, payload: &[u8]) -> Result<()> {
+    let mut message = vec![0u8; NOISE_MAX_MESSAGE];
+    let len = handshake.write_message(payload, &mut message)?;
+    framing::write_frame(stream, &message[..len]).await
+}
+
+async fn receive_handshake<S: AsyncRead + Unpin>(stream: &mut S, handshake: &mut HandshakeState) -> Result<Vec<u8>> {
+    let message = framing::read_frame(stream, NOISE_MAX_MESSAGE).await?
+        .ok_or_else(|| anyhow::anyhow!("Connection closed during the handshake"))?;
+    let mut payload = vec![0u8; NOISE_MAX_MESSAGE];
+    let len = handshake.read_message(&message, &mut payload)
+        .map_err(|e| anyhow::anyhow!("Handshake failed: {}", e))?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+async fn receive_proof<S: AsyncRead + Unpin>(stream: &mut S, handshake: &mut HandshakeState) -> Result<String> {
+    let payload = receive_handshake(stream, handshake).await?;
+    let static_key = handshake.get_remote_static()
+        .ok_or_else(|| anyhow::anyhow!("Peer sent no transport key"))?;
+    serde_json::from_slice::<IdentityProof>(&payload)?.verify(static_key)
+}
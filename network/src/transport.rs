@@ -0,0 +1,249 @@
+// XMBL Transport - NetworkMessage delivery between nodes
+//
+// Every peer gets at most one pooled connection, whichever side opened it.
+// Each connection runs a writer task fed by a channel and a reader task that
+// hands incoming messages either to whoever is waiting on a request with
+// the same ID, or to the service's inbox. A message's `from` must be the node
+// that completed the handshake, so peers cannot speak for each other, and a
+// response only completes a request sent to that node. A full inbox drops
+// messages rather than stall the connection's responses. Connections open
+// with a Hello like any other node connection, and messages then travel as
+// P2PMessage::Network in signed envelopes, written at the version the
+// handshake settled on.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use anyhow::Result;
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_protocol::{self as protocol, Hello, P2PMessage};
+
+use crate::NetworkMessage;
+use crate::dht::now_secs;
+use crate::handshake;
+use crate::secure::SecureStream;
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Setting up a connection, either way round, hellos included
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Messages queued per connection before senders have to wait
+const OUTBOUND_QUEUE: usize = 64;
+
+// The node a request went to, and whoever waits for its answer
+type Pending = (String, oneshot::Sender<NetworkMessage>);
+
+pub struct Transport {
+    pub identity: Arc<NodeIdentity>,
+    // Writer channels of open connections, by remote node ID
+    connections: Arc<Mutex<HashMap<String, mpsc::Sender<NetworkMessage>>>>,
+    // Requests waiting for a response, by message ID
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    inbox: mpsc::Sender<NetworkMessage>,
+    replay: Arc<Mutex<ReplayGuard>>,
+    // What we tell peers about ourselves; a client's until set_hello
+    hello: Mutex<Arc<Hello>>,
+}
+
+impl Transport {
+    pub fn new(identity: Arc<NodeIdentity>, inbox: mpsc::Sender<NetworkMessage>) -> Self {
+        Transport {
+            hello: Mutex::new(Arc::new(Hello::client(&identity.node_id))),
+            identity,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            inbox,
+            replay: Arc::new(Mutex::new(ReplayGuard::default())),
+        }
+    }
+
+    // Accept connections until the listener fails
+    pub fn listen(self: &Arc<Self>, listener: TcpListener) {
+        let transport = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Stopped accepting connections: {}", e);
+                        return;
+                    }
+                };
+                let transport = Arc::clone(&transport);
+                tokio::spawn(async move {
+                    // A peer that connects and goes quiet doesn't get to hold the task
+                    let accepting = async {
+                        let mut stream = SecureStream::accept(stream, &transport.identity).await?;
+                        let peer = handshake::welcome(&mut stream, &transport.identity, &transport.hello(), &transport.replay).await?;
+                        anyhow::Ok((stream, peer.version))
+                    };
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, accepting).await {
+                        Ok(Ok((stream, version))) => { transport.attach(stream, version, None); }
+                        Ok(Err(e)) => log::debug!("Rejected incoming connection: {}", e),
+                        Err(_) => log::debug!("Incoming connection timed out after {:?}", HANDSHAKE_TIMEOUT),
+                    }
+                });
+            }
+        });
+    }
+
+    pub fn set_hello(&self, hello: Hello) {
+        *self.hello.lock().unwrap() = Arc::new(hello);
+    }
+
+    pub fn hello(&self) -> Arc<Hello> {
+        Arc::clone(&self.hello.lock().unwrap())
+    }
+
+    // Take over a connection someone else accepted and said hello on,
+    // starting with the message they already read from it
+    pub fn adopt(&self, stream: SecureStream, version: u32, first: NetworkMessage) {
+        self.attach(stream, version, Some(first));
+    }
+
+    pub fn is_connected(&self, node_id: &str) -> bool {
+        self.connections.lock().unwrap().get(node_id).is_some_and(|tx| !tx.is_closed())
+    }
+
+    // Queue a message for its `to` node, dialing `address` if there is no
+    // open connection to reuse
+    pub async fn send(&self, message: NetworkMessage, address: Option<&str>) -> Result<()> {
+        let connection = match self.connection(&message.to) {
+            Some(connection) => connection,
+            None => {
+                let address = address
+                    .ok_or_else(|| anyhow::anyhow!("No address known for node {}", message.to))?;
+                self.dial(&message.to, address).await?
+            }
+        };
+        connection.send(message).await
+            .map_err(|_| anyhow::anyhow!("Connection closed while sending"))
+    }
+
+    // Send and wait for the message that comes back with the same ID
+    pub async fn request(&self, message: NetworkMessage, address: Option<&str>) -> Result<NetworkMessage> {
+        let id = message.id.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), (message.to.clone(), tx));
+
+        let result = match self.send(message, address).await {
+            Ok(()) => match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(anyhow::anyhow!("Request {} was dropped", id)),
+                Err(_) => Err(anyhow::anyhow!("No response to request {} within {:?}", id, REQUEST_TIMEOUT)),
+            },
+            Err(e) => Err(e),
+        };
+        self.pending.lock().unwrap().remove(&id);
+        result
+    }
+
+    fn connection(&self, node_id: &str) -> Option<mpsc::Sender<NetworkMessage>> {
+        self.connections.lock().unwrap().get(node_id)
+            .filter(|tx| !tx.is_closed())
+            .cloned()
+    }
+
+    async fn dial(&self, node_id: &str, address: &str) -> Result<mpsc::Sender<NetworkMessage>> {
+        let connecting = async {
+            let mut stream = SecureStream::connect(address, &self.identity).await?;
+            if stream.remote_node_id() != node_id {
+                return Err(anyhow::anyhow!("{} answered as {} instead of {}", address, stream.remote_node_id(), node_id));
+            }
+            let peer = handshake::greet(&mut stream, &self.identity, &self.hello(), &self.replay).await?;
+            Ok((stream, peer.version))
+        };
+        let (stream, version) = tokio::time::timeout(HANDSHAKE_TIMEOUT, connecting).await
+            .map_err(|_| anyhow::anyhow!("Connecting to {} timed out after {:?}", address, HANDSHAKE_TIMEOUT))??;
+        Ok(self.attach(stream, version, None))
+    }
+
+    // Pool an established connection and start moving messages over it
+    fn attach(&self, stream: SecureStream, version: u32, mut first: Option<NetworkMessage>) -> mpsc::Sender<NetworkMessage> {
+        let remote = stream.remote_node_id().to_string();
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = mpsc::channel::<NetworkMessage>(OUTBOUND_QUEUE);
+        self.connections.lock().unwrap().insert(remote.clone(), tx.clone());
+
+        let identity = Arc::clone(&self.identity);
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                let to = writer.remote_node_id().to_string();
+                let sent = match protocol::seal_version(&identity, &to, &P2PMessage::Network { message }, version, now_secs()) {
+                    Ok(envelope) => writer.write_json(&envelope).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    log::debug!("Dropping connection to {}: {}", writer.remote_node_id(), e);
+                    break;
+                }
+            }
+        });
+
+        let (pending, connections, inbox, writer_tx) = (Arc::clone(&self.pending), Arc::clone(&self.connections), self.inbox.clone(), tx.clone());
+        let (identity, replay) = (Arc::clone(&self.identity), Arc::clone(&self.replay));
+        tokio::spawn(async move {
+            loop {
+                let read = match first.take() {
+                    Some(message) => Ok(Some(message)),
+                    None => Self::receive(&mut reader, &identity, &replay).await,
+                };
+                let message = match read {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::debug!("Dropping connection from {}: {}", remote, e);
+                        break;
+                    }
+                };
+                if message.from != remote {
+                    log::warn!("Dropping connection from {}: message claims to be from {}", remote, message.from);
+                    break;
+                }
+                let waiting = {
+                    let mut pending = pending.lock().unwrap();
+                    match pending.get(&message.id) {
+                        Some((to, _)) if *to == remote => pending.remove(&message.id),
+                        Some((to, _)) => {
+                            log::warn!("Ignoring response to {} from {}, it was sent to {}", message.id, remote, to);
+                            continue;
+                        }
+                        None => None,
+                    }
+                };
+                match waiting {
+                    Some((_, waiting)) => { let _ = waiting.send(message); }
+                    None => match inbox.try_send(message) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(message)) => {
+                            log::warn!("Inbox full, dropping message {} from {}", message.id, remote);
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    },
+                }
+            }
+            // Forget the connection unless it has already been replaced
+            let mut connections = connections.lock().unwrap();
+            if connections.get(&remote).is_some_and(|tx| tx.same_channel(&writer_tx)) {
+                connections.remove(&remote);
+            }
+        });
+        tx
+    }
+
+    // The next NetworkMessage, from an envelope the remote node signed
+    async fn receive<S: tokio::io::AsyncRead + Unpin>(reader: &mut SecureStream<S>, identity: &NodeIdentity, replay: &Mutex<ReplayGuard>) -> Result<Option<NetworkMessage>> {
+        let envelope = match reader.read_json::<SignedEnvelope>().await? {
+            Some(envelope) => envelope,
+            None => return Ok(None),
+        };
+        let (sender, message) = protocol::open(&envelope, &identity.node_id, &mut replay.lock().unwrap(), now_secs())?;
+        match message {
+            P2PMessage::Network { message } if sender == reader.remote_node_id() => Ok(Some(message)),
+            P2PMessage::Network { .. } => Err(anyhow::anyhow!("Message signed by {} on a connection with {}", sender, reader.remote_node_id())),
+            _ => Err(anyhow::anyhow!("Expected a network message from {}", sender)),
+        }
+    }
+}
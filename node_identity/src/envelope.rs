@@ -0,0 +1,158 @@
+// XMBL Envelopes - signed wrappers for messages between nodes
+//
+// The sender serializes its message, signs it together with a fresh nonce,
+// the current time and the node ID it is meant for, and ships the result
+// alongside its public key. The receiver checks the signature, derives the
+// sender's node ID from the key rather than trusting anything the message
+// says about itself, and refuses envelopes addressed to another node, too
+// old, from the future, or already seen.
+
+use secp256k1::PublicKey;
+use secp256k1::ecdsa::Signature;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use anyhow::Result;
+
+use crate::NodeIdentity;
+
+// How far an envelope's timestamp may drift from the receiver's clock
+pub const DEFAULT_MAX_SKEW_SECS: u64 = 120;
+// Nonces remembered at once for one sender; past this its new envelopes are
+// refused until old ones age out, rather than forgetting nonces that could
+// then be replayed. Other senders are unaffected.
+pub const DEFAULT_MAX_PER_SENDER: usize = 10_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedEnvelope {
+    pub public_key: PublicKey,
+    pub nonce: String,
+    pub timestamp: u64,
+    // Node ID the envelope is addressed to, so it can't be replayed to another
+    pub recipient: String,
+    // Hex-encoded compact ECDSA signature over signed_bytes
+    pub signature: String,
+    // The message as JSON, kept as sent so the signed bytes survive the trip
+    pub payload: String,
+}
+
+impl SignedEnvelope {
+    pub fn seal<T: Serialize>(identity: &NodeIdentity, recipient: &str, message: &T, now: u64) -> Result<Self> {
+        let nonce = uuid::Uuid::new_v4().to_string();
+        let payload = serde_json::to_string(message)?;
+        let signature = identity.sign(&signed_bytes(&nonce, now, recipient, &payload));
+        Ok(SignedEnvelope {
+            public_key: identity.public_key,
+            signature: hex::encode(signature.serialize_compact()),
+            nonce,
+            timestamp: now,
+            recipient: recipient.to_string(),
+            payload,
+        })
+    }
+
+    // Node ID of whoever holds the signing key
+    pub fn sender(&self) -> String {
+        NodeIdentity::node_id_for(&self.public_key)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let signature = Signature::from_compact(&hex::decode(&self.signature)?)?;
+        let data = signed_bytes(&self.nonce, self.timestamp, &self.recipient, &self.payload);
+        NodeIdentity::verify(&self.public_key, &data, &signature)
+            .map_err(|_| anyhow::anyhow!("Envelope signature does not match its public key"))
+    }
+
+    // Verify, check it was meant for recipient, reject replays, and decode;
+    // returns the sender's node ID
+    pub fn open<T: DeserializeOwned>(&self, recipient: &str, replay: &mut ReplayGuard, now: u64) -> Result<(String, T)> {
+        self.verify()?;
+        let sender = self.sender();
+        if self.recipient != recipient {
+            return Err(anyhow::anyhow!("Envelope from {} is addressed to {}, not {}", sender, self.recipient, recipient));
+        }
+        replay.check(&sender, &self.nonce, self.timestamp, now)?;
+        Ok((sender, serde_json::from_str(&self.payload)?))
+    }
+}
+
+fn signed_bytes(nonce: &str, timestamp: u64, recipient: &str, payload: &str) -> Vec<u8> {
+    let mut data = b"xmbl-envelope\0".to_vec();
+    data.extend_from_slice(nonce.as_bytes());
+    data.push(0);
+    data.extend_from_slice(&timestamp.to_be_bytes());
+    data.extend_from_slice(recipient.as_bytes());
+    data.push(0);
+    data.extend_from_slice(payload.as_bytes());
+    data
+}
+
+// Nonces seen within the skew window. Anything older is rejected on its
+// timestamp alone, so those nonces can be forgotten; expiry walks them in
+// timestamp order and stops at the first one still inside the window.
+#[derive(Clone, Debug)]
+pub struct ReplayGuard {
+    pub max_skew_secs: u64,
+    pub max_per_sender: usize,
+    seen: HashMap<String, HashSet<String>>,
+    expiry: BTreeSet<(u64, String, String)>,
+}
+
+impl ReplayGuard {
+    pub fn new(max_skew_secs: u64) -> Self {
+        ReplayGuard {
+            max_skew_secs,
+            max_per_sender: DEFAULT_MAX_PER_SENDER,
+            seen: HashMap::new(),
+            expiry: BTreeSet::new(),
+        }
+    }
+
+    pub fn check(&mut self, sender: &str, nonce: &str, timestamp: u64, now: u64) -> Result<()> {
+        if timestamp.abs_diff(now) > self.max_skew_secs {
+            return Err(anyhow::anyhow!("Envelope timestamp {} is outside the accepted window", timestamp));
+        }
+        self.expire(now.saturating_sub(self.max_skew_secs));
+
+        let nonces = self.seen.entry(sender.to_string()).or_default();
+        if nonces.contains(nonce) {
+            return Err(anyhow::anyhow!("Replayed envelope from {}", sender));
+        }
+        if nonces.len() >= self.max_per_sender {
+            return Err(anyhow::anyhow!("Too many recent envelopes from {}", sender));
+        }
+        nonces.insert(nonce.to_string());
+        self.expiry.insert((timestamp, sender.to_string(), nonce.to_string()));
+        Ok(())
+    }
+
+    // Forget every nonce stamped before oldest
+    fn expire(&mut self, oldest: u64) {
+        while let Some(first) = self.expiry.first() {
+            if first.0 >= oldest {
+                break;
+            }
+            let (_, sender, nonce) = self.expiry.pop_first().unwrap();
+            if let Some(nonces) = self.seen.get_mut(&sender) {
+                nonces.remove(&nonce);
+                if nonces.is_empty() {
+                    self.seen.remove(&sender);
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.expiry.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expiry.is_empty()
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SKEW_SECS)
+    }
+}
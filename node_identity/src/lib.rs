@@ -1,11 +1,17 @@
 // XMBL Node Identity System
 // INDEPENDENT DEVELOPMENT - No external dependencies
 
-use secp256k1::{Secp256k1, SecretKey, PublicKey};
+use secp256k1::{Secp256k1, SecretKey, PublicKey, Message};
+use secp256k1::ecdsa::Signature;
 use sha3::{Digest, Keccak256};
-use hex;
 use rand::rngs::OsRng;
 use serde::Serialize;
+use std::path::Path;
+use anyhow::Result;
+
+pub mod envelope;
+
+pub use envelope::{SignedEnvelope, ReplayGuard, DEFAULT_MAX_PER_SENDER, DEFAULT_MAX_SKEW_SECS};
 
 #[derive(Serialize)]
 pub struct NodeIdentity {
@@ -18,23 +24,82 @@ pub struct NodeIdentity {
 
 impl NodeIdentity {
     pub fn new() -> Self {
-        let secp = Secp256k1::new();
         let mut rng = OsRng;
-        let secret_key = SecretKey::new(&mut rng);
+        Self::from_secret_key(SecretKey::new(&mut rng))
+    }
+    
+    pub fn from_secret_key(secret_key: SecretKey) -> Self {
+        let secp = Secp256k1::new();
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
         
+        NodeIdentity {
+            node_id: Self::node_id_for(&public_key),
+            public_key,
+            token_balance: 0,
+            secret_key,
+        }
+    }
+    
+    pub fn node_id_for(public_key: &PublicKey) -> String {
         let pubkey_bytes = public_key.serialize_uncompressed();
         let mut hasher = Keccak256::new();
         hasher.update(&pubkey_bytes[1..]); // skip leading 0x04
         let result = hasher.finalize();
         let node_id = &result[result.len()-20..];
-        
-        NodeIdentity {
-            node_id: format!("0x{}", hex::encode(node_id)),
-            public_key,
-            token_balance: 0,
-            secret_key,
+        format!("0x{}", hex::encode(node_id))
+    }
+    
+    // The key file holds the hex-encoded secret key and nothing else
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let encoded = std::fs::read_to_string(path)?;
+        let secret_key = SecretKey::from_slice(&hex::decode(encoded.trim())?)?;
+        Ok(Self::from_secret_key(secret_key))
+    }
+    
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, hex::encode(self.secret_key.secret_bytes()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
         }
+        Ok(())
+    }
+    
+    pub fn load_or_create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Self::load(path);
+        }
+        let identity = Self::new();
+        identity.save(path)?;
+        Ok(identity)
+    }
+    
+    // Derive a 32-byte key for a given purpose (e.g. "xmbl-storage-encryption")
+    // from the node's secret key, so subsystems never touch the raw key
+    pub fn derive_key(&self, context: &str) -> [u8; 32] {
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(None, &self.secret_key.secret_bytes());
+        let mut key = [0u8; 32];
+        hkdf.expand(context.as_bytes(), &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    // ECDSA over the sha256 of data
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        let digest: [u8; 32] = sha2::Sha256::digest(data).into();
+        Secp256k1::signing_only().sign_ecdsa(&Message::from_digest(digest), &self.secret_key)
+    }
+
+    pub fn verify(public_key: &PublicKey, data: &[u8], signature: &Signature) -> Result<()> {
+        let digest: [u8; 32] = sha2::Sha256::digest(data).into();
+        Secp256k1::verification_only().verify_ecdsa(&Message::from_digest(digest), signature, public_key)?;
+        Ok(())
     }
 
     pub fn get_public_key(&self) -> PublicKey {
@@ -77,4 +142,64 @@ mod tests {
         identity.update_token_balance(1000);
         assert_eq!(identity.get_token_balance(), 1000);
     }
+
+    #[test]
+    fn test_identity_persists_and_derives_stable_keys() {
+        let path = std::env::temp_dir().join(format!("xmbl_identity_{}", uuid::Uuid::new_v4()));
+        let identity = NodeIdentity::load_or_create(&path).unwrap();
+        let reloaded = NodeIdentity::load_or_create(&path).unwrap();
+
+        assert_eq!(identity.node_id, reloaded.node_id);
+        assert_eq!(identity.derive_key("storage"), reloaded.derive_key("storage"));
+        assert_ne!(identity.derive_key("storage"), identity.derive_key("other"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_envelope_authenticates_sender_and_rejects_replays() {
+        let identity = NodeIdentity::new();
+        let to = NodeIdentity::new().node_id;
+        let mut replay = ReplayGuard::default();
+        let now = 1_700_000_000;
+
+        let envelope = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now).unwrap();
+        let (sender, message): (String, String) = envelope.open(&to, &mut replay, now).unwrap();
+        assert_eq!(sender, identity.node_id);
+        assert_eq!(message, "hello");
+        assert!(envelope.open::<String>(&to, &mut replay, now).is_err());
+
+        let mut forged = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now).unwrap();
+        forged.public_key = NodeIdentity::new().public_key;
+        assert!(forged.open::<String>(&to, &mut replay, now).is_err());
+
+        let mut tampered = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now).unwrap();
+        tampered.payload = "\"goodbye\"".to_string();
+        assert!(tampered.open::<String>(&to, &mut replay, now).is_err());
+
+        let stale = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now - DEFAULT_MAX_SKEW_SECS - 1).unwrap();
+        assert!(stale.open::<String>(&to, &mut replay, now).is_err());
+
+        // Meant for another node, whether it says so or not
+        let elsewhere = SignedEnvelope::seal(&identity, &identity.node_id, &"hello".to_string(), now).unwrap();
+        assert!(elsewhere.open::<String>(&to, &mut replay, now).is_err());
+        let mut redirected = elsewhere.clone();
+        redirected.recipient = to.clone();
+        assert!(redirected.open::<String>(&to, &mut replay, now).is_err());
+
+        // A sender at its cap is refused new envelopes until old ones age
+        // out, while everyone else carries on
+        let mut replay = ReplayGuard::default();
+        replay.max_per_sender = 1;
+        let first = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now).unwrap();
+        let second = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), now).unwrap();
+        assert!(first.open::<String>(&to, &mut replay, now).is_ok());
+        assert!(second.open::<String>(&to, &mut replay, now).is_err());
+        let other = SignedEnvelope::seal(&NodeIdentity::new(), &to, &"hello".to_string(), now).unwrap();
+        assert!(other.open::<String>(&to, &mut replay, now).is_ok());
+        let later = now + DEFAULT_MAX_SKEW_SECS + 1;
+        let third = SignedEnvelope::seal(&identity, &to, &"hello".to_string(), later).unwrap();
+        assert!(third.open::<String>(&to, &mut replay, later).is_ok());
+        assert_eq!(replay.len(), 1);
+    }
 }
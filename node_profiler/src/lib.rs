@@ -5,15 +5,7 @@ use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use anyhow::Result;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct NodeCapabilities {
-    pub storage_gb: f64,
-    pub compute_flops: u64,
-    pub bandwidth_mbps: f64,
-    pub memory_gb: f64,
-    pub cpu_cores: u32,
-    pub gpu_memory_gb: f64,
-}
+pub use xmbl_types::NodeCapabilities;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NodeProfile {
@@ -2,16 +2,36 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use uuid::Uuid;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
 
 // Import our actual Rust crates
-use xmbl_storage::StorageService;
-use xmbl_network::NetworkService;
-use xmbl_compute::ComputeService;
+use xmbl_storage::{StorageService, ProofVerifier, ShardCommitment, RepairManager, RepairAction, ErasureCoder, ErasureConfig, ErasureManifest};
+use xmbl_network::{NetworkService, Contact, DhtMessage, NodeKey};
+use xmbl_network::{dht, framing, ConnectionPool, PooledStream, SecureStream};
+use xmbl_compute::{ComputeService, ComputeTask, ExecutionProfile, TaskJournal, ShardSource, TaskType, DEFAULT_PRIORITY};
+use xmbl_compute::{ComputeReputation, PeerOutcome, Verdict, VerificationPolicy, VerificationReport};
+use xmbl_compute::{batch, determinism, modules, mpc, verification, BatchJob, MpcConfig};
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_protocol::{self as protocol, Feature, Hello, P2PMessage, PeerInfo};
+use xmbl_network::handshake;
+use xmbl_node_profiler::{NodeCapabilities, NodeProfiler};
+
+// Under the node's data directory
+const TASK_JOURNAL_FILE: &str = "tasks.jsonl";
+const IDENTITY_FILE: &str = "identity.key";
+// Leases on shards peers store here, unless XMBL_LEASE_SECS says otherwise
+const DEFAULT_LEASE_SECS: u64 = 30 * 24 * 60 * 60;
+// Longest we wait between renewing our leases on peers; shorter leases are
+// renewed four times per term
+const LEASE_RENEWAL_SECS: u64 = 60 * 60;
+// Connecting to a peer and exchanging hellos, either way round
+const HANDSHAKE_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+// Handshakes remembered before the least recently seen peer is forgotten
+const MAX_REMEMBERED_PEERS: usize = 10_000;
 
 pub struct P2PNode {
     pub node_id: String,
@@ -19,62 +39,124 @@ pub struct P2PNode {
     pub storage_service: Arc<Mutex<StorageService>>,
     pub network_service: Arc<Mutex<NetworkService>>,
     pub compute_service: Arc<Mutex<ComputeService>>,
-    pub peers: HashMap<String, PeerInfo>,
+    pub proof_verifier: Arc<Mutex<ProofVerifier>>,
+    pub repair_manager: Arc<Mutex<RepairManager>>,
+    pub compute_reputation: Arc<Mutex<ComputeReputation>>,
+    pub auth: MessageAuth,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PeerInfo {
-    pub node_id: String,
-    pub address: String,
-    pub capabilities: NodeCapabilities,
-    pub last_seen: u64,
+// Signs everything this node sends and checks everything it receives. The
+// node ID is derived from the identity key, so a peer can only speak as
+// itself. Also introduces us on every connection and remembers what each
+// peer said about itself in return, and keeps one connection open to each
+// peer we send requests to.
+#[derive(Clone)]
+pub struct MessageAuth {
+    pub identity: Arc<NodeIdentity>,
+    pub hello: Arc<Hello>,
+    pub peers: Arc<std::sync::Mutex<HashMap<String, PeerInfo>>>,
+    replay: Arc<std::sync::Mutex<ReplayGuard>>,
+    pub connections: Arc<ConnectionPool>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct NodeCapabilities {
-    pub storage_gb: f64,
-    pub compute_flops: u64,
-    pub bandwidth_mbps: f64,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum P2PMessage {
-    Ping { from: String, timestamp: u64 },
-    Pong { from: String, timestamp: u64 },
-    StoreRequest { data: Vec<u8>, redundancy: u8, from: String },
-    StoreResponse { shard_id: String, success: bool, message: String },
-    RetrieveRequest { shard_id: String, from: String },
-    RetrieveResponse { data: Option<Vec<u8>>, success: bool, message: String },
-    ComputeRequest { wasm_bytes: Vec<u8>, input_data: Vec<u8>, from: String },
-    ComputeResponse { result: Option<Vec<u8>>, success: bool, message: String },
-    DiscoveryRequest { from: String },
-    DiscoveryResponse { nodes: Vec<PeerInfo> },
+impl MessageAuth {
+    pub fn new(identity: NodeIdentity, hello: Hello) -> Self {
+        MessageAuth {
+            identity: Arc::new(identity),
+            hello: Arc::new(hello),
+            peers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            replay: Arc::new(std::sync::Mutex::new(ReplayGuard::default())),
+            connections: Arc::new(ConnectionPool::new()),
+        }
+    }
+    
+    pub async fn greet(&self, stream: &mut SecureStream) -> anyhow::Result<PeerInfo> {
+        let peer = handshake::greet(stream, &self.identity, &self.hello, &self.replay).await?;
+        self.remember(&peer);
+        Ok(peer)
+    }
+    
+    pub async fn welcome(&self, stream: &mut SecureStream) -> anyhow::Result<PeerInfo> {
+        let peer = handshake::welcome(stream, &self.identity, &self.hello, &self.replay).await?;
+        self.remember(&peer);
+        Ok(peer)
+    }
+    
+    // One DHT request, keeping the handshake like any other
+    pub async fn query_dht(&self, address: &str, request: DhtMessage) -> anyhow::Result<(String, DhtMessage)> {
+        let (peer, answer) = dht::query_node(&self.identity, &self.hello, address, request).await?;
+        self.remember(&peer);
+        Ok((peer.node_id, answer))
+    }
+    
+    fn remember(&self, peer: &PeerInfo) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.len() >= MAX_REMEMBERED_PEERS && !peers.contains_key(&peer.node_id) {
+            let oldest = peers.values().min_by_key(|p| p.last_seen).map(|p| p.node_id.clone());
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer.node_id.clone(), peer.clone());
+    }
+    
+    pub fn peer(&self, node_id: &str) -> Option<PeerInfo> {
+        self.peers.lock().unwrap().get(node_id).cloned()
+    }
+    
+    pub fn node_id(&self) -> &str {
+        &self.identity.node_id
+    }
+    
+    // Written at the version our last handshake with `to` settled on
+    pub fn seal(&self, to: &str, message: &P2PMessage) -> anyhow::Result<Vec<u8>> {
+        let version = self.peer(to).map_or(protocol::PROTOCOL_VERSION, |peer| peer.version);
+        Ok(serde_json::to_vec(&protocol::seal_version(&self.identity, to, message, version, now_secs())?)?)
+    }
+    
+    // The verified sender and its message; envelopes meant for another node,
+    // or a `from` naming anyone but the signer, are refused
+    pub fn open(&self, envelope: &SignedEnvelope) -> anyhow::Result<(String, P2PMessage)> {
+        protocol::open(envelope, self.node_id(), &mut self.replay.lock().unwrap(), now_secs())
+    }
 }
 
 impl P2PNode {
-    pub fn new(node_id: String, address: SocketAddr, storage_gb: f64) -> Self {
-        let storage_service = Arc::new(Mutex::new(StorageService::new(
-            node_id.clone(),
-            storage_gb
-        )));
+    pub fn new(identity: NodeIdentity, address: SocketAddr, storage_gb: f64, data_dir: PathBuf) -> anyhow::Result<Self> {
+        let node_id = identity.node_id.clone();
+        let journal_path = data_dir.join(TASK_JOURNAL_FILE);
+        let mut storage = StorageService::open(node_id.clone(), storage_gb, data_dir)?;
+        // Without a lease length nothing ever expires and the GC never frees space
+        storage.default_lease_secs = std::env::var("XMBL_LEASE_SECS").ok()
+            .and_then(|v| v.parse().ok())
+            .or(Some(DEFAULT_LEASE_SECS));
+        let storage_service = Arc::new(Mutex::new(storage));
         
-        let network_service = Arc::new(Mutex::new(NetworkService::new(
-            node_id.clone()
+        // Capabilities are measured when the node starts
+        let hello = Hello::new(&node_id, vec![Feature::Storage, Feature::Compute], None, vec![address.to_string()]);
+        let auth = MessageAuth::new(identity, hello);
+        let network_service = Arc::new(Mutex::new(NetworkService::with_identity(
+            Arc::clone(&auth.identity)
         )));
         
-        let compute_service = Arc::new(Mutex::new(ComputeService::new(
-            node_id.clone(),
-            1000 // max_concurrent_tasks
-        )));
+        // Tasks are CPU-bound, run one per core
+        let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        let mut compute = ComputeService::new(node_id.clone(), workers);
+        compute.shard_source = Some(Arc::new(LocalShards(Arc::clone(&storage_service))));
+        compute.journal = TaskJournal::open(journal_path)?;
+        let compute_service = Arc::new(Mutex::new(compute));
         
-        P2PNode {
+        Ok(P2PNode {
             node_id,
             address,
             storage_service,
             network_service,
             compute_service,
-            peers: HashMap::new(),
-        }
+            proof_verifier: Arc::new(Mutex::new(ProofVerifier::new())),
+            repair_manager: Arc::new(Mutex::new(RepairManager::new())),
+            compute_reputation: Arc::new(Mutex::new(ComputeReputation::new())),
+            auth,
+        })
     }
     
     pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -82,6 +164,33 @@ impl P2PNode {
         println!("📍 Address: {}", self.address);
         println!("💾 Storage: {}GB", self.storage_service.lock().await.total_storage_gb);
         
+        // Tell peers what we can do, offering the storage we were given
+        // rather than the whole disk. The profiler only measures CPU cores;
+        // the rest stays zero rather than repeat its placeholder numbers.
+        let storage_gb = self.storage_service.lock().await.total_storage_gb;
+        let capabilities = match NodeProfiler::new(self.node_id.clone()).profile_system().await {
+            Ok(profile) => Some(NodeCapabilities {
+                storage_gb,
+                compute_flops: 0,
+                bandwidth_mbps: 0.0,
+                memory_gb: 0.0,
+                cpu_cores: profile.capabilities.cpu_cores,
+                gpu_memory_gb: 0.0,
+            }),
+            Err(e) => {
+                eprintln!("⚠️ Could not profile this machine, advertising no capabilities: {}", e);
+                None
+            }
+        };
+        let mut hello = (*self.auth.hello).clone();
+        hello.capabilities = capabilities;
+        self.auth.hello = Arc::new(hello);
+        // Our network service introduces us the same way when it dials
+        self.network_service.lock().await.transport.set_hello((*self.auth.hello).clone());
+        
+        // Answer what peers' network services send us
+        self.start_network_inbox().await;
+        
         // Start network discovery
         self.discover_peers().await?;
         
@@ -94,46 +203,40 @@ impl P2PNode {
     async fn discover_peers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         println!("🔍 Discovering peers on network...");
         
-        // Only add peers that are different from this node
-        let mock_peers = vec![
-            ("node_001".to_string(), "127.0.0.1:3010".to_string()),
-            ("node_002".to_string(), "127.0.0.1:3011".to_string()),
-            ("node_003".to_string(), "127.0.0.1:3012".to_string()),
-            ("node_004".to_string(), "127.0.0.1:3013".to_string()),
-            ("node_005".to_string(), "127.0.0.1:3014".to_string()),
-            ("node_006".to_string(), "127.0.0.1:3015".to_string()),
-        ];
-        
-        for (peer_id, peer_addr) in mock_peers {
-            if peer_id != self.node_id {
-                let peer_info = PeerInfo {
-                    node_id: peer_id.clone(),
-                    address: peer_addr.clone(),
-                    capabilities: NodeCapabilities {
-                        storage_gb: 100.0,
-                        compute_flops: 1_000_000_000,
-                        bandwidth_mbps: 100.0,
-                    },
-                    last_seen: std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
-                };
-                
-                self.peers.insert(peer_id.clone(), peer_info);
-                println!("✅ Discovered peer: {} at {}", peer_id, peer_addr);
-            }
-        }
+        let seeds = self.network_service.lock().await.seeds.clone();
+        println!("🌱 Bootstrapping from seeds: {}", seeds.join(", "));
+        Self::refresh_routing(&self.network_service, &self.auth, &self.address.to_string(), &seeds).await;
         
-        println!("🌐 Total peers discovered: {}", self.peers.len());
+        let peers = Self::known_peers(&self.network_service).await;
+        for peer in peers.values() {
+            println!("✅ Discovered peer: {} at {}", peer.node_id, peer.address);
+        }
+        println!("🌐 Total peers discovered: {}", peers.len());
         
-        // Actively connect to peers to form the swarm
-        println!("🔗 Forming P2P swarm...");
-        self.connect_to_peers().await?;
+        // Keep looking for peers that join later
+        self.start_dht_refresh();
         
         // Start heartbeat to maintain swarm connectivity
         self.start_heartbeat().await?;
         
+        // Run queued compute tasks in the background
+        ComputeService::spawn_workers(&self.compute_service).await;
+        
+        // Periodically check that peers still hold what they stored for us
+        self.start_storage_audits();
+        
+        // Put lost replicas back when peers disappear
+        self.start_repair();
+        
+        // Reclaim shards whose leases ran out
+        self.start_lease_gc();
+        
+        // Keep our own leases on peers from running out
+        self.start_lease_renewal();
+        
+        // Keep the task journal within its retention policy
+        self.start_task_history_pruning();
+        
         // Display swarm status
         self.display_swarm_status().await;
         
@@ -142,45 +245,121 @@ impl P2PNode {
         Ok(())
     }
     
-    async fn connect_to_peers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🔗 Connecting to peer nodes to form swarm...");
+    // Look ourselves up, starting from the seeds and everyone we already
+    // know, and keep every node that answered
+    async fn refresh_routing(network: &Arc<Mutex<NetworkService>>, auth: &MessageAuth, own_address: &str, seeds: &[String]) {
+        let known: Vec<String> = network.lock().await.routing.contacts().into_iter().map(|c| c.address).collect();
+        let starts: Vec<String> = seeds.iter().cloned().chain(known).filter(|a| a != own_address).collect();
         
-        for (peer_id, peer_info) in &self.peers {
-            if peer_id != &self.node_id {
-                println!("🔌 Attempting connection to peer: {} at {}", peer_id, peer_info.address);
+        let query_auth = auth.clone();
+        let lookup = dht::bootstrap(NodeKey::from_node_id(auth.node_id()), &starts, now_secs(), move |address, request| {
+            let auth = query_auth.clone();
+            async move { auth.query_dht(&address, request).await }
+        }).await;
+        
+        for contact in lookup.closest {
+            Self::learn_contact(network, auth, contact).await;
+        }
+        for contact in lookup.failed {
+            network.lock().await.routing.remove(&contact.node_id);
+        }
+    }
+    
+    // Add a contact, or if its bucket is full, take the place of the
+    // bucket's oldest contact provided that one no longer answers
+    async fn learn_contact(network: &Arc<Mutex<NetworkService>>, auth: &MessageAuth, contact: Contact) {
+        let outcome = network.lock().await.routing.insert(contact.clone());
+        if let dht::InsertOutcome::Full { oldest } = outcome {
+            let ping = P2PMessage::Ping { from: auth.node_id().to_string(), timestamp: now_secs() };
+            if !matches!(Self::send_request(auth, &oldest, &ping).await, Ok(P2PMessage::Pong { .. })) {
+                network.lock().await.routing.replace(&oldest.node_id, contact);
+            }
+        }
+    }
+    
+    // A contact whose address we only have its word for. A known node at the
+    // same address is just refreshed; otherwise it must answer a ping there
+    // before it goes in the table.
+    async fn learn_claimed_contact(network: &Arc<Mutex<NetworkService>>, auth: &MessageAuth, contact: Contact) {
+        let known = network.lock().await.routing.get(&contact.node_id)
+            .is_some_and(|known| known.address == contact.address);
+        if !known {
+            let ping = P2PMessage::Ping { from: auth.node_id().to_string(), timestamp: now_secs() };
+            match Self::send_request(auth, &contact, &ping).await {
+                Ok(P2PMessage::Pong { .. }) => {}
+                _ => return,
+            }
+        }
+        Self::learn_contact(network, auth, contact).await;
+    }
+    
+    // Everyone in the routing table, by node ID
+    async fn known_peers(network: &Arc<Mutex<NetworkService>>) -> HashMap<String, Contact> {
+        network.lock().await.routing.contacts().into_iter().map(|c| (c.node_id.clone(), c)).collect()
+    }
+    
+    fn start_dht_refresh(&self) {
+        let network = Arc::clone(&self.network_service);
+        let auth = self.auth.clone();
+        let own_address = self.address.to_string();
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            interval.tick().await;
+            
+            loop {
+                interval.tick().await;
                 
-                match TcpStream::connect(&peer_info.address).await {
-                    Ok(mut stream) => {
-                        // Send discovery message to establish connection
-                        let message = P2PMessage::DiscoveryRequest {
-                            from: self.node_id.clone(),
-                        };
-                        
-                        if let Ok(message_data) = serde_json::to_vec(&message) {
-                            if stream.write_all(&message_data).await.is_ok() {
-                                println!("✅ Successfully connected to peer: {}", peer_id);
-                                
-                                // Update peer status - we'll do this after the loop to avoid borrow checker issues
-                                println!("✅ Successfully connected to peer: {}", peer_id);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("⚠️  Could not connect to peer {}: {} (will retry later)", peer_id, e);
-                    }
+                let seeds = network.lock().await.seeds.clone();
+                Self::refresh_routing(&network, &auth, &own_address, &seeds).await;
+                let expired = network.lock().await.values.expire(now_secs());
+                if expired > 0 {
+                    println!("🧹 Dropped {} expired DHT values", expired);
                 }
             }
+        });
+    }
+    
+    // Store a value on the nodes closest to its key
+    pub async fn dht_store(&self, key: &str, value: Vec<u8>) -> anyhow::Result<usize> {
+        let lookup = self.dht_lookup(NodeKey::for_value(key), DhtMessage::FindNode { target: NodeKey::for_value(key).to_hex() }).await;
+        let mut stored = 0;
+        for contact in lookup.closest {
+            let request = DhtMessage::Store { key: key.to_string(), value: value.clone() };
+            let response = self.auth.query_dht(&contact.address, request).await;
+            if let Ok((_, DhtMessage::StoreResponse { success: true, .. })) = response {
+                stored += 1;
+            }
         }
-        
-        println!("🌐 Swarm connection attempts completed");
-        Ok(())
+        if stored == 0 {
+            return Err(anyhow::anyhow!("No node accepted the value for {}", key));
+        }
+        Ok(stored)
+    }
+    
+    pub async fn dht_find_value(&self, key: &str) -> Option<Vec<u8>> {
+        if let Some(value) = self.network_service.lock().await.values.get(key, now_secs()) {
+            return Some(value);
+        }
+        self.dht_lookup(NodeKey::for_value(key), DhtMessage::FindValue { key: key.to_string() }).await.value
+    }
+    
+    async fn dht_lookup(&self, target: NodeKey, request: DhtMessage) -> dht::Lookup {
+        let seeds = self.network_service.lock().await.routing.closest(&target, dht::K);
+        let auth = self.auth.clone();
+        dht::lookup(target, seeds, request, move |address, request| {
+            let auth = auth.clone();
+            async move { auth.query_dht(&address, request).await }
+        }).await
     }
     
     async fn start_heartbeat(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         println!("💓 Starting heartbeat to maintain swarm connectivity...");
         
-        let peers = self.peers.clone();
+        let network = Arc::clone(&self.network_service);
         let node_id = self.node_id.clone();
+        let auth = self.auth.clone();
+        let repair = Arc::clone(&self.repair_manager);
         
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
@@ -188,21 +367,22 @@ impl P2PNode {
             loop {
                 interval.tick().await;
                 
-                for (peer_id, peer_info) in &peers {
+                for (peer_id, peer_info) in &Self::known_peers(&network).await {
                     if peer_id != &node_id {
                         let message = P2PMessage::Ping {
                             from: node_id.clone(),
-                            timestamp: std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
+                            timestamp: now_secs(),
                         };
                         
-                        if let Ok(message_data) = serde_json::to_vec(&message) {
-                            if let Ok(mut stream) = TcpStream::connect(&peer_info.address).await {
-                                let _ = stream.write_all(&message_data).await;
-                                println!("💓 Heartbeat sent to peer: {}", peer_id);
+                        // Only peers that answer count as alive for repair purposes
+                        if let Ok(P2PMessage::Pong { .. }) = Self::send_request(&auth, peer_info, &message).await {
+                            repair.lock().await.record_heartbeat(peer_id, now_secs());
+                            let mut network = network.lock().await;
+                            if let Some(mut contact) = network.routing.get(peer_id).cloned() {
+                                contact.last_seen = now_secs();
+                                network.routing.insert(contact);
                             }
+                            println!("💓 Heartbeat acknowledged by peer: {}", peer_id);
                         }
                     }
                 }
@@ -212,23 +392,337 @@ impl P2PNode {
         Ok(())
     }
     
+    async fn start_network_inbox(&self) {
+        let network = Arc::clone(&self.network_service);
+        let mut inbox = network.lock().await.take_inbox();
+        tokio::spawn(async move {
+            while let Some(message) = inbox.recv().await {
+                if let Err(e) = network.lock().await.handle_message(message).await {
+                    eprintln!("❌ Network message failed: {}", e);
+                }
+            }
+        });
+    }
+    
+    fn start_lease_gc(&self) {
+        let storage = Arc::clone(&self.storage_service);
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            
+            loop {
+                interval.tick().await;
+                
+                match storage.lock().await.collect_garbage(now_secs()) {
+                    Ok(reclaimed) if !reclaimed.is_empty() => {
+                        println!("🧹 Reclaimed {} shards with expired leases", reclaimed.len());
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("❌ Lease garbage collection failed: {}", e),
+                }
+            }
+        });
+    }
+    
+    // Renew the leases on everything we placed on peers, well before they
+    // lapse; a holder that refuses loses its place and repair puts the
+    // shard back elsewhere
+    fn start_lease_renewal(&self) {
+        let network = Arc::clone(&self.network_service);
+        let storage = Arc::clone(&self.storage_service);
+        let auth = self.auth.clone();
+        let repair = Arc::clone(&self.repair_manager);
+        
+        tokio::spawn(async move {
+            let lease_secs = storage.lock().await.default_lease_secs;
+            let every = lease_secs.map_or(LEASE_RENEWAL_SECS, |secs| (secs / 4).clamp(1, LEASE_RENEWAL_SECS));
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(every));
+            
+            loop {
+                interval.tick().await;
+                
+                let peers = Self::known_peers(&network).await;
+                let held = repair.lock().await.held_shards();
+                for (shard_id, holder) in held {
+                    let Some(peer) = peers.get(&holder) else { continue };
+                    let message = P2PMessage::RenewLeaseRequest {
+                        shard_id: shard_id.clone(),
+                        lease_secs,
+                        from: auth.node_id().to_string(),
+                    };
+                    match Self::send_request(&auth, peer, &message).await {
+                        Ok(P2PMessage::RenewLeaseResponse { success: true, .. }) => {}
+                        Ok(P2PMessage::RenewLeaseResponse { message, .. }) => {
+                            println!("⚠️  {} would not renew our lease on {}: {}", holder, shard_id, message);
+                            repair.lock().await.remove_replica(&shard_id, &holder);
+                        }
+                        Ok(_) => println!("❌ Unexpected response renewing {} on {}", shard_id, holder),
+                        Err(e) => println!("❌ Renewing {} on {} failed: {}", shard_id, holder, e),
+                    }
+                }
+            }
+        });
+    }
+    
+    // Drop finished tasks the journal's retention policy no longer covers
+    fn start_task_history_pruning(&self) {
+        let compute = Arc::clone(&self.compute_service);
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60 * 60));
+            
+            loop {
+                interval.tick().await;
+                
+                match compute.lock().await.prune_history(now_secs()) {
+                    Ok(pruned) if !pruned.is_empty() => {
+                        println!("🧹 Pruned {} finished tasks from the task journal", pruned.len());
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("❌ Task journal pruning failed: {}", e),
+                }
+            }
+        });
+    }
+    
+    // Restore shards whose holders went offline or keep failing proofs
+    fn start_repair(&self) {
+        println!("🩹 Starting background repair of under-replicated shards...");
+        
+        let network = Arc::clone(&self.network_service);
+        let auth = self.auth.clone();
+        let repair = Arc::clone(&self.repair_manager);
+        let verifier = Arc::clone(&self.proof_verifier);
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+            
+            loop {
+                interval.tick().await;
+                
+                let peers = Self::known_peers(&network).await;
+                let candidates: Vec<String> = peers.keys().cloned().collect();
+                let actions = repair.lock().await.plan(now_secs(), &candidates);
+                
+                for action in actions {
+                    if let Err(e) = Self::run_repair(action, &auth, &peers, &repair, &verifier).await {
+                        println!("❌ Repair failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+    
+    async fn run_repair(
+        action: RepairAction,
+        auth: &MessageAuth,
+        peers: &HashMap<String, Contact>,
+        repair: &Arc<Mutex<RepairManager>>,
+        verifier: &Arc<Mutex<ProofVerifier>>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match action {
+            RepairAction::Replicate { shard_id, sources, targets } => {
+                let mut data = None;
+                for source in &sources {
+                    if let Some(peer) = peers.get(source) {
+                        if let Ok(bytes) = Self::fetch_shard(peer, &shard_id, auth).await {
+                            data = Some(bytes);
+                            break;
+                        }
+                    }
+                }
+                let data = data.ok_or_else(|| format!("No source could serve shard {}", shard_id))?;
+                
+                for target in targets {
+                    let peer = peers.get(&target).ok_or("Unknown repair target")?;
+                    if Self::push_shard(peer, data.clone(), auth).await.is_ok() {
+                        repair.lock().await.record_replica(&shard_id, &target);
+                        verifier.lock().await.register(ShardCommitment::from_data(&shard_id, &data), &target);
+                        println!("🩹 Re-replicated shard {} onto {}", shard_id, target);
+                    }
+                }
+            }
+            RepairAction::Reencode { manifest, missing, targets } => {
+                let mut available = Vec::new();
+                for location in &manifest.fragments {
+                    let mut fragment = None;
+                    if !missing.contains(&location.index) {
+                        if let Some(peer) = location.node_id.as_ref().and_then(|n| peers.get(n)) {
+                            fragment = Self::fetch_shard(peer, &location.cid, auth).await.ok();
+                        }
+                    }
+                    available.push(fragment);
+                }
+                
+                let fragments = manifest.regenerate(available, &missing)?;
+                for (fragment, target) in fragments.into_iter().zip(targets) {
+                    let peer = peers.get(&target).ok_or("Unknown repair target")?;
+                    if Self::push_shard(peer, fragment.data.clone(), auth).await.is_ok() {
+                        repair.lock().await.record_fragment(&manifest.file_cid, fragment.index, &target);
+                        verifier.lock().await.register(ShardCommitment::from_data(&fragment.cid, &fragment.data), &target);
+                        println!("🩹 Rebuilt fragment {} of {} onto {}", fragment.index, manifest.file_cid, target);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    async fn fetch_shard(peer: &Contact, shard_id: &str, auth: &MessageAuth) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let message = P2PMessage::RetrieveRequest { shard_id: shard_id.to_string(), from: auth.node_id().to_string() };
+        match Self::send_request(auth, peer, &message).await? {
+            P2PMessage::RetrieveResponse { data: Some(data), success: true, .. } => Ok(data),
+            P2PMessage::RetrieveResponse { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+    
+    async fn push_shard(peer: &Contact, data: Vec<u8>, auth: &MessageAuth) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let message = P2PMessage::StoreRequest { data, redundancy: 1, from: auth.node_id().to_string() };
+        match Self::send_request(auth, peer, &message).await? {
+            P2PMessage::StoreResponse { shard_id, success: true, .. } => Ok(shard_id),
+            P2PMessage::StoreResponse { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+    
+    fn start_storage_audits(&self) {
+        println!("🔎 Starting proof-of-storage audits...");
+        
+        let network = Arc::clone(&self.network_service);
+        let auth = self.auth.clone();
+        let verifier = Arc::clone(&self.proof_verifier);
+        let repair = Arc::clone(&self.repair_manager);
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            
+            loop {
+                interval.tick().await;
+                
+                for (peer_id, peer_info) in &Self::known_peers(&network).await {
+                    let shard_ids = verifier.lock().await.shards_held_by(peer_id);
+                    for shard_id in shard_ids {
+                        let challenge = match verifier.lock().await.issue_challenge(&shard_id, peer_id) {
+                            Ok(challenge) => challenge,
+                            Err(_) => continue,
+                        };
+                        let challenge_id = challenge.challenge_id.clone();
+                        let message = P2PMessage::StorageChallenge { challenge, from: auth.node_id().to_string() };
+                        
+                        match Self::send_request(&auth, peer_info, &message).await {
+                            Ok(P2PMessage::StorageProofResponse { proof: Some(proof), .. }) => {
+                                if verifier.lock().await.verify(&proof) {
+                                    println!("✅ Peer {} proved storage of {}", peer_id, shard_id);
+                                } else {
+                                    println!("❌ Peer {} failed storage proof for {}", peer_id, shard_id);
+                                }
+                            }
+                            Ok(P2PMessage::StorageProofResponse { message, .. }) => {
+                                verifier.lock().await.record_failure(&challenge_id, &message);
+                            }
+                            Ok(_) => {
+                                verifier.lock().await.record_failure(&challenge_id, "Unexpected response");
+                            }
+                            Err(e) => {
+                                verifier.lock().await.record_failure(&challenge_id, &e.to_string());
+                            }
+                        }
+                    }
+                }
+                
+                // Holders that keep failing stop counting as replicas, so the
+                // repair loop copies their shards elsewhere
+                let failing = {
+                    let mut verifier = verifier.lock().await;
+                    verifier.expire_pending();
+                    verifier.failing_nodes()
+                };
+                let mut repair = repair.lock().await;
+                for peer_id in failing {
+                    println!("⚠️  Peer {} keeps failing storage proofs, re-replicating its shards", peer_id);
+                    repair.mark_unhealthy(&peer_id);
+                }
+            }
+        });
+    }
+    
+    // Send one signed message and read back the response, on the open
+    // connection to the peer if it is idle. A pooled connection may have
+    // been closed since it was last used, so a failure on one is retried
+    // once on a fresh connection.
+    async fn send_request(auth: &MessageAuth, peer: &Contact, message: &P2PMessage) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let (mut stream, reused) = Self::checkout(auth, peer).await?;
+            match Self::exchange(auth, &mut stream, message).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    auth.connections.remove(&peer.node_id, &stream);
+                    if !reused {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+    
+    // The pooled connection to a peer if nobody is using it, otherwise a new
+    // one that is pooled if the peer has none yet. Says which it was.
+    async fn checkout(auth: &MessageAuth, peer: &Contact) -> Result<(PooledStream, bool), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(stream) = auth.connections.idle(&peer.node_id) {
+            return Ok((stream, true));
+        }
+        let stream = Self::connect(auth, peer).await?;
+        Ok((auth.connections.insert(&peer.node_id, stream), false))
+    }
+    
+    // Open a connection to a peer and introduce ourselves, refusing whoever
+    // answers at its address unless it is that peer
+    async fn connect(auth: &MessageAuth, peer: &Contact) -> Result<SecureStream, Box<dyn std::error::Error + Send + Sync>> {
+        let connecting = async {
+            let mut stream = SecureStream::connect(&peer.address, &auth.identity).await?;
+            if stream.remote_node_id() != peer.node_id {
+                return Err(format!("{} answered as {}, not {}", peer.address, stream.remote_node_id(), peer.node_id).into());
+            }
+            auth.greet(&mut stream).await?;
+            Ok(stream)
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, connecting).await
+            .map_err(|_| format!("{} did not complete the handshake within {:?}", peer.address, HANDSHAKE_TIMEOUT))?
+    }
+    
+    // One request/response pair on an open connection, which stays usable
+    // for the next
+    async fn exchange(auth: &MessageAuth, stream: &mut SecureStream, message: &P2PMessage) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        stream.write_frame(&auth.seal(stream.remote_node_id(), message)?).await?;
+        let response = stream.read_frame(framing::MAX_FRAME_BYTES).await?
+            .ok_or("Connection closed before a response arrived")?;
+        let (sender, response) = auth.open(&serde_json::from_slice::<SignedEnvelope>(&response)?)?;
+        if sender != stream.remote_node_id() {
+            return Err(format!("Response signed by {} on a connection with {}", sender, stream.remote_node_id()).into());
+        }
+        Ok(response)
+    }
+    
     async fn display_swarm_status(&self) {
-        println!("");
+        println!();
         println!("🌐 P2P SWARM STATUS");
         println!("===================");
         println!("Node ID: {}", self.node_id);
         println!("Address: {}", self.address);
-        println!("Connected Peers: {}", self.peers.len());
-        println!("");
+        let peers = Self::known_peers(&self.network_service).await;
+        println!("Connected Peers: {}", peers.len());
+        println!();
         
-        for (peer_id, peer_info) in &self.peers {
-            if peer_id != &self.node_id {
-                let status = if peer_info.last_seen > 0 { "🟢 ONLINE" } else { "🔴 OFFLINE" };
-                println!("  {} - {} - {}GB storage - {}Mbps bandwidth", 
-                    status, peer_id, peer_info.capabilities.storage_gb, peer_info.capabilities.bandwidth_mbps);
-            }
+        for (peer_id, peer_info) in &peers {
+            let status = if peer_info.last_seen > 0 { "🟢 ONLINE" } else { "🔴 OFFLINE" };
+            let offers = match self.auth.peer(peer_id) {
+                Some(peer) => format!("v{} {:?}", peer.version, peer.features),
+                None => "no handshake yet".to_string(),
+            };
+            println!("  {} - {} - {} - {}", status, peer_id, peer_info.address, offers);
         }
-        println!("");
+        println!();
     }
     
     async fn listen_for_connections(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -262,29 +756,69 @@ impl P2PNode {
             storage_service: Arc::clone(&self.storage_service),
             network_service: Arc::clone(&self.network_service),
             compute_service: Arc::clone(&self.compute_service),
-            peers: self.peers.clone(),
+            proof_verifier: Arc::clone(&self.proof_verifier),
+            repair_manager: Arc::clone(&self.repair_manager),
+            compute_reputation: Arc::clone(&self.compute_reputation),
+            auth: self.auth.clone(),
         }))
     }
     
     async fn handle_connection(
-        mut socket: TcpStream,
+        socket: TcpStream,
         node: Arc<Mutex<P2PNode>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buffer = [0; 4096];
+        let (auth, network) = {
+            let node = node.lock().await;
+            (node.auth.clone(), Arc::clone(&node.network_service))
+        };
+        // A peer that connects and goes quiet doesn't get to hold the task
+        let accepting = async {
+            let mut socket = SecureStream::accept(socket, &auth.identity).await?;
+            let peer = auth.welcome(&mut socket).await?;
+            anyhow::Ok((socket, peer.version))
+        };
+        let (mut socket, version) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, accepting).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                eprintln!("❌ Handshake failed: {}", e);
+                return Ok(());
+            }
+            Err(_) => {
+                eprintln!("❌ Handshake timed out after {:?}", HANDSHAKE_TIMEOUT);
+                return Ok(());
+            }
+        };
         
+        // Answer requests one frame at a time until the peer hangs up
         loop {
-            match socket.read(&mut buffer).await {
-                Ok(n) if n > 0 => {
-                    let message_data = &buffer[..n];
-                    
+            match socket.read_frame(framing::MAX_FRAME_BYTES).await {
+                Ok(Some(message_data)) => {
                     // Try to deserialize the message
-                    match serde_json::from_slice::<P2PMessage>(message_data) {
-                        Ok(message) => {
+                    match serde_json::from_slice::<SignedEnvelope>(&message_data) {
+                        Ok(envelope) => {
+                            // Only the peer that completed the handshake may
+                            // speak on this connection
+                            let message = match auth.open(&envelope) {
+                                Ok((sender, message)) if sender == socket.remote_node_id() => message,
+                                Ok((sender, _)) => {
+                                    eprintln!("❌ Rejected message signed by {} on a connection with {}", sender, socket.remote_node_id());
+                                    break;
+                                }
+                                Err(e) => {
+                                    eprintln!("❌ Rejected message: {}", e);
+                                    break;
+                                }
+                            };
+                            // Peers' network services reach us at the same
+                            // address; their connections belong to ours
+                            if let P2PMessage::Network { message } = message {
+                                network.lock().await.transport.adopt(socket, version, message);
+                                return Ok(());
+                            }
                             let response = Self::process_message(message, &node).await;
                             
                             // Send response back
-                            let response_data = serde_json::to_vec(&response)?;
-                            socket.write_all(&response_data).await?;
+                            socket.write_frame(&auth.seal(socket.remote_node_id(), &response)?).await?;
                         }
                         Err(e) => {
                             eprintln!("❌ Failed to deserialize message: {}", e);
@@ -292,14 +826,10 @@ impl P2PNode {
                         }
                     }
                 }
-                Ok(0) => {
+                Ok(None) => {
                     // Connection closed
                     break;
                 }
-                Ok(_) => {
-                    // Other positive values, continue
-                    continue;
-                }
                 Err(e) => {
                     eprintln!("❌ Read error: {}", e);
                     break;
@@ -327,14 +857,29 @@ impl P2PNode {
                 println!("💾 Store request from: {} ({} bytes, {}x redundancy)", from, data.len(), redundancy);
                 
                 let node_guard = node.lock().await;
-                let mut storage = node_guard.storage_service.lock().await;
-                match storage.store_data(data, redundancy).await {
+                // Peers pushing replicas get a local copy; clients asking for
+                // more than one also get the rest spread over the swarm
+                let spread = (redundancy > 1 && !node_guard.is_storage_peer(&from)).then(|| data.clone());
+                let stored = {
+                    let mut storage = node_guard.storage_service.lock().await;
+                    // Shards are held under a lease owned by the requesting peer
+                    let lease_secs = storage.default_lease_secs;
+                    storage.store_data_for(&from, data, redundancy, lease_secs).await
+                };
+                match stored {
                     Ok(shard_id) => {
                         println!("✅ Stored data successfully: {}", shard_id);
+                        let message = match spread {
+                            Some(data) => match node_guard.place_on_network(&shard_id, data, redundancy).await {
+                                Ok(peers) => format!("Data stored successfully, with copies on {} peers", peers),
+                                Err(e) => format!("Data stored successfully on this node only: {}", e),
+                            },
+                            None => "Data stored successfully".to_string(),
+                        };
                         P2PMessage::StoreResponse {
                             shard_id,
                             success: true,
-                            message: "Data stored successfully".to_string(),
+                            message,
                         }
                     }
                     Err(e) => {
@@ -352,8 +897,16 @@ impl P2PNode {
                 println!("📥 Retrieve request from: {} for shard: {}", from, shard_id);
                 
                 let node_guard = node.lock().await;
-                let storage = node_guard.storage_service.lock().await;
-                match storage.retrieve_data(&shard_id).await {
+                let local = node_guard.storage_service.lock().await.retrieve_data(&shard_id).await;
+                // Clients may be after something placed elsewhere; peers only
+                // ever get what is here, so lookups can't bounce between nodes
+                let found = match local {
+                    Err(_) if !node_guard.is_storage_peer(&from) => {
+                        node_guard.retrieve_data_from_network(&shard_id).await.map_err(|e| anyhow::anyhow!(e.to_string()))
+                    }
+                    found => found,
+                };
+                match found {
                     Ok(data) => {
                         println!("✅ Retrieved data successfully: {} bytes", data.len());
                         P2PMessage::RetrieveResponse {
@@ -373,40 +926,205 @@ impl P2PNode {
                 }
             }
             
-            P2PMessage::ComputeRequest { wasm_bytes, input_data, from } => {
-                println!("⚡ Compute request from: {} ({} bytes WASM, {} bytes input)", from, wasm_bytes.len(), input_data.len());
+            P2PMessage::DeleteRequest { shard_id, from } => {
+                println!("🗑️  Delete request from: {} for shard: {}", from, shard_id);
                 
-                let node_guard = node.lock().await;
-                let mut compute = node_guard.compute_service.lock().await;
-                // Create a mock task for now since execute_wasm doesn't exist
-                let task_id = format!("task_{}", Uuid::new_v4());
-                match compute.execute_task(&task_id).await {
-                    Ok(result) => {
+                let storage = Arc::clone(&node.lock().await.storage_service);
+                let result = storage.lock().await.delete_data_for(&from, &shard_id).await;
+                match result {
+                    Ok(()) => P2PMessage::DeleteResponse { success: true, message: "Lease released".to_string() },
+                    Err(e) => P2PMessage::DeleteResponse { success: false, message: format!("Delete failed: {}", e) },
+                }
+            }
+            
+            P2PMessage::RenewLeaseRequest { shard_id, lease_secs, from } => {
+                println!("⏳ Lease renewal from: {} for shard: {}", from, shard_id);
+                
+                let storage = Arc::clone(&node.lock().await.storage_service);
+                let mut storage = storage.lock().await;
+                // Peers get no longer, nor more permanent, a lease than a store would
+                let lease_secs = match (lease_secs, storage.default_lease_secs) {
+                    (Some(asked), Some(most)) => Some(asked.min(most)),
+                    (None, most) => most,
+                    (asked, None) => asked,
+                };
+                match storage.renew_lease(&shard_id, &from, lease_secs) {
+                    Ok(lease_expires_at) => P2PMessage::RenewLeaseResponse {
+                        lease_expires_at,
+                        success: true,
+                        message: "Lease renewed".to_string(),
+                    },
+                    Err(e) => P2PMessage::RenewLeaseResponse {
+                        lease_expires_at: None,
+                        success: false,
+                        message: format!("Renewal failed: {}", e),
+                    },
+                }
+            }
+            
+            P2PMessage::ComputeRequest { wasm_bytes, input_data, from, hash_only, verification, task_id, profile, task_type, module_hash, mpc } => {
+                println!("⚡ Compute request from: {} ({} bytes WASM, module {:?}, {} bytes input)", from, wasm_bytes.len(), module_hash, input_data.len());
+                
+                // Don't hold the node while the task waits its turn
+                let compute = Arc::clone(&node.lock().await.compute_service);
+                
+                if let Some(config) = mpc {
+                    if task_type != TaskType::MPC {
+                        return Self::compute_failure("An MPC config needs task_type MPC".to_string(), None);
+                    }
+                    return match Self::run_mpc(node, (wasm_bytes, module_hash), input_data, config).await {
+                        Ok(output) => P2PMessage::ComputeResponse {
+                            output_hash: Some(verification::output_hash(&output)),
+                            result: (!hash_only).then_some(output),
+                            success: true,
+                            message: "MPC completed successfully".to_string(),
+                            verification: None,
+                            missing_module: None,
+                        },
+                        Err(e) => {
+                            println!("❌ MPC failed: {}", e);
+                            Self::compute_error(e)
+                        }
+                    };
+                }
+                if let Some(Err(e)) = verification.as_ref().map(|p| p.validate()) {
+                    return Self::compute_failure(format!("Invalid verification policy: {}", e), None);
+                }
+                if let Some(policy) = verification.filter(|p| p.replicas > 1) {
+                    return match Self::fanout_module(&compute, wasm_bytes, module_hash).await {
+                        Ok(module) => Self::coordinate_compute(node, module, input_data, policy).await,
+                        Err(e) => Self::compute_error(e),
+                    };
+                }
+                
+                // Peers pick their own IDs, so keep them apart by sender; replicas
+                // of one task still share an ID since they share a coordinator
+                let task_id = task_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+                let task = ComputeTask {
+                    task_id: format!("{}:{}", from, task_id),
+                    wasm_bytes,
+                    input_data,
+                    task_type,
+                    priority: DEFAULT_PRIORITY,
+                    submitter: from,
+                    profile,
+                    module_hash,
+                };
+                let submitted = compute.lock().await.enqueue(task).await;
+                let outcome = match submitted {
+                    Ok(task_id) => ComputeService::wait_for(&compute, &task_id).await,
+                    Err(e) => Err(e),
+                };
+                match outcome {
+                    Ok(result) if result.success => {
                         println!("✅ Compute completed successfully: task {}", result.task_id);
                         P2PMessage::ComputeResponse {
-                            result: Some(result.output_data),
+                            output_hash: Some(verification::output_hash(&result.output_data)),
+                            result: (!hash_only).then_some(result.output_data),
                             success: true,
                             message: "Compute completed successfully".to_string(),
+                            verification: None,
+                            missing_module: None,
                         }
                     }
+                    Ok(result) => {
+                        let error = result.error_message.unwrap_or_default();
+                        println!("❌ Compute task {} failed: {}", result.task_id, error);
+                        Self::compute_failure(format!("Compute failed: {}", error), None)
+                    }
                     Err(e) => {
                         println!("❌ Compute failed: {}", e);
-                        P2PMessage::ComputeResponse {
-                            result: None,
+                        Self::compute_error(e)
+                    }
+                }
+            }
+            
+            P2PMessage::ModuleUpload { wasm_bytes, from } => {
+                println!("📦 Module upload from: {} ({} bytes)", from, wasm_bytes.len());
+                let compute = Arc::clone(&node.lock().await.compute_service);
+                let registered = compute.lock().await.register_module(wasm_bytes);
+                match registered {
+                    Ok(module_hash) => P2PMessage::ModuleUploadResponse {
+                        module_hash: Some(module_hash),
+                        success: true,
+                        message: "Module registered".to_string(),
+                    },
+                    Err(e) => P2PMessage::ModuleUploadResponse {
+                        module_hash: None,
+                        success: false,
+                        message: format!("Module rejected: {}", e),
+                    },
+                }
+            }
+            
+            P2PMessage::JobRequest { wasm_bytes, inputs, file_id, chunk_size, reducer, from } => {
+                println!("🗂️  Batch job from: {} ({} inputs, file {:?})", from, inputs.len(), file_id);
+                match Self::start_job(node, &from, wasm_bytes, inputs, file_id, chunk_size, reducer).await {
+                    Ok(job_id) => P2PMessage::JobResponse {
+                        job_id: Some(job_id),
+                        success: true,
+                        message: "Job started".to_string(),
+                    },
+                    Err(e) => {
+                        println!("❌ Failed to start job: {}", e);
+                        P2PMessage::JobResponse {
+                            job_id: None,
                             success: false,
-                            message: format!("Compute failed: {}", e),
+                            message: format!("Failed to start job: {}", e),
                         }
                     }
                 }
             }
             
-            P2PMessage::DiscoveryRequest { from } => {
-                println!("🔍 Discovery request from: {}", from);
+            P2PMessage::JobStatusRequest { job_id, from } => {
+                println!("📊 Job status request from: {} for {}", from, job_id);
+                let compute = Arc::clone(&node.lock().await.compute_service);
+                // Jobs are private to whoever started them
+                let status = compute.lock().await.job_status(&job_id, &from);
+                P2PMessage::JobStatusResponse { status }
+            }
+            
+            P2PMessage::StorageChallenge { challenge, from } => {
+                println!("🔎 Storage challenge from: {} for shard: {}", from, challenge.shard_id);
                 
                 let node_guard = node.lock().await;
-                let peers: Vec<PeerInfo> = node_guard.peers.values().cloned().collect();
-                
-                P2PMessage::DiscoveryResponse { nodes: peers }
+                let storage = node_guard.storage_service.lock().await;
+                match storage.prove(&challenge) {
+                    Ok(proof) => P2PMessage::StorageProofResponse {
+                        proof: Some(proof),
+                        success: true,
+                        message: "Proof generated".to_string(),
+                    },
+                    Err(e) => P2PMessage::StorageProofResponse {
+                        proof: None,
+                        success: false,
+                        message: format!("Cannot prove storage: {}", e),
+                    },
+                }
+            }
+            
+            P2PMessage::Dht { message, from, address } => {
+                let (network, auth, own_address) = {
+                    let node = node.lock().await;
+                    (Arc::clone(&node.network_service), node.auth.clone(), node.address.to_string())
+                };
+                let response = {
+                    let mut network = network.lock().await;
+                    let network = &mut *network;
+                    P2PMessage::Dht {
+                        message: dht::respond(&network.routing, &mut network.values, message, now_secs()),
+                        from: auth.node_id().to_string(),
+                        address: own_address,
+                    }
+                };
+                // Anyone who talks to us with an address is a contact worth
+                // keeping, once it turns out they can be reached there. That
+                // can take a while, so it happens after we answer.
+                if !address.is_empty() {
+                    let contact = Contact::new(&from, &address, now_secs());
+                    tokio::spawn(async move { Self::learn_claimed_contact(&network, &auth, contact).await });
+                }
+                response
             }
             
             _ => {
@@ -419,111 +1137,489 @@ impl P2PNode {
         }
     }
     
-    pub async fn store_data_on_network(&self, data: Vec<u8>, redundancy: u8) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // Run the task on several peers: the best-ranked returns the output, the
+    // others only its hash, and the majority decides what the answer is
+    async fn coordinate_compute(
+        node: &Arc<Mutex<P2PNode>>,
+        (module_hash, wasm_bytes): (String, Arc<Vec<u8>>),
+        input_data: Vec<u8>,
+        policy: VerificationPolicy,
+    ) -> P2PMessage {
+        // Hashes can only agree if every replica computes the same bytes
+        if let Err(e) = determinism::check_module(&wasm_bytes) {
+            return Self::compute_failure(e.to_string(), None);
+        }
+        let task_id = Uuid::new_v4().to_string();
+        let (auth, peers, mut chosen) = Self::ranked_compute_peers(node).await;
+        chosen.truncate(policy.replicas);
+        let reputation = Arc::clone(&node.lock().await.compute_reputation);
+        if chosen.len() < policy.quorum {
+            return Self::compute_failure(
+                format!("Only {} peers available, verification needs {}", chosen.len(), policy.quorum),
+                None,
+            );
+        }
+        println!("🧮 Running verified compute on {} peers (quorum {})", chosen.len(), policy.quorum);
+        
+        let requests: Vec<_> = chosen.iter().enumerate()
+            .map(|(i, peer_id)| {
+                let peer = peers[peer_id].clone();
+                let wasm_bytes = Arc::clone(&wasm_bytes);
+                let auth = auth.clone();
+                let message = P2PMessage::ComputeRequest {
+                    wasm_bytes: Vec::new(),
+                    input_data: input_data.clone(),
+                    from: auth.node_id().to_string(),
+                    hash_only: i > 0,
+                    verification: None,
+                    task_id: Some(task_id.clone()),
+                    profile: ExecutionProfile::Deterministic,
+                    task_type: TaskType::WASM,
+                    module_hash: Some(module_hash.clone()),
+                    mpc: None,
+                };
+                (peer_id.clone(), tokio::spawn(async move { Self::send_compute_request(&auth, &peer, &message, &wasm_bytes).await }))
+            })
+            .collect();
+        
+        let mut outcomes = Vec::new();
+        let mut outputs: HashMap<String, Vec<u8>> = HashMap::new();
+        for (peer_id, request) in requests {
+            let response = match request.await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => P2PNode::compute_failure(e.to_string(), None),
+                Err(e) => P2PNode::compute_failure(e.to_string(), None),
+            };
+            outcomes.push(match response {
+                P2PMessage::ComputeResponse { success: true, result: Some(output), .. } => {
+                    // Hash full outputs ourselves rather than trusting the claim
+                    let hash = verification::output_hash(&output);
+                    outputs.insert(hash.clone(), output);
+                    PeerOutcome { peer_id, output_hash: Some(hash), error: None }
+                }
+                P2PMessage::ComputeResponse { success: true, output_hash: Some(hash), .. } => {
+                    PeerOutcome { peer_id, output_hash: Some(hash), error: None }
+                }
+                P2PMessage::ComputeResponse { message, .. } => {
+                    PeerOutcome { peer_id, output_hash: None, error: Some(message) }
+                }
+                _ => PeerOutcome { peer_id, output_hash: None, error: Some("Unexpected response".to_string()) },
+            });
+        }
+        
+        let report = verification::resolve(&policy, &outcomes);
+        reputation.lock().await.record(&report);
+        let agreed_hash = match report.output_hash.clone() {
+            Some(hash) => hash,
+            None => {
+                println!("❌ Verified compute failed: no output reached quorum");
+                return Self::compute_failure("No output reached quorum".to_string(), Some(report));
+            }
+        };
+        for peer_id in report.peers_with(Verdict::Disagreed) {
+            println!("⚠️  Peer {} disagreed with the majority", peer_id);
+        }
+        
+        // The peer asked for the full output may have been outvoted
+        let mut output = outputs.remove(&agreed_hash);
+        if output.is_none() {
+            for peer_id in report.peers_with(Verdict::Agreed) {
+                let message = P2PMessage::ComputeRequest {
+                    wasm_bytes: Vec::new(),
+                    input_data: input_data.clone(),
+                    from: auth.node_id().to_string(),
+                    hash_only: false,
+                    verification: None,
+                    task_id: Some(task_id.clone()),
+                    profile: ExecutionProfile::Deterministic,
+                    task_type: TaskType::WASM,
+                    module_hash: Some(module_hash.clone()),
+                    mpc: None,
+                };
+                if let Ok(P2PMessage::ComputeResponse { result: Some(data), .. }) = Self::send_compute_request(&auth, &peers[&peer_id], &message, &wasm_bytes).await {
+                    if verification::output_hash(&data) == agreed_hash {
+                        output = Some(data);
+                        break;
+                    }
+                }
+            }
+        }
+        
+        match output {
+            Some(output) => {
+                println!("✅ Verified compute agreed by {} peers", report.peers_with(Verdict::Agreed).len());
+                P2PMessage::ComputeResponse {
+                    result: Some(output),
+                    success: true,
+                    message: "Compute verified by majority".to_string(),
+                    output_hash: Some(agreed_hash),
+                    verification: Some(report),
+                    missing_module: None,
+                }
+            }
+            None => Self::compute_failure("Could not fetch the agreed output from any peer".to_string(), Some(report)),
+        }
+    }
+    
+    // Run an MPC computation for this node. The input is split into
+    // additive shares here, so each peer is only ever sent its own share of
+    // it, and the output shares are added back together here too.
+    async fn run_mpc(
+        node: &Arc<Mutex<P2PNode>>,
+        (wasm_bytes, module_hash): (Vec<u8>, Option<String>),
+        input_data: Vec<u8>,
+        config: MpcConfig,
+    ) -> anyhow::Result<Vec<u8>> {
+        config.validate().map_err(anyhow::Error::msg)?;
+        let compute = Arc::clone(&node.lock().await.compute_service);
+        let (module_hash, wasm_bytes) = Self::fanout_module(&compute, wasm_bytes, module_hash).await?;
+        let (auth, peers, mut chosen) = Self::ranked_compute_peers(node).await;
+        chosen.truncate(config.parties);
+        if chosen.len() < config.parties {
+            return Err(anyhow::anyhow!("Only {} peers available, MPC needs {}", chosen.len(), config.parties));
+        }
+        println!("🔐 Running MPC over {} peers for {} rounds", config.parties, config.rounds);
+        
+        let parties: Arc<Vec<Contact>> = Arc::new(chosen.iter().map(|id| peers[id].clone()).collect());
+        let output = mpc::coordinate(&config, &input_data, move |party, _round, party_input| {
+            let peer = parties[party].clone();
+            let wasm_bytes = Arc::clone(&wasm_bytes);
+            let message = Self::peer_compute_request(module_hash.clone(), party_input, auth.node_id(), TaskType::MPC);
+            let auth = auth.clone();
+            async move { Self::compute_output(Self::send_compute_request(&auth, &peer, &message, &wasm_bytes).await) }
+        }).await?;
+        println!("✅ MPC completed: {} byte result", output.len());
+        Ok(output)
+    }
+    
+    // Start a batch job: map tasks go out to peers, or run on this node's
+    // own workers if it has none. Progress is kept in the compute service.
+    async fn start_job(
+        node: &Arc<Mutex<P2PNode>>,
+        submitter: &str,
+        wasm_bytes: Vec<u8>,
+        inputs: Vec<Vec<u8>>,
+        file_id: Option<String>,
+        chunk_size: Option<usize>,
+        reducer: Option<Vec<u8>>,
+    ) -> anyhow::Result<String> {
+        let (storage, compute) = {
+            let node = node.lock().await;
+            (Arc::clone(&node.storage_service), Arc::clone(&node.compute_service))
+        };
+        let mut job = match file_id {
+            Some(file_id) => {
+                // Same rule as xmbl.fetch_shard: only files the submitter leases
+                let storage = storage.lock().await;
+                if !LocalShards::owned_by(&storage, submitter, &file_id) {
+                    return Err(anyhow::anyhow!("{} holds no lease on {}", submitter, file_id));
+                }
+                let data = storage.retrieve_data(&file_id).await?;
+                BatchJob::chunked(wasm_bytes, &data, chunk_size.unwrap_or(batch::DEFAULT_CHUNK_BYTES))?
+            }
+            None => BatchJob::new(wasm_bytes, inputs),
+        };
+        job.reducer = reducer;
+        job.submitter = submitter.to_string();
+        
+        let (auth, peers, ranked) = Self::ranked_compute_peers(node).await;
+        if ranked.is_empty() {
+            return ComputeService::submit_job(&compute, job).await;
+        }
+        let jobs = Arc::clone(&compute.lock().await.jobs);
+        jobs.start(&job)?;
+        let job_id = job.job_id.clone();
+        
+        let workers: Arc<Vec<Contact>> = Arc::new(ranked.iter().map(|id| peers[id].clone()).collect());
+        let run = move |unit: usize, attempt: u32, wasm_bytes: Arc<Vec<u8>>, input_data| {
+            // Each retry goes to the next peer along
+            let peer = workers[(unit + attempt as usize) % workers.len()].clone();
+            let message = Self::peer_compute_request(modules::module_hash(&wasm_bytes), input_data, auth.node_id(), TaskType::Batch);
+            let auth = auth.clone();
+            async move { Self::compute_output(Self::send_compute_request(&auth, &peer, &message, &wasm_bytes).await) }
+        };
+        tokio::spawn(batch::run_job(job, jobs, run));
+        
+        Ok(job_id)
+    }
+    
+    // A single task for a peer to run as-is
+    fn peer_compute_request(module_hash: String, input_data: Vec<u8>, from: &str, task_type: TaskType) -> P2PMessage {
+        P2PMessage::ComputeRequest {
+            wasm_bytes: Vec::new(),
+            input_data,
+            from: from.to_string(),
+            hash_only: false,
+            verification: None,
+            task_id: None,
+            profile: ExecutionProfile::Standard,
+            task_type,
+            module_hash: Some(module_hash),
+            mpc: None,
+        }
+    }
+    
+    // Send a request that names its module by hash, uploading the module
+    // first if the peer has never seen it, all on one connection
+    async fn send_compute_request(auth: &MessageAuth, peer: &Contact, message: &P2PMessage, wasm_bytes: &[u8]) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let (mut stream, reused) = Self::checkout(auth, peer).await?;
+            match Self::compute_exchange(auth, &mut stream, message, wasm_bytes).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    auth.connections.remove(&peer.node_id, &stream);
+                    if !reused {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+    
+    async fn compute_exchange(auth: &MessageAuth, stream: &mut SecureStream, message: &P2PMessage, wasm_bytes: &[u8]) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        let response = Self::exchange(auth, stream, message).await?;
+        let unknown_module = matches!(&response, P2PMessage::ComputeResponse { missing_module: Some(_), .. });
+        let from = match message {
+            P2PMessage::ComputeRequest { from, .. } if unknown_module => from.clone(),
+            _ => return Ok(response),
+        };
+        
+        let upload = P2PMessage::ModuleUpload { wasm_bytes: wasm_bytes.to_vec(), from };
+        match Self::exchange(auth, stream, &upload).await? {
+            P2PMessage::ModuleUploadResponse { success: true, .. } => Self::exchange(auth, stream, message).await,
+            P2PMessage::ModuleUploadResponse { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response to module upload".into()),
+        }
+    }
+    
+    // The module a coordinated run sends to peers by hash, kept in our own
+    // registry so it can be uploaded to peers that lack it
+    async fn fanout_module(compute: &Arc<Mutex<ComputeService>>, wasm_bytes: Vec<u8>, module_hash: Option<String>) -> anyhow::Result<(String, Arc<Vec<u8>>)> {
+        let compute = compute.lock().await;
+        let hash = match module_hash {
+            Some(hash) => hash,
+            None => compute.register_module(wasm_bytes)?,
+        };
+        let wasm_bytes = compute.modules.bytes(&hash)
+            .ok_or_else(|| modules::UnknownModule(hash.clone()))?;
+        Ok((hash, wasm_bytes))
+    }
+    
+    fn compute_output(response: Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>>) -> anyhow::Result<Vec<u8>> {
+        match response {
+            Ok(P2PMessage::ComputeResponse { success: true, result: Some(output), .. }) => Ok(output),
+            Ok(P2PMessage::ComputeResponse { message, .. }) => Err(anyhow::anyhow!(message)),
+            Ok(_) => Err(anyhow::anyhow!("Unexpected response")),
+            Err(e) => Err(anyhow::anyhow!(e.to_string())),
+        }
+    }
+    
+    // Peers other than this node, most trusted compute peers first
+    async fn ranked_compute_peers(node: &Arc<Mutex<P2PNode>>) -> (MessageAuth, HashMap<String, Contact>, Vec<String>) {
+        let (auth, network, reputation) = {
+            let node = node.lock().await;
+            (node.auth.clone(), Arc::clone(&node.network_service), Arc::clone(&node.compute_reputation))
+        };
+        let peers = Self::known_peers(&network).await;
+        // Peers whose hello didn't offer compute are left out; ones we haven't
+        // shaken hands with yet get the benefit of the doubt
+        let candidates: Vec<String> = peers.keys()
+            .filter(|id| *id != auth.node_id())
+            .filter(|id| auth.peer(id).is_none_or(|peer| peer.supports(Feature::Compute)))
+            .cloned()
+            .collect();
+        let ranked = reputation.lock().await.rank(&candidates);
+        (auth, peers, ranked)
+    }
+    
+    fn compute_failure(message: String, report: Option<VerificationReport>) -> P2PMessage {
+        P2PMessage::ComputeResponse {
+            result: None,
+            success: false,
+            message,
+            output_hash: None,
+            verification: report,
+            missing_module: None,
+        }
+    }
+    
+    // A failed task, flagging an unknown module so the sender can upload it
+    fn compute_error(e: anyhow::Error) -> P2PMessage {
+        P2PMessage::ComputeResponse {
+            result: None,
+            success: false,
+            message: format!("Compute failed: {}", e),
+            output_hash: None,
+            verification: None,
+            missing_module: e.downcast_ref::<modules::UnknownModule>().map(|unknown| unknown.0.clone()),
+        }
+    }
+    
+    // Give whole copies to up to `redundancy` peers; returns the peers that
+    // took one
+    pub async fn store_data_on_network(&self, data: Vec<u8>, redundancy: u8) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
         println!("🌐 Storing data on P2P network with {}x redundancy...", redundancy);
         
-        let mut shard_ids = Vec::new();
-        let mut successful_stores = 0;
+        let mut holders = Vec::new();
         
         // Try to store on multiple peers
-        for (peer_id, peer_info) in &self.peers {
-            if successful_stores >= redundancy as usize {
+        for (peer_id, peer_info) in &Self::known_peers(&self.network_service).await {
+            if holders.len() >= redundancy as usize {
                 break;
             }
+            if peer_id == &self.node_id {
+                continue;
+            }
             
             println!("📤 Sending to peer: {} at {}", peer_id, peer_info.address);
             
-            // In a real implementation, this would establish a TCP connection
-            // For now, we'll simulate the storage
-            match TcpStream::connect(&peer_info.address).await {
-                Ok(mut stream) => {
-                    let message = P2PMessage::StoreRequest {
-                        data: data.clone(),
-                        redundancy: 1, // Each peer gets 1x redundancy
-                        from: self.node_id.clone(),
-                    };
-                    
-                    let message_data = serde_json::to_vec(&message)?;
-                    stream.write_all(&message_data).await?;
-                    
-                    // Read response
-                    let mut buffer = [0; 1024];
-                    match stream.read(&mut buffer).await {
-                        Ok(n) if n > 0 => {
-                            let response_data = &buffer[..n];
-                            if let Ok(response) = serde_json::from_slice::<P2PMessage>(response_data) {
-                                if let P2PMessage::StoreResponse { shard_id, success, message } = response {
-                                    if success {
-                                        shard_ids.push(shard_id);
-                                        successful_stores += 1;
-                                        println!("✅ Stored on peer {}: {}", peer_id, message);
-                                    } else {
-                                        println!("❌ Failed on peer {}: {}", peer_id, message);
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            println!("❌ No response from peer {}", peer_id);
-                        }
+            let message = P2PMessage::StoreRequest {
+                data: data.clone(),
+                redundancy: 1, // Each peer gets 1x redundancy
+                from: self.node_id.clone(),
+            };
+            
+            match Self::send_request(&self.auth, peer_info, &message).await {
+                Ok(P2PMessage::StoreResponse { shard_id, success, message }) => {
+                    if success {
+                        // Remember what this peer promised to hold so it can be audited
+                        let commitment = ShardCommitment::from_data(&shard_id, &data);
+                        self.proof_verifier.lock().await.register(commitment, peer_id);
+                        let mut repair = self.repair_manager.lock().await;
+                        repair.track(&shard_id, redundancy);
+                        repair.record_replica(&shard_id, peer_id);
+                        holders.push(peer_id.clone());
+                        println!("✅ Stored on peer {}: {}", peer_id, message);
+                    } else {
+                        println!("❌ Failed on peer {}: {}", peer_id, message);
                     }
                 }
+                Ok(_) => {
+                    println!("❌ Unexpected response from peer {}", peer_id);
+                }
                 Err(e) => {
-                    println!("❌ Failed to connect to peer {}: {}", peer_id, e);
+                    println!("❌ Failed to store on peer {}: {}", peer_id, e);
                 }
             }
         }
         
-        if successful_stores > 0 {
-            println!("✅ Successfully stored data on {} peers", successful_stores);
-            Ok(shard_ids)
+        if !holders.is_empty() {
+            println!("✅ Successfully stored data on {} peers", holders.len());
+            Ok(holders)
         } else {
             Err("Failed to store data on any peers".into())
         }
     }
     
-    pub async fn retrieve_data_from_network(&self, shard_id: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // Erasure-code the data and give each fragment to a different peer. The
+    // manifest is handed to repair, which rebuilds fragments whose holders
+    // go missing.
+    pub async fn store_encoded_on_network(&self, data: Vec<u8>, redundancy: u8) -> Result<ErasureManifest, Box<dyn std::error::Error + Send + Sync>> {
+        let (mut manifest, fragments) = ErasureCoder::new(ErasureConfig::from_redundancy(redundancy)?)?.encode(&data)?;
+        let peers: Vec<(String, Contact)> = Self::known_peers(&self.network_service).await.into_iter()
+            .filter(|(peer_id, _)| peer_id != &self.node_id)
+            .collect();
+        if peers.len() < manifest.config.total_shards {
+            return Err(format!("Need a separate peer for each of {} fragments, only {} known",
+                manifest.config.total_shards, peers.len()).into());
+        }
+        
+        for (fragment, (peer_id, peer_info)) in fragments.into_iter().zip(&peers) {
+            match Self::push_shard(peer_info, fragment.data.clone(), &self.auth).await {
+                Ok(shard_id) => {
+                    let commitment = ShardCommitment::from_data(&shard_id, &fragment.data);
+                    self.proof_verifier.lock().await.register(commitment, peer_id);
+                    manifest.place(fragment.index, peer_id.clone())?;
+                    println!("✅ Stored fragment {} on peer {}", fragment.index, peer_id);
+                }
+                Err(e) => println!("❌ Failed to store fragment {} on peer {}: {}", fragment.index, peer_id, e),
+            }
+        }
+        
+        let placed = manifest.fragments.iter().filter(|f| f.node_id.is_some()).count();
+        if placed < manifest.config.data_shards {
+            return Err(format!("Only {} of {} fragments stored, need at least {}",
+                placed, manifest.config.total_shards, manifest.config.data_shards).into());
+        }
+        // Whatever didn't make it shows up as missing and gets rebuilt
+        self.repair_manager.lock().await.track_erasure(manifest.clone());
+        Ok(manifest)
+    }
+    
+    // Other nodes say they offer storage when they shake hands; clients and
+    // relays don't
+    fn is_storage_peer(&self, node_id: &str) -> bool {
+        self.auth.peer(node_id).is_some_and(|peer| peer.supports(Feature::Storage))
+    }
+    
+    // Spread a client's upload over the swarm: erasure-coded when there are
+    // enough peers for a fragment each, otherwise as whole copies. Either
+    // way the placement is audited, repaired and its leases renewed from
+    // here, and published in the DHT so any node can find it again. Returns
+    // how many peers took part.
+    pub async fn place_on_network(&self, shard_id: &str, data: Vec<u8>, redundancy: u8) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let peers = Self::known_peers(&self.network_service).await.len();
+        let needed = ErasureConfig::from_redundancy(redundancy)?.total_shards;
+        let placement = if peers >= needed {
+            let manifest = self.store_encoded_on_network(data, redundancy).await?;
+            NetworkPlacement::Encoded { manifest }
+        } else {
+            // Our own copy is one of the replicas
+            let holders = self.store_data_on_network(data, redundancy.saturating_sub(1).max(1)).await?;
+            NetworkPlacement::Replicas { holders }
+        };
+        let holders = match &placement {
+            NetworkPlacement::Encoded { manifest } => manifest.fragments.iter().filter(|f| f.node_id.is_some()).count(),
+            NetworkPlacement::Replicas { holders } => holders.len(),
+        };
+        if let Err(e) = self.dht_store(shard_id, serde_json::to_vec(&placement)?).await {
+            println!("⚠️  Could not publish where {} is stored: {}", shard_id, e);
+        }
+        Ok(holders)
+    }
+    
+    pub async fn retrieve_data_from_network(&self, shard_id: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
         println!("🌐 Retrieving data from P2P network: {}", shard_id);
         
-        // Try to retrieve from peers
-        for (peer_id, peer_info) in &self.peers {
-            println!("📥 Requesting from peer: {} at {}", peer_id, peer_info.address);
-            
-            match TcpStream::connect(&peer_info.address).await {
-                Ok(mut stream) => {
-                    let message = P2PMessage::RetrieveRequest {
-                        shard_id: shard_id.to_string(),
-                        from: self.node_id.clone(),
+        let peers = Self::known_peers(&self.network_service).await;
+        
+        // Go straight to the holders when the DHT knows where it went
+        let placement = self.dht_find_value(shard_id).await
+            .and_then(|value| serde_json::from_slice::<NetworkPlacement>(&value).ok());
+        match placement {
+            Some(NetworkPlacement::Encoded { manifest }) => {
+                let mut available = Vec::new();
+                for fragment in &manifest.fragments {
+                    let holder = fragment.node_id.as_ref().and_then(|n| peers.get(n));
+                    let data = match holder {
+                        Some(peer) => Self::fetch_shard(peer, &fragment.cid, &self.auth).await.ok(),
+                        None => None,
                     };
-                    
-                    let message_data = serde_json::to_vec(&message)?;
-                    stream.write_all(&message_data).await?;
-                    
-                    // Read response
-                    let mut buffer = [0; 4096];
-                    match stream.read(&mut buffer).await {
-                        Ok(n) if n > 0 => {
-                            let response_data = &buffer[..n];
-                            if let Ok(response) = serde_json::from_slice::<P2PMessage>(response_data) {
-                                if let P2PMessage::RetrieveResponse { data, success, message } = response {
-                                    if success {
-                                        if let Some(retrieved_data) = data {
-                                            println!("✅ Retrieved data from peer {}: {} bytes", peer_id, retrieved_data.len());
-                                            return Ok(retrieved_data);
-                                        }
-                                    } else {
-                                        println!("❌ Failed from peer {}: {}", peer_id, message);
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            println!("❌ No response from peer {}", peer_id);
-                        }
+                    available.push(data);
+                }
+                return Ok(manifest.reconstruct(available)?);
+            }
+            Some(NetworkPlacement::Replicas { holders }) => {
+                for peer in holders.iter().filter_map(|h| peers.get(h)) {
+                    if let Ok(data) = Self::fetch_shard(peer, shard_id, &self.auth).await {
+                        return Ok(data);
                     }
                 }
+            }
+            None => {}
+        }
+        
+        // Otherwise ask around
+        for (peer_id, peer_info) in &peers {
+            println!("📥 Requesting from peer: {} at {}", peer_id, peer_info.address);
+            
+            match Self::fetch_shard(peer_info, shard_id, &self.auth).await {
+                Ok(retrieved_data) => {
+                    println!("✅ Retrieved data from peer {}: {} bytes", peer_id, retrieved_data.len());
+                    return Ok(retrieved_data);
+                }
                 Err(e) => {
-                    println!("❌ Failed to connect to peer {}: {}", peer_id, e);
+                    println!("❌ Failed from peer {}: {}", peer_id, e);
                 }
             }
         }
@@ -532,13 +1628,59 @@ impl P2PNode {
     }
 }
 
+// Where place_on_network put a file, published in the DHT under its ID
+#[derive(Serialize, Deserialize)]
+enum NetworkPlacement {
+    Replicas { holders: Vec<String> },
+    Encoded { manifest: ErasureManifest },
+}
+
+// Lets compute tasks read shards held by this node through xmbl.fetch_shard,
+// as long as whoever submitted the task holds a lease on them. Tasks run on
+// blocking threads, so block on the storage lock directly.
+#[derive(Debug)]
+struct LocalShards(Arc<Mutex<StorageService>>);
+
+impl LocalShards {
+    fn owned_by(storage: &StorageService, submitter: &str, shard_id: &str) -> bool {
+        storage.get_shard_info(shard_id)
+            .is_some_and(|meta| !meta.internal && meta.owners().iter().any(|owner| owner == submitter))
+    }
+}
+
+impl ShardSource for LocalShards {
+    fn shard_size(&self, submitter: &str, shard_id: &str) -> Option<u64> {
+        let storage = self.0.blocking_lock();
+        if !Self::owned_by(&storage, submitter, shard_id) {
+            return None;
+        }
+        storage.logical_size(shard_id).ok()
+    }
+    
+    fn fetch_shard(&self, submitter: &str, shard_id: &str) -> Option<Vec<u8>> {
+        let storage = self.0.blocking_lock();
+        if !Self::owned_by(&storage, submitter, shard_id) {
+            return None;
+        }
+        tokio::runtime::Handle::current().block_on(storage.retrieve_data(shard_id)).ok()
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
     
-    let node_id = args.get(1)
+    // Names the default data directory; the node ID comes from its identity key
+    let node_name = args.get(1)
         .cloned()
-        .unwrap_or_else(|| format!("node_{}", Uuid::new_v4().to_string()[..8].to_string()));
+        .unwrap_or_else(|| format!("node_{}", &Uuid::new_v4().to_string()[..8]));
     
     let port = args.get(2)
         .and_then(|p| p.parse::<u16>().ok())
@@ -548,19 +1690,187 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .and_then(|s| s.parse::<f64>().ok())
         .unwrap_or(100.0);
     
+    let data_dir = args.get(4)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("data").join(&node_name));
+    
+    let identity = NodeIdentity::load_or_create(data_dir.join(IDENTITY_FILE))?;
+    
+    // Comma-separated seed addresses, otherwise XMBL_SEEDS or the default seed
+    let seeds = args.get(5)
+        .map(|s| dht::parse_seeds(s))
+        .unwrap_or_else(dht::seeds_from_env);
+    
     let address: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
     
     println!("🚀 XMBL P2P Node Starting...");
     println!("=================================");
-    println!("Node ID: {}", node_id);
+    println!("Node: {}", node_name);
+    println!("Node ID: {}", identity.node_id);
     println!("Address: {}", address);
     println!("Storage: {}GB", storage_gb);
-    println!("");
+    println!("Data Dir: {}", data_dir.display());
+    println!();
     
-    let mut node = P2PNode::new(node_id, address, storage_gb);
+    let mut node = P2PNode::new(identity, address, storage_gb, data_dir)?;
+    node.network_service.lock().await.seeds = seeds;
     
     // Start the node
     node.start().await?;
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // A node answering on its own port with its compute workers running, but
+    // without bootstrapping or the other background loops; returns a handle
+    // sharing its services
+    async fn spawn_node() -> (Arc<Mutex<P2PNode>>, Contact) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let data_dir = std::env::temp_dir().join(format!("xmbl_node_{}", Uuid::new_v4()));
+        let mut node = P2PNode::new(NodeIdentity::new(), address, 1.0, data_dir).unwrap();
+        ComputeService::spawn_workers(&node.compute_service).await;
+        let handle = node.clone_for_connection();
+        let contact = Contact::new(&node.node_id, &address.to_string(), now_secs());
+        tokio::spawn(async move {
+            let _ = node.listen_for_connections().await;
+        });
+        while tokio::net::TcpStream::connect(address).await.is_err() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        (handle, contact)
+    }
+    
+    fn client() -> MessageAuth {
+        let identity = NodeIdentity::new();
+        let hello = Hello::new(&identity.node_id, Vec::new(), None, Vec::new());
+        MessageAuth::new(identity, hello)
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_requests_reuse_one_connection_per_peer() {
+        let (_node, contact) = spawn_node().await;
+        let client = client();
+        let ping = || P2PMessage::Ping { from: client.node_id().to_string(), timestamp: now_secs() };
+        P2PNode::send_request(&client, &contact, &ping()).await.unwrap();
+        assert!(P2PNode::send_request(&client, &contact, &ping()).await.is_ok());
+        assert_eq!(client.connections.len(), 1);
+        
+        // While the pooled connection is busy, a request gets its own
+        let busy = client.connections.idle(&contact.node_id).expect("connection kept open");
+        assert!(P2PNode::send_request(&client, &contact, &ping()).await.is_ok());
+        assert_eq!(client.connections.len(), 1);
+        drop(busy);
+        
+        // Once free, the pooled connection carries the next request again
+        let (mut stream, reused) = P2PNode::checkout(&client, &contact).await.unwrap();
+        assert!(reused);
+        assert!(P2PNode::exchange(&client, &mut stream, &ping()).await.is_ok());
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_uploads_are_placed_audited_and_found_again() {
+        let (coordinator, coordinator_contact) = spawn_node().await;
+        let (other, other_contact) = spawn_node().await;
+        let mut holders = Vec::new();
+        for _ in 0..2 {
+            let (_, peer) = spawn_node().await;
+            coordinator.lock().await.network_service.lock().await.routing.insert(peer.clone());
+            other.lock().await.network_service.lock().await.routing.insert(peer.clone());
+            holders.push(peer.node_id);
+        }
+        
+        let client = client();
+        let data = b"spread me over the swarm".to_vec();
+        let store = P2PMessage::StoreRequest { data: data.clone(), redundancy: 3, from: client.node_id().to_string() };
+        let shard_id = match P2PNode::send_request(&client, &coordinator_contact, &store).await.unwrap() {
+            P2PMessage::StoreResponse { shard_id, success: true, message } => {
+                assert!(message.contains("copies on 2 peers"), "{}", message);
+                shard_id
+            }
+            other => panic!("unexpected response: {:?}", other),
+        };
+        
+        // Both copies are audited and kept at redundancy by the node that placed them
+        let coordinator = coordinator.lock().await;
+        for holder in &holders {
+            assert_eq!(coordinator.proof_verifier.lock().await.shards_held_by(holder), vec![shard_id.clone()]);
+        }
+        let mut placed = coordinator.repair_manager.lock().await.placement(&shard_id).unwrap().holders.clone();
+        placed.sort();
+        holders.sort();
+        assert_eq!(placed, holders);
+        
+        // A node that never saw the upload finds it through the DHT
+        assert!(other.lock().await.dht_find_value(&shard_id).await.is_some());
+        let retrieve = P2PMessage::RetrieveRequest { shard_id, from: client.node_id().to_string() };
+        match P2PNode::send_request(&client, &other_contact, &retrieve).await.unwrap() {
+            P2PMessage::RetrieveResponse { data: found, success: true, .. } => assert_eq!(found, Some(data)),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mpc_request_runs_across_peers() {
+        // Triples every word of the share; party 0 also adds 1
+        const AFFINE_WAT: &str = r#"
+            (module
+              (import "xmbl" "input_len" (func $input_len (result i32)))
+              (import "xmbl" "read_input" (func $read_input (param i32 i32) (result i32)))
+              (import "xmbl" "write_output" (func $write_output (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "run") (result i32)
+                (local $len i32) (local $i i32)
+                (local.set $len (call $read_input (i32.const 0) (call $input_len)))
+                (local.set $i (i32.const 12))
+                (block $done
+                  (loop $words
+                    (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                    (i32.store (local.get $i) (i32.mul (i32.load (local.get $i)) (i32.const 3)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 4)))
+                    (br $words)))
+                (if (i32.and (i32.eqz (i32.load (i32.const 0))) (i32.gt_u (local.get $len) (i32.const 12)))
+                  (then (i32.store (i32.const 12) (i32.add (i32.load (i32.const 12)) (i32.const 1)))))
+                (call $write_output (i32.const 12) (i32.sub (local.get $len) (i32.const 12)))
+                (i32.const 0)))
+        "#;
+        
+        let (coordinator, coordinator_contact) = spawn_node().await;
+        for _ in 0..2 {
+            let (_, party) = spawn_node().await;
+            coordinator.lock().await.network_service.lock().await.routing.insert(party);
+        }
+        
+        let client = client();
+        let input: Vec<u8> = [5u32, 100, u32::MAX].iter().flat_map(|w| w.to_le_bytes()).collect();
+        let request = |parties| P2PMessage::ComputeRequest {
+            wasm_bytes: wat::parse_str(AFFINE_WAT).unwrap(),
+            input_data: input.clone(),
+            from: client.node_id().to_string(),
+            hash_only: false,
+            verification: None,
+            task_id: None,
+            profile: ExecutionProfile::Standard,
+            task_type: TaskType::MPC,
+            module_hash: None,
+            mpc: Some(MpcConfig { parties, rounds: 2 }),
+        };
+        
+        let response = P2PNode::send_request(&client, &coordinator_contact, &request(2)).await.unwrap();
+        let expected: Vec<u8> = [49u32, 900, u32::MAX.wrapping_mul(9)].iter().flat_map(|w| w.to_le_bytes()).collect();
+        match response {
+            P2PMessage::ComputeResponse { result, success: true, .. } => assert_eq!(result, Some(expected)),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        
+        // There are only two parties to be had
+        match P2PNode::send_request(&client, &coordinator_contact, &request(3)).await.unwrap() {
+            P2PMessage::ComputeResponse { success, message, .. } => assert!(!success && message.contains("MPC needs 3"), "{}", message),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+}
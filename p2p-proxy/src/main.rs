@@ -1,14 +1,25 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::RwLock;
 use futures_util::{SinkExt, StreamExt};
 use tokio_tungstenite::{accept_async, WebSocketStream};
 use tokio_tungstenite::tungstenite::Message;
 
+// Import our actual Rust crates
+use xmbl_storage::{ErasureCoder, ErasureConfig, ErasureManifest};
+use xmbl_network::{dht, handshake, ConnectionPool, PooledStream, SecureStream};
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_protocol::{self as protocol, Feature, Hello, P2PMessage};
+
+// Under XMBL_DATA_DIR
+const IDENTITY_FILE: &str = "identity.key";
+
+// What browser clients send over the WebSocket. Nodes are spoken to in
+// P2PMessage; this is only the client-facing JSON.
 #[derive(Debug, Serialize, Deserialize)]
-struct P2PMessage {
+struct ClientRequest {
     message_type: String,
     data: Option<Vec<u8>>,
     redundancy: Option<u32>,
@@ -20,7 +31,7 @@ struct P2PMessage {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct P2PResponse {
+struct ClientResponse {
     success: bool,
     message: String,
     data: Option<Vec<u8>>,
@@ -29,130 +40,253 @@ struct P2PResponse {
 }
 
 struct P2PProxy {
-    nodes: HashMap<String, String>, // node_id -> address
+    nodes: RwLock<HashMap<String, String>>, // node_id -> address
+    seeds: Vec<String>,
+    // Fragments are leased to this identity, so it has to survive restarts
+    // for the proxy to renew or delete what it stored
+    identity: Arc<NodeIdentity>,
+    replay: std::sync::Mutex<ReplayGuard>,
+    // One open connection per node, reused across client requests
+    connections: ConnectionPool,
 }
 
 impl P2PProxy {
-    fn new() -> Self {
-        let mut nodes = HashMap::new();
-        nodes.insert("node_001".to_string(), "127.0.0.1:3001".to_string());
-        nodes.insert("node_002".to_string(), "127.0.0.1:3002".to_string());
-        nodes.insert("node_003".to_string(), "127.0.0.1:3003".to_string());
-        nodes.insert("node_004".to_string(), "127.0.0.1:3004".to_string());
-        
-        P2PProxy { nodes }
+    fn new(identity: NodeIdentity, seeds: Vec<String>) -> Self {
+        P2PProxy {
+            nodes: RwLock::new(HashMap::new()),
+            seeds,
+            identity: Arc::new(identity),
+            replay: std::sync::Mutex::new(ReplayGuard::default()),
+            connections: ConnectionPool::new(),
+        }
+    }
+    
+    // Replace the node list with whatever the DHT currently knows
+    async fn refresh_nodes(&self) -> usize {
+        let lookup = dht::discover(Arc::clone(&self.identity), &self.seeds).await;
+        let nodes: HashMap<String, String> = lookup.closest.into_iter()
+            .map(|c| (c.node_id, c.address))
+            .collect();
+        let count = nodes.len();
+        *self.nodes.write().await = nodes;
+        count
+    }
+    
+    // Forward on the open connection to the node if it is idle, retrying
+    // once on a fresh connection if the pooled one has gone stale
+    async fn forward_to_node(&self, node_id: &str, message: &P2PMessage) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let (mut stream, reused) = match self.connections.idle(node_id) {
+                Some(stream) => (stream, true),
+                None => (self.connections.insert(node_id, self.connect(node_id).await?), false),
+            };
+            match self.exchange(&mut stream, message).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    self.connections.remove(node_id, &stream);
+                    if !reused {
+                        return Err(e);
+                    }
+                }
+            }
+        }
     }
     
-    async fn forward_to_node(&self, node_id: &str, message: &P2PMessage) -> Result<P2PResponse, Box<dyn std::error::Error + Send + Sync>> {
-        let node_address = self.nodes.get(node_id)
+    async fn connect(&self, node_id: &str) -> Result<SecureStream, Box<dyn std::error::Error + Send + Sync>> {
+        let node_address = self.nodes.read().await.get(node_id)
+            .cloned()
             .ok_or("Node not found")?;
         
-        let mut stream = TcpStream::connect(node_address).await?;
-        
-        // Serialize and send the message
-        let message_json = serde_json::to_string(message)?;
-        stream.write_all(message_json.as_bytes()).await?;
+        let mut stream = SecureStream::connect(&node_address, &self.identity).await?;
+        if stream.remote_node_id() != node_id {
+            return Err(format!("{} answered as {}", node_address, stream.remote_node_id()).into());
+        }
+        let hello = Hello::new(&self.identity.node_id, vec![Feature::Relay], None, Vec::new());
+        handshake::greet(&mut stream, &self.identity, &hello, &self.replay).await?;
+        Ok(stream)
+    }
+    
+    async fn exchange(&self, stream: &mut PooledStream, message: &P2PMessage) -> Result<P2PMessage, Box<dyn std::error::Error + Send + Sync>> {
+        let node_id = stream.remote_node_id().to_string();
+        stream.write_json(&protocol::seal(&self.identity, &node_id, message, now_secs())?).await?;
+        let envelope: SignedEnvelope = stream.read_json().await?
+            .ok_or("Node closed the connection without answering")?;
+        let (sender, response) = protocol::open(&envelope, &self.identity.node_id, &mut self.replay.lock().unwrap(), now_secs())?;
+        if sender != node_id {
+            return Err(format!("Response from {} was signed by {}", node_id, sender).into());
+        }
+        Ok(response)
+    }
+    
+    // Erasure-code the file and spread its fragments across nodes instead of
+    // sending the whole blob to each of them. Every fragment goes to a
+    // different node, so losing one node never costs more than one fragment.
+    async fn distribute_storage(&self, data: &[u8], redundancy: u32) -> Result<ErasureManifest, Box<dyn std::error::Error + Send + Sync>> {
+        let config = ErasureConfig::from_redundancy(redundancy.min(u8::MAX as u32) as u8)?;
+        let (mut manifest, fragments) = ErasureCoder::new(config)?.encode(data)?;
         
-        // Read response
-        let mut buffer = Vec::new();
-        let mut temp_buffer = [0; 1024];
+        let mut nodes: Vec<String> = self.nodes.read().await.keys().cloned().collect();
+        if nodes.len() < manifest.config.total_shards {
+            return Err(format!("Need a separate node for each of {} fragments, only {} known",
+                manifest.config.total_shards, nodes.len()).into());
+        }
+        nodes.sort();
         
-        loop {
-            match stream.read(&mut temp_buffer).await {
-                Ok(0) => break, // EOF
-                Ok(n) => buffer.extend_from_slice(&temp_buffer[..n]),
-                Err(_) => break,
+        // Nodes that failed to take a fragment are skipped for the rest
+        let mut candidates = nodes.into_iter();
+        for fragment in fragments {
+            let mut placed = false;
+            for node_id in candidates.by_ref() {
+                let message = P2PMessage::StoreRequest {
+                    data: fragment.data.clone(),
+                    redundancy: 1,
+                    from: self.identity.node_id.clone(),
+                };
+                
+                match self.forward_to_node(&node_id, &message).await {
+                    Ok(P2PMessage::StoreResponse { success: true, .. }) => {
+                        manifest.place(fragment.index, node_id.clone())?;
+                        println!("✅ Stored fragment {} ({}) on node {}", fragment.index, fragment.cid, node_id);
+                        placed = true;
+                        break;
+                    }
+                    Ok(P2PMessage::StoreResponse { message, .. }) => println!("❌ Node {} refused fragment {}: {}", node_id, fragment.index, message),
+                    Ok(_) => println!("❌ Node {} gave an unexpected answer for fragment {}", node_id, fragment.index),
+                    Err(e) => println!("❌ Could not reach node {} for fragment {}: {}", node_id, fragment.index, e),
+                }
+            }
+            
+            if !placed {
+                self.remove_fragments(&manifest).await;
+                return Err(format!("Ran out of nodes to hold fragment {} of {}",
+                    fragment.index, manifest.config.total_shards).into());
             }
         }
         
-        // Try to deserialize response
-        if let Ok(response) = serde_json::from_slice::<P2PResponse>(&buffer) {
-            Ok(response)
-        } else {
-            // Fallback response
-            Ok(P2PResponse {
-                success: true,
-                message: "Message forwarded to node".to_string(),
-                data: None,
-                shard_id: None,
-                result: None,
-            })
+        Ok(manifest)
+    }
+    
+    // Undo a partial store so no node keeps holding orphaned fragments
+    async fn remove_fragments(&self, manifest: &ErasureManifest) {
+        for fragment in &manifest.fragments {
+            let Some(node_id) = &fragment.node_id else { continue };
+            let message = P2PMessage::DeleteRequest {
+                shard_id: fragment.cid.clone(),
+                from: self.identity.node_id.clone(),
+            };
+            match self.forward_to_node(node_id, &message).await {
+                Ok(P2PMessage::DeleteResponse { success: true, .. }) => println!("🗑️  Removed fragment {} from node {}", fragment.index, node_id),
+                Ok(P2PMessage::DeleteResponse { message, .. }) => println!("⚠️  Node {} kept fragment {}: {}", node_id, fragment.index, message),
+                Ok(_) => println!("⚠️  Node {} gave an unexpected answer removing fragment {}", node_id, fragment.index),
+                Err(e) => println!("⚠️  Could not reach node {} to remove fragment {}: {}", node_id, fragment.index, e),
+            }
         }
     }
     
-    async fn distribute_storage(&self, data: &[u8], redundancy: u32) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut shard_ids = Vec::new();
-        let nodes: Vec<&String> = self.nodes.keys().collect();
-        
-        for i in 0..redundancy {
-            let node_id = nodes[i as usize % nodes.len()];
-            let shard_id = format!("shard_{}_{}_{}", 
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                i,
-                node_id
-            );
-            
-            let message = P2PMessage {
-                message_type: "StoreRequest".to_string(),
-                data: Some(data.to_vec()),
-                redundancy: Some(redundancy),
-                from: "p2p_client".to_string(),
-                shard_id: Some(shard_id.clone()),
-                wasm_bytes: None,
-                input_data: None,
-                timestamp: Some(std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()),
+    // Fragments are held under leases the proxy owns, which lapse unless the
+    // client asks for them to be renewed. Each holder renews for as long as
+    // it would lease a new store; returns how many fragments it reached.
+    async fn renew_fragments(&self, manifest: &ErasureManifest) -> usize {
+        let mut renewed = 0;
+        for fragment in &manifest.fragments {
+            let Some(node_id) = &fragment.node_id else { continue };
+            let message = P2PMessage::RenewLeaseRequest {
+                shard_id: fragment.cid.clone(),
+                lease_secs: None,
+                from: self.identity.node_id.clone(),
             };
-            
-            if let Ok(_) = self.forward_to_node(node_id, &message).await {
-                shard_ids.push(shard_id.clone());
-                println!("✅ Stored shard {} on node {}", shard_id, node_id);
+            match self.forward_to_node(node_id, &message).await {
+                Ok(P2PMessage::RenewLeaseResponse { success: true, .. }) => renewed += 1,
+                Ok(P2PMessage::RenewLeaseResponse { message, .. }) => println!("⚠️  Node {} would not renew fragment {}: {}", node_id, fragment.index, message),
+                Ok(_) => println!("⚠️  Node {} gave an unexpected answer renewing fragment {}", node_id, fragment.index),
+                Err(e) => println!("⚠️  Could not reach node {} to renew fragment {}: {}", node_id, fragment.index, e),
+            }
+        }
+        renewed
+    }
+    
+    // Fetch whatever fragments are still reachable and rebuild the file from
+    // any data_shards of them that check out against the manifest
+    async fn reconstruct_file(&self, manifest: &ErasureManifest) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut available: Vec<Option<Vec<u8>>> = vec![None; manifest.config.total_shards];
+        for fragment in &manifest.fragments {
+            let Some(node_id) = &fragment.node_id else { continue };
+            let message = P2PMessage::RetrieveRequest {
+                shard_id: fragment.cid.clone(),
+                from: self.identity.node_id.clone(),
+            };
+            match self.forward_to_node(node_id, &message).await {
+                Ok(P2PMessage::RetrieveResponse { data: Some(data), success: true, .. }) => {
+                    if let Some(slot) = available.get_mut(fragment.index) {
+                        *slot = Some(data);
+                    }
+                }
+                Ok(P2PMessage::RetrieveResponse { message, .. }) => println!("❌ Node {} could not return fragment {}: {}", node_id, fragment.index, message),
+                Ok(_) => println!("❌ Node {} gave an unexpected answer for fragment {}", node_id, fragment.index),
+                Err(e) => println!("❌ Could not reach node {} for fragment {}: {}", node_id, fragment.index, e),
             }
         }
         
-        Ok(shard_ids)
+        Ok(manifest.reconstruct(available)?)
     }
     
     async fn execute_compute(&self, wasm_bytes: &[u8], input_data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
         // Find a node with compute capabilities
-        let compute_node = self.nodes.keys().next()
+        let compute_node = self.nodes.read().await.keys().next()
+            .cloned()
             .ok_or("No compute nodes available")?;
         
-        let message = P2PMessage {
-            message_type: "ComputeRequest".to_string(),
-            data: None,
-            redundancy: None,
-            from: "p2p_client".to_string(),
-            shard_id: None,
-            wasm_bytes: Some(wasm_bytes.to_vec()),
-            input_data: Some(input_data.to_vec()),
-            timestamp: Some(std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()),
+        let message = P2PMessage::ComputeRequest {
+            wasm_bytes: wasm_bytes.to_vec(),
+            input_data: input_data.to_vec(),
+            from: self.identity.node_id.clone(),
+            hash_only: false,
+            verification: None,
+            task_id: None,
+            profile: Default::default(),
+            task_type: Default::default(),
+            module_hash: None,
+            mpc: None,
         };
         
-        let response = self.forward_to_node(compute_node, &message).await?;
-        
-        if response.success {
-            response.result.ok_or("No result data received".into())
-        } else {
-            Err(response.message.into())
+        match self.forward_to_node(&compute_node, &message).await? {
+            P2PMessage::ComputeResponse { success: true, result, .. } => result.ok_or("No result data received".into()),
+            P2PMessage::ComputeResponse { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response to compute request".into()),
         }
     }
 }
 
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("🚀 Starting P2P WebSocket Proxy...");
     println!("📍 Listening on ws://localhost:3006");
     
-    let proxy = Arc::new(P2PProxy::new());
+    let data_dir = std::env::var("XMBL_DATA_DIR")
+        .unwrap_or_else(|_| "data/p2p_proxy".to_string());
+    let identity = NodeIdentity::load_or_create(std::path::Path::new(&data_dir).join(IDENTITY_FILE))?;
+    println!("🔑 Proxy ID: {}", identity.node_id);
+    
+    let proxy = Arc::new(P2PProxy::new(identity, dht::seeds_from_env()));
+    println!("🔍 Discovered {} nodes from seeds {}", proxy.refresh_nodes().await, proxy.seeds.join(", "));
+    
+    // Pick up nodes that join or leave while the proxy runs
+    let refresh = Arc::clone(&proxy);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            refresh.refresh_nodes().await;
+        }
+    });
     
     let addr = "127.0.0.1:3006";
     let listener = TcpListener::bind(addr).await?;
@@ -178,17 +312,18 @@ async fn handle_websocket(ws_stream: WebSocketStream<TcpStream>, proxy: Arc<P2PP
     while let Some(msg) = ws_receiver.next().await {
         match msg {
             Ok(Message::Text(text)) => {
-                if let Ok(message) = serde_json::from_str::<P2PMessage>(&text) {
+                if let Ok(message) = serde_json::from_str::<ClientRequest>(&text) {
                     match message.message_type.as_str() {
                         "StoreRequest" => {
                             if let (Some(data), Some(redundancy)) = (message.data, message.redundancy) {
                                 match proxy.distribute_storage(&data, redundancy).await {
-                                    Ok(shard_ids) => {
-                                        let response = P2PResponse {
+                                    Ok(manifest) => {
+                                        let response = ClientResponse {
                                             success: true,
-                                            message: format!("File stored with {}x redundancy", redundancy),
-                                            data: None,
-                                            shard_id: None,
+                                            message: format!("File stored as {}-of-{} erasure-coded fragments",
+                                                manifest.config.data_shards, manifest.config.total_shards),
+                                            data: serde_json::to_vec(&manifest).ok(),
+                                            shard_id: Some(manifest.file_cid.clone()),
                                             result: None,
                                         };
                                         
@@ -197,7 +332,7 @@ async fn handle_websocket(ws_stream: WebSocketStream<TcpStream>, proxy: Arc<P2PP
                                         }
                                     }
                                     Err(e) => {
-                                        let response = P2PResponse {
+                                        let response = ClientResponse {
                                             success: false,
                                             message: format!("Storage failed: {}", e),
                                             data: None,
@@ -212,11 +347,54 @@ async fn handle_websocket(ws_stream: WebSocketStream<TcpStream>, proxy: Arc<P2PP
                                 }
                             }
                         }
+                        // The client hands back the manifest it got from StoreRequest
+                        "RetrieveRequest" => {
+                            if let Some(manifest) = message.data.and_then(|data| serde_json::from_slice::<ErasureManifest>(&data).ok()) {
+                                let response = match proxy.reconstruct_file(&manifest).await {
+                                    Ok(data) => ClientResponse {
+                                        success: true,
+                                        message: format!("Rebuilt {} bytes from fragments", data.len()),
+                                        data: Some(data),
+                                        shard_id: Some(manifest.file_cid.clone()),
+                                        result: None,
+                                    },
+                                    Err(e) => ClientResponse {
+                                        success: false,
+                                        message: format!("Retrieve failed: {}", e),
+                                        data: None,
+                                        shard_id: Some(manifest.file_cid.clone()),
+                                        result: None,
+                                    },
+                                };
+                                
+                                if let Ok(response_json) = serde_json::to_string(&response) {
+                                    let _ = ws_sender.send(Message::Text(response_json)).await;
+                                }
+                            }
+                        }
+                        // Also takes the manifest; the file stays readable while
+                        // at least data_shards fragments are renewed
+                        "RenewRequest" => {
+                            if let Some(manifest) = message.data.and_then(|data| serde_json::from_slice::<ErasureManifest>(&data).ok()) {
+                                let renewed = proxy.renew_fragments(&manifest).await;
+                                let response = ClientResponse {
+                                    success: renewed >= manifest.config.data_shards,
+                                    message: format!("Renewed {} of {} fragments", renewed, manifest.config.total_shards),
+                                    data: None,
+                                    shard_id: Some(manifest.file_cid.clone()),
+                                    result: None,
+                                };
+                                
+                                if let Ok(response_json) = serde_json::to_string(&response) {
+                                    let _ = ws_sender.send(Message::Text(response_json)).await;
+                                }
+                            }
+                        }
                         "ComputeRequest" => {
                             if let (Some(wasm_bytes), Some(input_data)) = (message.wasm_bytes, message.input_data) {
                                 match proxy.execute_compute(&wasm_bytes, &input_data).await {
                                     Ok(result) => {
-                                        let response = P2PResponse {
+                                        let response = ClientResponse {
                                             success: true,
                                             message: "Compute completed successfully".to_string(),
                                             data: None,
@@ -229,7 +407,7 @@ async fn handle_websocket(ws_stream: WebSocketStream<TcpStream>, proxy: Arc<P2PP
                                         }
                                     }
                                     Err(e) => {
-                                        let response = P2PResponse {
+                                        let response = ClientResponse {
                                             success: false,
                                             message: format!("Compute failed: {}", e),
                                             data: None,
@@ -0,0 +1,340 @@
+// XMBL Protocol - the messages nodes exchange with each other and clients
+//
+// Everything sent between nodes is a P2PMessage, tagged with the protocol
+// version it was written for and sealed in a signed envelope. Receivers
+// check the version before looking at the message, so a peer speaking a
+// version we don't understand is refused with an error that says so rather
+// than a confusing parse failure. The exception is the handshake: every
+// connection opens with a Hello, readable at any version, in which both
+// sides state the versions they speak and what they offer, so mismatched
+// peers can still tell each other why they part. Payload types the
+// subsystems share with the protocol (storage proofs, compute policies)
+// come from xmbl_types, so depending on the protocol doesn't pull in
+// storage or compute themselves.
+
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+pub use xmbl_types::NodeCapabilities;
+use xmbl_types::{ExecutionProfile, JobStatus, MpcConfig, StorageChallenge, StorageProof, TaskType, VerificationPolicy, VerificationReport};
+
+// Bump on any change an older node would misread
+pub const PROTOCOL_VERSION: u32 = 1;
+// Oldest version this build still reads
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// A node as the DHT knows it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub node_id: String,
+    pub address: String,
+    pub last_seen: u64,
+}
+
+impl Contact {
+    pub fn new(node_id: &str, address: &str, now: u64) -> Self {
+        Contact {
+            node_id: node_id.to_string(),
+            address: address.to_string(),
+            last_seen: now,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DhtMessage {
+    FindNode { target: String },
+    FindNodeResponse { nodes: Vec<Contact> },
+    Store { key: String, value: Vec<u8> },
+    StoreResponse { success: bool, message: String },
+    FindValue { key: String },
+    FindValueResponse { value: Option<Vec<u8>>, nodes: Vec<Contact> },
+}
+
+// What NetworkService sends between nodes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NetworkMessage {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub payload: Vec<u8>,
+    pub message_type: MessageType,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MessageType {
+    Ping,
+    Pong,
+    Data,
+    Control,
+    Discovery,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Feature {
+    Storage,
+    Compute,
+    // Forwards requests from clients that can't reach nodes themselves
+    Relay,
+}
+
+// What a peer says about itself when a connection opens. Its layout must
+// not change between versions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub node_id: String,
+    pub version: u32,
+    pub min_version: u32,
+    pub features: Vec<Feature>,
+    // None for clients that only make requests
+    pub capabilities: Option<NodeCapabilities>,
+    // Where the peer accepts connections, empty for clients
+    pub addresses: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(node_id: &str, features: Vec<Feature>, capabilities: Option<NodeCapabilities>, addresses: Vec<String>) -> Self {
+        Hello {
+            node_id: node_id.to_string(),
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
+            capabilities,
+            addresses,
+        }
+    }
+
+    pub fn client(node_id: &str) -> Self {
+        Self::new(node_id, Vec::new(), None, Vec::new())
+    }
+
+    // The newest version both sides speak
+    pub fn negotiate(&self, theirs: &Hello) -> Result<u32> {
+        let version = self.version.min(theirs.version);
+        if version < self.min_version.max(theirs.min_version) {
+            return Err(anyhow::anyhow!("{} speaks protocol versions {} to {}, we speak {} to {}",
+                theirs.node_id, theirs.min_version, theirs.version, self.min_version, self.version));
+        }
+        Ok(version)
+    }
+}
+
+// A peer as its handshake described it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    pub addresses: Vec<String>,
+    // Negotiated, not the newest the peer speaks
+    pub version: u32,
+    pub features: Vec<Feature>,
+    pub capabilities: Option<NodeCapabilities>,
+    pub last_seen: u64,
+}
+
+impl PeerInfo {
+    pub fn new(hello: Hello, version: u32, now: u64) -> Self {
+        PeerInfo {
+            node_id: hello.node_id,
+            addresses: hello.addresses,
+            version,
+            features: hello.features,
+            capabilities: hello.capabilities,
+            last_seen: now,
+        }
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum P2PMessage {
+    // Opens every connection, answered by HelloAck or HelloRejected
+    Hello { hello: Hello },
+    HelloAck { hello: Hello },
+    HelloRejected { reason: String },
+    Ping { from: String, timestamp: u64 },
+    Pong { from: String, timestamp: u64 },
+    StoreRequest { data: Vec<u8>, redundancy: u8, from: String },
+    StoreResponse { shard_id: String, success: bool, message: String },
+    RetrieveRequest { shard_id: String, from: String },
+    RetrieveResponse { data: Option<Vec<u8>>, success: bool, message: String },
+    // Drops the sender's own lease on the shard
+    DeleteRequest { shard_id: String, from: String },
+    DeleteResponse { success: bool, message: String },
+    // Pushes the sender's own lease out; None asks for a permanent one
+    RenewLeaseRequest { shard_id: String, lease_secs: Option<u64>, from: String },
+    RenewLeaseResponse { lease_expires_at: Option<u64>, success: bool, message: String },
+    ComputeRequest {
+        // Empty when the module is named by module_hash
+        #[serde(default)]
+        wasm_bytes: Vec<u8>,
+        input_data: Vec<u8>,
+        from: String,
+        // Validators answer with the output hash only
+        #[serde(default)]
+        hash_only: bool,
+        // Have the receiving node coordinate a redundant, verified run
+        #[serde(default)]
+        verification: Option<VerificationPolicy>,
+        // Replicas of one verified run share an ID, and so xmbl.random's seed
+        #[serde(default)]
+        task_id: Option<String>,
+        #[serde(default)]
+        profile: ExecutionProfile,
+        #[serde(default)]
+        task_type: TaskType,
+        // A module registered with ModuleUpload
+        #[serde(default)]
+        module_hash: Option<String>,
+        // With task_type MPC, have the receiving node secret-share the input
+        // and coordinate the run across this many peers
+        #[serde(default)]
+        mpc: Option<MpcConfig>,
+    },
+    ComputeResponse {
+        result: Option<Vec<u8>>,
+        success: bool,
+        message: String,
+        #[serde(default)]
+        output_hash: Option<String>,
+        #[serde(default)]
+        verification: Option<VerificationReport>,
+        // The module hash the request named that this node doesn't have;
+        // uploading the module and asking again fixes it
+        #[serde(default)]
+        missing_module: Option<String>,
+    },
+    ModuleUpload { wasm_bytes: Vec<u8>, from: String },
+    ModuleUploadResponse { module_hash: Option<String>, success: bool, message: String },
+    // Map wasm_bytes over inputs, or over chunks of a stored file, then reduce
+    JobRequest {
+        wasm_bytes: Vec<u8>,
+        inputs: Vec<Vec<u8>>,
+        file_id: Option<String>,
+        chunk_size: Option<usize>,
+        reducer: Option<Vec<u8>>,
+        from: String,
+    },
+    JobResponse { job_id: Option<String>, success: bool, message: String },
+    JobStatusRequest { job_id: String, from: String },
+    JobStatusResponse { status: Option<JobStatus> },
+    // Kademlia requests and their answers; address is where the sender
+    // accepts connections, empty for clients that don't
+    Dht { message: DhtMessage, from: String, address: String },
+    StorageChallenge { challenge: StorageChallenge, from: String },
+    StorageProofResponse { proof: Option<StorageProof>, success: bool, message: String },
+    // NetworkService traffic, on connections its transport keeps open
+    Network { message: NetworkMessage },
+}
+
+impl P2PMessage {
+    pub fn claimed_sender(&self) -> Option<&str> {
+        match self {
+            P2PMessage::Hello { hello } | P2PMessage::HelloAck { hello } => Some(&hello.node_id),
+            P2PMessage::Ping { from, .. }
+            | P2PMessage::Pong { from, .. }
+            | P2PMessage::StoreRequest { from, .. }
+            | P2PMessage::RetrieveRequest { from, .. }
+            | P2PMessage::DeleteRequest { from, .. }
+            | P2PMessage::RenewLeaseRequest { from, .. }
+            | P2PMessage::ComputeRequest { from, .. }
+            | P2PMessage::ModuleUpload { from, .. }
+            | P2PMessage::JobRequest { from, .. }
+            | P2PMessage::JobStatusRequest { from, .. }
+            | P2PMessage::Dht { from, .. }
+            | P2PMessage::StorageChallenge { from, .. } => Some(from),
+            P2PMessage::Network { message } => Some(&message.from),
+            _ => None,
+        }
+    }
+
+    pub fn is_handshake(&self) -> bool {
+        matches!(self, P2PMessage::Hello { .. } | P2PMessage::HelloAck { .. } | P2PMessage::HelloRejected { .. })
+    }
+}
+
+// What actually goes inside an envelope
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub message: T,
+}
+
+pub fn is_supported(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+pub fn seal(identity: &NodeIdentity, to: &str, message: &P2PMessage, now: u64) -> Result<SignedEnvelope> {
+    seal_version(identity, to, message, PROTOCOL_VERSION, now)
+}
+
+// For peers whose handshake settled on an older version than ours
+pub fn seal_version(identity: &NodeIdentity, to: &str, message: &P2PMessage, version: u32, now: u64) -> Result<SignedEnvelope> {
+    if !is_supported(version) {
+        return Err(anyhow::anyhow!("Cannot write protocol version {}, we support {} to {}", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+    }
+    SignedEnvelope::seal(identity, to, &Versioned { version, message }, now)
+}
+
+// Verify that it was sent to us, check the version, and decode; returns the
+// sender's node ID. A message whose `from` names anyone but the signer is
+// refused.
+pub fn open(envelope: &SignedEnvelope, me: &str, replay: &mut ReplayGuard, now: u64) -> Result<(String, P2PMessage)> {
+    let (sender, versioned) = envelope.open::<Versioned<serde_json::Value>>(me, replay, now)?;
+    let message = match serde_json::from_value::<P2PMessage>(versioned.message) {
+        Ok(message) if is_supported(versioned.version) || message.is_handshake() => message,
+        Err(e) if is_supported(versioned.version) => return Err(e.into()),
+        _ => return Err(anyhow::anyhow!("{} speaks protocol version {}, we support {} to {}",
+            sender, versioned.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)),
+    };
+    if let Some(from) = message.claimed_sender() {
+        if from != sender {
+            return Err(anyhow::anyhow!("Message claims to be from {} but is signed by {}", from, sender));
+        }
+    }
+    Ok((sender, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versioned_messages_round_trip_and_reject_unknown_versions() {
+        let (client, node) = (NodeIdentity::new(), NodeIdentity::new());
+        let mut replay = ReplayGuard::default();
+        let now = 1_700_000_000;
+
+        // What the proxy sends is what a node reads
+        let store = P2PMessage::StoreRequest { data: b"fragment".to_vec(), redundancy: 1, from: client.node_id.clone() };
+        let (sender, message) = open(&seal(&client, &node.node_id, &store, now).unwrap(), &node.node_id, &mut replay, now).unwrap();
+        assert_eq!(sender, client.node_id);
+        assert!(matches!(message, P2PMessage::StoreRequest { data, redundancy: 1, .. } if data == b"fragment"));
+
+        // A newer peer's message is refused by version, even if it would parse
+        let future = Versioned { version: PROTOCOL_VERSION + 1, message: serde_json::json!({ "Future": {} }) };
+        let envelope = SignedEnvelope::seal(&node, &client.node_id, &future, now).unwrap();
+        let error = open(&envelope, &client.node_id, &mut replay, now).unwrap_err().to_string();
+        assert!(error.contains("protocol version"), "{}", error);
+
+        // Speaking for someone else is caught after decoding
+        let forged = P2PMessage::Ping { from: node.node_id.clone(), timestamp: now };
+        assert!(open(&seal(&client, &node.node_id, &forged, now).unwrap(), &node.node_id, &mut replay, now).is_err());
+
+        // NetworkService messages are sealed the same way, `from` included
+        let message = NetworkMessage {
+            id: "1".to_string(),
+            from: client.node_id.clone(),
+            to: node.node_id.clone(),
+            payload: b"data".to_vec(),
+            message_type: MessageType::Data,
+        };
+        let network = P2PMessage::Network { message: message.clone() };
+        let (_, opened) = open(&seal(&client, &node.node_id, &network, now).unwrap(), &node.node_id, &mut replay, now).unwrap();
+        assert!(matches!(opened, P2PMessage::Network { message } if message.payload == b"data"));
+        let forged = P2PMessage::Network { message: NetworkMessage { from: node.node_id.clone(), ..message } };
+        assert!(open(&seal(&client, &node.node_id, &forged, now).unwrap(), &node.node_id, &mut replay, now).is_err());
+    }
+}
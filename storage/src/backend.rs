@@ -0,0 +1,302 @@
+// XMBL Storage Backends - where StorageService keeps shard bytes
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+use crate::{StorageShard, ShardKind, Lease, EncryptionMode};
+
+// Index entry describing a shard without its payload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShardMeta {
+    pub shard_id: String,
+    pub size_bytes: u64,
+    pub redundancy: u8,
+    pub checksum: String,
+    // Number of store_data calls currently sharing this content
+    #[serde(default = "default_ref_count")]
+    pub ref_count: u32,
+    #[serde(default)]
+    pub kind: ShardKind,
+    #[serde(default)]
+    pub internal: bool,
+    // One per reference; shards indexed before leases existed have none
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+    // How the stored bytes were encrypted, if they were
+    #[serde(default)]
+    pub encryption: Option<EncryptionMode>,
+}
+
+fn default_ref_count() -> u32 {
+    1
+}
+
+impl ShardMeta {
+    pub fn from_shard(shard: &StorageShard) -> Self {
+        ShardMeta {
+            shard_id: shard.shard_id.clone(),
+            size_bytes: shard.data.len() as u64,
+            redundancy: shard.redundancy,
+            checksum: shard.checksum.clone(),
+            ref_count: 1,
+            kind: shard.kind,
+            internal: shard.internal,
+            leases: shard.leases.clone(),
+            encryption: None,
+        }
+    }
+
+    pub fn owners(&self) -> Vec<String> {
+        let mut owners: Vec<String> = self.leases.iter().map(|l| l.owner.clone()).collect();
+        owners.sort();
+        owners.dedup();
+        owners
+    }
+}
+
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    fn put(&mut self, shard: &StorageShard) -> Result<()>;
+    fn get(&self, shard_id: &str) -> Result<Option<StorageShard>>;
+    fn remove(&mut self, shard_id: &str) -> Result<Option<ShardMeta>>;
+    fn meta(&self, shard_id: &str) -> Option<ShardMeta>;
+    fn update_meta(&mut self, meta: ShardMeta) -> Result<()>;
+    fn list(&self) -> Vec<ShardMeta>;
+
+    fn used_bytes(&self) -> u64 {
+        self.list().iter().map(|m| m.size_bytes).sum()
+    }
+}
+
+// IN-MEMORY BACKEND
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    shards: HashMap<String, (ShardMeta, StorageShard)>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put(&mut self, shard: &StorageShard) -> Result<()> {
+        self.shards.insert(shard.shard_id.clone(), (ShardMeta::from_shard(shard), shard.clone()));
+        Ok(())
+    }
+
+    fn get(&self, shard_id: &str) -> Result<Option<StorageShard>> {
+        Ok(self.shards.get(shard_id).map(|(_, shard)| shard.clone()))
+    }
+
+    fn remove(&mut self, shard_id: &str) -> Result<Option<ShardMeta>> {
+        Ok(self.shards.remove(shard_id).map(|(meta, _)| meta))
+    }
+
+    fn meta(&self, shard_id: &str) -> Option<ShardMeta> {
+        self.shards.get(shard_id).map(|(meta, _)| meta.clone())
+    }
+
+    fn update_meta(&mut self, meta: ShardMeta) -> Result<()> {
+        let entry = self.shards.get_mut(&meta.shard_id)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        entry.1.redundancy = meta.redundancy;
+        entry.1.internal = meta.internal;
+        entry.1.leases = meta.leases.clone();
+        entry.0 = meta;
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<ShardMeta> {
+        self.shards.values().map(|(meta, _)| meta.clone()).collect()
+    }
+}
+
+// FILESYSTEM BACKEND
+//
+// Layout under the root directory:
+//   objects/<first two hex chars>/<sha256>   shard bytes, named by content
+//   index.json                               shard_id -> ShardMeta
+//
+// Every file is written to a temporary sibling, fsynced and renamed into
+// place, so a crash leaves either the old or the new version on disk.
+#[derive(Debug)]
+pub struct FsBackend {
+    root: PathBuf,
+    index: HashMap<String, ShardMeta>,
+}
+
+const INDEX_FILE: &str = "index.json";
+const OBJECTS_DIR: &str = "objects";
+const TMP_SUFFIX: &str = ".tmp";
+
+impl FsBackend {
+    pub fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+
+        let index_path = root.join(INDEX_FILE);
+        let index: HashMap<String, ShardMeta> = if index_path.exists() {
+            serde_json::from_slice(&fs::read(&index_path)?)?
+        } else {
+            HashMap::new()
+        };
+
+        let mut backend = FsBackend { root, index };
+        backend.recover()?;
+        Ok(backend)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Drop index entries whose object never made it to disk and clean up
+    // temporary or unreferenced object files left behind by a crash
+    fn recover(&mut self) -> Result<()> {
+        let before = self.index.len();
+        let root = self.root.clone();
+        self.index.retain(|shard_id, meta| {
+            let present = object_path(&root, &meta.checksum).exists();
+            if !present {
+                log::warn!("Dropping shard {} from index: object file missing", shard_id);
+            }
+            present
+        });
+
+        let referenced: std::collections::HashSet<&str> =
+            self.index.values().map(|m| m.checksum.as_str()).collect();
+
+        for bucket in fs::read_dir(self.root.join(OBJECTS_DIR))? {
+            let bucket = bucket?.path();
+            if !bucket.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&bucket)? {
+                let path = entry?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                if name.ends_with(TMP_SUFFIX) || !referenced.contains(name) {
+                    log::debug!("Removing stale object file {:?}", path);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        let index_tmp = self.root.join(format!("{}{}", INDEX_FILE, TMP_SUFFIX));
+        if index_tmp.exists() {
+            fs::remove_file(index_tmp)?;
+        }
+
+        if self.index.len() != before {
+            self.write_index()?;
+        }
+        Ok(())
+    }
+
+    fn write_index(&self) -> Result<()> {
+        let data = serde_json::to_vec(&self.index)?;
+        write_atomic(&self.root.join(INDEX_FILE), &data)
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn put(&mut self, shard: &StorageShard) -> Result<()> {
+        let path = object_path(&self.root, &shard.checksum);
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_atomic(&path, &shard.data)?;
+        }
+
+        self.index.insert(shard.shard_id.clone(), ShardMeta::from_shard(shard));
+        if let Err(e) = self.write_index() {
+            self.index.remove(&shard.shard_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn get(&self, shard_id: &str) -> Result<Option<StorageShard>> {
+        let meta = match self.index.get(shard_id) {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+
+        let data = fs::read(object_path(&self.root, &meta.checksum))?;
+        Ok(Some(StorageShard {
+            shard_id: meta.shard_id.clone(),
+            data,
+            redundancy: meta.redundancy,
+            checksum: meta.checksum.clone(),
+            kind: meta.kind,
+            internal: meta.internal,
+            leases: meta.leases.clone(),
+        }))
+    }
+
+    fn remove(&mut self, shard_id: &str) -> Result<Option<ShardMeta>> {
+        let meta = match self.index.remove(shard_id) {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        self.write_index()?;
+
+        // Objects are shared by content, only delete once nothing points at it
+        if !self.index.values().any(|m| m.checksum == meta.checksum) {
+            let path = object_path(&self.root, &meta.checksum);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(Some(meta))
+    }
+
+    fn meta(&self, shard_id: &str) -> Option<ShardMeta> {
+        self.index.get(shard_id).cloned()
+    }
+
+    fn update_meta(&mut self, meta: ShardMeta) -> Result<()> {
+        let previous = self.index.insert(meta.shard_id.clone(), meta.clone())
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        if let Err(e) = self.write_index() {
+            self.index.insert(meta.shard_id, previous);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<ShardMeta> {
+        self.index.values().cloned().collect()
+    }
+}
+
+fn object_path(root: &Path, checksum: &str) -> PathBuf {
+    let bucket = checksum.get(..2).unwrap_or("00");
+    root.join(OBJECTS_DIR).join(bucket).join(checksum)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let file_name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid storage path: {:?}", path))?;
+    let tmp_path = path.with_file_name(format!("{}{}", file_name, TMP_SUFFIX));
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+    if let Some(parent) = path.parent() {
+        // Persist the rename itself; not every platform allows opening directories
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
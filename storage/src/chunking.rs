@@ -0,0 +1,132 @@
+// XMBL Chunking - fixed-size chunks described by a Merkle-rooted manifest
+//
+// Large files are cut into chunk_size pieces, each stored under its own CID.
+// The manifest lists the chunks in order and commits to them with a Merkle
+// root over the chunk digests, so any single chunk can be verified on its
+// own and ranged reads only need to touch the chunks they overlap.
+
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+
+use crate::Cid;
+use crate::merkle::{self, MerkleTree, ProofStep, Hash};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub index: usize,
+    pub cid: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub file_cid: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub merkle_root: String,
+    pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub index: usize,
+    pub cid: String,
+    pub data: Vec<u8>,
+}
+
+pub struct Chunker {
+    chunk_size: usize,
+}
+
+impl Chunker {
+    pub fn new(chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(anyhow::anyhow!("Chunk size must be greater than zero"));
+        }
+        Ok(Chunker { chunk_size })
+    }
+
+    pub fn split(&self, data: &[u8]) -> (FileManifest, Vec<Chunk>) {
+        let chunks: Vec<Chunk> = data.chunks(self.chunk_size)
+            .enumerate()
+            .map(|(index, piece)| Chunk {
+                index,
+                cid: Cid::for_data(piece).to_string(),
+                data: piece.to_vec(),
+            })
+            .collect();
+
+        // Leaves cover the chunks' digests so the manifest alone can rebuild them
+        let leaves = chunks.iter().map(|c| merkle::leaf_hash(Cid::for_data(&c.data).digest())).collect();
+        let manifest = FileManifest {
+            file_cid: Cid::for_data(data).to_string(),
+            total_size: data.len() as u64,
+            chunk_size: self.chunk_size as u64,
+            merkle_root: MerkleTree::new(leaves).root_hex(),
+            chunks: chunks.iter()
+                .map(|c| ChunkRef { index: c.index, cid: c.cid.clone(), size: c.data.len() as u64 })
+                .collect(),
+        };
+
+        (manifest, chunks)
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Chunker { chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+}
+
+impl FileManifest {
+    pub fn merkle_tree(&self) -> Result<MerkleTree> {
+        let leaves = self.chunks.iter()
+            .map(|c| c.cid.parse::<Cid>().map(|cid| merkle::leaf_hash(cid.digest())))
+            .collect::<Result<Vec<Hash>>>()?;
+        Ok(MerkleTree::new(leaves))
+    }
+
+    // Check that the chunk list matches the committed root and sizes add up
+    pub fn validate(&self) -> Result<()> {
+        if self.merkle_tree()?.root_hex() != self.merkle_root {
+            return Err(anyhow::anyhow!("Manifest Merkle root mismatch"));
+        }
+        let total: u64 = self.chunks.iter().map(|c| c.size).sum();
+        if total != self.total_size {
+            return Err(anyhow::anyhow!("Manifest chunk sizes do not add up to file size"));
+        }
+        Ok(())
+    }
+
+    pub fn chunk_proof(&self, index: usize) -> Result<Vec<ProofStep>> {
+        self.merkle_tree()?
+            .proof(index)
+            .ok_or_else(|| anyhow::anyhow!("Chunk {} out of range", index))
+    }
+
+    // Chunks overlapping [offset, offset + length), with the byte range to
+    // take from each one
+    pub fn chunks_for_range(&self, offset: u64, length: u64) -> Result<Vec<(&ChunkRef, std::ops::Range<usize>)>> {
+        let end = offset.checked_add(length)
+            .filter(|end| *end <= self.total_size)
+            .ok_or_else(|| anyhow::anyhow!("Range {}+{} out of bounds for {} byte file", offset, length, self.total_size))?;
+
+        let mut ranges = Vec::new();
+        let mut chunk_start = 0u64;
+        for chunk in &self.chunks {
+            let chunk_end = chunk_start + chunk.size;
+            if chunk_end > offset && chunk_start < end {
+                let from = offset.max(chunk_start) - chunk_start;
+                let to = end.min(chunk_end) - chunk_start;
+                ranges.push((chunk, from as usize..to as usize));
+            }
+            if chunk_start >= end {
+                break;
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(ranges)
+    }
+}
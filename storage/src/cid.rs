@@ -0,0 +1,117 @@
+// XMBL Content Identifiers
+//
+// Shards are keyed by a CIDv1 over the raw bytes: version 0x01, codec 0x55
+// (raw), then a sha2-256 multihash (0x12, 0x20, digest). The binary form is
+// rendered as lowercase base32 with the multibase prefix 'b', so identifiers
+// look like "bafkrei..." and can be checked by anyone holding the data.
+
+use std::fmt;
+use std::str::FromStr;
+use anyhow::Result;
+use sha2::{Sha256, Digest};
+
+const CID_VERSION: u8 = 0x01;
+const RAW_CODEC: u8 = 0x55;
+const SHA2_256: u8 = 0x12;
+const SHA2_256_LEN: u8 = 0x20;
+const MULTIBASE_BASE32: char = 'b';
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cid {
+    digest: [u8; 32],
+}
+
+impl Cid {
+    pub fn for_data(data: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        Cid { digest: hasher.finalize().into() }
+    }
+
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+
+    // Hex sha256 of the content, the same value as StorageShard.checksum
+    pub fn digest_hex(&self) -> String {
+        hex::encode(self.digest)
+    }
+
+    pub fn verify(&self, data: &[u8]) -> bool {
+        Cid::for_data(data) == *self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CID_VERSION, RAW_CODEC, SHA2_256, SHA2_256_LEN];
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 36 {
+            return Err(anyhow::anyhow!("Invalid CID length: {}", bytes.len()));
+        }
+        if bytes[..4] != [CID_VERSION, RAW_CODEC, SHA2_256, SHA2_256_LEN] {
+            return Err(anyhow::anyhow!("Unsupported CID prefix"));
+        }
+
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&bytes[4..]);
+        Ok(Cid { digest })
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", MULTIBASE_BASE32, base32_encode(&self.to_bytes()))
+    }
+}
+
+impl FromStr for Cid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s.strip_prefix(MULTIBASE_BASE32)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported multibase prefix in CID: {}", s))?;
+        Cid::from_bytes(&base32_decode(encoded)?)
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| anyhow::anyhow!("Invalid base32 character: {}", c as char))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,198 @@
+// XMBL Client-Side Encryption - XChaCha20-Poly1305 before data leaves the uploader
+//
+// Every file gets its own 32-byte file key. The file key is wrapped under the
+// uploader's master key (derived from their NodeIdentity) and the wrapped
+// copy travels in the blob header, so the uploader can always decrypt with
+// their keyring while storage nodes only ever see ciphertext. The plain file
+// key is handed back to the caller for sharing and is never stored.
+//
+// Blob layout:
+//   "XENC" | version | mode | wrap_nonce[24] | wrapped_key[48] | data_nonce[24] | ciphertext
+//
+// Convergent mode derives the file key and nonces from the plaintext, so the
+// same identity uploading the same file produces the same blob (and CID) and
+// deduplication keeps working. PerFile mode uses fresh randomness every time.
+
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, KeyInit};
+use chacha20poly1305::aead::Aead;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Sha256, Digest};
+
+const MAGIC: &[u8; 4] = b"XENC";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + TAG_LEN;
+const HEADER_LEN: usize = 4 + 1 + 1 + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+// Context string passed to NodeIdentity::derive_key for the storage master key
+pub const KEY_CONTEXT: &str = "xmbl-storage-encryption-v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncryptionMode {
+    PerFile,
+    Convergent,
+}
+
+impl EncryptionMode {
+    fn to_byte(self) -> u8 {
+        match self {
+            EncryptionMode::PerFile => 0,
+            EncryptionMode::Convergent => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(EncryptionMode::PerFile),
+            1 => Ok(EncryptionMode::Convergent),
+            other => Err(anyhow::anyhow!("Unknown encryption mode: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct FileKey([u8; KEY_LEN]);
+
+impl FileKey {
+    pub fn from_hex(encoded: &str) -> Result<Self> {
+        let bytes: [u8; KEY_LEN] = hex::decode(encoded)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("File key must be {} bytes", KEY_LEN))?;
+        Ok(FileKey(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+// Never print key material
+impl std::fmt::Debug for FileKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FileKey(..)")
+    }
+}
+
+pub struct Keyring {
+    master: [u8; KEY_LEN],
+}
+
+impl Keyring {
+    // Typically NodeIdentity::derive_key(KEY_CONTEXT)
+    pub fn new(master: [u8; KEY_LEN]) -> Self {
+        Keyring { master }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8], mode: EncryptionMode) -> Result<(Vec<u8>, FileKey)> {
+        let (file_key, wrap_nonce, data_nonce) = match mode {
+            EncryptionMode::PerFile => {
+                let mut rng = rand::thread_rng();
+                let mut key = [0u8; KEY_LEN];
+                let mut wrap_nonce = [0u8; NONCE_LEN];
+                let mut data_nonce = [0u8; NONCE_LEN];
+                rng.fill_bytes(&mut key);
+                rng.fill_bytes(&mut wrap_nonce);
+                rng.fill_bytes(&mut data_nonce);
+                (key, wrap_nonce, data_nonce)
+            }
+            EncryptionMode::Convergent => {
+                let key = hmac(&self.master, &[b"convergent-key", Sha256::digest(plaintext).as_slice()]);
+                let wrap_nonce = nonce_from(&hmac(&self.master, &[b"wrap-nonce", &key]));
+                let data_nonce = nonce_from(&hmac(&key, &[b"data-nonce"]));
+                (key, wrap_nonce, data_nonce)
+            }
+        };
+
+        let wrapped_key = seal(&self.master, &wrap_nonce, &file_key)?;
+        let ciphertext = seal(&file_key, &data_nonce, plaintext)?;
+
+        let mut blob = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        blob.extend_from_slice(MAGIC);
+        blob.push(VERSION);
+        blob.push(mode.to_byte());
+        blob.extend_from_slice(&wrap_nonce);
+        blob.extend_from_slice(&wrapped_key);
+        blob.extend_from_slice(&data_nonce);
+        blob.extend_from_slice(&ciphertext);
+
+        Ok((blob, FileKey(file_key)))
+    }
+
+    pub fn decrypt(&self, blob: &[u8]) -> Result<Vec<u8>> {
+        let header = BlobHeader::parse(blob)?;
+        let file_key: [u8; KEY_LEN] = open(&self.master, header.wrap_nonce, header.wrapped_key)
+            .map_err(|_| anyhow::anyhow!("File was not encrypted for this identity"))?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid wrapped key"))?;
+        decrypt_with_key(&FileKey(file_key), blob)
+    }
+}
+
+// Decrypt with a shared file key, without access to the uploader's keyring
+pub fn decrypt_with_key(key: &FileKey, blob: &[u8]) -> Result<Vec<u8>> {
+    let header = BlobHeader::parse(blob)?;
+    open(&key.0, header.data_nonce, &blob[HEADER_LEN..])
+}
+
+pub fn is_encrypted(blob: &[u8]) -> bool {
+    BlobHeader::parse(blob).is_ok()
+}
+
+struct BlobHeader<'a> {
+    wrap_nonce: &'a [u8],
+    wrapped_key: &'a [u8],
+    data_nonce: &'a [u8],
+}
+
+impl<'a> BlobHeader<'a> {
+    fn parse(blob: &'a [u8]) -> Result<Self> {
+        if blob.len() < HEADER_LEN + TAG_LEN || &blob[..4] != MAGIC {
+            return Err(anyhow::anyhow!("Not an encrypted blob"));
+        }
+        if blob[4] != VERSION {
+            return Err(anyhow::anyhow!("Unsupported encryption version: {}", blob[4]));
+        }
+        EncryptionMode::from_byte(blob[5])?;
+
+        let wrap_start = 6;
+        let key_start = wrap_start + NONCE_LEN;
+        let data_nonce_start = key_start + WRAPPED_KEY_LEN;
+        Ok(BlobHeader {
+            wrap_nonce: &blob[wrap_start..key_start],
+            wrapped_key: &blob[key_start..data_nonce_start],
+            data_nonce: &blob[data_nonce_start..HEADER_LEN],
+        })
+    }
+}
+
+fn seal(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], plaintext: &[u8]) -> Result<Vec<u8>> {
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(XNonce::from_slice(nonce), plaintext)
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))
+}
+
+fn open(key: &[u8; KEY_LEN], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Decryption failed: wrong key or tampered data"))
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn nonce_from(bytes: &[u8; KEY_LEN]) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&bytes[..NONCE_LEN]);
+    nonce
+}
@@ -0,0 +1,208 @@
+// XMBL Erasure Coding - Reed-Solomon k-of-n fragments
+//
+// A file is split into k data fragments plus (n - k) parity fragments of
+// equal size. Any k of the n fragments are enough to rebuild the original.
+// The manifest records the layout and where each fragment was placed.
+
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::Cid;
+
+pub const DEFAULT_DATA_SHARDS: usize = 3;
+pub const DEFAULT_TOTAL_SHARDS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureConfig {
+    pub data_shards: usize,
+    pub total_shards: usize,
+}
+
+impl ErasureConfig {
+    pub fn new(data_shards: usize, total_shards: usize) -> Result<Self> {
+        if data_shards == 0 || total_shards <= data_shards {
+            return Err(anyhow::anyhow!(
+                "Invalid erasure config: need 0 < k < n, got {}-of-{}", data_shards, total_shards));
+        }
+        if total_shards > 256 {
+            return Err(anyhow::anyhow!("Invalid erasure config: at most 256 fragments"));
+        }
+        Ok(ErasureConfig { data_shards, total_shards })
+    }
+
+    // A redundancy of r tolerates r - 1 lost fragments, the same number of
+    // failures r full replicas would survive
+    pub fn from_redundancy(redundancy: u8) -> Result<Self> {
+        let parity = (redundancy as usize).saturating_sub(1).max(1);
+        Self::new(DEFAULT_DATA_SHARDS, DEFAULT_DATA_SHARDS + parity)
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.total_shards - self.data_shards
+    }
+}
+
+impl Default for ErasureConfig {
+    fn default() -> Self {
+        ErasureConfig {
+            data_shards: DEFAULT_DATA_SHARDS,
+            total_shards: DEFAULT_TOTAL_SHARDS,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Fragment {
+    pub index: usize,
+    pub cid: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FragmentLocation {
+    pub index: usize,
+    pub cid: String,
+    pub node_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErasureManifest {
+    pub file_cid: String,
+    pub original_size: u64,
+    pub config: ErasureConfig,
+    pub fragment_size: usize,
+    pub fragments: Vec<FragmentLocation>,
+}
+
+impl ErasureManifest {
+    pub fn place(&mut self, index: usize, node_id: String) -> Result<()> {
+        let location = self.fragments.iter_mut()
+            .find(|f| f.index == index)
+            .ok_or_else(|| anyhow::anyhow!("Fragment {} not in manifest", index))?;
+        location.node_id = Some(node_id);
+        Ok(())
+    }
+
+    pub fn fragments_on(&self, node_id: &str) -> Vec<usize> {
+        self.fragments.iter()
+            .filter(|f| f.node_id.as_deref() == Some(node_id))
+            .map(|f| f.index)
+            .collect()
+    }
+
+    // Rebuild the file from whatever fragments could be fetched, indexed by
+    // fragment number. Fragments that fail their CID check are ignored.
+    pub fn reconstruct(&self, mut available: Vec<Option<Vec<u8>>>) -> Result<Vec<u8>> {
+        available.resize(self.config.total_shards, None);
+
+        for (location, slot) in self.fragments.iter().zip(available.iter_mut()) {
+            if let Some(data) = slot {
+                let valid = location.cid.parse::<Cid>()
+                    .map(|cid| cid.verify(data))
+                    .unwrap_or(false);
+                if !valid || data.len() != self.fragment_size {
+                    log::warn!("Discarding corrupt fragment {} of {}", location.index, self.file_cid);
+                    *slot = None;
+                }
+            }
+        }
+
+        let present = available.iter().filter(|f| f.is_some()).count();
+        if present < self.config.data_shards {
+            return Err(anyhow::anyhow!(
+                "Not enough fragments to reconstruct: have {}, need {}", present, self.config.data_shards));
+        }
+
+        let rs = ReedSolomon::new(self.config.data_shards, self.config.parity_shards())
+            .map_err(|e| anyhow::anyhow!("Erasure coder error: {:?}", e))?;
+        rs.reconstruct_data(&mut available)
+            .map_err(|e| anyhow::anyhow!("Reconstruction failed: {:?}", e))?;
+
+        let mut data: Vec<u8> = available.into_iter()
+            .take(self.config.data_shards)
+            .flat_map(|f| f.unwrap_or_default())
+            .collect();
+        data.truncate(self.original_size as usize);
+
+        if !self.file_cid.parse::<Cid>().map(|cid| cid.verify(&data)).unwrap_or(false) {
+            return Err(anyhow::anyhow!("Data corruption detected"));
+        }
+        Ok(data)
+    }
+}
+
+impl ErasureManifest {
+    // Rebuild lost fragments from the survivors. Encoding is deterministic,
+    // so regenerated fragments carry the same CIDs the manifest expects.
+    pub fn regenerate(&self, available: Vec<Option<Vec<u8>>>, indices: &[usize]) -> Result<Vec<Fragment>> {
+        let data = self.reconstruct(available)?;
+        let (_, fragments) = ErasureCoder::new(self.config)?.encode(&data)?;
+
+        fragments.into_iter()
+            .filter(|f| indices.contains(&f.index))
+            .map(|f| {
+                let expected = self.fragments.iter().find(|l| l.index == f.index).map(|l| l.cid.as_str());
+                if expected != Some(f.cid.as_str()) {
+                    return Err(anyhow::anyhow!("Regenerated fragment {} does not match manifest", f.index));
+                }
+                Ok(f)
+            })
+            .collect()
+    }
+}
+
+pub struct ErasureCoder {
+    config: ErasureConfig,
+    rs: ReedSolomon,
+}
+
+impl ErasureCoder {
+    pub fn new(config: ErasureConfig) -> Result<Self> {
+        let rs = ReedSolomon::new(config.data_shards, config.parity_shards())
+            .map_err(|e| anyhow::anyhow!("Erasure coder error: {:?}", e))?;
+        Ok(ErasureCoder { config, rs })
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Result<(ErasureManifest, Vec<Fragment>)> {
+        // Pad to a multiple of k so every fragment has the same length
+        let fragment_size = data.len().div_ceil(self.config.data_shards).max(1);
+        let mut shards: Vec<Vec<u8>> = (0..self.config.total_shards)
+            .map(|i| {
+                let start = (i * fragment_size).min(data.len());
+                let end = ((i + 1) * fragment_size).min(data.len());
+                let mut shard = if i < self.config.data_shards {
+                    data[start..end].to_vec()
+                } else {
+                    Vec::new()
+                };
+                shard.resize(fragment_size, 0);
+                shard
+            })
+            .collect();
+
+        self.rs.encode(&mut shards)
+            .map_err(|e| anyhow::anyhow!("Erasure encoding failed: {:?}", e))?;
+
+        let fragments: Vec<Fragment> = shards.into_iter()
+            .enumerate()
+            .map(|(index, data)| Fragment {
+                index,
+                cid: Cid::for_data(&data).to_string(),
+                data,
+            })
+            .collect();
+
+        let manifest = ErasureManifest {
+            file_cid: Cid::for_data(data).to_string(),
+            original_size: data.len() as u64,
+            config: self.config,
+            fragment_size,
+            fragments: fragments.iter()
+                .map(|f| FragmentLocation { index: f.index, cid: f.cid.clone(), node_id: None })
+                .collect(),
+        };
+
+        Ok((manifest, fragments))
+    }
+}
@@ -0,0 +1,55 @@
+// XMBL Leases - who a shard is held for, and for how long
+//
+// Every reference to a shard is a lease taken out by an owner (the node ID
+// that stored it). A lease without an expiry lasts until it is deleted; one
+// with an expiry must be renewed or the garbage collector drops it, and the
+// shard goes once its last lease is gone. Quotas cap the bytes an owner may
+// hold under leases at any time.
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub owner: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    // Bytes charged against the owner's quota for this lease
+    pub size_bytes: u64,
+}
+
+impl Lease {
+    pub fn new(owner: &str, size_bytes: u64, duration_secs: Option<u64>, now: u64) -> Self {
+        Lease {
+            owner: owner.to_string(),
+            created_at: now,
+            expires_at: duration_secs.map(|secs| now.saturating_add(secs)),
+            size_bytes,
+        }
+    }
+
+    // Lease on a chunk or fragment, which lives exactly as long as the
+    // lease on the file it belongs to and is not charged separately
+    pub fn for_child(&self) -> Self {
+        Lease {
+            owner: self.owner.clone(),
+            created_at: self.created_at,
+            expires_at: None,
+            size_bytes: 0,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+
+    pub fn renew(&mut self, duration_secs: Option<u64>, now: u64) {
+        self.expires_at = duration_secs.map(|secs| now.saturating_add(secs));
+    }
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -2,8 +2,27 @@
 
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use std::path::Path;
 use anyhow::Result;
-use uuid::Uuid;
+
+pub mod backend;
+pub mod chunking;
+pub mod cid;
+pub mod encryption;
+pub mod erasure;
+pub mod lease;
+pub mod merkle;
+pub mod proof;
+pub mod repair;
+
+pub use backend::{StorageBackend, MemoryBackend, FsBackend, ShardMeta};
+pub use chunking::{Chunker, Chunk, ChunkRef, FileManifest, DEFAULT_CHUNK_SIZE};
+pub use cid::Cid;
+pub use encryption::{Keyring, FileKey, EncryptionMode};
+pub use lease::Lease;
+pub use proof::{ProofVerifier, ShardCommitment, StorageChallenge, StorageProof};
+pub use repair::{RepairManager, RepairAction, ShardPlacement};
+pub use erasure::{ErasureConfig, ErasureCoder, ErasureManifest, Fragment, FragmentLocation};
 
 // MOCK TYPES
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -24,50 +43,260 @@ pub struct StorageShard {
     pub data: Vec<u8>,
     pub redundancy: u8,
     pub checksum: String,
+    #[serde(default)]
+    pub kind: ShardKind,
+    // Held only as part of another file (a chunk or fragment), not uploaded directly
+    #[serde(default)]
+    pub internal: bool,
+    // Owner, creation time and expiry of each reference to this shard
+    #[serde(default)]
+    pub leases: Vec<Lease>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShardKind {
+    #[default]
+    Data,
+    Fragment,
+    ErasureManifest,
+    FileManifest,
+}
+
+#[derive(Debug)]
 pub struct StorageService {
     pub node_id: String,
-    pub shards: HashMap<String, StorageShard>,
+    pub backend: Box<dyn StorageBackend>,
     pub total_storage_gb: f64,
     pub used_storage_gb: f64,
+    // When set, store_data erasure-codes files instead of storing them whole
+    pub erasure: Option<ErasureConfig>,
+    // Files larger than this are split into chunks behind a FileManifest
+    pub chunk_size: usize,
+    // Lease length for store_data; None keeps shards until deleted
+    pub default_lease_secs: Option<u64>,
+    // Per-owner byte limits, falling back to default_quota_bytes
+    pub quotas: HashMap<String, u64>,
+    pub default_quota_bytes: Option<u64>,
 }
 
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
 impl StorageService {
     pub fn new(node_id: String, total_storage_gb: f64) -> Self {
+        Self::with_backend(node_id, total_storage_gb, Box::new(MemoryBackend::new()))
+    }
+
+    pub fn with_backend(node_id: String, total_storage_gb: f64, backend: Box<dyn StorageBackend>) -> Self {
+        // Backends may already hold shards from a previous run
+        let used_storage_gb = backend.used_bytes() as f64 / BYTES_PER_GB;
+
         StorageService {
             node_id,
-            shards: HashMap::new(),
+            backend,
             total_storage_gb,
-            used_storage_gb: 0.0,
+            used_storage_gb,
+            erasure: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            default_lease_secs: None,
+            quotas: HashMap::new(),
+            default_quota_bytes: None,
         }
     }
+
+    pub fn open(node_id: String, total_storage_gb: f64, data_dir: impl AsRef<Path>) -> Result<Self> {
+        let backend = FsBackend::open(data_dir)?;
+        log::info!("Opened storage at {:?} ({} shards)", backend.root(), backend.list().len());
+        Ok(Self::with_backend(node_id, total_storage_gb, Box::new(backend)))
+    }
     
     pub async fn store_data(&mut self, data: Vec<u8>, redundancy: u8) -> Result<String> {
-        let shard_id = Uuid::new_v4().to_string();
-        let checksum = self.calculate_checksum(&data);
+        let owner = self.node_id.clone();
+        self.store_data_for(&owner, data, redundancy, self.default_lease_secs).await
+    }
+    
+    // Store on behalf of owner, charging the file against their quota under
+    // a lease that expires after lease_secs unless renewed
+    pub async fn store_data_for(&mut self, owner: &str, data: Vec<u8>, redundancy: u8, lease_secs: Option<u64>) -> Result<String> {
+        let lease = self.new_lease(owner, data.len() as u64, lease_secs)?;
+        if data.len() > self.chunk_size {
+            return self.chunk_blob(data, redundancy, lease);
+        }
+        self.store_blob(data, redundancy, false, lease)
+    }
+    
+    // Split the file into fixed-size chunks, each stored under its own CID,
+    // and keep a Merkle-rooted manifest under the CID of the whole file
+    pub async fn store_chunked(&mut self, data: Vec<u8>, redundancy: u8) -> Result<String> {
+        let owner = self.node_id.clone();
+        let lease = self.new_lease(&owner, data.len() as u64, self.default_lease_secs)?;
+        self.chunk_blob(data, redundancy, lease)
+    }
+    
+    fn chunk_blob(&mut self, data: Vec<u8>, redundancy: u8, lease: Lease) -> Result<String> {
+        let (manifest, chunks) = Chunker::new(self.chunk_size)?.split(&data);
+        let file_cid = manifest.file_cid.clone();
+        if self.backend.meta(&file_cid).is_some() {
+            self.put_shard(&file_cid, Vec::new(), redundancy, ShardKind::FileManifest, false, lease)?;
+            return Ok(file_cid);
+        }
+        
+        if self.used_storage_gb + data.len() as f64 / BYTES_PER_GB > self.total_storage_gb {
+            return Err(anyhow::anyhow!("Insufficient storage space"));
+        }
+        drop(data);
+        
+        let mut stored = Vec::new();
+        for chunk in chunks {
+            match self.store_blob(chunk.data, redundancy, true, lease.for_child()) {
+                Ok(id) => stored.push(id),
+                Err(e) => {
+                    // Don't leave half a file behind
+                    for id in stored {
+                        self.release_shard(&id, Some(&lease.for_child()))?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+        self.put_shard(&file_cid, manifest_bytes, redundancy, ShardKind::FileManifest, false, lease)?;
+        Ok(file_cid)
+    }
+    
+    // Encrypt before storing so this node (or any peer it replicates to) only
+    // holds ciphertext. The returned key is the caller's to keep or share.
+    pub async fn store_encrypted(&mut self, data: Vec<u8>, redundancy: u8, keyring: &Keyring, mode: EncryptionMode) -> Result<(String, FileKey)> {
+        let owner = self.node_id.clone();
+        self.store_encrypted_for(&owner, data, redundancy, self.default_lease_secs, keyring, mode).await
+    }
+    
+    // Encrypt before storing and remember the mode in the shard's metadata,
+    // so readers never have to guess from the bytes
+    pub async fn store_encrypted_for(&mut self, owner: &str, data: Vec<u8>, redundancy: u8, lease_secs: Option<u64>, keyring: &Keyring, mode: EncryptionMode) -> Result<(String, FileKey)> {
+        let (blob, file_key) = keyring.encrypt(&data, mode)?;
+        let shard_id = self.store_data_for(owner, blob, redundancy, lease_secs).await?;
+        let mut meta = self.backend.meta(&shard_id)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        meta.encryption = Some(mode);
+        self.backend.update_meta(meta)?;
+        Ok((shard_id, file_key))
+    }
+    
+    pub async fn retrieve_decrypted(&self, shard_id: &str, keyring: &Keyring) -> Result<Vec<u8>> {
+        let meta = self.backend.meta(shard_id)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        if meta.encryption.is_none() {
+            return Err(anyhow::anyhow!("Shard {} was not stored encrypted", shard_id));
+        }
+        keyring.decrypt(&self.read_blob(shard_id)?)
+    }
+    
+    // Split the data into k-of-n Reed-Solomon fragments held as separate
+    // shards, plus a manifest stored under the CID of the original data
+    pub async fn store_encoded(&mut self, data: Vec<u8>, redundancy: u8, config: ErasureConfig) -> Result<String> {
+        let owner = self.node_id.clone();
+        let lease = self.new_lease(&owner, data.len() as u64, self.default_lease_secs)?;
+        self.encode_blob(data, redundancy, config, false, lease)
+    }
+    
+    fn store_blob(&mut self, data: Vec<u8>, redundancy: u8, internal: bool, lease: Lease) -> Result<String> {
+        if let Some(config) = self.erasure {
+            return self.encode_blob(data, redundancy, config, internal, lease);
+        }
+        
+        let shard_id = Cid::for_data(&data).to_string();
+        self.put_shard(&shard_id, data, redundancy, ShardKind::Data, internal, lease)?;
+        Ok(shard_id)
+    }
+    
+    fn encode_blob(&mut self, data: Vec<u8>, redundancy: u8, config: ErasureConfig, internal: bool, lease: Lease) -> Result<String> {
+        let file_cid = Cid::for_data(&data).to_string();
+        if self.backend.meta(&file_cid).is_some() {
+            self.put_shard(&file_cid, Vec::new(), redundancy, ShardKind::ErasureManifest, internal, lease)?;
+            return Ok(file_cid);
+        }
+        
+        let (mut manifest, fragments) = ErasureCoder::new(config)?.encode(&data)?;
+        let encoded_size: usize = fragments.iter().map(|f| f.data.len()).sum();
+        if self.used_storage_gb + encoded_size as f64 / BYTES_PER_GB > self.total_storage_gb {
+            return Err(anyhow::anyhow!("Insufficient storage space"));
+        }
         
+        for fragment in fragments {
+            self.put_shard(&fragment.cid, fragment.data, redundancy, ShardKind::Fragment, true, lease.for_child())?;
+            manifest.place(fragment.index, self.node_id.clone())?;
+        }
+        
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+        self.put_shard(&file_cid, manifest_bytes, redundancy, ShardKind::ErasureManifest, internal, lease)?;
+        Ok(file_cid)
+    }
+    
+    // Store bytes under shard_id, or add a reference if it is already held
+    fn put_shard(&mut self, shard_id: &str, data: Vec<u8>, redundancy: u8, kind: ShardKind, internal: bool, lease: Lease) -> Result<()> {
+        // Identical content is stored once and shared by reference
+        if let Some(mut meta) = self.backend.meta(shard_id) {
+            meta.ref_count += 1;
+            meta.redundancy = meta.redundancy.max(redundancy);
+            meta.internal &= internal;
+            meta.leases.push(lease);
+            return self.backend.update_meta(meta);
+        }
+        
+        let checksum = self.calculate_checksum(&data);
         let shard = StorageShard {
-            shard_id: shard_id.clone(),
+            shard_id: shard_id.to_string(),
             data,
             redundancy,
             checksum,
+            kind,
+            internal,
+            leases: vec![lease],
         };
         
-        let data_size_gb = shard.data.len() as f64 / (1024.0 * 1024.0 * 1024.0);
+        let data_size_gb = shard.data.len() as f64 / BYTES_PER_GB;
         if self.used_storage_gb + data_size_gb > self.total_storage_gb {
             return Err(anyhow::anyhow!("Insufficient storage space"));
         }
         
-        self.shards.insert(shard_id.clone(), shard);
+        self.backend.put(&shard)?;
         self.used_storage_gb += data_size_gb;
         
-        Ok(shard_id)
+        Ok(())
     }
     
     pub async fn retrieve_data(&self, shard_id: &str) -> Result<Vec<u8>> {
-        let shard = self.shards.get(shard_id)
+        self.read_blob(shard_id)
+    }
+    
+    // Read length bytes starting at offset. Chunked files only fetch and
+    // verify the chunks that overlap the range.
+    pub async fn retrieve_range(&self, shard_id: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        if let Some(manifest) = self.get_file_manifest(shard_id)? {
+            manifest.validate()?;
+            // Size the buffer from the range the manifest allows, not the request
+            let ranges = manifest.chunks_for_range(offset, length)?;
+            let mut out = Vec::with_capacity(ranges.iter().map(|(_, range)| range.len()).sum());
+            for (chunk, range) in ranges {
+                let data = self.read_blob(&chunk.cid)?;
+                if data.len() as u64 != chunk.size {
+                    return Err(anyhow::anyhow!("Data corruption detected"));
+                }
+                out.extend_from_slice(&data[range]);
+            }
+            return Ok(out);
+        }
+        
+        let data = self.read_blob(shard_id)?;
+        let end = offset.checked_add(length)
+            .filter(|end| *end <= data.len() as u64)
+            .ok_or_else(|| anyhow::anyhow!("Range {}+{} out of bounds for {} byte file", offset, length, data.len()))?;
+        Ok(data[offset as usize..end as usize].to_vec())
+    }
+    
+    fn read_blob(&self, shard_id: &str) -> Result<Vec<u8>> {
+        let shard = self.backend.get(shard_id)?
             .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
         
         // Verify checksum
@@ -76,17 +305,217 @@ impl StorageService {
             return Err(anyhow::anyhow!("Data corruption detected"));
         }
         
-        Ok(shard.data.clone())
+        let data = match shard.kind {
+            ShardKind::ErasureManifest => {
+                let manifest: ErasureManifest = serde_json::from_slice(&shard.data)?;
+                self.reconstruct(&manifest)?
+            }
+            ShardKind::FileManifest => {
+                let manifest: FileManifest = serde_json::from_slice(&shard.data)?;
+                self.reassemble(&manifest)?
+            }
+            ShardKind::Data | ShardKind::Fragment => shard.data,
+        };
+        
+        // Content must also match the identifier the caller asked for
+        if let Ok(cid) = shard_id.parse::<Cid>() {
+            if !cid.verify(&data) {
+                return Err(anyhow::anyhow!("Data corruption detected"));
+            }
+        }
+        
+        Ok(data)
+    }
+    
+    pub fn get_erasure_manifest(&self, shard_id: &str) -> Result<Option<ErasureManifest>> {
+        match self.backend.get(shard_id)? {
+            Some(shard) if shard.kind == ShardKind::ErasureManifest => {
+                Ok(Some(serde_json::from_slice(&shard.data)?))
+            }
+            _ => Ok(None),
+        }
+    }
+    
+    pub fn get_file_manifest(&self, shard_id: &str) -> Result<Option<FileManifest>> {
+        match self.backend.get(shard_id)? {
+            Some(shard) if shard.kind == ShardKind::FileManifest => {
+                Ok(Some(serde_json::from_slice(&shard.data)?))
+            }
+            _ => Ok(None),
+        }
+    }
+    
+    // Rebuild from whichever fragments this node still holds; any k will do
+    fn reconstruct(&self, manifest: &ErasureManifest) -> Result<Vec<u8>> {
+        let available = manifest.fragments.iter()
+            .map(|location| {
+                self.backend.get(&location.cid)
+                    .unwrap_or_else(|e| {
+                        log::warn!("Failed to read fragment {}: {}", location.cid, e);
+                        None
+                    })
+                    .map(|shard| shard.data)
+            })
+            .collect();
+        manifest.reconstruct(available)
+    }
+    
+    // Concatenate chunks in order, each checked against its CID on the way
+    fn reassemble(&self, manifest: &FileManifest) -> Result<Vec<u8>> {
+        manifest.validate()?;
+        let mut data = Vec::with_capacity(manifest.total_size as usize);
+        for chunk in &manifest.chunks {
+            data.extend(self.read_blob(&chunk.cid)?);
+        }
+        Ok(data)
+    }
+    
+    // Commitment a verifier keeps before handing this shard to another node.
+    // It covers the file content, however this node happens to lay it out.
+    pub fn commitment(&self, shard_id: &str) -> Result<ShardCommitment> {
+        Ok(ShardCommitment::from_data(shard_id, &self.read_blob(shard_id)?))
+    }
+    
+    // Answer a proof-of-storage challenge over the content held for the shard
+    pub fn prove(&self, challenge: &StorageChallenge) -> Result<StorageProof> {
+        proof::prove(challenge, &self.read_blob(&challenge.shard_id)?)
     }
     
     pub async fn delete_data(&mut self, shard_id: &str) -> Result<()> {
-        if let Some(shard) = self.shards.remove(shard_id) {
-            let data_size_gb = shard.data.len() as f64 / (1024.0 * 1024.0 * 1024.0);
-            self.used_storage_gb -= data_size_gb;
+        self.release_shard(shard_id, None)
+    }
+    
+    // Drop one of owner's leases on the shard
+    pub async fn delete_data_for(&mut self, owner: &str, shard_id: &str) -> Result<()> {
+        let meta = self.backend.meta(shard_id)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        let lease = meta.leases.iter().rev()
+            .find(|l| l.owner == owner)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} holds no lease on {}", owner, shard_id))?;
+        self.release_shard(shard_id, Some(&lease))
+    }
+    
+    // Release the given lease, or the most recent one when none is named
+    fn release_shard(&mut self, shard_id: &str, lease: Option<&Lease>) -> Result<()> {
+        let mut meta = match self.backend.meta(shard_id) {
+            Some(meta) => meta,
+            None => return Ok(()),
+        };
+        let position = lease.and_then(|lease| meta.leases.iter().position(|l| l == lease));
+        let released = match position {
+            Some(index) => Some(meta.leases.remove(index)),
+            None => meta.leases.pop(),
+        };
+        
+        // Only free the bytes once the last reference goes away
+        if meta.ref_count > 1 {
+            meta.ref_count -= 1;
+            return self.backend.update_meta(meta);
+        }
+        
+        let children: Vec<String> = match self.backend.get(shard_id)? {
+            Some(shard) if shard.kind == ShardKind::ErasureManifest => {
+                let manifest: ErasureManifest = serde_json::from_slice(&shard.data)?;
+                manifest.fragments.into_iter().map(|f| f.cid).collect()
+            }
+            Some(shard) if shard.kind == ShardKind::FileManifest => {
+                let manifest: FileManifest = serde_json::from_slice(&shard.data)?;
+                manifest.chunks.into_iter().map(|c| c.cid).collect()
+            }
+            _ => Vec::new(),
+        };
+        
+        if let Some(meta) = self.backend.remove(shard_id)? {
+            let data_size_gb = meta.size_bytes as f64 / BYTES_PER_GB;
+            self.used_storage_gb = (self.used_storage_gb - data_size_gb).max(0.0);
+        }
+        
+        let child_lease = released.map(|l| l.for_child());
+        for child in children {
+            self.release_shard(&child, child_lease.as_ref())?;
         }
         Ok(())
     }
     
+    fn new_lease(&self, owner: &str, size_bytes: u64, lease_secs: Option<u64>) -> Result<Lease> {
+        self.check_quota(owner, size_bytes)?;
+        Ok(Lease::new(owner, size_bytes, lease_secs, lease::now_secs()))
+    }
+    
+    pub fn check_quota(&self, owner: &str, size_bytes: u64) -> Result<()> {
+        if let Some(limit) = self.quota_for(owner) {
+            let used = self.owner_usage(owner);
+            if used + size_bytes > limit {
+                return Err(anyhow::anyhow!(
+                    "Quota exceeded for {}: {} of {} bytes used, {} more requested",
+                    owner, used, limit, size_bytes
+                ));
+            }
+        }
+        Ok(())
+    }
+    
+    pub fn set_quota(&mut self, owner: &str, bytes: u64) {
+        self.quotas.insert(owner.to_string(), bytes);
+    }
+    
+    pub fn quota_for(&self, owner: &str) -> Option<u64> {
+        self.quotas.get(owner).copied().or(self.default_quota_bytes)
+    }
+    
+    // Bytes charged to owner across all of their leases
+    pub fn owner_usage(&self, owner: &str) -> u64 {
+        self.backend.list().iter()
+            .flat_map(|meta| meta.leases.iter())
+            .filter(|l| l.owner == owner)
+            .map(|l| l.size_bytes)
+            .sum()
+    }
+    
+    // Push owner's lease on the shard out to lease_secs from now, or make it
+    // permanent with None. Returns the new expiry.
+    pub fn renew_lease(&mut self, shard_id: &str, owner: &str, lease_secs: Option<u64>) -> Result<Option<u64>> {
+        let mut meta = self.backend.meta(shard_id)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))?;
+        if meta.internal {
+            return Err(anyhow::anyhow!("Shard {} is part of another file", shard_id));
+        }
+        let now = lease::now_secs();
+        let lease = meta.leases.iter_mut()
+            .filter(|l| l.owner == owner && !l.is_expired(now))
+            .max_by_key(|l| l.expires_at.unwrap_or(u64::MAX))
+            .ok_or_else(|| anyhow::anyhow!("{} holds no active lease on {}", owner, shard_id))?;
+        lease.renew(lease_secs, now);
+        let expires_at = lease.expires_at;
+        self.backend.update_meta(meta)?;
+        Ok(expires_at)
+    }
+    
+    // Drop every lease that has run out by now and reclaim the shards that
+    // no longer have any. Returns the IDs of the shards removed.
+    pub fn collect_garbage(&mut self, now: u64) -> Result<Vec<String>> {
+        let expired: Vec<(String, Lease)> = self.backend.list().into_iter()
+            .filter(|meta| !meta.internal)
+            .flat_map(|meta| {
+                let shard_id = meta.shard_id;
+                meta.leases.into_iter()
+                    .filter(|l| l.is_expired(now))
+                    .map(move |l| (shard_id.clone(), l))
+            })
+            .collect();
+        
+        let mut reclaimed = Vec::new();
+        for (shard_id, lease) in expired {
+            log::info!("Lease on {} held by {} expired", shard_id, lease.owner);
+            self.release_shard(&shard_id, Some(&lease))?;
+            if self.backend.meta(&shard_id).is_none() {
+                reclaimed.push(shard_id);
+            }
+        }
+        Ok(reclaimed)
+    }
+    
     fn calculate_checksum(&self, data: &[u8]) -> String {
         use sha2::{Sha256, Digest};
         let mut hasher = Sha256::new();
@@ -97,11 +526,37 @@ impl StorageService {
     pub fn get_storage_stats(&self) -> (f64, f64) {
         (self.used_storage_gb, self.total_storage_gb)
     }
+
+    pub fn get_shard_info(&self, shard_id: &str) -> Option<ShardMeta> {
+        self.backend.meta(shard_id)
+    }
+
+    // Size of the file a shard stands for, rather than of its stored bytes
+    pub fn logical_size(&self, shard_id: &str) -> Result<u64> {
+        if let Some(manifest) = self.get_file_manifest(shard_id)? {
+            return Ok(manifest.total_size);
+        }
+        if let Some(manifest) = self.get_erasure_manifest(shard_id)? {
+            return Ok(manifest.original_size);
+        }
+        self.backend.meta(shard_id)
+            .map(|meta| meta.size_bytes)
+            .ok_or_else(|| anyhow::anyhow!("Shard not found"))
+    }
+
+    pub fn list_shards(&self) -> Vec<ShardMeta> {
+        self.backend.list()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.backend.list().len()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use uuid::Uuid;
 
     #[tokio::test]
     async fn test_storage_service_creation() {
@@ -120,4 +575,421 @@ mod tests {
         
         assert_eq!(test_data, retrieved_data);
     }
+
+    #[tokio::test]
+    async fn test_fs_backend_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("xmbl_storage_{}", Uuid::new_v4()));
+        let test_data = b"persist me".to_vec();
+
+        let shard_id = {
+            let mut service = StorageService::open("test_node".to_string(), 100.0, &dir).unwrap();
+            service.store_data(test_data.clone(), 3).await.unwrap()
+        };
+
+        let mut service = StorageService::open("test_node".to_string(), 100.0, &dir).unwrap();
+        assert_eq!(service.retrieve_data(&shard_id).await.unwrap(), test_data);
+        assert!(service.used_storage_gb > 0.0);
+        assert_eq!(service.shard_count(), 1);
+
+        service.delete_data(&shard_id).await.unwrap();
+        let service = StorageService::open("test_node".to_string(), 100.0, &dir).unwrap();
+        assert_eq!(service.shard_count(), 0);
+        assert_eq!(service.used_storage_gb, 0.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fs_backend_recovers_from_partial_write() {
+        let dir = std::env::temp_dir().join(format!("xmbl_storage_{}", Uuid::new_v4()));
+        let shard_id = {
+            let mut service = StorageService::open("test_node".to_string(), 100.0, &dir).unwrap();
+            service.store_data(b"keep".to_vec(), 1).await.unwrap()
+        };
+
+        // Simulate a crash mid-write: a stray temp file and an index entry whose object is gone
+        let bucket = dir.join("objects").join("ab");
+        std::fs::create_dir_all(&bucket).unwrap();
+        std::fs::write(bucket.join("abcd.tmp"), b"junk").unwrap();
+        let mut index: std::collections::HashMap<String, ShardMeta> =
+            serde_json::from_slice(&std::fs::read(dir.join("index.json")).unwrap()).unwrap();
+        index.insert("lost".to_string(), ShardMeta {
+            shard_id: "lost".to_string(),
+            size_bytes: 10,
+            redundancy: 1,
+            checksum: "ff".repeat(32),
+            ref_count: 1,
+            kind: ShardKind::Data,
+            internal: false,
+            leases: Vec::new(),
+            encryption: None,
+        });
+        std::fs::write(dir.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+
+        let service = StorageService::open("test_node".to_string(), 100.0, &dir).unwrap();
+        assert_eq!(service.shard_count(), 1);
+        assert!(service.get_shard_info("lost").is_none());
+        assert_eq!(service.retrieve_data(&shard_id).await.unwrap(), b"keep".to_vec());
+        assert!(!bucket.join("abcd.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shard_id_is_content_address() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        let test_data = b"Hello, World!".to_vec();
+
+        let shard_id = service.store_data(test_data.clone(), 3).await.unwrap();
+        assert!(shard_id.starts_with("bafkrei"));
+
+        let cid: Cid = shard_id.parse().unwrap();
+        assert!(cid.verify(&test_data));
+        assert_eq!(cid.to_string(), shard_id);
+        assert_eq!(cid.digest_hex(), service.get_shard_info(&shard_id).unwrap().checksum);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_uploads_are_reference_counted() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        let test_data = b"same bytes".to_vec();
+
+        let first = service.store_data(test_data.clone(), 1).await.unwrap();
+        let used_after_first = service.used_storage_gb;
+        let second = service.store_data(test_data.clone(), 3).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(service.shard_count(), 1);
+        assert_eq!(service.used_storage_gb, used_after_first);
+        let meta = service.get_shard_info(&first).unwrap();
+        assert_eq!(meta.ref_count, 2);
+        assert_eq!(meta.redundancy, 3);
+
+        service.delete_data(&first).await.unwrap();
+        assert_eq!(service.retrieve_data(&first).await.unwrap(), test_data);
+
+        service.delete_data(&first).await.unwrap();
+        assert!(service.retrieve_data(&first).await.is_err());
+        assert_eq!(service.used_storage_gb, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_erasure_coded_retrieval_survives_lost_fragments() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        service.erasure = Some(ErasureConfig::new(3, 5).unwrap());
+        let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+
+        let file_id = service.store_data(test_data.clone(), 3).await.unwrap();
+        assert_eq!(file_id, Cid::for_data(&test_data).to_string());
+
+        let manifest = service.get_erasure_manifest(&file_id).unwrap().unwrap();
+        assert_eq!(manifest.fragments.len(), 5);
+        assert_eq!(manifest.fragments_on("test_node").len(), 5);
+
+        // Lose any two fragments, 3-of-5 still reconstructs
+        service.backend.remove(&manifest.fragments[0].cid).unwrap();
+        service.backend.remove(&manifest.fragments[3].cid).unwrap();
+        assert_eq!(service.retrieve_data(&file_id).await.unwrap(), test_data);
+
+        service.backend.remove(&manifest.fragments[4].cid).unwrap();
+        assert!(service.retrieve_data(&file_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_erasure_manifest_rejects_corrupt_fragments() {
+        let coder = ErasureCoder::new(ErasureConfig::default()).unwrap();
+        let test_data = b"erasure coded payload spanning several fragments".to_vec();
+        let (manifest, fragments) = coder.encode(&test_data).unwrap();
+
+        let mut available: Vec<Option<Vec<u8>>> = fragments.into_iter().map(|f| Some(f.data)).collect();
+        available[1].as_mut().unwrap()[0] ^= 0xff;
+        available[2] = None;
+        assert_eq!(manifest.reconstruct(available.clone()).unwrap(), test_data);
+
+        available[4] = None;
+        assert!(manifest.reconstruct(available).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deleting_erasure_coded_file_frees_fragments() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        let file_id = service.store_encoded(b"short".to_vec(), 2, ErasureConfig::from_redundancy(2).unwrap()).await.unwrap();
+        assert!(service.shard_count() > 1);
+
+        service.delete_data(&file_id).await.unwrap();
+        assert_eq!(service.shard_count(), 0);
+        assert_eq!(service.used_storage_gb, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_large_files_are_chunked_and_reassembled() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        service.chunk_size = 64;
+        let test_data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 256) as u8).collect();
+
+        let file_id = service.store_data(test_data.clone(), 1).await.unwrap();
+        let manifest = service.get_file_manifest(&file_id).unwrap().unwrap();
+        assert_eq!(manifest.chunks.len(), 16);
+        assert_eq!(manifest.total_size, 1000);
+        manifest.validate().unwrap();
+
+        assert_eq!(service.retrieve_data(&file_id).await.unwrap(), test_data);
+        assert_eq!(service.retrieve_range(&file_id, 60, 200).await.unwrap(), test_data[60..260].to_vec());
+        assert_eq!(service.retrieve_range(&file_id, 990, 10).await.unwrap(), test_data[990..].to_vec());
+        assert!(service.retrieve_range(&file_id, 990, 11).await.is_err());
+        assert!(service.retrieve_range(&file_id, 0, u64::MAX).await.is_err());
+
+        // Chunks are internal and go away with the file
+        let listed: Vec<ShardMeta> = service.list_shards().into_iter().filter(|s| !s.internal).collect();
+        assert_eq!(listed.len(), 1);
+        service.delete_data(&file_id).await.unwrap();
+        assert_eq!(service.shard_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_merkle_proofs_verify_against_manifest_root() {
+        let (manifest, chunks) = Chunker::new(10).unwrap().split(&[42u8; 95]);
+        let root: merkle::Hash = hex::decode(&manifest.merkle_root).unwrap().try_into().unwrap();
+
+        for chunk in &chunks {
+            let proof = manifest.chunk_proof(chunk.index).unwrap();
+            let leaf = merkle::leaf_hash(Cid::for_data(&chunk.data).digest());
+            assert!(merkle::verify_proof(&root, &leaf, &proof));
+        }
+
+        let wrong_leaf = merkle::leaf_hash(b"not a chunk");
+        assert!(!merkle::verify_proof(&root, &wrong_leaf, &manifest.chunk_proof(3).unwrap()));
+
+        // An interior node's preimage is not a leaf, even with an empty path
+        let leaves = vec![merkle::leaf_hash(b"a"), merkle::leaf_hash(b"b")];
+        let tree = merkle::MerkleTree::new(leaves.clone());
+        let interior = [&[0x01u8][..], &leaves[0], &leaves[1]].concat();
+        assert!(!merkle::verify_proof(&tree.root(), &merkle::leaf_hash(&interior), &[]));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage_round_trip() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        let keyring = Keyring::new([7u8; 32]);
+        let secret = b"customer records".to_vec();
+
+        let (shard_id, file_key) = service.store_encrypted(secret.clone(), 3, &keyring, EncryptionMode::PerFile).await.unwrap();
+
+        assert_eq!(service.get_shard_info(&shard_id).unwrap().encryption, Some(EncryptionMode::PerFile));
+        let stored = service.retrieve_data(&shard_id).await.unwrap();
+        assert!(encryption::is_encrypted(&stored));
+        assert!(!stored.windows(secret.len()).any(|w| w == secret.as_slice()));
+        let raw_key = hex::decode(file_key.to_hex()).unwrap();
+        assert!(!stored.windows(32).any(|w| w == raw_key.as_slice()));
+
+        assert_eq!(service.retrieve_decrypted(&shard_id, &keyring).await.unwrap(), secret);
+        assert_eq!(encryption::decrypt_with_key(&file_key, &stored).unwrap(), secret);
+        assert!(service.retrieve_decrypted(&shard_id, &Keyring::new([8u8; 32])).await.is_err());
+
+        let plain = service.store_data(b"public notes".to_vec(), 1).await.unwrap();
+        assert!(service.get_shard_info(&plain).unwrap().encryption.is_none());
+        assert!(service.retrieve_decrypted(&plain, &keyring).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_convergent_encryption_preserves_deduplication() {
+        let mut service = StorageService::new("test_node".to_string(), 100.0);
+        let keyring = Keyring::new([7u8; 32]);
+
+        let (first, _) = service.store_encrypted(b"dup".to_vec(), 1, &keyring, EncryptionMode::Convergent).await.unwrap();
+        let (second, _) = service.store_encrypted(b"dup".to_vec(), 1, &keyring, EncryptionMode::Convergent).await.unwrap();
+        let (third, _) = service.store_encrypted(b"dup".to_vec(), 1, &keyring, EncryptionMode::PerFile).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_ne!(first, third);
+        assert_eq!(service.get_shard_info(&first).unwrap().ref_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_storage_challenge_round_trip() {
+        let mut holder = StorageService::new("holder".to_string(), 100.0);
+        let test_data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        let shard_id = holder.store_data(test_data.clone(), 1).await.unwrap();
+
+        let mut verifier = ProofVerifier::new();
+        verifier.register(ShardCommitment::from_data(&shard_id, &test_data), "holder");
+
+        for _ in 0..5 {
+            let challenge = verifier.issue_challenge(&shard_id, "holder").unwrap();
+            let proof = holder.prove(&challenge).unwrap();
+            assert!(verifier.verify(&proof));
+        }
+        assert_eq!(verifier.stats("holder").passed, 5);
+        assert!(verifier.failing_nodes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failing_storage_challenges_flag_node_for_replication() {
+        let test_data = vec![9u8; 3000];
+        let shard_id = Cid::for_data(&test_data).to_string();
+        let mut verifier = ProofVerifier::new();
+        verifier.register(ShardCommitment::from_data(&shard_id, &test_data), "cheater");
+
+        // Holding different bytes cannot satisfy the commitment
+        let forged = vec![8u8; 3000];
+        for _ in 0..proof::MAX_CONSECUTIVE_FAILURES {
+            let challenge = verifier.issue_challenge(&shard_id, "cheater").unwrap();
+            let proof = proof::prove(&challenge, &forged).unwrap();
+            assert!(!verifier.verify(&proof));
+        }
+        assert_eq!(verifier.failing_nodes(), vec!["cheater".to_string()]);
+        assert_eq!(verifier.shards_needing_replication(), vec![shard_id.clone()]);
+
+        // One valid proof resets the streak and clears the flag
+        let challenge = verifier.issue_challenge(&shard_id, "cheater").unwrap();
+        let proof = proof::prove(&challenge, &test_data).unwrap();
+        assert!(verifier.verify(&proof));
+        assert!(verifier.failing_nodes().is_empty());
+
+        // Unknown or replayed proofs are rejected outright
+        assert!(!verifier.verify(&proof));
+
+        assert_eq!(verifier.stats("cheater").failed, proof::MAX_CONSECUTIVE_FAILURES as u64);
+        assert_eq!(verifier.history().len(), proof::MAX_CONSECUTIVE_FAILURES as usize + 1);
+    }
+
+    #[test]
+    fn test_repair_plans_replicas_for_offline_holders() {
+        let mut repair = RepairManager::new();
+        let nodes: Vec<String> = (1..=4).map(|i| format!("node_{}", i)).collect();
+        for node in &nodes {
+            repair.record_heartbeat(node, 100);
+        }
+
+        repair.track("shard_a", 3);
+        for node in &nodes[..3] {
+            repair.record_replica("shard_a", node);
+        }
+        assert!(repair.plan(110, &nodes).is_empty());
+
+        // node_1 goes quiet, node_2 fails its proofs
+        for node in &nodes[1..] {
+            repair.record_heartbeat(node, 140);
+        }
+        repair.mark_unhealthy("node_2");
+        assert_eq!(repair.under_replicated(140), vec!["shard_a".to_string()]);
+
+        match &repair.plan(140, &nodes)[..] {
+            [RepairAction::Replicate { shard_id, sources, targets }] => {
+                assert_eq!(shard_id, "shard_a");
+                assert_eq!(sources, &vec!["node_3".to_string()]);
+                assert_eq!(targets, &vec!["node_4".to_string()]);
+            }
+            other => panic!("unexpected plan: {:?}", other),
+        }
+
+        // Answering heartbeats doesn't clear node_2 until its shard is restored
+        repair.record_heartbeat("node_2", 150);
+        assert!(!repair.is_healthy("node_2", 150));
+        for node in ["node_1", "node_3", "node_4"] {
+            repair.record_heartbeat(node, 150);
+        }
+        repair.record_replica("shard_a", "node_4");
+        repair.record_heartbeat("node_2", 150);
+        assert!(repair.is_healthy("node_2", 150));
+    }
+
+    #[test]
+    fn test_repair_regenerates_lost_erasure_fragments() {
+        let test_data = b"fragments scattered over the swarm".to_vec();
+        let (mut manifest, fragments) = ErasureCoder::new(ErasureConfig::default()).unwrap().encode(&test_data).unwrap();
+        let nodes: Vec<String> = (1..=6).map(|i| format!("node_{}", i)).collect();
+        for (i, node) in nodes.iter().enumerate().take(5) {
+            manifest.place(i, node.clone()).unwrap();
+        }
+
+        let mut repair = RepairManager::new();
+        repair.track_erasure(manifest);
+        for node in nodes.iter().filter(|n| *n != "node_2") {
+            repair.record_heartbeat(node, 100);
+        }
+
+        let (manifest, missing, targets) = match repair.plan(100, &nodes).pop() {
+            Some(RepairAction::Reencode { manifest, missing, targets }) => (manifest, missing, targets),
+            other => panic!("unexpected plan: {:?}", other),
+        };
+        assert_eq!(missing, vec![1]);
+        assert_eq!(targets, vec!["node_6".to_string()]);
+
+        let available = fragments.iter().map(|f| if f.index == 1 { None } else { Some(f.data.clone()) }).collect();
+        let rebuilt = manifest.regenerate(available, &missing).unwrap();
+        assert_eq!(rebuilt[0].data, fragments[1].data);
+
+        repair.record_fragment(&manifest.file_cid, 1, "node_6");
+        assert!(repair.under_replicated(100).is_empty());
+    }
+
+    #[test]
+    fn test_refused_renewals_drop_the_holder() {
+        let (mut manifest, _) = ErasureCoder::new(ErasureConfig::default()).unwrap().encode(b"leased fragments").unwrap();
+        manifest.place(0, "node_1".to_string()).unwrap();
+        let fragment_cid = manifest.fragments[0].cid.clone();
+
+        let mut repair = RepairManager::new();
+        repair.track("shard_a", 1);
+        repair.record_replica("shard_a", "node_2");
+        repair.track_erasure(manifest);
+        let mut held = repair.held_shards();
+        held.sort();
+        let mut expected = vec![
+            (fragment_cid.clone(), "node_1".to_string()),
+            ("shard_a".to_string(), "node_2".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(held, expected);
+
+        repair.remove_replica("shard_a", "node_2");
+        repair.remove_replica(&fragment_cid, "node_1");
+        assert!(repair.held_shards().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_owner_quota_enforced() {
+        let mut storage = StorageService::new("test_node".to_string(), 1.0);
+        storage.set_quota("alice", 100);
+
+        let first = storage.store_data_for("alice", vec![1u8; 60], 1, None).await.unwrap();
+        assert_eq!(storage.owner_usage("alice"), 60);
+        assert!(storage.store_data_for("alice", vec![2u8; 60], 1, None).await.is_err());
+
+        // Other owners are unaffected, and deleting frees alice's quota
+        storage.store_data_for("bob", vec![2u8; 60], 1, None).await.unwrap();
+        assert!(storage.delete_data_for("bob", &first).await.is_err());
+        storage.delete_data_for("alice", &first).await.unwrap();
+        assert_eq!(storage.owner_usage("alice"), 0);
+        storage.store_data_for("alice", vec![2u8; 60], 1, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_leases_are_garbage_collected() {
+        let mut storage = StorageService::new("test_node".to_string(), 1.0);
+        storage.chunk_size = 16;
+        let data: Vec<u8> = (0..100u8).collect();
+
+        let shard_id = storage.store_data_for("alice", data.clone(), 1, Some(60)).await.unwrap();
+        storage.store_data_for("bob", data.clone(), 1, Some(3600)).await.unwrap();
+        let info = storage.get_shard_info(&shard_id).unwrap();
+        assert_eq!(info.owners(), vec!["alice".to_string(), "bob".to_string()]);
+
+        // Alice's lease runs out first, bob's still holds the file
+        let now = lease::now_secs();
+        assert!(storage.collect_garbage(now + 120).unwrap().is_empty());
+        assert_eq!(storage.get_shard_info(&shard_id).unwrap().owners(), vec!["bob".to_string()]);
+        assert!(storage.renew_lease(&shard_id, "alice", Some(60)).is_err());
+
+        // Renewing pushes bob's expiry past the next sweep
+        let expires_at = storage.renew_lease(&shard_id, "bob", Some(7200)).unwrap().unwrap();
+        assert!(expires_at >= now + 7200);
+        assert!(storage.collect_garbage(now + 3700).unwrap().is_empty());
+        assert_eq!(storage.retrieve_data(&shard_id).await.unwrap(), data);
+
+        assert_eq!(storage.collect_garbage(expires_at).unwrap(), vec![shard_id]);
+        assert_eq!(storage.shard_count(), 0);
+        assert_eq!(storage.get_storage_stats().0, 0.0);
+    }
 }
@@ -0,0 +1,107 @@
+// XMBL Merkle Trees over sha256 leaves
+//
+// Leaves hash sha256(0x00 || data) and interior nodes sha256(0x01 || left ||
+// right), so leaf data can never pass for an interior node. A node without
+// a sibling is carried up to the next level unchanged, so no leaf is ever
+// duplicated and proofs stay unambiguous.
+
+use sha2::{Sha256, Digest};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// One step from a leaf towards the root: the sibling hash and which side it sits on
+pub use xmbl_types::{ProofStep, Side};
+
+#[derive(Clone, Debug)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().map(|l| l.len() > 1).unwrap_or(false) {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last()
+            .and_then(|l| l.first().copied())
+            .unwrap_or_else(|| Sha256::digest([]).into())
+    }
+
+    pub fn root_hex(&self) -> String {
+        hex::encode(self.root())
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn proof(&self, index: usize) -> Option<Vec<ProofStep>> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if sibling < level.len() {
+                proof.push(ProofStep {
+                    sibling: hex::encode(level[sibling]),
+                    side: if sibling < position { Side::Left } else { Side::Right },
+                });
+            }
+            position /= 2;
+        }
+        Some(proof)
+    }
+}
+
+pub fn verify_proof(root: &Hash, leaf: &Hash, proof: &[ProofStep]) -> bool {
+    let mut current = *leaf;
+    for step in proof {
+        let sibling: Hash = match hex::decode(&step.sibling).ok().and_then(|b| b.try_into().ok()) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        current = match step.side {
+            Side::Left => node_hash(&sibling, &current),
+            Side::Right => node_hash(&current, &sibling),
+        };
+    }
+    current == *root
+}
+
+pub fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
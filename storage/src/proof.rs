@@ -0,0 +1,266 @@
+// XMBL Proof of Storage - random-segment Merkle challenges
+//
+// Before handing a shard to a peer the uploader computes a commitment: the
+// Merkle root over fixed-size segments of the shard. Later it challenges the
+// peer with a random segment index and a fresh nonce. The peer must return
+// that segment, its Merkle path and sha256(nonce || segment). Without the
+// bytes it cannot answer, and the verifier only needs the 32-byte root.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use anyhow::Result;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::lease::now_secs;
+use crate::merkle::{self, MerkleTree};
+pub use xmbl_types::{StorageChallenge, StorageProof};
+
+pub const SEGMENT_SIZE: usize = 1024;
+pub const CHALLENGE_TIMEOUT_SECS: u64 = 30;
+// Consecutive failures after which a node's shards should be re-replicated
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShardCommitment {
+    pub shard_id: String,
+    pub merkle_root: String,
+    pub segment_count: usize,
+    pub segment_size: usize,
+}
+
+impl ShardCommitment {
+    pub fn from_data(shard_id: &str, data: &[u8]) -> Self {
+        let tree = segment_tree(data, SEGMENT_SIZE);
+        ShardCommitment {
+            shard_id: shard_id.to_string(),
+            merkle_root: tree.root_hex(),
+            segment_count: tree.leaf_count(),
+            segment_size: SEGMENT_SIZE,
+        }
+    }
+}
+
+// Answer a challenge from the raw bytes of the shard
+pub fn prove(challenge: &StorageChallenge, data: &[u8]) -> Result<StorageProof> {
+    if challenge.segment_size == 0 {
+        return Err(anyhow::anyhow!("Invalid segment size"));
+    }
+    let tree = segment_tree(data, challenge.segment_size);
+    let merkle_path = tree.proof(challenge.segment_index)
+        .ok_or_else(|| anyhow::anyhow!("Segment {} out of range", challenge.segment_index))?;
+    let segment = segment_at(data, challenge.segment_size, challenge.segment_index).to_vec();
+
+    Ok(StorageProof {
+        challenge_id: challenge.challenge_id.clone(),
+        shard_id: challenge.shard_id.clone(),
+        segment_index: challenge.segment_index,
+        response: challenge_response(&challenge.nonce, &segment),
+        segment,
+        merkle_path,
+    })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProofRecord {
+    pub node_id: String,
+    pub shard_id: String,
+    pub challenge_id: String,
+    pub passed: bool,
+    pub reason: Option<String>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeProofStats {
+    pub passed: u64,
+    pub failed: u64,
+    pub consecutive_failures: u32,
+}
+
+// Tracks what each peer has promised to hold, issues challenges and records
+// the outcome so failing peers can be penalized
+#[derive(Debug, Default)]
+pub struct ProofVerifier {
+    commitments: HashMap<String, ShardCommitment>,
+    holders: HashMap<String, Vec<String>>,
+    pending: HashMap<String, (String, StorageChallenge)>,
+    stats: HashMap<String, NodeProofStats>,
+    history: Vec<ProofRecord>,
+}
+
+impl ProofVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, commitment: ShardCommitment, node_id: &str) {
+        let holders = self.holders.entry(commitment.shard_id.clone()).or_default();
+        if !holders.iter().any(|h| h == node_id) {
+            holders.push(node_id.to_string());
+        }
+        self.commitments.insert(commitment.shard_id.clone(), commitment);
+    }
+
+    pub fn unregister(&mut self, shard_id: &str, node_id: &str) {
+        if let Some(holders) = self.holders.get_mut(shard_id) {
+            holders.retain(|h| h != node_id);
+        }
+    }
+
+    pub fn holders(&self, shard_id: &str) -> Vec<String> {
+        self.holders.get(shard_id).cloned().unwrap_or_default()
+    }
+
+    pub fn shards_held_by(&self, node_id: &str) -> Vec<String> {
+        self.holders.iter()
+            .filter(|(_, holders)| holders.iter().any(|h| h == node_id))
+            .map(|(shard_id, _)| shard_id.clone())
+            .collect()
+    }
+
+    pub fn issue_challenge(&mut self, shard_id: &str, node_id: &str) -> Result<StorageChallenge> {
+        let commitment = self.commitments.get(shard_id)
+            .ok_or_else(|| anyhow::anyhow!("No commitment for shard {}", shard_id))?;
+
+        let mut rng = rand::thread_rng();
+        let nonce: [u8; 32] = rng.gen();
+        let challenge = StorageChallenge {
+            challenge_id: Uuid::new_v4().to_string(),
+            shard_id: shard_id.to_string(),
+            segment_index: rng.gen_range(0..commitment.segment_count.max(1)),
+            segment_size: commitment.segment_size,
+            nonce: hex::encode(nonce),
+            issued_at: now_secs(),
+        };
+
+        self.pending.insert(challenge.challenge_id.clone(), (node_id.to_string(), challenge.clone()));
+        Ok(challenge)
+    }
+
+    pub fn verify(&mut self, proof: &StorageProof) -> bool {
+        let (node_id, challenge) = match self.pending.remove(&proof.challenge_id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let result = self.check(&challenge, proof);
+        let passed = result.is_ok();
+        self.record(&node_id, &challenge, result.err());
+        passed
+    }
+
+    // A peer that could not be reached or refused to answer fails the challenge
+    pub fn record_failure(&mut self, challenge_id: &str, reason: &str) {
+        if let Some((node_id, challenge)) = self.pending.remove(challenge_id) {
+            self.record(&node_id, &challenge, Some(reason.to_string()));
+        }
+    }
+
+    // Challenges nobody answered in time count as failures
+    pub fn expire_pending(&mut self) {
+        let now = now_secs();
+        let expired: Vec<String> = self.pending.iter()
+            .filter(|(_, (_, c))| now.saturating_sub(c.issued_at) > CHALLENGE_TIMEOUT_SECS)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for challenge_id in expired {
+            self.record_failure(&challenge_id, "Challenge timed out");
+        }
+    }
+
+    pub fn stats(&self, node_id: &str) -> NodeProofStats {
+        self.stats.get(node_id).cloned().unwrap_or_default()
+    }
+
+    pub fn history(&self) -> &[ProofRecord] {
+        &self.history
+    }
+
+    // Nodes whose last MAX_CONSECUTIVE_FAILURES challenges all failed; a
+    // single passed challenge takes a node off the list again
+    pub fn failing_nodes(&self) -> Vec<String> {
+        self.stats.iter()
+            .filter(|(_, s)| s.consecutive_failures >= MAX_CONSECUTIVE_FAILURES)
+            .map(|(node_id, _)| node_id.clone())
+            .collect()
+    }
+
+    // Shards with at least one holder that keeps failing its challenges
+    pub fn shards_needing_replication(&self) -> Vec<String> {
+        let failing = self.failing_nodes();
+        let mut shards: Vec<String> = failing.iter()
+            .flat_map(|node_id| self.shards_held_by(node_id))
+            .collect();
+        shards.sort();
+        shards.dedup();
+        shards
+    }
+
+    fn check(&self, challenge: &StorageChallenge, proof: &StorageProof) -> Result<(), String> {
+        let commitment = self.commitments.get(&challenge.shard_id)
+            .ok_or("Commitment no longer tracked")?;
+        if now_secs().saturating_sub(challenge.issued_at) > CHALLENGE_TIMEOUT_SECS {
+            return Err("Proof arrived after the deadline".to_string());
+        }
+        if proof.shard_id != challenge.shard_id || proof.segment_index != challenge.segment_index {
+            return Err("Proof does not answer the challenge".to_string());
+        }
+        if proof.response != challenge_response(&challenge.nonce, &proof.segment) {
+            return Err("Challenge response mismatch".to_string());
+        }
+
+        let root: merkle::Hash = hex::decode(&commitment.merkle_root).ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or("Invalid commitment root")?;
+        if !merkle::verify_proof(&root, &merkle::leaf_hash(&proof.segment), &proof.merkle_path) {
+            return Err("Merkle proof does not match commitment".to_string());
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, node_id: &str, challenge: &StorageChallenge, failure: Option<String>) {
+        let stats = self.stats.entry(node_id.to_string()).or_default();
+        match &failure {
+            None => {
+                stats.passed += 1;
+                stats.consecutive_failures = 0;
+            }
+            Some(reason) => {
+                stats.failed += 1;
+                stats.consecutive_failures += 1;
+                log::warn!("Node {} failed storage challenge for {}: {}", node_id, challenge.shard_id, reason);
+            }
+        }
+
+        self.history.push(ProofRecord {
+            node_id: node_id.to_string(),
+            shard_id: challenge.shard_id.clone(),
+            challenge_id: challenge.challenge_id.clone(),
+            passed: failure.is_none(),
+            reason: failure,
+            timestamp: now_secs(),
+        });
+    }
+}
+
+fn segment_tree(data: &[u8], segment_size: usize) -> MerkleTree {
+    let leaves = if data.is_empty() {
+        vec![merkle::leaf_hash(&[])]
+    } else {
+        data.chunks(segment_size).map(merkle::leaf_hash).collect()
+    };
+    MerkleTree::new(leaves)
+}
+
+fn segment_at(data: &[u8], segment_size: usize, index: usize) -> &[u8] {
+    let start = (index * segment_size).min(data.len());
+    let end = (start + segment_size).min(data.len());
+    &data[start..end]
+}
+
+fn challenge_response(nonce: &str, segment: &[u8]) -> String {
+    let mut input = nonce.as_bytes().to_vec();
+    input.extend_from_slice(segment);
+    hex::encode(merkle::sha256(&input))
+}
@@ -0,0 +1,246 @@
+// XMBL Repair - keep every file at its requested redundancy
+//
+// The repair manager tracks which nodes hold each shard (or each fragment of
+// an erasure-coded file) and when each node was last heard from. Nodes that
+// miss heartbeats, or that are flagged by failed storage proofs, stop
+// counting as holders. plan() then lists the work needed to get back to the
+// target: copy whole replicas onto healthy peers, or rebuild lost fragments
+// from any k survivors and place the regenerated fragments elsewhere.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::ErasureManifest;
+
+pub const HEARTBEAT_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShardPlacement {
+    pub shard_id: String,
+    pub target_redundancy: u8,
+    pub holders: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum RepairAction {
+    // Copy the shard from one of the sources onto each target
+    Replicate {
+        shard_id: String,
+        sources: Vec<String>,
+        targets: Vec<String>,
+    },
+    // Rebuild the missing fragment indices and place one on each target
+    Reencode {
+        manifest: ErasureManifest,
+        missing: Vec<usize>,
+        targets: Vec<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct RepairManager {
+    placements: HashMap<String, ShardPlacement>,
+    erasure_files: HashMap<String, ErasureManifest>,
+    last_seen: HashMap<String, u64>,
+    unhealthy: HashSet<String>,
+}
+
+impl RepairManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(&mut self, shard_id: &str, target_redundancy: u8) {
+        let placement = self.placements.entry(shard_id.to_string())
+            .or_insert_with(|| ShardPlacement {
+                shard_id: shard_id.to_string(),
+                target_redundancy,
+                holders: Vec::new(),
+            });
+        placement.target_redundancy = placement.target_redundancy.max(target_redundancy);
+    }
+
+    pub fn untrack(&mut self, shard_id: &str) {
+        self.placements.remove(shard_id);
+        self.erasure_files.remove(shard_id);
+    }
+
+    pub fn record_replica(&mut self, shard_id: &str, node_id: &str) {
+        if let Some(placement) = self.placements.get_mut(shard_id) {
+            if !placement.holders.iter().any(|h| h == node_id) {
+                placement.holders.push(node_id.to_string());
+            }
+        }
+    }
+
+    // shard_id may also be an erasure fragment's CID, which then counts as
+    // missing until it is rebuilt somewhere
+    pub fn remove_replica(&mut self, shard_id: &str, node_id: &str) {
+        if let Some(placement) = self.placements.get_mut(shard_id) {
+            placement.holders.retain(|h| h != node_id);
+        }
+        for fragment in self.erasure_files.values_mut().flat_map(|m| m.fragments.iter_mut()) {
+            if fragment.cid == shard_id && fragment.node_id.as_deref() == Some(node_id) {
+                fragment.node_id = None;
+            }
+        }
+    }
+
+    // Erasure-coded files are tracked by their manifest, whose fragment
+    // locations say where each piece lives
+    pub fn track_erasure(&mut self, manifest: ErasureManifest) {
+        self.erasure_files.insert(manifest.file_cid.clone(), manifest);
+    }
+
+    pub fn record_fragment(&mut self, file_cid: &str, index: usize, node_id: &str) {
+        if let Some(manifest) = self.erasure_files.get_mut(file_cid) {
+            let _ = manifest.place(index, node_id.to_string());
+        }
+    }
+
+    pub fn placement(&self, shard_id: &str) -> Option<&ShardPlacement> {
+        self.placements.get(shard_id)
+    }
+
+    pub fn erasure_manifest(&self, file_cid: &str) -> Option<&ErasureManifest> {
+        self.erasure_files.get(file_cid)
+    }
+
+    // Every (shard, holder) pair this manager placed, whole replicas and
+    // erasure fragments alike; the leases on them are ours to renew
+    pub fn held_shards(&self) -> Vec<(String, String)> {
+        let replicas = self.placements.values()
+            .flat_map(|p| p.holders.iter().map(move |h| (p.shard_id.clone(), h.clone())));
+        let fragments = self.erasure_files.values()
+            .flat_map(|m| m.fragments.iter())
+            .filter_map(|f| f.node_id.clone().map(|node| (f.cid.clone(), node)));
+        replicas.chain(fragments).collect()
+    }
+
+    // A flagged node stays flagged while anything it held is still waiting
+    // to be restored elsewhere, however often it answers heartbeats
+    pub fn record_heartbeat(&mut self, node_id: &str, now: u64) {
+        self.last_seen.insert(node_id.to_string(), now);
+        if !self.repair_pending_on(node_id, now) {
+            self.unhealthy.remove(node_id);
+        }
+    }
+
+    // Used for nodes that are reachable but misbehaving, e.g. failing proofs
+    pub fn mark_unhealthy(&mut self, node_id: &str) {
+        self.unhealthy.insert(node_id.to_string());
+    }
+
+    pub fn is_healthy(&self, node_id: &str, now: u64) -> bool {
+        if self.unhealthy.contains(node_id) {
+            return false;
+        }
+        self.last_seen.get(node_id)
+            .map(|seen| now.saturating_sub(*seen) <= HEARTBEAT_TIMEOUT_SECS)
+            .unwrap_or(false)
+    }
+
+    pub fn healthy_holders(&self, shard_id: &str, now: u64) -> Vec<String> {
+        self.placements.get(shard_id)
+            .map(|p| p.holders.iter().filter(|h| self.is_healthy(h, now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn under_replicated(&self, now: u64) -> Vec<String> {
+        let mut shards: Vec<String> = self.placements.values()
+            .filter(|p| self.healthy_holders(&p.shard_id, now).len() < p.target_redundancy as usize)
+            .map(|p| p.shard_id.clone())
+            .collect();
+        shards.extend(self.erasure_files.values()
+            .filter(|m| !self.missing_fragments(m, now).is_empty())
+            .map(|m| m.file_cid.clone()));
+        shards
+    }
+
+    // Work needed to restore every tracked file, using healthy candidates
+    // that don't already hold a copy as targets
+    pub fn plan(&self, now: u64, candidates: &[String]) -> Vec<RepairAction> {
+        let healthy: Vec<&String> = candidates.iter()
+            .filter(|c| self.is_healthy(c, now))
+            .collect();
+        let mut actions = Vec::new();
+
+        for placement in self.placements.values() {
+            let sources = self.healthy_holders(&placement.shard_id, now);
+            let needed = (placement.target_redundancy as usize).saturating_sub(sources.len());
+            if needed == 0 {
+                continue;
+            }
+            if sources.is_empty() {
+                log::error!("Shard {} has no healthy replicas left to repair from", placement.shard_id);
+                continue;
+            }
+
+            let targets: Vec<String> = healthy.iter()
+                .filter(|c| !placement.holders.contains(c))
+                .take(needed)
+                .map(|c| c.to_string())
+                .collect();
+            if !targets.is_empty() {
+                actions.push(RepairAction::Replicate {
+                    shard_id: placement.shard_id.clone(),
+                    sources,
+                    targets,
+                });
+            }
+        }
+
+        for manifest in self.erasure_files.values() {
+            let missing = self.missing_fragments(manifest, now);
+            if missing.is_empty() {
+                continue;
+            }
+            let surviving = manifest.fragments.len() - missing.len();
+            if surviving < manifest.config.data_shards {
+                log::error!("File {} has only {} fragments left, cannot rebuild", manifest.file_cid, surviving);
+                continue;
+            }
+
+            // Spread fragments: prefer nodes that don't hold a piece of this file yet
+            let in_use: HashSet<&str> = manifest.fragments.iter()
+                .filter_map(|f| f.node_id.as_deref())
+                .filter(|n| self.is_healthy(n, now))
+                .collect();
+            let mut pool: Vec<String> = healthy.iter()
+                .filter(|c| !in_use.contains(c.as_str()))
+                .map(|c| c.to_string())
+                .collect();
+            if pool.is_empty() {
+                pool = healthy.iter().map(|c| c.to_string()).collect();
+            }
+            if pool.is_empty() {
+                continue;
+            }
+
+            let targets = (0..missing.len()).map(|i| pool[i % pool.len()].clone()).collect();
+            actions.push(RepairAction::Reencode {
+                manifest: manifest.clone(),
+                missing,
+                targets,
+            });
+        }
+
+        actions
+    }
+
+    fn repair_pending_on(&self, node_id: &str, now: u64) -> bool {
+        let replicas = self.placements.values()
+            .filter(|p| p.holders.iter().any(|h| h == node_id))
+            .any(|p| self.healthy_holders(&p.shard_id, now).len() < p.target_redundancy as usize);
+        replicas || self.erasure_files.values()
+            .filter(|m| !m.fragments_on(node_id).is_empty())
+            .any(|m| !self.missing_fragments(m, now).is_empty())
+    }
+
+    fn missing_fragments(&self, manifest: &ErasureManifest, now: u64) -> Vec<usize> {
+        manifest.fragments.iter()
+            .filter(|f| !f.node_id.as_deref().map(|n| self.is_healthy(n, now)).unwrap_or(false))
+            .map(|f| f.index)
+            .collect()
+    }
+}
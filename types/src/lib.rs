@@ -0,0 +1,211 @@
+// XMBL Types - payloads shared by the subsystems and the protocol
+//
+// The protocol carries storage proofs, compute policies and node
+// capabilities without needing the crates that produce them, so the types
+// live here and those crates re-export them. Only plain data belongs here;
+// anything that needs a subsystem's machinery stays in the subsystem.
+
+use serde::{Serialize, Deserialize};
+
+// Storage
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// One step from a leaf towards the root: the sibling hash and which side it sits on
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub sibling: String,
+    pub side: Side,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageChallenge {
+    pub challenge_id: String,
+    pub shard_id: String,
+    pub segment_index: usize,
+    pub segment_size: usize,
+    pub nonce: String,
+    pub issued_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageProof {
+    pub challenge_id: String,
+    pub shard_id: String,
+    pub segment_index: usize,
+    pub segment: Vec<u8>,
+    pub merkle_path: Vec<ProofStep>,
+    pub response: String,
+}
+
+// Compute
+
+// Party headers come from peers, so keep what they can ask for in reach
+pub const MAX_PARTIES: usize = 16;
+pub const MAX_ROUNDS: u32 = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MpcConfig {
+    pub parties: usize,
+    pub rounds: u32,
+}
+
+impl MpcConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.parties < 2 {
+            return Err(format!("MPC needs at least 2 parties, got {}", self.parties));
+        }
+        if self.parties > MAX_PARTIES {
+            return Err(format!("MPC is limited to {} parties, got {}", MAX_PARTIES, self.parties));
+        }
+        if self.rounds == 0 {
+            return Err("MPC needs at least 1 round".to_string());
+        }
+        if self.rounds > MAX_ROUNDS {
+            return Err(format!("MPC is limited to {} rounds, got {}", MAX_ROUNDS, self.rounds));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MpcConfig {
+    fn default() -> Self {
+        MpcConfig { parties: 3, rounds: 1 }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TaskType {
+    #[default]
+    WASM,
+    // One party's round of an MPC computation, see mpc
+    MPC,
+    Batch,
+    RealTime,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExecutionProfile {
+    #[default]
+    Standard,
+    Deterministic,
+}
+
+// Most peers one verified compute run fans out to
+pub const MAX_VERIFICATION_REPLICAS: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationPolicy {
+    pub replicas: usize,
+    pub quorum: usize,
+}
+
+impl VerificationPolicy {
+    pub fn majority(replicas: usize) -> Self {
+        VerificationPolicy {
+            replicas,
+            quorum: replicas / 2 + 1,
+        }
+    }
+
+    // A quorum must be a strict majority of the replicas, so two hashes can
+    // never both reach it, and a coordinator fans out to at most
+    // MAX_VERIFICATION_REPLICAS peers
+    pub fn validate(&self) -> Result<(), String> {
+        if self.replicas == 0 || self.replicas > MAX_VERIFICATION_REPLICAS {
+            return Err(format!("replicas must be between 1 and {}, not {}", MAX_VERIFICATION_REPLICAS, self.replicas));
+        }
+        if self.quorum <= self.replicas / 2 || self.quorum > self.replicas {
+            return Err(format!("quorum must be a majority of {} replicas, not {}", self.replicas, self.quorum));
+        }
+        Ok(())
+    }
+}
+
+impl Default for VerificationPolicy {
+    fn default() -> Self {
+        Self::majority(3)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verdict {
+    Agreed,
+    Disagreed,
+    Failed,
+    // Produced an output, but no hash reached the quorum to judge it by
+    Inconclusive,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerificationReport {
+    // Hash the quorum agreed on, None if no hash reached it
+    pub output_hash: Option<String>,
+    pub verdicts: Vec<(String, Verdict)>,
+}
+
+impl VerificationReport {
+    pub fn verified(&self) -> bool {
+        self.output_hash.is_some()
+    }
+
+    pub fn peers_with(&self, verdict: Verdict) -> Vec<String> {
+        self.verdicts.iter()
+            .filter(|(_, v)| *v == verdict)
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Mapping,
+    Reducing,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job_id: String,
+    pub state: JobState,
+    pub total: usize,
+    pub succeeded: usize,
+    // Inputs whose map task ran out of attempts
+    pub failed: Vec<usize>,
+    pub retries: u32,
+    pub output: Option<Vec<u8>>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Completed | JobState::Failed)
+    }
+
+    // Share of map tasks done, successful or not
+    pub fn progress(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.succeeded + self.failed.len()) as f64 / self.total as f64
+    }
+}
+
+// Node profiles
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeCapabilities {
+    pub storage_gb: f64,
+    pub compute_flops: u64,
+    pub bandwidth_mbps: f64,
+    pub memory_gb: f64,
+    pub cpu_cores: u32,
+    pub gpu_memory_gb: f64,
+}
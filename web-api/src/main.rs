@@ -1,31 +1,71 @@
 use axum::{
-    routing::{post, get, delete},
+    routing::{post, get},
     http::{StatusCode, HeaderMap, HeaderValue, Method},
     Json, Router,
-    extract::{State, Path},
+    extract::{State, Path, Query},
     response::IntoResponse,
 };
 use tower_http::cors::{CorsLayer, Any};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::collections::HashMap;
 
 // Import our actual Rust crates
-use xmbl_storage::{StorageService, StorageShard};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use xmbl_storage::{StorageService, Cid, Keyring, EncryptionMode};
+use xmbl_storage::{encryption, lease};
+use xmbl_compute::{journal, modules, ComputeService, TaskJournal, TaskQuery, TaskRecord, TaskType, DEFAULT_PRIORITY};
+use xmbl_node_identity::{NodeIdentity, ReplayGuard, SignedEnvelope};
+use xmbl_network::{dht, handshake, Contact, SecureStream};
+use xmbl_protocol::{self as protocol, Hello, P2PMessage};
 
 #[derive(Clone)]
 struct AppState {
     storage: Arc<Mutex<StorageService>>,
+    keyring: Arc<Keyring>,
+    compute: Arc<Mutex<ComputeService>>,
+    identity: Arc<NodeIdentity>,
+    replay: Arc<std::sync::Mutex<ReplayGuard>>,
+}
+
+// Header carrying a SignedEnvelope over an ApiAuth, for requests made on
+// behalf of a particular node
+const AUTH_HEADER: &str = "x-xmbl-auth";
+
+// What a caller signs: the action and the shard it applies to (for compute,
+// the module's hash; for uploads, the CID of the data), so the signature
+// can't be reused for a different request
+#[derive(Serialize, Deserialize)]
+struct ApiAuth {
+    action: String,
+    shard_id: String,
+}
+
+// Node ID that signed this request, or None if it carries no signature.
+// A signature that fails to verify, or covers another request, is refused.
+fn authenticate(state: &AppState, headers: &HeaderMap, action: &str, shard_id: &str) -> Result<Option<String>, StatusCode> {
+    let Some(header) = headers.get(AUTH_HEADER) else { return Ok(None) };
+    let envelope: SignedEnvelope = header.to_str().ok()
+        .and_then(|value| serde_json::from_str(value).ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let (sender, auth): (String, ApiAuth) = envelope.open(&state.identity.node_id, &mut state.replay.lock().unwrap(), lease::now_secs())
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    if auth.action != action || auth.shard_id != shard_id {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(Some(sender))
 }
 
 #[derive(Deserialize)]
 struct FileUploadRequest {
+    #[allow(dead_code)]
     filename: String,
     data: Vec<u8>,
     redundancy: u8,
+    #[serde(default)]
+    encryption: Option<EncryptionMode>,
+    // Lease length in seconds; defaults to XMBL_LEASE_SECS, or no expiry
+    #[serde(default)]
+    lease_secs: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -34,6 +74,32 @@ struct FileUploadResponse {
     checksum: String,
     nodes: Vec<String>,
     message: String,
+    encrypted: bool,
+    // Only returned to the uploader, never stored alongside the shard
+    file_key: Option<String>,
+    owner: String,
+    lease_expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ComputeSubmitRequest {
+    wasm_bytes: Vec<u8>,
+    #[serde(default)]
+    input_data: Vec<u8>,
+}
+
+// Renewed for whoever signed the request
+#[derive(Deserialize)]
+struct LeaseRenewRequest {
+    // None makes the lease permanent
+    lease_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct QuotaResponse {
+    owner: String,
+    used_bytes: u64,
+    quota_bytes: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -52,50 +118,79 @@ struct FileInfo {
     checksum: String,
     redundancy: u8,
     timestamp: String,
+    owners: Vec<String>,
+    lease_expires_at: Option<u64>,
+}
+
+// CIDs share a common "bafkrei" prefix, so name files after the digest instead
+fn default_filename(shard_id: &str) -> String {
+    match shard_id.parse::<Cid>() {
+        Ok(cid) => format!("file_{}", &cid.digest_hex()[..8]),
+        Err(_) => format!("file_{}", shard_id.chars().take(8).collect::<String>()),
+    }
 }
 
 async fn upload_file(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<FileUploadRequest>,
 ) -> Result<Json<FileUploadResponse>, StatusCode> {
+    // The file is leased to, and counted against, whoever signed the upload;
+    // unsigned uploads belong to this node
+    let signer = authenticate(&state, &headers, "upload", &Cid::for_data(&payload.data).to_string())?;
     let mut storage = state.storage.lock().await;
+    let owner = signer.unwrap_or_else(|| storage.node_id.clone());
+    let lease_secs = payload.lease_secs.or(storage.default_lease_secs);
     
-    // Store the file data using real storage service
-    let shard_id = storage.store_data(payload.data.clone(), payload.redundancy)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    storage.check_quota(&owner, payload.data.len() as u64)
+        .map_err(|_| StatusCode::INSUFFICIENT_STORAGE)?;
+    
+    // Encrypt first if asked, so only ciphertext reaches storage
+    let (shard_id, file_key) = match payload.encryption {
+        Some(mode) => {
+            let (shard_id, key) = storage.store_encrypted_for(&owner, payload.data, payload.redundancy, lease_secs, &state.keyring, mode)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            (shard_id, Some(key.to_hex()))
+        }
+        None => {
+            let shard_id = storage.store_data_for(&owner, payload.data, payload.redundancy, lease_secs)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            (shard_id, None)
+        }
+    };
     
     // Get the shard to get checksum
-    let shard = storage.shards.get(&shard_id)
+    let shard = storage.get_shard_info(&shard_id)
         .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let lease_expires_at = shard.leases.last().and_then(|l| l.expires_at);
     
-    // Get available P2P nodes for redundancy
-    let p2p_nodes = vec![
-        "node_001".to_string(),
-        "node_002".to_string(),
-        "node_003".to_string(),
-        "node_004".to_string(),
-        "node_005".to_string(),
-        "node_006".to_string(),
-    ];
+    // The nodes actually holding the file, which so far is only this one
+    let nodes = vec![storage.node_id.clone()];
     
     Ok(Json(FileUploadResponse {
         shard_id,
-        checksum: shard.checksum.clone(),
-        nodes: p2p_nodes,
-        message: format!("File stored successfully with {}x redundancy on P2P swarm", payload.redundancy),
+        checksum: shard.checksum,
+        nodes,
+        message: format!("File stored successfully with {}x redundancy", payload.redundancy),
+        encrypted: file_key.is_some(),
+        file_key,
+        owner,
+        lease_expires_at,
     }))
 }
 
 async fn get_storage_stats(
     State(state): State<AppState>,
 ) -> Result<Json<StorageStatsResponse>, StatusCode> {
-    let storage = state.storage.lock().await;
-    
-    let (used_gb, total_gb) = storage.get_storage_stats();
-    let shard_count = storage.shards.len();
-    // P2P swarm has 6 nodes available
-    let available_nodes = 6;
+    let (used_gb, total_gb, shard_count) = {
+        let storage = state.storage.lock().await;
+        let (used_gb, total_gb) = storage.get_storage_stats();
+        (used_gb, total_gb, storage.shard_count())
+    };
+    // Whatever the DHT can find right now, without waiting on pings
+    let available_nodes = swarm_nodes(&state).await.len();
     
     Ok(Json(StorageStatsResponse {
         used_gb,
@@ -110,14 +205,26 @@ async fn list_files(
 ) -> Result<Json<Vec<FileInfo>>, StatusCode> {
     let storage = state.storage.lock().await;
     
-    let files: Vec<FileInfo> = storage.shards.values()
-        .map(|shard| FileInfo {
-            shard_id: shard.shard_id.clone(),
-            filename: format!("file_{}", shard.shard_id[..8].to_string()),
-            size_bytes: shard.data.len(),
-            checksum: shard.checksum.clone(),
-            redundancy: shard.redundancy,
-            timestamp: chrono::Utc::now().to_rfc3339(),
+    // Chunks and fragments are internal, list the files they belong to
+    let files: Vec<FileInfo> = storage.list_shards().into_iter()
+        .filter(|shard| !shard.internal)
+        .map(|shard| {
+            // Latest expiry across the file's leases, unless one never expires
+            let lease_expires_at = if shard.leases.iter().any(|l| l.expires_at.is_none()) {
+                None
+            } else {
+                shard.leases.iter().filter_map(|l| l.expires_at).max()
+            };
+            FileInfo {
+                filename: default_filename(&shard.shard_id),
+                size_bytes: storage.logical_size(&shard.shard_id).unwrap_or(shard.size_bytes) as usize,
+                owners: shard.owners(),
+                lease_expires_at,
+                shard_id: shard.shard_id,
+                checksum: shard.checksum,
+                redundancy: shard.redundancy,
+                timestamp: chrono::Utc::now().to_rfc3339(),
+            }
         })
         .collect();
     
@@ -126,41 +233,147 @@ async fn list_files(
 
 async fn delete_file(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     let shard_id = payload["shard_id"].as_str()
         .ok_or(StatusCode::BAD_REQUEST)?;
+    let owner = authenticate(&state, &headers, "delete", shard_id)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
     
     let mut storage = state.storage.lock().await;
     
-    storage.delete_data(shard_id)
+    // Only the signer's own lease is dropped; the file goes once none are left
+    storage.delete_data_for(&owner, shard_id)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| StatusCode::NOT_FOUND)?;
     
     Ok(Json(serde_json::json!({
         "message": "File deleted successfully",
-        "shard_id": shard_id
+        "shard_id": shard_id,
+        "owner": owner
     })))
 }
 
+async fn renew_lease(
+    State(state): State<AppState>,
+    Path(shard_id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<LeaseRenewRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let owner = authenticate(&state, &headers, "renew", &shard_id)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let mut storage = state.storage.lock().await;
+    
+    let lease_expires_at = storage.renew_lease(&shard_id, &owner, payload.lease_secs)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    
+    Ok(Json(serde_json::json!({
+        "message": "Lease renewed",
+        "shard_id": shard_id,
+        "owner": owner,
+        "lease_expires_at": lease_expires_at
+    })))
+}
+
+async fn get_quota(
+    State(state): State<AppState>,
+    Path(owner): Path<String>,
+) -> Result<Json<QuotaResponse>, StatusCode> {
+    let storage = state.storage.lock().await;
+    
+    Ok(Json(QuotaResponse {
+        used_bytes: storage.owner_usage(&owner),
+        quota_bytes: storage.quota_for(&owner),
+        owner,
+    }))
+}
+
+async fn submit_compute_task(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ComputeSubmitRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    // Tasks run on this node's workers, so only signed submitters get them
+    let module_hash = modules::module_hash(&request.wasm_bytes);
+    let submitter = authenticate(&state, &headers, "compute", &module_hash)
+        .map_err(|status| (status, "Invalid compute signature".to_string()))?
+        .ok_or((StatusCode::UNAUTHORIZED, "Compute submissions must be signed".to_string()))?;
+    let mut compute = state.compute.lock().await;
+    
+    match compute.submit(request.wasm_bytes, request.input_data, TaskType::WASM, &submitter, DEFAULT_PRIORITY).await {
+        Ok(task_id) => {
+            println!("⚡ Compute task {} submitted by {}", task_id, submitter);
+            Ok(Json(serde_json::json!({ "task_id": task_id, "submitter": submitter })))
+        }
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+}
+
+async fn get_compute_task(
+    State(state): State<AppState>,
+    Path(task_id): Path<String>,
+) -> Result<Json<TaskRecord>, StatusCode> {
+    let compute = state.compute.lock().await;
+    compute.journal.get(&task_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Filter with ?submitter=&status=&since=&until=&limit=
+async fn list_compute_tasks(
+    State(state): State<AppState>,
+    Query(query): Query<TaskQuery>,
+) -> Json<Vec<TaskRecord>> {
+    Json(state.compute.lock().await.task_history(&query))
+}
+
+// A node counts as online if it answers a signed ping with a signed reply
+async fn ping_node(identity: &NodeIdentity, address: &str) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let ping = P2PMessage::Ping { from: identity.node_id.clone(), timestamp: now };
+    let replay = std::sync::Mutex::new(ReplayGuard::default());
+    let mut stream = SecureStream::connect(address, identity).await?;
+    handshake::greet(&mut stream, identity, &Hello::client(&identity.node_id), &replay).await?;
+    let to = stream.remote_node_id().to_string();
+    stream.write_json(&protocol::seal(identity, &to, &ping, now)?).await?;
+    let reply: SignedEnvelope = stream.read_json().await?
+        .ok_or_else(|| anyhow::anyhow!("Node closed the connection"))?;
+    let (sender, reply) = protocol::open(&reply, &identity.node_id, &mut replay.lock().unwrap(), now)?;
+    if sender != stream.remote_node_id() {
+        return Err(anyhow::anyhow!("Reply signed by {} on a connection with {}", sender, stream.remote_node_id()));
+    }
+    if !matches!(reply, P2PMessage::Pong { .. }) {
+        return Err(anyhow::anyhow!("Node answered a ping with {:?}", reply));
+    }
+    Ok(())
+}
+
+// Signed requests are addressed to this node ID
+async fn get_identity(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "node_id": state.identity.node_id }))
+}
+
+// The nodes a DHT lookup from the seeds turns up
+async fn swarm_nodes(state: &AppState) -> Vec<Contact> {
+    dht::discover(Arc::clone(&state.identity), &dht::seeds_from_env()).await.closest
+}
+
 async fn get_network_status(
-    _state: State<AppState>,
+    State(state): State<AppState>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Directly check P2P swarm status by connecting to nodes
-    let p2p_nodes = vec![
-        ("node_001", "127.0.0.1:3010"),
-        ("node_002", "127.0.0.1:3011"),
-        ("node_003", "127.0.0.1:3012"),
-        ("node_004", "127.0.0.1:3013"),
-        ("node_005", "127.0.0.1:3014"),
-        ("node_006", "127.0.0.1:3015"),
-    ];
+    // Find the swarm through the DHT, then check each node directly
+    let p2p_nodes: Vec<(String, String)> = swarm_nodes(&state).await.into_iter()
+        .map(|c| (c.node_id, c.address))
+        .collect();
     
     let mut online_nodes = 0;
     let mut available_nodes = 0;
     
-    for (node_id, address) in &p2p_nodes {
-        if let Ok(_stream) = TcpStream::connect(*address).await {
+    for (_node_id, address) in &p2p_nodes {
+        let ping = tokio::time::timeout(std::time::Duration::from_secs(2), ping_node(&state.identity, address));
+        if let Ok(Ok(())) = ping.await {
             online_nodes += 1;
             available_nodes += 1;
         }
@@ -179,14 +392,30 @@ async fn get_network_status(
 async fn download_file(
     State(state): State<AppState>,
     Path(shard_id): Path<String>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
+    let caller = authenticate(&state, &headers, "download", &shard_id)?;
     let storage = state.storage.lock().await;
+    let meta = storage.get_shard_info(&shard_id).ok_or(StatusCode::NOT_FOUND)?;
     
     // Try to retrieve the file data
-    match storage.retrieve_data(&shard_id).await {
+    let data = if meta.encryption.is_some() {
+        // Our keyring opens every file encrypted here, so only an owner
+        // who signed the request gets the plaintext
+        let caller = caller.ok_or(StatusCode::UNAUTHORIZED)?;
+        if !meta.owners().contains(&caller) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        storage.retrieve_decrypted(&shard_id, &state.keyring).await
+    } else {
+        storage.retrieve_data(&shard_id).await
+    };
+    
+    match data {
         Ok(data) => {
+            
             // Create a response with the file data
-            let filename = format!("file_{}", &shard_id[..8]);
+            let filename = default_filename(&shard_id);
             
             let mut headers = HeaderMap::new();
             headers.insert("Content-Type", HeaderValue::from_static("application/octet-stream"));
@@ -198,15 +427,72 @@ async fn download_file(
     }
 }
 
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
 #[tokio::main]
 async fn main() {
-    // Initialize real storage service
-    let storage = Arc::new(Mutex::new(StorageService::new(
+    // Initialize real storage service, persisted under XMBL_DATA_DIR
+    let data_dir = std::env::var("XMBL_DATA_DIR")
+        .unwrap_or_else(|_| "data/web_api_node".to_string());
+    let mut storage = StorageService::open(
         "web_api_node".to_string(),
-        100.0 // 100GB storage
-    )));
+        100.0, // 100GB storage
+        &data_dir,
+    ).expect("Failed to open storage directory");
+    storage.default_lease_secs = env_u64("XMBL_LEASE_SECS");
+    storage.default_quota_bytes = env_u64("XMBL_QUOTA_BYTES");
+    let storage = Arc::new(Mutex::new(storage));
+    
+    // Sweep expired leases in the background
+    let gc_storage = Arc::clone(&storage);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let now = journal::now_secs();
+            match gc_storage.lock().await.collect_garbage(now) {
+                Ok(reclaimed) if !reclaimed.is_empty() => {
+                    println!("🧹 Reclaimed {} files with expired leases", reclaimed.len());
+                }
+                Ok(_) => {}
+                Err(e) => println!("❌ Lease garbage collection failed: {}", e),
+            }
+        }
+    });
+    
+    // Encryption keys derive from this node's persisted identity
+    let identity = NodeIdentity::load_or_create(std::path::Path::new(&data_dir).join("identity.key"))
+        .expect("Failed to load node identity");
+    let keyring = Arc::new(Keyring::new(identity.derive_key(encryption::KEY_CONTEXT)));
+    
+    // Compute tasks run on a local worker pool; their history survives restarts
+    let mut compute = ComputeService::new("web_api_node".to_string(), 2);
+    compute.journal = TaskJournal::open(std::path::Path::new(&data_dir).join("tasks.jsonl"))
+        .expect("Failed to open task journal");
+    let compute = Arc::new(Mutex::new(compute));
+    ComputeService::spawn_workers(&compute).await;
+    
+    let prune_compute = Arc::clone(&compute);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let now = journal::now_secs();
+            if let Err(e) = prune_compute.lock().await.prune_history(now) {
+                println!("❌ Task journal pruning failed: {}", e);
+            }
+        }
+    });
     
-    let state = AppState { storage };
+    let state = AppState {
+        storage,
+        keyring,
+        compute,
+        identity: Arc::new(identity),
+        replay: Arc::new(std::sync::Mutex::new(ReplayGuard::default())),
+    };
     
     // Build our application with a route
     let cors = CorsLayer::new()
@@ -220,15 +506,21 @@ async fn main() {
         .route("/api/files", get(list_files))
         .route("/api/files/:shard_id/download", get(download_file))
         .route("/api/files/delete", post(delete_file))
+        .route("/api/files/:shard_id/lease", post(renew_lease))
+        .route("/api/quota/:owner", get(get_quota))
+        .route("/api/compute/submit", post(submit_compute_task))
+        .route("/api/compute/tasks", get(list_compute_tasks))
+        .route("/api/compute/tasks/:task_id", get(get_compute_task))
         .route("/api/network/status", get(get_network_status))
+        .route("/api/identity", get(get_identity))
         .layer(cors)
         .with_state(state);
     
     // Run it
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3200").await.unwrap();
     println!("🚀 P2P Swarm Web API running on http://127.0.0.1:3200");
-    println!("📁 Real storage service: ACTIVE");
-    println!("🌐 P2P Swarm connection: ACTIVE (6 nodes on ports 3010-3015)");
+    println!("📁 Real storage service: ACTIVE ({})", data_dir);
+    println!("🌐 P2P Swarm seeds: {}", dht::seeds_from_env().join(", "));
     
     axum::serve(listener, app).await.unwrap();
 }